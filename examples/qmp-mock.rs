@@ -0,0 +1,55 @@
+//! Serves a fake QMP socket, for trying out the `qmp` command without a VM
+//!
+//! ```sh
+//! cargo run --example qmp-mock -- /tmp/qmp.sock --device net0=e1000 &
+//! qmp -s /tmp/qmp.sock del-device --wait net0
+//! ```
+
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
+use qemucomm::Pair;
+use qemucomm::mock::{MockQmp, Reply};
+
+#[derive(Parser, Debug)]
+struct Cli {
+	/// path of the unix socket to listen on
+	socket: PathBuf,
+	/// a device that already exists in the peripheral tree
+	#[arg(short, long, value_name = "ID=DRIVER")]
+	device: Vec<Pair<String, String>>,
+	/// always answer COMMAND with a canned JSON return value
+	#[arg(short, long, value_name = "COMMAND=JSON")]
+	reply: Vec<Pair<String, String>>,
+	/// how long the guest takes to release unplugged devices
+	#[arg(short, long, value_name = "MILLISECONDS", default_value_t = 0)]
+	unplug_delay: u64,
+	/// the guest never releases unplugged devices
+	#[arg(long, conflicts_with = "unplug_delay")]
+	no_unplug: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+	::env_logger::init();
+
+	let args = Cli::parse();
+
+	let mut mock = MockQmp::new()
+		.with_unplug_delay(match args.no_unplug {
+			true => None,
+			false => Some(Duration::from_millis(args.unplug_delay)),
+		});
+	for Pair { key, value } in args.device {
+		mock = mock.with_device(key, value);
+	}
+	for Pair { key, value } in args.reply {
+		mock = mock.with_reply(key, Reply::Return(serde_json::from_str(&value)?));
+	}
+
+	let _ = std::fs::remove_file(&args.socket);
+	mock.listen(&args.socket)?.await?;
+
+	Ok(())
+}
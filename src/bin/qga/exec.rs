@@ -1,6 +1,7 @@
 use anyhow::Result;
 use qapi::qga;
 use clap::Parser;
+use serde::Serialize;
 use futures::future::{FutureExt, Fuse};
 use futures::stream::StreamExt;
 use futures::{pin_mut, select};
@@ -9,7 +10,9 @@ use std::time::Duration;
 use std::path::PathBuf;
 use std::fs::File;
 use std::io::{self, Read};
-use super::{GlobalArgs, QgaStream};
+use qemucomm::OutputFormat;
+use qemucomm::qga::Client;
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 /// Executes a process inside the guest
@@ -28,7 +31,7 @@ pub(crate) struct Exec {
 }
 
 fn guest_kill(pid: i64, os: qga::GuestOSInfo, force: bool) -> qga::guest_exec {
-	let os_id = os.id.as_ref().map(String::as_str).unwrap_or("");
+	let os_id = os.id.as_deref().unwrap_or("");
 	match os_id {
 		"mswindows" => qga::guest_exec {
 			path: "powershell.exe".into(),
@@ -57,8 +60,16 @@ fn guest_kill(pid: i64, os: qga::GuestOSInfo, force: bool) -> qga::guest_exec {
 
 const SIGINT: i32 = 2;
 
+/// The structured output of `exec`, with all output collected
+#[derive(Serialize, Debug)]
+struct ExecOutput {
+	pid: i64,
+	#[serde(flatten)]
+	status: qga::GuestExecStatus,
+}
+
 impl Exec {
-	pub async fn run(self, qga: QgaStream, _args: GlobalArgs) -> Result<i32> {
+	pub async fn run(self, qga: &Client, args: GlobalArgs) -> Result<i32> {
 		let guest_exec = qga::guest_exec {
 			path: self.path,
 			arg: Some(self.arguments),
@@ -79,8 +90,11 @@ impl Exec {
 		log::trace!("QEMU GA PID {:?}", pid);
 
 		if self.no_wait {
+			args.output().print(&qga::GuestExec { pid })?;
 			return Ok(0)
 		}
+		let mut out = Vec::new();
+		let mut err = Vec::new();
 
 		let ctrlc = StreamExt::fuse(async_ctrlc::CtrlC::new().expect("ctrl+c"));
 		let mut ctrlc_counter = 0u8;
@@ -93,15 +107,17 @@ impl Exec {
 		let status = loop {
 			select! {
 				_ = ctrlc.next() => {
+					// an in-flight status request holds the agent until it's abandoned
+					status.set(Fuse::terminated());
 					ctrlc_counter = ctrlc_counter.saturating_add(1);
 					match ctrlc_counter {
 						1 => {
-							let os_info = qga.execute(qga::guest_get_osinfo { }).await?;
+							let os_info = qga.osinfo().await?;
 							qga.execute(guest_kill(pid, os_info, false)).await?;
 							timeout.set(sleep(Duration::from_millis(1)).fuse());
 						},
 						2 => {
-							let os_info = qga.execute(qga::guest_get_osinfo { }).await?;
+							let os_info = qga.osinfo().await?;
 							qga.execute(guest_kill(pid, os_info, true)).await?;
 							timeout.set(sleep(Duration::from_millis(1)).fuse());
 						},
@@ -117,11 +133,19 @@ impl Exec {
 					let status = status?;
 					timeout.set(sleep(Duration::from_millis(5)).fuse());
 
-					if let Some(err) = &status.err_data {
-						io::copy(&mut &err[..], &mut io::stderr())?;
-					}
-					if let Some(out) = &status.out_data {
-						io::copy(&mut &out[..], &mut io::stdout())?;
+					match args.output() {
+						OutputFormat::Human => {
+							if let Some(err) = &status.err_data {
+								io::copy(&mut &err[..], &mut io::stderr())?;
+							}
+							if let Some(out) = &status.out_data {
+								io::copy(&mut &out[..], &mut io::stdout())?;
+							}
+						},
+						_ => {
+							err.extend(status.err_data.iter().flatten());
+							out.extend(status.out_data.iter().flatten());
+						},
 					}
 
 					if status.exited {
@@ -139,6 +163,17 @@ impl Exec {
 			log::warn!("STDOUT truncated")
 		}
 
+		if args.output() != OutputFormat::Human {
+			args.output().print(&ExecOutput {
+				pid,
+				status: qga::GuestExecStatus {
+					out_data: Some(out),
+					err_data: Some(err),
+					..status.clone()
+				},
+			})?;
+		}
+
 		match status.exited {
 			false => Ok(1),
 			true => match (status.exitcode, status.signal) {
@@ -1,13 +1,13 @@
-use anyhow::{Result, anyhow};
-use futures::Future;
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
 use qapi::qga;
-use log::{debug, warn};
-use bytes::BytesMut;
-use tokio::{time::{timeout, Duration}, io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt}};
+use serde::Serialize;
+use tokio::time::{timeout, Duration};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use clap::{Parser, ValueEnum};
-use super::{GlobalArgs, QgaStream};
-
-type QgaResult<T> = std::result::Result<T, qapi::ExecuteError>;
+use qemucomm::OutputFormat;
+use qemucomm::qga::{Client, QgaFile};
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 /// Write a file to the guest filesystem
@@ -24,8 +24,8 @@ pub(crate) struct FileOpen {
 }
 
 impl FileOpen {
-	pub async fn open<'q>(self, qga: &'q QgaStream) -> Result<QgaFile<'q>> {
-		let file = QgaFile::open(qga, self.path, self.mode).await?;
+	pub async fn open(self, qga: &Client) -> Result<QgaFile> {
+		let file = qga.file_open(self.path, self.mode).await?;
 
 		if let Some(offset) = self.offset {
 			if let Err(e) = file.seek(self.seek.into(), offset).await {
@@ -37,12 +37,12 @@ impl FileOpen {
 		Ok(file)
 	}
 
-	pub async fn run<'q, O: FnOnce(QgaFile<'q>) -> F, F: Future<Output=Result<i32>> + 'q>(self, qga: &'q QgaStream, _args: GlobalArgs, op: O) -> Result<i32> {
+	pub async fn run<T, O: FnOnce(&mut QgaFile) -> BoxFuture<'_, Result<T>>>(self, qga: &Client, op: O) -> Result<T> {
 		let duration = Duration::from_secs(self.timeout_seconds);
 		let res = async move {
-			let file = self.open(qga).await?;
+			let mut file = self.open(qga).await?;
 
-			let res = op(file.clone()).await;
+			let res = op(&mut file).await;
 			let _ = file.close().await;
 			res
 		};
@@ -61,14 +61,29 @@ pub(crate) struct WriteFile {
 	open: FileOpen,
 }
 
-impl WriteFile {
-	pub async fn run(self, qga: QgaStream, args: GlobalArgs) -> Result<i32> {
-		self.open.run(&qga, args, |file| async move {
-			let read = tokio::io::stdin();
-			file.write_from(read).await?;
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct WriteOutput {
+	path: String,
+	handle: i64,
+	bytes_written: u64,
+}
 
-			Ok(0)
-		}).await
+impl WriteFile {
+	pub async fn run(self, qga: &Client, args: GlobalArgs) -> Result<i32> {
+		let path = self.open.path.clone();
+		let written = self.open.run(qga, |file| async move {
+			let bytes_written = io::copy(&mut io::stdin(), file).await?;
+
+			Ok(WriteOutput {
+				path,
+				handle: file.handle(),
+				bytes_written,
+			})
+		}.boxed()).await?;
+
+		args.output().print(&written)?;
+		Ok(0)
 	}
 }
 
@@ -79,103 +94,46 @@ pub(crate) struct ReadFile {
 	open: FileOpen,
 }
 
-impl ReadFile {
-	pub async fn run(self, qga: QgaStream, args: GlobalArgs) -> Result<i32> {
-		self.open.run(&qga, args, |file| async move {
-			let write = tokio::io::stdout();
-			file.read_to(write).await?;
-
-			Ok(0)
-		}).await
-	}
-}
-
-#[derive(Clone)]
-pub struct QgaFile<'q> {
-	qga: &'q QgaStream,
+/// The structured output of `read-file`, with the contents in base64 as `guest-file-read` does
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct ReadOutput {
+	path: String,
 	handle: i64,
+	bytes_read: u64,
+	#[serde(serialize_with = "serialize_base64")]
+	buf_b64: Vec<u8>,
 }
 
-impl<'q> QgaFile<'q> {
-	pub async fn open<P: Into<String>>(qga: &'q QgaStream, path: P, mode: Option<String>) -> QgaResult<QgaFile<'q>> {
-		let path = path.into();
-		debug!("opening guest path {:?}", path);
-		let handle = qga.execute(qga::guest_file_open {
-			path,
-			mode,
-		}).await?;
-		debug!("opened guest file:{}", handle);
-		Ok(QgaFile {
-			qga,
-			handle,
-		})
-	}
-
-	pub async fn close(self) -> QgaResult<()> {
-		let res = self.qga.execute(qga::guest_file_close {
-			handle: self.handle,
-		}).await;
-		if let Err(e) = &res {
-			warn!("failed to close guest file: {e}");
-		}
-		res.map(drop)
-	}
-
-	pub async fn seek(&self, whence: qga::QGASeek, offset: i64) -> QgaResult<qga::GuestFileSeek> {
-		let res = self.qga.execute(qga::guest_file_seek {
-			handle: self.handle,
-			offset,
-			whence: qga::GuestFileWhence::name(whence),
-		}).await?;
-		debug!("seeked file:{} to {}{}", self.handle, res.position, if res.eof { " (EOF)" } else { "" });
-		Ok(res)
-	}
-
-	pub async fn write_buf<B: Into<Vec<u8>>>(&self, buf: B) -> QgaResult<qga::GuestFileWrite> {
-		let buf = buf.into();
-		self.qga.execute(qga::guest_file_write {
-			handle: self.handle,
-			count: Some(buf.len() as i64),
-			buf_b64: buf,
-		}).await
-	}
-
-	pub async fn read_buf(&self, len: Option<usize>) -> QgaResult<qga::GuestFileRead> {
-		self.qga.execute(qga::guest_file_read {
-			handle: self.handle,
-			count: len.and_then(|len| len.try_into().ok()),
-		}).await
-	}
+fn serialize_base64<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.serialize_str(&base64::encode(data))
+}
 
-	pub async fn write_from<R: AsyncRead + Unpin>(&self, mut read: R) -> Result<u64> {
-		let mut buf = BytesMut::with_capacity(0x1000);
-		let mut total = 0u64;
-		loop {
-			match read.read_buf(&mut buf).await? {
-				0 if buf.is_empty() => break,
-				_ => (),
+impl ReadFile {
+	pub async fn run(self, qga: &Client, args: GlobalArgs) -> Result<i32> {
+		let path = self.open.path.clone();
+		self.open.run(qga, |file| async move {
+			match args.output() {
+				OutputFormat::Human => {
+					let mut stdout = io::stdout();
+					io::copy(file, &mut stdout).await?;
+					stdout.flush().await?;
+					Ok(())
+				},
+				format => {
+					let mut buf_b64 = Vec::new();
+					file.read_to_end(&mut buf_b64).await?;
+					format.print(&ReadOutput {
+						path,
+						handle: file.handle(),
+						bytes_read: buf_b64.len() as u64,
+						buf_b64,
+					})
+				},
 			}
-			let written = match self.write_buf(&buf[..]).await?.count {
-				w if w <= 0 => return Err(anyhow!("Guest wrote {w} bytes")),
-				w => w as usize,
-			};
-			total = total.saturating_add(written as u64);
-			let _ = buf.split_to(written);
-		}
-		Ok(total)
-	}
+		}.boxed()).await?;
 
-	pub async fn read_to<W: AsyncWrite + Unpin>(&self, mut write: W) -> Result<u64> {
-		let mut total = 0u64;
-		loop {
-			let read = self.read_buf(None).await?;
-			write.write_all(&read.buf_b64).await?;
-			total = total.saturating_add(read.count as u64);
-			if read.eof || read.count == 0 {
-				break
-			}
-		}
-		Ok(total)
+		Ok(0)
 	}
 }
 
@@ -185,9 +143,9 @@ pub enum SeekWhence {
 	End,
 }
 
-impl Into<qga::QGASeek> for SeekWhence {
-	fn into(self) -> qga::QGASeek {
-		match self {
+impl From<SeekWhence> for qga::QGASeek {
+	fn from(whence: SeekWhence) -> Self {
+		match whence {
 			SeekWhence::Beginning => qga::QGASeek::set,
 			SeekWhence::End => qga::QGASeek::end,
 		}
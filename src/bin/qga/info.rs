@@ -1,8 +1,10 @@
 use anyhow::Result;
-use qapi::qga;
 use clap::Parser;
+use qapi::qga;
+use serde::Serialize;
 use tokio::time::{Duration, timeout};
-use super::{GlobalArgs, QgaStream};
+use qemucomm::qga::Client;
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 /// Displays information about the guest, and can be used to check that the guest agent is running
@@ -11,15 +13,22 @@ pub(crate) struct Info {
 	os_info: bool,
 }
 
-impl Info {
-	pub async fn run(self, qga: QgaStream, _args: GlobalArgs) -> Result<i32> {
-		let info = qga.execute(qga::guest_info { }).await?;
-		println!("Guest Info: {:#?}", info);
+#[derive(Serialize, Debug)]
+struct InfoOutput {
+	#[serde(flatten)]
+	info: qga::GuestAgentInfo,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	os: Option<qga::GuestOSInfo>,
+}
 
-		if self.os_info {
-			let osinfo = qga.execute(qga::guest_get_osinfo { }).await?;
-			println!("Guest OS Info: {:#?}", osinfo);
-		}
+impl Info {
+	pub async fn run(self, qga: &Client, args: GlobalArgs) -> Result<i32> {
+		let info = qga.info().await?;
+		let os = match self.os_info {
+			true => Some(qga.osinfo().await?),
+			false => None,
+		};
+		args.output().print(&InfoOutput { info, os })?;
 
 		Ok(0)
 	}
@@ -39,16 +48,15 @@ impl Ping {
 		self.timeout_seconds.map(Duration::from_secs)
 	}
 
-	pub async fn run(self, qga: QgaStream, args: GlobalArgs) -> Result<i32> {
+	pub async fn run(self, qga: &Client, args: GlobalArgs) -> Result<i32> {
+		let output = args.output();
 		let duration = self.timeout();
 		if self.repeat {
 			qemucomm::wait(duration, async move {
-				let mut sync_value = 0;
 				loop {
-					match timeout(Duration::from_secs(1), self.ping(&qga, &args)).await {
+					match timeout(Duration::from_secs(1), self.ping(qga, &args)).await {
 						Err(_) => {
-							let _ = timeout(Duration::from_secs(1), qga.guest_sync(sync_value)).await;
-							sync_value = sync_value.wrapping_add(1);
+							let _ = timeout(Duration::from_secs(1), qga.sync()).await;
 						},
 						Ok(Err(e)) => break Err(e),
 						Ok(Ok(())) => break Ok(()),
@@ -56,13 +64,13 @@ impl Ping {
 				}
 			}).await?;
 		} else {
-			qemucomm::wait(duration, self.ping(&qga, &args)).await?;
+			qemucomm::wait(duration, self.ping(qga, &args)).await?;
 		}
+		output.print_done()?;
 		Ok(0)
 	}
 
-	async fn ping(&self, qga: &QgaStream, _args: &GlobalArgs) -> Result<()> {
-		qga.execute(qga::guest_ping { }).await?;
-		Ok(())
+	async fn ping(&self, qga: &Client, _args: &GlobalArgs) -> Result<()> {
+		qga.ping().await
 	}
 }
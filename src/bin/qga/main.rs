@@ -1,30 +1,39 @@
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
-use tokio::task::JoinHandle;
-use tokio::time::timeout;
 use std::time::Duration;
-use std::path::PathBuf;
+use qemucomm::{SocketAddr, OutputFormat};
+use qemucomm::qga::Client;
 
 mod exec;
 mod file;
 mod info;
 mod shutdown;
 
-pub(crate) type QgaStream = qapi::futures::QapiService<qapi::futures::QgaStreamTokio<tokio::io::WriteHalf<tokio::net::UnixStream>>>;
-
 #[derive(Args, Debug)]
 pub(crate) struct GlobalArgs {
+	/// output format: human, json, or yaml [default: human]
+	#[arg(long, global = true)]
+	output: Option<OutputFormat>,
+}
+
+impl GlobalArgs {
+	pub fn output(&self) -> OutputFormat {
+		self.output.unwrap_or(OutputFormat::Human)
+	}
 }
 
 #[derive(Args, Debug)]
 pub(crate) struct ConnectionArgs {
-	/// QEMU guest agent socket path
+	/// QEMU guest agent socket: PATH, unix:PATH, unix-abstract:NAME (or @NAME), tcp:HOST:PORT, or fd:N
 	#[arg(short, long, env("QEMUCOMM_QGA_SOCKET_PATH"))]
-	socket: PathBuf,
+	socket: SocketAddr,
 	#[arg(long, short = 'S')]
 	no_sync: bool,
 	#[arg(short, long)]
 	wait: bool,
+	/// listen on the socket and wait for QEMU to connect to it
+	#[arg(short, long, conflicts_with = "wait")]
+	listen: bool,
 	#[arg(short, long = "timeout")]
 	timeout_seconds: Option<u64>,
 }
@@ -58,24 +67,18 @@ async fn main() -> Result<()> {
 
 	let args = Cli::parse();
 
-	let (qga, handle) = args.connection.connect().await?;
-
-	let sync_value = &qga as *const _ as usize as i32;
-	qga.guest_sync(sync_value).await?;
+	let qga = args.connection.connect().await?;
 
 	let res = match args.command {
-		Command::Ping(c) => c.run(qga, args.args).await,
-		Command::Info(c) => c.run(qga, args.args).await,
-		Command::Exec(c) => c.run(qga, args.args).await,
-		Command::ReadFile(c) => c.run(qga, args.args).await,
-		Command::WriteFile(c) => c.run(qga, args.args).await,
-		Command::Shutdown(c) => c.run(qga, args.args).await,
+		Command::Ping(c) => c.run(&qga, args.args).await,
+		Command::Info(c) => c.run(&qga, args.args).await,
+		Command::Exec(c) => c.run(&qga, args.args).await,
+		Command::ReadFile(c) => c.run(&qga, args.args).await,
+		Command::WriteFile(c) => c.run(&qga, args.args).await,
+		Command::Shutdown(c) => c.run(&qga, args.args).await,
 	};
 
-	match timeout(Duration::from_secs(1), handle).await {
-		Err(_elapsed) => log::warn!("timed out waiting for handle to clean up"),
-		Ok(res) => res?,
-	}
+	qga.close().await;
 
 	match res {
 		Err(e) => Err(e),
@@ -88,25 +91,24 @@ async fn main() -> Result<()> {
 
 impl ConnectionArgs {
 	fn timeout(&self) -> Option<Option<Duration>> {
-		match self.wait {
+		match self.wait || self.listen {
 			true => Some(self.timeout_seconds.map(Duration::from_secs)),
 			false => None,
 		}
 	}
 
-	async fn connect(&self) -> Result<(QgaStream, JoinHandle<()>)> {
-		if let Some(timeout) = self.timeout() {
-			qemucomm::wait(timeout, qemucomm::wait_for_socket(&self.socket)).await?;
-		}
-
-		let stream = qapi::futures::QgaStreamTokio::open_uds(&self.socket).await?;
-		let (qga, handle) = stream.spawn_tokio();
+	async fn connect(&self) -> Result<Client> {
+		let stream = match self.timeout() {
+			Some(timeout) if self.listen => qemucomm::wait(timeout, self.socket.accept()).await?,
+			Some(timeout) => qemucomm::wait(timeout, self.socket.wait_connect()).await?,
+			None => self.socket.connect().await?,
+		};
+		let qga = Client::new(stream);
 
 		if !self.no_sync {
-			let sync_value = &qga as *const _ as usize as i32;
-			qga.guest_sync(sync_value).await?;
+			qga.sync().await?;
 		}
 
-		Ok((qga, handle))
+		Ok(qga)
 	}
 }
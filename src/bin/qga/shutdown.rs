@@ -3,7 +3,8 @@ use qapi::qga;
 use log::warn;
 use tokio::time::{timeout, Duration};
 use clap::{Parser, ValueEnum};
-use super::{GlobalArgs, QgaStream};
+use qemucomm::qga::Client;
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 /// Tells the guest to initiate a system shutdown
@@ -13,16 +14,15 @@ pub(crate) struct Shutdown {
 }
 
 impl Shutdown {
-	pub async fn run(self, qga: QgaStream, _args: GlobalArgs) -> Result<i32> {
-		let cmd = qga.execute(qga::guest_shutdown {
-			mode: Some(self.mode.into()),
-		});
+	pub async fn run(self, qga: &Client, args: GlobalArgs) -> Result<i32> {
+		let cmd = qga.shutdown(Some(self.mode.into()));
 
 		match timeout(Duration::from_secs(1), cmd).await {
-			Ok(res) => res.map(drop)?,
+			Ok(res) => res?,
 			Err(_) => warn!("Shutdown response timed out"),
 		}
 
+		args.output().print_done()?;
 		Ok(0)
 	}
 }
@@ -35,9 +35,9 @@ pub enum Mode {
 	Reboot,
 }
 
-impl Into<qga::GuestShutdownMode> for Mode {
-	fn into(self) -> qga::GuestShutdownMode {
-		match self {
+impl From<Mode> for qga::GuestShutdownMode {
+	fn from(mode: Mode) -> Self {
+		match mode {
 			Mode::Reboot => qga::GuestShutdownMode::Reboot,
 			Mode::Powerdown => qga::GuestShutdownMode::Powerdown,
 			Mode::Halt => qga::GuestShutdownMode::Halt,
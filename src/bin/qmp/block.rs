@@ -0,0 +1,55 @@
+use anyhow::{Result, format_err};
+use qapi::qmp;
+use qemucomm::qmp::Client;
+
+pub(crate) type Drive = qmp::BlockInfo;
+
+pub(crate) trait DriveExt {
+	/// The device id (or QOM path) when there is one, which newer commands require
+	fn name(&self) -> &str;
+	fn id(&self) -> Result<String>;
+	/// The `device` and `id` arguments that name it to commands
+	fn target(&self) -> (Option<String>, Option<String>);
+	fn tray(&self) -> &'static str;
+}
+
+impl DriveExt for Drive {
+	fn name(&self) -> &str {
+		self.qdev.as_deref().unwrap_or(&self.device)
+	}
+
+	fn id(&self) -> Result<String> {
+		self.qdev.clone()
+			.ok_or_else(|| format_err!("drive {} isn't attached to a device", self.device))
+	}
+
+	fn target(&self) -> (Option<String>, Option<String>) {
+		match &self.qdev {
+			Some(id) => (None, Some(id.clone())),
+			None => (Some(self.device.clone()), None),
+		}
+	}
+
+	fn tray(&self) -> &'static str {
+		match self.tray_open {
+			Some(true) => "open",
+			Some(false) => "closed",
+			None => "-",
+		}
+	}
+}
+
+pub(crate) async fn drives(qmp: &Client) -> Result<Vec<Drive>> {
+	qmp.execute(qmp::query_block { }).await.map_err(Into::into)
+}
+
+pub(crate) async fn find_drive(qmp: &Client, name: &str) -> Result<Drive> {
+	let matches = |drive: &Drive| drive.device == name || match drive.qdev.as_deref() {
+		// qdev is a QOM path for devices without an id
+		Some(qdev) => qdev == name || qdev.rsplit('/').next() == Some(name),
+		None => false,
+	};
+	drives(qmp).await?.into_iter()
+		.find(matches)
+		.ok_or_else(|| format_err!("drive {} not found", name))
+}
@@ -1,15 +1,16 @@
 use anyhow::Result;
 use clap::Parser;
-use qapi::qmp;
-use super::{GlobalArgs, QmpStream};
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 pub(crate) struct StopCommand {
 }
 
 impl StopCommand {
-	pub async fn run(self, qmp: QmpStream, _args: GlobalArgs) -> Result<i32> {
-		qmp.execute(qmp::stop { }).await?;
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		qmp.stop().await?;
+		args.output().print_done()?;
 		Ok(0)
 	}
 }
@@ -19,8 +20,9 @@ pub(crate) struct ContinueCommand {
 }
 
 impl ContinueCommand {
-	pub async fn run(self, qmp: QmpStream, _args: GlobalArgs) -> Result<i32> {
-		qmp.execute(qmp::cont { }).await?;
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		qmp.cont().await?;
+		args.output().print_done()?;
 		Ok(0)
 	}
 }
@@ -30,8 +32,9 @@ pub(crate) struct QuitCommand {
 }
 
 impl QuitCommand {
-	pub async fn run(self, qmp: QmpStream, _args: GlobalArgs) -> Result<i32> {
-		qmp.execute(qmp::quit { }).await?;
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		qmp.quit().await?;
+		args.output().print_done()?;
 		Ok(0)
 	}
 }
@@ -1,18 +1,21 @@
-use anyhow::{Result, Error, format_err};
+use anyhow::Result;
 use clap::Parser;
 use qapi::qmp;
-use tokio::time::{Duration, sleep, sleep_until};
-use tokio::sync::broadcast;
-use futures::{TryFutureExt, future};
-use qemucomm::Pair;
-use super::{GlobalArgs, QmpStream};
+use serde::Serialize;
+use qemucomm::{Pair, Key, keyval_dict};
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 pub(crate) struct AddDevice {
 	#[clap(short, long)]
 	id: Option<String>,
 	driver: String,
-	arguments: Vec<Pair<String, String>>,
+	/// device properties
+	///
+	/// Values are typed according to the properties of the driver, and can be
+	/// overridden with an explicit `KEY:str=value` (or int, bool, size, number, json).
+	arguments: Vec<Pair<Key, String>>,
 	#[clap(short, long)]
 	bus: Option<String>,
 	#[clap(short, long)]
@@ -28,83 +31,63 @@ pub(crate) struct DelDevice {
 	wait: bool,
 }
 
-impl AddDevice {
-	pub async fn run(self, qmp: QmpStream, mut events: broadcast::Receiver<qmp::Event>, _args: GlobalArgs) -> Result<i32> {
-		let deadline = None;
+/// What `add-device` did
+#[derive(Serialize, Debug)]
+struct Added {
+	id: Option<String>,
+	driver: String,
+	/// false when the device already existed and was left alone
+	added: bool,
+	/// an existing device with the same id was removed first
+	replaced: bool,
+}
 
+impl AddDevice {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let types = qmp.property_types(qmp::device_list_properties { typename: self.driver.clone() }).await;
 		let add = qmp::device_add {
+			arguments: keyval_dict(self.arguments, &types)?,
 			driver: self.driver,
 			bus: self.bus,
 			id: self.id,
-			arguments: self.arguments.into_iter().map(Pair::object_pair).collect(),
 		};
 
+		let mut added = Added {
+			id: add.id.clone(),
+			driver: add.driver.clone(),
+			added: true,
+			replaced: false,
+		};
 		if let Some(id) = add.id.as_ref() {
 			let exists = if self.force || self.no_clobber {
-				device_exists(&qmp, &id).await?
+				qmp.device_exists(id).await?
 			} else {
 				false
 			};
 			if exists {
 				if self.no_clobber {
 					log::warn!("{} already exists, nothing to do", id);
+					added.added = false;
+					args.output().print(&added)?;
 					return Ok(0)
 				} else if self.force {
 					log::info!("{} already exists, replacing...", id);
-					device_del(&qmp, &mut events, true, id).await?;
+					qmp.device_del(id, true).await?;
+					added.replaced = true;
 				}
 			}
 		}
 
-		if let Some(deadline) = deadline {
-			sleep_until(deadline).await;
-		}
-
-		qmp.execute(add).await?;
+		qmp.device_add(add).await?;
+		args.output().print(&added)?;
 		Ok(0)
 	}
 }
 
 impl DelDevice {
-	pub async fn run(self, qmp: QmpStream, mut events: broadcast::Receiver<qmp::Event>, _args: GlobalArgs) -> Result<i32> {
-		device_del(&qmp, &mut events, self.wait, &self.id).await?;
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		qmp.device_del(&self.id, self.wait).await?;
+		args.output().print_done()?;
 		Ok(0)
 	}
 }
-
-async fn device_exists(qmp: &QmpStream, id: &str) -> Result<bool> {
-	let path = format!("/machine/peripheral/{}", id);
-	match qmp.execute(qmp::qom_list { path }).await {
-		Ok(..) => Ok(true),
-		Err(qapi::ExecuteError::Qapi(qapi::Error { class: qapi::ErrorClass::DeviceNotFound, .. })) =>
-			Ok(false),
-		Err(e) => Err(e.into()),
-	}
-}
-
-async fn device_del(qmp: &QmpStream, events: &mut broadcast::Receiver<qmp::Event>, wait: bool, id: &str) -> Result<()> {
-	let delete = qmp.execute(qapi::qmp::device_del { id: id.into() })
-		.map_err(Error::from)
-		.map_ok(drop);
-	if wait {
-		let wait = async move {
-			loop {
-				match events.recv().await {
-					Ok(qapi::qmp::Event::DEVICE_DELETED { ref data, .. }) if data.device.as_ref().map(|s| &s[..]) == Some(&id) => {
-						// work around qemu bug. without this delay, device_add will work but the new device might be immediately deleted
-						sleep(Duration::from_millis(128)).await;
-
-						break Ok(())
-					},
-					Err(broadcast::error::RecvError::Closed) =>
-						break Err(format_err!("Expected DEVICE_DELETED event")),
-					_ => (),
-				}
-			}
-		};
-		future::try_join(wait, delete).await
-			.map(|((), ())| ())
-	} else {
-		delete.await
-	}
-}
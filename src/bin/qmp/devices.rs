@@ -0,0 +1,237 @@
+use anyhow::Result;
+use clap::Parser;
+use futures::future::join_all;
+use qapi::qmp;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use qemucomm::{OutputFormat, print_table};
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
+use super::shell_words::shell_word;
+
+#[derive(Parser, Debug)]
+/// Lists the devices added on the command line or with add-device
+///
+/// Properties are only shown where they differ from the defaults of the device type.
+pub(crate) struct Devices {
+	/// print each device as arguments to add-device, one per line
+	#[clap(short, long)]
+	export: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct Device {
+	/// `None` for devices added without one
+	id: Option<String>,
+	path: String,
+	#[serde(rename = "type")]
+	driver: String,
+	bus: Option<String>,
+	/// the PCI slot and function, as `addr` is given to add-device
+	addr: Option<String>,
+	/// the PCI address, as `BUS:SLOT.FUNCTION`
+	pci: Option<String>,
+	properties: qapi::Dictionary,
+}
+
+/// The parts of `PciDeviceInfo` that are needed to find a device, and those behind it
+#[derive(Deserialize, Debug)]
+struct PciDevice {
+	bus: u8,
+	slot: u8,
+	function: u8,
+	qdev_id: String,
+	#[serde(default)]
+	pci_bridge: Option<PciBridge>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PciBridge {
+	#[serde(default)]
+	devices: Vec<PciDevice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PciBus {
+	devices: Vec<PciDevice>,
+}
+
+impl PciDevice {
+	fn devfn(&self) -> u8 {
+		self.slot << 3 | self.function
+	}
+
+	fn address(&self) -> String {
+		format!("{:02x}:{:02x}.{}", self.bus, self.slot, self.function)
+	}
+
+	/// Collects the device, followed by any behind it if it's a bridge
+	fn flatten(mut self, devices: &mut Vec<PciDevice>) {
+		let behind = self.pci_bridge.take().map(|bridge| bridge.devices).unwrap_or_default();
+		devices.push(self);
+		for device in behind {
+			device.flatten(devices);
+		}
+	}
+}
+
+/// Every device on every PCI bus, or none if the machine has no PCI
+async fn pci_devices(qmp: &Client) -> Vec<PciDevice> {
+	let buses = match qmp.execute_any("query-pci", None).await {
+		Ok(buses) => buses,
+		Err(e) => {
+			log::info!("failed to query PCI devices: {}", e);
+			return Default::default()
+		},
+	};
+	let buses: Vec<PciBus> = match serde_json::from_value(buses) {
+		Ok(buses) => buses,
+		Err(e) => {
+			log::warn!("unexpected query-pci result: {}", e);
+			return Default::default()
+		},
+	};
+	let mut devices = Vec::new();
+	for device in buses.into_iter().flat_map(|bus| bus.devices) {
+		device.flatten(&mut devices);
+	}
+	devices
+}
+
+async fn qom_get(qmp: &Client, path: &str, property: &str) -> Result<qapi::Any> {
+	qmp.execute(qmp::qom_get {
+		path: path.into(),
+		property: property.into(),
+	}).await.map_err(Into::into)
+}
+
+/// Whether a property is worth showing, because it isn't the default
+fn interesting(prop: &qmp::ObjectPropertyInfo, value: &qapi::Any) -> bool {
+	match (&prop.default_value, value) {
+		(Some(default), value) => default != value,
+		(None, qapi::Any::Null) => false,
+		(None, qapi::Any::String(value)) => !value.is_empty(),
+		(None, _) => true,
+	}
+}
+
+/// Reads the interesting properties of a device, given the properties of its type
+async fn properties(qmp: &Client, path: &str, props: &[qmp::ObjectPropertyInfo]) -> qapi::Dictionary {
+	let props: Vec<_> = props.iter()
+		// the address is found separately, because its value is a devfn rather than what add-device takes
+		.filter(|prop| prop.name != "addr")
+		.collect();
+	let values = join_all(props.iter().map(|prop| qom_get(qmp, path, &prop.name))).await;
+	props.into_iter().zip(values)
+		.filter_map(|(prop, value)| match value {
+			Ok(value) => Some((prop, value)),
+			Err(e) => {
+				log::info!("failed to read {}.{}: {}", path, prop.name, e);
+				None
+			},
+		})
+		.filter(|(prop, value)| interesting(prop, value))
+		.map(|(prop, value)| (prop.name.clone(), value))
+		.collect()
+}
+
+/// A value as add-device takes it, typed if it can't be guessed from the properties of the device
+fn argument(name: &str, value: &qapi::Any) -> String {
+	match value {
+		qapi::Any::String(value) => format!("{}={}", name, value),
+		qapi::Any::Bool(value) => format!("{}={}", name, if *value { "on" } else { "off" }),
+		qapi::Any::Number(value) => format!("{}={}", name, value),
+		value => format!("{}:json={}", name, value),
+	}
+}
+
+impl Device {
+	fn arguments(&self) -> Vec<String> {
+		let id = self.id.iter().flat_map(|id| ["-i".into(), id.clone()]);
+		let bus = self.bus.iter().flat_map(|bus| ["--bus".into(), bus.clone()]);
+		id.chain(bus)
+			.chain(Some(self.driver.clone()))
+			.chain(self.addr.iter().map(|addr| format!("addr={}", addr)))
+			.chain(self.properties.iter().map(|(name, value)| argument(name, value)))
+			.collect()
+	}
+}
+
+impl Devices {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let pci = pci_devices(qmp).await;
+		let mut types: BTreeMap<String, Vec<qmp::ObjectPropertyInfo>> = BTreeMap::new();
+		let mut devices = Vec::new();
+		for (parent, anon) in [("/machine/peripheral", false), ("/machine/peripheral-anon", true)] {
+			let children = qmp.execute(qmp::qom_list { path: parent.into() }).await?;
+			let mut children: Vec<_> = children.into_iter()
+				.filter_map(|child| Some((child.type_.strip_prefix("child<")?.strip_suffix('>')?.to_owned(), child.name)))
+				.collect();
+			// anonymous devices are numbered in the order they were added
+			children.sort_by_key(|(_, name)| (
+				name.strip_prefix("device[").and_then(|i| i.strip_suffix(']')).and_then(|i| i.parse::<u64>().ok()),
+				name.clone(),
+			));
+
+			for (driver, name) in children {
+				let path = format!("{}/{}", parent, name);
+				if !types.contains_key(&driver) {
+					let props = qmp.execute(qmp::device_list_properties { typename: driver.clone() }).await
+						.unwrap_or_else(|e| {
+							log::warn!("failed to list the properties of {}: {}", driver, e);
+							Default::default()
+						});
+					types.insert(driver.clone(), props);
+				}
+				let props = &types[&driver];
+
+				let bus = match qom_get(qmp, &path, "parent_bus").await {
+					Ok(qapi::Any::String(bus)) => bus.rsplit('/').next().filter(|bus| !bus.is_empty()).map(Into::into),
+					_ => None,
+				};
+				let devfn = match props.iter().any(|prop| prop.name == "addr") {
+					true => qom_get(qmp, &path, "addr").await.ok()
+						.and_then(|addr| addr.as_u64())
+						.and_then(|devfn| u8::try_from(devfn).ok()),
+					false => None,
+				};
+				let id = Some(name).filter(|_| !anon);
+				let pci = match &id {
+					Some(id) => pci.iter().find(|dev| &dev.qdev_id == id),
+					None => {
+						let mut found = pci.iter().filter(|dev| dev.qdev_id.is_empty() && Some(dev.devfn()) == devfn);
+						found.next().filter(|_| found.next().is_none())
+					},
+				};
+				devices.push(Device {
+					addr: devfn.map(|devfn| format!("{:02x}.{}", devfn >> 3, devfn & 7)),
+					pci: pci.map(PciDevice::address),
+					properties: properties(qmp, &path, props).await,
+					id,
+					path,
+					driver,
+					bus,
+				});
+			}
+		}
+
+		match (args.output(), self.export) {
+			(OutputFormat::Human, true) => for device in &devices {
+				let words: Vec<_> = device.arguments().into_iter()
+					.map(|word| shell_word(&word).into_owned())
+					.collect();
+				println!("{}", words.join(" "));
+			},
+			(format, true) => format.print(&devices.iter().map(Device::arguments).collect::<Vec<_>>())?,
+			(OutputFormat::Human, false) => print_table(&["id", "type", "bus", "pci", "properties"], devices.iter().map(|device| vec![
+				device.id.clone().unwrap_or_else(|| "-".into()),
+				device.driver.clone(),
+				device.bus.clone().unwrap_or_else(|| "-".into()),
+				device.pci.clone().unwrap_or_else(|| "-".into()),
+				device.properties.iter().map(|(name, value)| argument(name, value)).collect::<Vec<_>>().join(" "),
+			]))?,
+			(format, false) => format.print(&devices)?,
+		}
+		Ok(0)
+	}
+}
@@ -0,0 +1,256 @@
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use qapi::qmp;
+use serde::Serialize;
+use std::os::unix::fs::FileTypeExt;
+use std::fs;
+use qemucomm::{Pair, Key, keyval_dict};
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Hotplugs a disk image or block device
+///
+/// A protocol node named `ID-file` and a format node named `ID-format` are created
+/// with `blockdev-add`, then attached to a new device. If anything fails, whatever
+/// was added is removed again.
+pub(crate) struct AddDisk {
+	/// id of the device
+	id: String,
+	/// image or block device on the QEMU host
+	filename: String,
+	/// device properties, typed like those of add-device
+	arguments: Vec<Pair<Key, String>>,
+	/// image format [default: qcow2 for *.qcow2 files, otherwise raw]
+	#[clap(short = 'F', long, value_enum)]
+	format: Option<Format>,
+	/// protocol driver [default: host_device if QMP is a unix socket and the filename is a block device on this machine, otherwise file]
+	///
+	/// The filename is only checked when QMP is a unix socket, since QEMU is then running on this machine.
+	/// Pass `--protocol host_device` for block devices of a QEMU reached over tcp or an inherited fd.
+	#[clap(short, long, value_enum)]
+	protocol: Option<Protocol>,
+	/// device driver
+	#[clap(short, long, default_value = "virtio-blk-pci")]
+	driver: String,
+	#[clap(short, long)]
+	bus: Option<String>,
+	/// caching mode, as with -drive
+	#[clap(short, long, value_enum)]
+	cache: Option<Cache>,
+	#[clap(short, long, value_enum)]
+	aio: Option<Aio>,
+	#[clap(long, value_enum)]
+	discard: Option<Discard>,
+	#[clap(short, long)]
+	read_only: bool,
+}
+
+#[derive(Parser, Debug)]
+/// Unplugs a disk added by add-disk, then deletes its block nodes
+///
+/// The device is only removed once the guest releases it.
+pub(crate) struct DelDisk {
+	id: String,
+	#[clap(short, long = "timeout")]
+	timeout_seconds: Option<u64>,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Format {
+	Qcow2,
+	Raw,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Protocol {
+	File,
+	#[clap(name = "host_device")]
+	HostDevice,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Cache {
+	Writeback,
+	None,
+	Writethrough,
+	Directsync,
+	Unsafe,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Aio {
+	Threads,
+	Native,
+	#[clap(name = "io_uring")]
+	IoUring,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Discard {
+	Ignore,
+	Unmap,
+}
+
+impl From<Aio> for qmp::BlockdevAioOptions {
+	fn from(aio: Aio) -> Self {
+		match aio {
+			Aio::Threads => qmp::BlockdevAioOptions::threads,
+			Aio::Native => qmp::BlockdevAioOptions::native,
+			Aio::IoUring => qmp::BlockdevAioOptions::io_uring,
+		}
+	}
+}
+
+impl From<Discard> for qmp::BlockdevDiscardOptions {
+	fn from(discard: Discard) -> Self {
+		match discard {
+			Discard::Ignore => qmp::BlockdevDiscardOptions::ignore,
+			Discard::Unmap => qmp::BlockdevDiscardOptions::unmap,
+		}
+	}
+}
+
+impl Cache {
+	/// The node options and device `write-cache` (on or off) that -drive would use
+	fn options(self) -> (qmp::BlockdevCacheOptions, &'static str) {
+		let (direct, no_flush, write_cache) = match self {
+			Cache::Writeback => (false, false, "on"),
+			Cache::None => (true, false, "on"),
+			Cache::Writethrough => (false, false, "off"),
+			Cache::Directsync => (true, false, "off"),
+			Cache::Unsafe => (false, true, "on"),
+		};
+		(qmp::BlockdevCacheOptions { direct: Some(direct), no_flush: Some(no_flush) }, write_cache)
+	}
+}
+
+/// The nodes of a disk, in the order they're added
+fn node_names(id: &str) -> [String; 2] {
+	[format!("{}-file", id), format!("{}-format", id)]
+}
+
+/// What `add-disk` and `del-disk` did
+#[derive(Serialize, Debug)]
+struct Disk {
+	id: String,
+	nodes: Vec<String>,
+}
+
+impl AddDisk {
+	fn base(&self, node_name: String) -> qmp::BlockdevOptionsBase {
+		qmp::BlockdevOptionsBase {
+			node_name: Some(node_name),
+			cache: self.cache.map(|c| c.options().0),
+			discard: self.discard.map(Into::into),
+			read_only: Some(self.read_only).filter(|&ro| ro),
+			auto_read_only: None,
+			detect_zeroes: None,
+			force_share: None,
+		}
+	}
+
+	/// `local` is whether QEMU shares our filesystem, so that the filename can be checked
+	fn protocol_node(&self, node_name: String, local: bool) -> qmp::BlockdevOptions {
+		let protocol = self.protocol.unwrap_or_else(|| match local.then(|| fs::metadata(&self.filename)) {
+			Some(Ok(meta)) if meta.file_type().is_block_device() => Protocol::HostDevice,
+			_ => Protocol::File,
+		});
+		let base = self.base(node_name);
+		let file = qmp::BlockdevOptionsFile {
+			filename: self.filename.clone(),
+			aio: self.aio.map(Into::into),
+			aio_max_batch: None,
+			drop_cache: None,
+			locking: None,
+			pr_manager: None,
+			x_check_cache_dropped: None,
+		};
+		match protocol {
+			Protocol::File => qmp::BlockdevOptions::file { base, file },
+			Protocol::HostDevice => qmp::BlockdevOptions::host_device { base, host_device: file },
+		}
+	}
+
+	fn format_node(&self, node_name: String, file: String) -> qmp::BlockdevOptions {
+		let format = self.format.unwrap_or(match self.filename.ends_with(".qcow2") {
+			true => Format::Qcow2,
+			false => Format::Raw,
+		});
+		let base = self.base(node_name);
+		let file = qmp::BlockdevOptionsGenericFormat {
+			file: qmp::BlockdevRef::reference(file),
+		};
+		match format {
+			Format::Qcow2 => qmp::BlockdevOptions::qcow2 { base, qcow2: file.into() },
+			Format::Raw => qmp::BlockdevOptions::raw { base, raw: file.into() },
+		}
+	}
+
+	/// Adds the nodes and device, recording the nodes in `added` as it goes
+	async fn add(&self, qmp: &Client, local: bool, added: &mut Vec<String>) -> Result<()> {
+		let [file, format] = node_names(&self.id);
+		qmp.blockdev_add(self.protocol_node(file.clone(), local)).await?;
+		added.push(file.clone());
+		qmp.blockdev_add(self.format_node(format.clone(), file)).await?;
+		added.push(format.clone());
+
+		let types = qmp.property_types(qmp::device_list_properties { typename: self.driver.clone() }).await;
+		let mut arguments = keyval_dict(self.arguments.iter().cloned(), &types)?;
+		arguments.insert("drive".into(), format.into());
+		if let Some(cache) = self.cache {
+			arguments.entry("write-cache").or_insert_with(|| cache.options().1.into());
+		}
+		qmp.device_add(qmp::device_add {
+			driver: self.driver.clone(),
+			id: Some(self.id.clone()),
+			bus: self.bus.clone(),
+			arguments,
+		}).await
+	}
+
+	pub async fn run(self, qmp: &Client, local: bool, args: GlobalArgs) -> Result<i32> {
+		let mut added = Vec::new();
+		if let Err(e) = self.add(qmp, local, &mut added).await {
+			for node_name in added.iter().rev() {
+				if let Err(e) = qmp.blockdev_del(node_name).await {
+					log::warn!("failed to clean up block node {}: {}", node_name, e);
+				}
+			}
+			return Err(e)
+		}
+
+		args.output().print(&Disk {
+			id: self.id,
+			nodes: added,
+		})?;
+		Ok(0)
+	}
+}
+
+impl DelDisk {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		// a disk whose device is already gone may still have nodes to clean up
+		if qmp.device_exists(&self.id).await? {
+			let duration = self.timeout_seconds.map(std::time::Duration::from_secs);
+			qemucomm::wait(duration, qmp.device_del(&self.id, true)).await?;
+		} else {
+			log::info!("device {} not found, deleting its nodes", self.id);
+		}
+
+		let existing = qmp.block_node_names().await?;
+		let mut deleted = Vec::new();
+		for node_name in node_names(&self.id).into_iter().rev() {
+			if existing.contains(&node_name) {
+				qmp.blockdev_del(&node_name).await?;
+				deleted.push(node_name);
+			}
+		}
+
+		args.output().print(&Disk {
+			id: self.id,
+			nodes: deleted,
+		})?;
+		Ok(0)
+	}
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use clap::Parser;
+use tokio::time::Duration;
+use std::io::{self, Write};
+use qemucomm::{Pair, Key, OutputFormat};
+use qemucomm::qmp::{Client, EventFilter, Events as EventStream, event_value, event_name};
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Prints QMP events as they arrive, one JSON object per line
+pub(crate) struct Events {
+	/// only print events with this name
+	#[clap(short, long = "filter")]
+	filter: Vec<String>,
+	/// exit after printing this many events
+	#[clap(short, long)]
+	count: Option<usize>,
+	/// exit after printing an event with this name, which is printed even if it isn't in the filter
+	#[clap(short, long)]
+	until: Option<String>,
+}
+
+impl Events {
+	fn matches(&self, name: &str) -> bool {
+		self.filter.is_empty() || self.filter.iter().chain(&self.until).any(|f| f.eq_ignore_ascii_case(name))
+	}
+
+	pub async fn run(self, _qmp: &Client, mut events: EventStream, args: GlobalArgs) -> Result<i32> {
+		let format = args.raw_output();
+		let mut count = 0usize;
+		loop {
+			if matches!(self.count, Some(c) if count >= c) {
+				break Ok(0)
+			}
+
+			let event = match events.recv().await {
+				Some(event) => event,
+				None => {
+					log::info!("QMP connection closed");
+					break Ok(if self.count.is_some() || self.until.is_some() { 1 } else { 0 })
+				},
+			};
+
+			let event = event_value(&event);
+			let name = event_name(&event);
+			if !self.matches(name) {
+				continue
+			}
+
+			print_event(&event, format)?;
+			count += 1;
+
+			if matches!(&self.until, Some(until) if until.eq_ignore_ascii_case(name)) {
+				break Ok(0)
+			}
+		}
+	}
+}
+
+#[derive(Parser, Debug)]
+/// Waits for an event to arrive, then prints it
+pub(crate) struct WaitEvent {
+	event: String,
+	/// only match events whose `data` contains this (dotted) KEY=value
+	#[clap(short, long = "match")]
+	matches: Vec<Pair<Key, String>>,
+	#[clap(short, long = "timeout")]
+	timeout_seconds: Option<u64>,
+}
+
+impl WaitEvent {
+	pub async fn run(self, _qmp: &Client, mut events: EventStream, args: GlobalArgs) -> Result<i32> {
+		let filter = EventFilter {
+			name: self.event,
+			data: self.matches,
+		};
+		let duration = self.timeout_seconds.map(Duration::from_secs);
+		let event = qemucomm::wait(duration, events.wait(&filter)).await?;
+		print_event(&event_value(&event), args.raw_output())?;
+		Ok(0)
+	}
+}
+
+/// Renders an event as a line of JSON, a YAML document, or a line of text with its timestamp
+pub(crate) fn render_event(event: &qapi::Any, format: OutputFormat) -> Result<String> {
+	match format {
+		OutputFormat::Human => {
+			let timestamp = &event["timestamp"];
+			let data = match &event["data"] {
+				qapi::Any::Null => String::new(),
+				qapi::Any::Object(data) if data.is_empty() => String::new(),
+				data => format!(" {}", data),
+			};
+			Ok(format!("{}.{:06} {}{}\n",
+				timestamp["seconds"], timestamp["microseconds"].as_u64().unwrap_or_default(),
+				event_name(event), data,
+			))
+		},
+		format => {
+			let mut record = Vec::new();
+			format.write_record(&mut record, event)?;
+			String::from_utf8(record).map_err(Into::into)
+		},
+	}
+}
+
+fn print_event(event: &qapi::Any, format: OutputFormat) -> Result<()> {
+	let mut stdout = io::stdout().lock();
+	stdout.write_all(render_event(event, format)?.as_bytes())?;
+	stdout.flush().map_err(Into::into)
+}
@@ -0,0 +1,58 @@
+use anyhow::{Result, format_err};
+use clap::Parser;
+use qemucomm::{Pair, Key, keyval_dict, error_exit_code};
+use std::path::PathBuf;
+use std::fs::File;
+use std::io;
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Executes an arbitrary QMP command and prints its return value
+///
+/// QMP errors exit with a distinct status per error class:
+/// GenericError=3, CommandNotFound=4, DeviceNotActive=5, DeviceNotFound=6, KVMMissingCap=7
+pub(crate) struct Execute {
+	command: String,
+	/// command arguments
+	arguments: Vec<Pair<Key, String>>,
+	/// command arguments as a JSON object
+	#[clap(long, conflicts_with_all = ["arguments", "json_file"])]
+	json: Option<String>,
+	/// read command arguments as a JSON object from a file, or `-` for stdin
+	#[clap(long, conflicts_with_all = ["arguments", "json"])]
+	json_file: Option<PathBuf>,
+}
+
+impl Execute {
+	fn arguments(self) -> Result<Option<qapi::Dictionary>> {
+		let arguments = match (self.json, self.json_file) {
+			(Some(json), _) => serde_json::from_str(&json)?,
+			(None, Some(path)) => match path.to_str() {
+				Some("-") => serde_json::from_reader(io::stdin())?,
+				_ => serde_json::from_reader(io::BufReader::new(File::open(path)?))?,
+			},
+			(None, None) if self.arguments.is_empty() => return Ok(None),
+			(None, None) => return keyval_dict(self.arguments, &Default::default()).map(Some),
+		};
+		match arguments {
+			qapi::Any::Object(arguments) => Ok(Some(arguments)),
+			arguments => Err(format_err!("expected a JSON object for arguments, got {}", arguments)),
+		}
+	}
+
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let command = self.command.clone();
+		match qmp.execute_any(command, self.arguments()?).await {
+			Ok(res) => {
+				args.raw_output().print(&res)?;
+				Ok(0)
+			},
+			Err(qapi::ExecuteError::Qapi(e)) => {
+				eprintln!("{:?}: {}", e.class, e.desc);
+				Ok(error_exit_code(&e.class))
+			},
+			Err(e) => Err(e.into()),
+		}
+	}
+}
@@ -1,22 +1,229 @@
-use anyhow::Result;
+use anyhow::{Result, format_err};
 use clap::Parser;
 use qapi::qmp;
-use super::{GlobalArgs, QmpStream};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::Helper;
+use is_terminal::IsTerminal;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::env;
+use qemucomm::qmp::Client;
+use qemucomm::{OutputFormat, parse_bool};
+use super::readline::{Readline, HistoryArgs};
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
+/// Runs a human monitor (HMP) command
+///
+/// The interactive console reads commands from a prompt over a single connection.
+/// Besides HMP commands, it understands `cpu [INDEX]` to show or switch the CPU that
+/// commands apply to, `pager [on|off]` to toggle paging, and `exit`.
 pub(crate) struct HumanCommand {
 	#[clap(long = "cpu")]
 	pub cpu_index: Option<i64>,
-	pub command: String,
+	/// run an interactive console instead of a single command
+	#[clap(short, long, conflicts_with = "command")]
+	pub interactive: bool,
+	/// page long output of the interactive console with $PAGER
+	#[clap(long, requires = "interactive")]
+	pub pager: bool,
+	#[clap(flatten)]
+	pub history: HistoryArgs,
+	#[clap(required_unless_present = "interactive")]
+	pub command: Option<String>,
 }
 
 impl HumanCommand {
-	pub async fn run(self, qmp: QmpStream, _args: GlobalArgs) -> Result<i32> {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let command = match self.command {
+			_ if self.interactive => return self.console(qmp, args.output()).await,
+			Some(command) => command,
+			None => return Err(format_err!("no command given")),
+		};
 		let response = qmp.execute(qmp::human_monitor_command {
 			cpu_index: self.cpu_index,
-			command_line: self.command,
+			command_line: command,
 		}).await?;
-		println!("{}", response);
+		match args.output() {
+			OutputFormat::Human => println!("{}", response),
+			format => format.print(&response)?,
+		}
 		Ok(0)
 	}
+
+	async fn console(self, qmp: &Client, format: OutputFormat) -> Result<i32> {
+		let mut cpu_index = self.cpu_index;
+		let mut pager = self.pager;
+		let helper = HmpHelper::query(qmp).await;
+		let mut readline = Readline::new(helper, self.history.path("hmp_history"))?;
+
+		loop {
+			let prompt = match cpu_index {
+				Some(cpu) => format!("(qemu cpu{}) ", cpu),
+				None => "(qemu) ".into(),
+			};
+			let line = match readline.read(&prompt).await? {
+				Some(line) => line,
+				None => break,
+			};
+			let line = line.trim();
+			let mut words = line.split_whitespace();
+			match (words.next(), words.next(), words.next()) {
+				(None, ..) => (),
+				(Some("exit"), None, _) => break,
+				(Some("cpu"), None, _) => match cpu_index {
+					Some(cpu) => println!("{}", cpu),
+					None => println!("default"),
+				},
+				(Some("cpu"), Some(cpu), None) => match select_cpu(qmp, cpu).await {
+					Ok(cpu) => cpu_index = cpu,
+					Err(e) => eprintln!("{:#}", e),
+				},
+				(Some("pager"), None, _) => println!("{}", if pager { "on" } else { "off" }),
+				(Some("pager"), Some(value), None) => match parse_bool(value) {
+					Some(value) => pager = value,
+					None => eprintln!("expected on or off"),
+				},
+				_ => match qmp.execute(qmp::human_monitor_command {
+					cpu_index,
+					command_line: line.into(),
+				}).await {
+					Ok(output) => match format {
+						OutputFormat::Human => print_output(&output.replace("\r\n", "\n"), pager)?,
+						format => format.print(&output)?,
+					},
+					Err(qapi::ExecuteError::Qapi(e)) => eprintln!("{:?}: {}", e.class, e.desc),
+					Err(e) => return Err(e.into()),
+				},
+			}
+		}
+
+		readline.close()?;
+		Ok(0)
+	}
+}
+
+/// Checks a CPU index against `query-cpus-fast`, or `default` to unset it
+async fn select_cpu(qmp: &Client, cpu: &str) -> Result<Option<i64>> {
+	if cpu == "default" {
+		return Ok(None)
+	}
+	let cpu = cpu.parse()
+		.map_err(|_| format_err!("invalid CPU index {}", cpu))?;
+	match qmp.execute_any("query-cpus-fast", None).await {
+		Ok(qapi::Any::Array(cpus)) => match cpus.iter().any(|c| c["cpu-index"].as_i64() == Some(cpu)) {
+			true => Ok(Some(cpu)),
+			false => Err(format_err!("CPU {} not found", cpu)),
+		},
+		res => {
+			log::warn!("failed to list CPUs: {:?}", res);
+			Ok(Some(cpu))
+		},
+	}
+}
+
+fn print_output(output: &str, pager: bool) -> Result<()> {
+	if output.is_empty() {
+		return Ok(())
+	}
+	let newline = match output.ends_with('\n') {
+		true => "",
+		false => "\n",
+	};
+	if !pager || !io::stdout().is_terminal() {
+		print!("{}{}", output, newline);
+		return io::stdout().flush().map_err(Into::into)
+	}
+
+	let pager = env::var("PAGER").ok()
+		.filter(|p| !p.is_empty())
+		.unwrap_or_else(|| "less".into());
+	tokio::task::block_in_place(|| {
+		let mut child = Command::new("sh").arg("-c").arg(&pager)
+			// only page output that doesn't fit on screen
+			.env("LESS", env::var("LESS").unwrap_or_else(|_| "FRX".into()))
+			.stdin(Stdio::piped())
+			.spawn()
+			.map_err(|e| format_err!("failed to run pager {}: {}", pager, e))?;
+		// the pager may quit before reading everything
+		let _ = write!(child.stdin.take().unwrap(), "{}{}", output, newline);
+		child.wait()?;
+		Ok(())
+	})
 }
+
+/// Completes command names from the output of HMP's `help` and `info`
+struct HmpHelper {
+	commands: Vec<String>,
+	info: Vec<String>,
+}
+
+impl HmpHelper {
+	async fn query(qmp: &Client) -> Self {
+		let help = |command: &'static str| async move {
+			let output = qmp.execute(qmp::human_monitor_command {
+				cpu_index: None,
+				command_line: command.into(),
+			}).await;
+			match output {
+				Ok(output) => output,
+				Err(e) => {
+					log::warn!("failed to query HMP {}, commands won't be completed: {}", command, e);
+					Default::default()
+				},
+			}
+		};
+		// lines look like `c|cont  -- resume emulation`
+		let mut commands: Vec<String> = help("help").await.lines()
+			.filter_map(|line| line.split_whitespace().next())
+			.flat_map(|names| names.split('|'))
+			.chain(["cpu", "pager", "exit"])
+			.map(Into::into)
+			.collect();
+		commands.sort();
+		commands.dedup();
+		// and `info status  -- show the current VM status`
+		let info = help("info").await.lines()
+			.filter_map(|line| line.strip_prefix("info "))
+			.filter_map(|line| line.split_whitespace().next())
+			.map(Into::into)
+			.collect();
+		HmpHelper {
+			commands,
+			info,
+		}
+	}
+}
+
+impl Completer for HmpHelper {
+	type Candidate = String;
+
+	fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context) -> rustyline::Result<(usize, Vec<String>)> {
+		let line = &line[..pos];
+		let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+		let word = &line[start..];
+		let candidates = match &line[..start].split_whitespace().collect::<Vec<_>>()[..] {
+			[] => &self.commands[..],
+			["info" | "i"] => &self.info[..],
+			_ => &[],
+		};
+		Ok((start, candidates.iter()
+			.filter(|c| c.starts_with(word))
+			.cloned()
+			.collect()
+		))
+	}
+}
+
+impl Hinter for HmpHelper {
+	type Hint = String;
+}
+
+impl Highlighter for HmpHelper { }
+
+impl Validator for HmpHelper { }
+
+impl Helper for HmpHelper { }
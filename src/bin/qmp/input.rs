@@ -0,0 +1,317 @@
+use anyhow::{Result, format_err};
+use clap::{Args, Parser, Subcommand};
+use qapi::{qmp, Enum};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::{fs, io};
+use qemucomm::{OutputFormat, print_table};
+use qemucomm::qmp::Client;
+use super::readline::state_dir;
+use super::GlobalArgs;
+
+/// The properties of an `input-linux` object, as read back with `qom-get`
+const PROPERTIES: &[&str] = &["evdev", "grab_all", "repeat", "grab-toggle"];
+
+#[derive(Parser, Debug)]
+/// Passes host input devices to the guest and back, using `input-linux` objects
+///
+/// QEMU grabs an evdev device for as long as its object exists, so detaching deletes the object,
+/// remembering its properties, and attaching adds it again.
+pub(crate) struct Input {
+	#[command(subcommand)]
+	command: InputCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum InputCommand {
+	List(List),
+	Attach(Attach),
+	Detach(Detach),
+	Toggle(Toggle),
+}
+
+#[derive(Args, Debug)]
+struct Store {
+	/// file to remember detached objects in [default: $XDG_STATE_HOME/qemucomm/input-linux.json]
+	///
+	/// Objects are remembered by the name of the VM, or its UUID if it has none.
+	#[clap(long)]
+	state: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+/// Lists input-linux objects, whether attached to the guest or detached
+struct List {
+	#[clap(flatten)]
+	store: Store,
+}
+
+#[derive(Parser, Debug)]
+/// Passes devices to the guest
+struct Attach {
+	/// object ids [default: all detached objects]
+	ids: Vec<String>,
+	#[clap(flatten)]
+	store: Store,
+}
+
+#[derive(Parser, Debug)]
+/// Returns devices to the host
+struct Detach {
+	/// object ids [default: all attached objects]
+	ids: Vec<String>,
+	#[clap(flatten)]
+	store: Store,
+}
+
+#[derive(Parser, Debug)]
+/// Detaches the devices if any of them are attached, otherwise attaches them all
+struct Toggle {
+	/// object ids [default: all objects]
+	ids: Vec<String>,
+	#[clap(flatten)]
+	store: Store,
+}
+
+#[derive(Serialize, Debug)]
+struct InputObject<'a> {
+	id: &'a str,
+	attached: bool,
+	#[serde(flatten)]
+	properties: &'a qmp::InputLinuxProperties,
+}
+
+/// The input-linux objects of a VM, and those detached from it
+struct Inputs {
+	path: PathBuf,
+	vm: String,
+	attached: BTreeMap<String, qmp::InputLinuxProperties>,
+	/// Detached objects of every VM, by name and then by id
+	detached: BTreeMap<String, BTreeMap<String, qmp::InputLinuxProperties>>,
+}
+
+impl Store {
+	fn path(&self) -> Result<PathBuf> {
+		self.state.clone().or_else(|| state_dir().map(|dir| dir.join("input-linux.json")))
+			.ok_or_else(|| format_err!("nowhere to remember detached objects, use --state"))
+	}
+
+	async fn load(&self, qmp: &Client) -> Result<Inputs> {
+		let path = self.path()?;
+		let detached = match fs::read(&path) {
+			Ok(data) => serde_json::from_slice(&data)
+				.map_err(|e| format_err!("failed to parse {}: {}", path.display(), e))?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+			Err(e) => return Err(format_err!("failed to read {}: {}", path.display(), e)),
+		};
+
+		let vm = match qmp.execute(qmp::query_name { }).await?.name {
+			Some(name) => name,
+			None => qmp.execute(qmp::query_uuid { }).await?.UUID,
+		};
+
+		let mut attached = BTreeMap::new();
+		let objects = qmp.execute(qmp::qom_list { path: "/objects".into() }).await?;
+		for object in objects.into_iter().filter(|o| o.type_ == "child<input-linux>") {
+			let properties = properties(qmp, &object.name).await?;
+			attached.insert(object.name, properties);
+		}
+
+		Ok(Inputs {
+			path,
+			vm,
+			attached,
+			detached,
+		})
+	}
+}
+
+/// Reads the properties of an object to add it again with
+async fn properties(qmp: &Client, id: &str) -> Result<qmp::InputLinuxProperties> {
+	let path = format!("/objects/{}", id);
+	let mut properties = qapi::Dictionary::new();
+	for &property in PROPERTIES {
+		match qmp.execute(qmp::qom_get { path: path.clone(), property: property.into() }).await {
+			Ok(value) => {
+				properties.insert(property.into(), value);
+			},
+			// older versions of QEMU lack some, like grab-toggle
+			Err(e) if property != "evdev" => log::info!("failed to read {}.{}: {}", id, property, e),
+			Err(e) => return Err(e.into()),
+		}
+	}
+	serde_json::from_value(qapi::Any::Object(properties))
+		.map_err(|e| format_err!("unexpected properties of {}: {}", id, e))
+}
+
+impl Inputs {
+	fn detached(&self) -> impl Iterator<Item=(&String, &qmp::InputLinuxProperties)> {
+		self.detached.get(&self.vm).into_iter().flatten()
+			.filter(|(id, _)| !self.attached.contains_key(*id))
+	}
+
+	fn objects(&self) -> Vec<InputObject<'_>> {
+		let mut objects: Vec<_> = self.attached.iter().map(|(id, properties)| (id, true, properties))
+			.chain(self.detached().map(|(id, properties)| (id, false, properties)))
+			.map(|(id, attached, properties)| InputObject { id, attached, properties })
+			.collect();
+		objects.sort_by_key(|o| o.id);
+		objects
+	}
+
+	/// Checks that the ids are of known objects, defaulting to those that are `attached`, or all of them
+	fn select(&self, ids: Vec<String>, attached: Option<bool>) -> Result<Vec<String>> {
+		match ids.is_empty() {
+			true => Ok(self.objects().into_iter()
+				.filter(|o| attached.map(|attached| o.attached == attached).unwrap_or(true))
+				.map(|o| o.id.to_owned())
+				.collect()
+			),
+			false => ids.into_iter()
+				.map(|id| match self.objects().iter().any(|o| o.id == id) {
+					true => Ok(id),
+					false => Err(format_err!("no input-linux object {} attached or detached", id)),
+				})
+				.collect(),
+		}
+	}
+
+	fn save(&self) -> Result<()> {
+		if let Some(dir) = self.path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		fs::write(&self.path, serde_json::to_vec_pretty(&self.detached)?)
+			.map_err(|e| format_err!("failed to write {}: {}", self.path.display(), e))
+	}
+
+	async fn attach(&mut self, qmp: &Client, id: &str) -> Result<()> {
+		let properties = match self.detached.get(&self.vm).and_then(|detached| detached.get(id)) {
+			Some(properties) if !self.attached.contains_key(id) => properties.clone(),
+			_ => return Ok(()),
+		};
+		qmp.object_add(qmp::ObjectOptions::input_linux {
+			id: id.into(),
+			input_linux: properties.clone(),
+		}).await?;
+		self.attached.insert(id.into(), properties);
+		self.forget(id)
+	}
+
+	async fn detach(&mut self, qmp: &Client, id: &str) -> Result<()> {
+		let properties = match self.attached.get(id) {
+			Some(properties) => properties.clone(),
+			None => return Ok(()),
+		};
+		// remember it first, so that it can't be lost
+		self.detached.entry(self.vm.clone()).or_default()
+			.insert(id.into(), properties);
+		self.save()?;
+		if let Err(e) = qmp.object_del(id).await {
+			self.forget(id)?;
+			return Err(e)
+		}
+		self.attached.remove(id);
+		Ok(())
+	}
+
+	fn forget(&mut self, id: &str) -> Result<()> {
+		if let Some(detached) = self.detached.get_mut(&self.vm) {
+			detached.remove(id);
+			if detached.is_empty() {
+				self.detached.remove(&self.vm);
+			}
+		}
+		self.save()
+	}
+
+	fn print(&self, ids: &[String], args: &GlobalArgs) -> Result<()> {
+		let objects: Vec<_> = self.objects().into_iter()
+			.filter(|o| ids.iter().any(|id| id == o.id))
+			.collect();
+		match args.output() {
+			OutputFormat::Human => print_table(&["id", "state"], objects.iter().map(|o| vec![
+				o.id.into(),
+				if o.attached { "attached" } else { "detached" }.into(),
+			]))?,
+			format => format.print(&objects)?,
+		}
+		Ok(())
+	}
+}
+
+impl Input {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		match self.command {
+			InputCommand::List(c) => c.run(qmp, args).await,
+			InputCommand::Attach(c) => c.run(qmp, args).await,
+			InputCommand::Detach(c) => c.run(qmp, args).await,
+			InputCommand::Toggle(c) => c.run(qmp, args).await,
+		}
+	}
+}
+
+impl List {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let inputs = self.store.load(qmp).await?;
+		let objects = inputs.objects();
+		match args.output() {
+			OutputFormat::Human => print_table(&["id", "state", "evdev", "grab_all", "repeat", "grab-toggle"], objects.iter().map(|o| {
+				let flag = |flag: Option<bool>| flag.map(|flag| flag.to_string()).unwrap_or_else(|| "-".into());
+				vec![
+					o.id.into(),
+					if o.attached { "attached" } else { "detached" }.into(),
+					o.properties.evdev.clone(),
+					flag(o.properties.grab_all),
+					flag(o.properties.repeat),
+					o.properties.grab_toggle.map(|keys| keys.name()).unwrap_or("-").into(),
+				]
+			}))?,
+			format => format.print(&objects)?,
+		}
+		Ok(0)
+	}
+}
+
+impl Attach {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let mut inputs = self.store.load(qmp).await?;
+		let ids = inputs.select(self.ids, Some(false))?;
+		for id in &ids {
+			inputs.attach(qmp, id).await?;
+		}
+		inputs.print(&ids, &args)?;
+		Ok(0)
+	}
+}
+
+impl Detach {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let mut inputs = self.store.load(qmp).await?;
+		let ids = inputs.select(self.ids, Some(true))?;
+		for id in &ids {
+			inputs.detach(qmp, id).await?;
+		}
+		inputs.print(&ids, &args)?;
+		Ok(0)
+	}
+}
+
+impl Toggle {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let mut inputs = self.store.load(qmp).await?;
+		let ids = inputs.select(self.ids, None)?;
+		if ids.is_empty() {
+			return Err(format_err!("there are no input-linux objects to toggle"))
+		}
+		let detach = ids.iter().any(|id| inputs.attached.contains_key(id));
+		for id in &ids {
+			match detach {
+				true => inputs.detach(qmp, id).await?,
+				false => inputs.attach(qmp, id).await?,
+			}
+		}
+		inputs.print(&ids, &args)?;
+		Ok(0)
+	}
+}
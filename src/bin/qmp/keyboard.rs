@@ -0,0 +1,146 @@
+use anyhow::{Result, format_err};
+use clap::Parser;
+use qapi::qmp::{self, QKeyCode};
+use std::io::{self, Read};
+use tokio::time::{Duration, sleep};
+use qemucomm::qmp::Client;
+use super::keymap::{Layout, Modifier, parse_combo};
+use super::screen::InputArgs;
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Presses key combinations, one after another
+///
+/// Keys are named as in QEMU's QKeyCode, like `ctrl`, `alt`, `f1`, `ret`, or `a`, or given as scancodes like `0x1d`.
+pub(crate) struct SendKey {
+	/// keys to press together, joined by `-` like ctrl-alt-delete
+	#[clap(required = true)]
+	combos: Vec<String>,
+	/// how long to hold each combination, in milliseconds [default: 100]
+	#[clap(long, value_name = "MS")]
+	hold_time: Option<u64>,
+	/// how long to wait between combinations, in milliseconds
+	#[clap(long, default_value = "100", value_name = "MS")]
+	delay: u64,
+}
+
+#[derive(Parser, Debug)]
+/// Types text into the guest, as if on a keyboard with the given layout
+///
+/// The escapes \n, \t, \b (backspace), \e (escape), and \\ are recognized unless --raw is given.
+/// Nothing is typed if any character can't be.
+pub(crate) struct Type {
+	/// text to type [default: read from stdin]
+	text: Option<String>,
+	/// type backslashes as they are
+	#[clap(short, long)]
+	raw: bool,
+	#[clap(short, long, value_enum, default_value = "us")]
+	layout: Layout,
+	/// how long to hold each key, in milliseconds
+	#[clap(long, default_value = "20", value_name = "MS")]
+	hold_time: u64,
+	/// how long to wait between keys, in milliseconds
+	#[clap(long, default_value = "20", value_name = "MS")]
+	delay: u64,
+	#[clap(flatten)]
+	input: InputArgs,
+}
+
+impl SendKey {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let combos = self.combos.iter()
+			.map(|combo| parse_combo(combo))
+			.collect::<Result<Vec<_>>>()?;
+		// send-key returns before the keys are released
+		let hold_time = self.hold_time.unwrap_or(100);
+		for (i, keys) in combos.into_iter().enumerate() {
+			if i > 0 {
+				sleep(Duration::from_millis(hold_time + self.delay)).await;
+			}
+			qmp.execute(qmp::send_key {
+				keys,
+				hold_time: self.hold_time.map(|ms| ms as i64),
+			}).await?;
+		}
+		args.output().print_done()?;
+		Ok(0)
+	}
+}
+
+/// Interprets backslash escapes
+fn unescape(text: &str) -> Result<String> {
+	let mut unescaped = String::with_capacity(text.len());
+	let mut chars = text.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			unescaped.push(c);
+			continue
+		}
+		unescaped.push(match chars.next() {
+			Some('n') => '\n',
+			Some('t') => '\t',
+			Some('b') => '\x08',
+			Some('e') => '\x1b',
+			Some('\\') => '\\',
+			Some(c) => return Err(format_err!("unknown escape \\{}, use --raw to type backslashes as they are", c)),
+			None => return Err(format_err!("trailing backslash, use \\\\ or --raw to type it")),
+		});
+	}
+	Ok(unescaped)
+}
+
+fn key_event(qcode: QKeyCode, down: bool) -> qmp::InputEvent {
+	qmp::InputEvent::key(qmp::InputKeyEvent {
+		key: qcode.into(),
+		down,
+	}.into())
+}
+
+impl Type {
+	async fn send(&self, qmp: &Client, events: Vec<qmp::InputEvent>) -> Result<()> {
+		qmp.execute(qmp::input_send_event {
+			device: self.input.device.clone(),
+			head: self.input.head,
+			events,
+		}).await?;
+		Ok(())
+	}
+
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let text = match &self.text {
+			Some(text) => text.clone(),
+			None => {
+				let mut text = String::new();
+				io::stdin().read_to_string(&mut text)?;
+				text
+			},
+		};
+		let text = match self.raw {
+			true => text,
+			false => unescape(&text)?,
+		};
+		let keys = text.chars()
+			.map(|c| self.layout.key(c).ok_or_else(|| format_err!("can't type {:?} with the {} layout", c, self.layout)))
+			.collect::<Result<Vec<(QKeyCode, Option<Modifier>)>>>()?;
+
+		let (hold_time, delay) = (Duration::from_millis(self.hold_time), Duration::from_millis(self.delay));
+		for (i, (qcode, modifier)) in keys.into_iter().enumerate() {
+			if i > 0 {
+				sleep(delay).await;
+			}
+			let modifier = modifier.map(Modifier::qcode);
+			self.send(qmp, modifier.into_iter().chain(Some(qcode))
+				.map(|qcode| key_event(qcode, true))
+				.collect()
+			).await?;
+			sleep(hold_time).await;
+			self.send(qmp, Some(qcode).into_iter().chain(modifier)
+				.map(|qcode| key_event(qcode, false))
+				.collect()
+			).await?;
+		}
+		args.output().print_done()?;
+		Ok(0)
+	}
+}
@@ -0,0 +1,184 @@
+use anyhow::{Result, format_err};
+use clap::ValueEnum;
+use qapi::qmp::{self, QKeyCode};
+use std::fmt;
+
+/// A keyboard layout, which decides the keys that type each character
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Layout {
+	Us,
+	De,
+}
+
+impl fmt::Display for Layout {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Layout::Us => "us",
+			Layout::De => "de",
+		})
+	}
+}
+
+/// What must be held down along with a key to type a character
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Modifier {
+	Shift,
+	AltGr,
+}
+
+impl Modifier {
+	pub fn qcode(self) -> QKeyCode {
+		match self {
+			Modifier::Shift => QKeyCode::shift,
+			Modifier::AltGr => QKeyCode::alt_r,
+		}
+	}
+}
+
+/// Keys and the characters they type: alone, with shift, and with AltGr
+type Keymap = &'static [(QKeyCode, &'static str)];
+
+const US: Keymap = &[
+	(QKeyCode::grave_accent, "`~"),
+	(QKeyCode::_1, "1!"),
+	(QKeyCode::_2, "2@"),
+	(QKeyCode::_3, "3#"),
+	(QKeyCode::_4, "4$"),
+	(QKeyCode::_5, "5%"),
+	(QKeyCode::_6, "6^"),
+	(QKeyCode::_7, "7&"),
+	(QKeyCode::_8, "8*"),
+	(QKeyCode::_9, "9("),
+	(QKeyCode::_0, "0)"),
+	(QKeyCode::minus, "-_"),
+	(QKeyCode::equal, "=+"),
+	(QKeyCode::bracket_left, "[{"),
+	(QKeyCode::bracket_right, "]}"),
+	(QKeyCode::backslash, "\\|"),
+	(QKeyCode::semicolon, ";:"),
+	(QKeyCode::apostrophe, "'\""),
+	(QKeyCode::comma, ",<"),
+	(QKeyCode::dot, ".>"),
+	(QKeyCode::slash, "/?"),
+];
+
+/// German QWERTZ, leaving out dead keys
+const DE: Keymap = &[
+	(QKeyCode::_1, "1!"),
+	(QKeyCode::_2, "2\"²"),
+	(QKeyCode::_3, "3§³"),
+	(QKeyCode::_4, "4$"),
+	(QKeyCode::_5, "5%"),
+	(QKeyCode::_6, "6&"),
+	(QKeyCode::_7, "7/{"),
+	(QKeyCode::_8, "8(["),
+	(QKeyCode::_9, "9)]"),
+	(QKeyCode::_0, "0=}"),
+	(QKeyCode::minus, "ß?\\"),
+	(QKeyCode::q, "qQ@"),
+	(QKeyCode::e, "eE€"),
+	(QKeyCode::y, "zZ"),
+	(QKeyCode::z, "yY"),
+	(QKeyCode::m, "mMµ"),
+	(QKeyCode::bracket_left, "üÜ"),
+	(QKeyCode::bracket_right, "+*~"),
+	(QKeyCode::semicolon, "öÖ"),
+	(QKeyCode::apostrophe, "äÄ"),
+	(QKeyCode::backslash, "#'"),
+	(QKeyCode::less, "<>|"),
+	(QKeyCode::comma, ",;"),
+	(QKeyCode::dot, ".:"),
+	(QKeyCode::slash, "-_"),
+];
+
+/// The letter keys, named after the characters they type on a US keyboard
+const LETTERS: &[QKeyCode] = &[
+	QKeyCode::a, QKeyCode::b, QKeyCode::c, QKeyCode::d, QKeyCode::e, QKeyCode::f, QKeyCode::g,
+	QKeyCode::h, QKeyCode::i, QKeyCode::j, QKeyCode::k, QKeyCode::l, QKeyCode::m, QKeyCode::n,
+	QKeyCode::o, QKeyCode::p, QKeyCode::q, QKeyCode::r, QKeyCode::s, QKeyCode::t, QKeyCode::u,
+	QKeyCode::v, QKeyCode::w, QKeyCode::x, QKeyCode::y, QKeyCode::z,
+];
+
+impl Layout {
+	fn keymap(self) -> Keymap {
+		match self {
+			Layout::Us => US,
+			Layout::De => DE,
+		}
+	}
+
+	/// The key, and any modifier, that types a character
+	pub fn key(self, c: char) -> Option<(QKeyCode, Option<Modifier>)> {
+		match c {
+			' ' => return Some((QKeyCode::spc, None)),
+			'\n' => return Some((QKeyCode::ret, None)),
+			'\t' => return Some((QKeyCode::tab, None)),
+			'\x08' => return Some((QKeyCode::backspace, None)),
+			'\x1b' => return Some((QKeyCode::esc, None)),
+			_ => (),
+		}
+		// layouts only list the letters they move or add characters to
+		let keymap = self.keymap();
+		let found = keymap.iter().find_map(|&(qcode, chars)| {
+			let modifier = match chars.chars().position(|k| k == c)? {
+				0 => None,
+				1 => Some(Modifier::Shift),
+				_ => Some(Modifier::AltGr),
+			};
+			Some((qcode, modifier))
+		});
+		found.or_else(|| {
+			let index = (c.to_ascii_lowercase() as usize).checked_sub('a' as usize)?;
+			let qcode = *LETTERS.get(index).filter(|_| c.is_ascii_alphabetic())?;
+			if keymap.iter().any(|&(moved, _)| moved == qcode) {
+				return None
+			}
+			Some((qcode, Some(Modifier::Shift).filter(|_| c.is_ascii_uppercase())))
+		})
+	}
+}
+
+/// Parses a key name, like those of HMP `sendkey`, or a raw scancode like `0x1d`
+pub(crate) fn parse_key(name: &str) -> Result<qmp::KeyValue> {
+	if let Some(hex) = name.strip_prefix("0x") {
+		return i64::from_str_radix(hex, 16)
+			.map(Into::into)
+			.map_err(|e| format_err!("invalid scancode {}: {}", name, e))
+	}
+	let qcode = match &name.to_ascii_lowercase()[..] {
+		"control" => "ctrl",
+		"altgr" => "alt_r",
+		"enter" | "return" => "ret",
+		"space" => "spc",
+		"escape" => "esc",
+		"del" => "delete",
+		"ins" => "insert",
+		"pageup" => "pgup",
+		"pagedown" => "pgdn",
+		"win" | "super" | "meta" => "meta_l",
+		"backtick" => "grave_accent",
+		"period" => "dot",
+		name => return name.parse::<QKeyCode>()
+			.map(Into::into)
+			.map_err(|()| format_err!("unknown key {}", name)),
+	};
+	Ok(qcode.parse::<QKeyCode>().map_err(|()| format_err!("unknown key {}", qcode))?.into())
+}
+
+/// Parses a combination of keys pressed together, like `ctrl-alt-delete`
+pub(crate) fn parse_combo(combo: &str) -> Result<Vec<qmp::KeyValue>> {
+	// a trailing `-` is the minus key, as in `ctrl--`
+	let (keys, minus) = match combo.strip_suffix("--") {
+		Some(keys) => (keys, true),
+		None if combo == "-" => ("", true),
+		None => (combo, false),
+	};
+	let mut keys = keys.split('-')
+		.filter(|key| !key.is_empty() || !minus)
+		.map(parse_key)
+		.collect::<Result<Vec<_>>>()?;
+	if minus {
+		keys.push(QKeyCode::minus.into());
+	}
+	Ok(keys)
+}
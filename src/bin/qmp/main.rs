@@ -1,32 +1,63 @@
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
-use futures::StreamExt;
-use tokio::time::timeout;
-use tokio::sync::broadcast;
 use std::time::Duration;
-use std::path::PathBuf;
+use qemucomm::{SocketAddr, OutputFormat};
+use qemucomm::qmp::{Client, Events as EventStream};
 
 mod command;
 mod status;
 mod device;
 mod object;
 mod hmp;
-
-pub(crate) type QmpStreamWrite = qapi::futures::QmpStreamTokio<tokio::io::WriteHalf<tokio::net::UnixStream>>;
-pub(crate) type QmpStreamRead = qapi::futures::QmpStreamTokio<tokio::io::ReadHalf<tokio::net::UnixStream>>;
-pub(crate) type QmpStream = qapi::futures::QapiService<QmpStreamWrite>;
+mod execute;
+mod events;
+mod readline;
+mod shell;
+mod block;
+mod media;
+mod disk;
+mod snapshot;
+mod migrate;
+mod state;
+mod screen;
+mod screenshot;
+mod wait_screen;
+mod keymap;
+mod keyboard;
+mod mouse;
+mod input;
+mod qom;
+mod devices;
+mod shell_words;
 
 #[derive(Args, Debug)]
 pub(crate) struct GlobalArgs {
+	/// output format: human, json, or yaml [default: json for raw QMP output like execute and events, otherwise human]
+	#[arg(long, global = true)]
+	output: Option<OutputFormat>,
+}
+
+impl GlobalArgs {
+	pub fn output(&self) -> OutputFormat {
+		self.output.unwrap_or(OutputFormat::Human)
+	}
+
+	/// The output format of commands that print QMP values as they are
+	pub fn raw_output(&self) -> OutputFormat {
+		self.output.unwrap_or(OutputFormat::Json)
+	}
 }
 
 #[derive(Args, Debug)]
 pub(crate) struct ConnectionArgs {
-	/// QEMU QMP socket path
+	/// QEMU QMP socket: PATH, unix:PATH, unix-abstract:NAME (or @NAME), tcp:HOST:PORT, or fd:N
 	#[arg(short, long, env("QEMUCOMM_QMP_SOCKET_PATH"))]
-	socket: PathBuf,
+	socket: SocketAddr,
 	#[arg(short, long)]
 	wait: bool,
+	/// listen on the socket and wait for QEMU to connect to it
+	#[arg(short, long, conflicts_with = "wait")]
+	listen: bool,
 	#[arg(short, long = "timeout")]
 	timeout_seconds: Option<u64>,
 }
@@ -50,12 +81,47 @@ enum Command {
 	HumanCommand(hmp::HumanCommand),
 	AddDevice(device::AddDevice),
 	DelDevice(device::DelDevice),
+	AddDisk(disk::AddDisk),
+	DelDisk(disk::DelDisk),
 	AddObject(object::AddObject),
 	DelObject(object::DelObject),
 	Stop(command::StopCommand),
 	#[command(alias = "cont")]
 	Continue(command::ContinueCommand),
 	Quit(command::QuitCommand),
+	Execute(execute::Execute),
+	Events(events::Events),
+	WaitEvent(events::WaitEvent),
+	Shell(shell::Shell),
+	Media(media::Media),
+	Snapshot(snapshot::Snapshot),
+	Migrate(migrate::Migrate),
+	MigrateIncoming(migrate::MigrateIncoming),
+	SaveState(state::SaveState),
+	RestoreState(state::RestoreState),
+	Screenshot(screenshot::Screenshot),
+	WaitScreen(wait_screen::WaitScreen),
+	SendKey(keyboard::SendKey),
+	Type(keyboard::Type),
+	Mouse(mouse::Mouse),
+	Input(input::Input),
+	Qom(qom::Qom),
+	Devices(devices::Devices),
+}
+
+impl Command {
+	/// Subscribing while connecting means no event can slip by before the command starts
+	fn reads_events(&self) -> bool {
+		match self {
+			Command::Events(..) | Command::WaitEvent(..) | Command::Shell(..) | Command::Media(..) | Command::Snapshot(..)
+			| Command::Migrate(..) | Command::MigrateIncoming(..) | Command::SaveState(..) | Command::RestoreState(..) => true,
+			Command::Ping | Command::Status(..) | Command::HumanCommand(..) | Command::AddDevice(..) | Command::DelDevice(..)
+			| Command::AddDisk(..) | Command::DelDisk(..) | Command::AddObject(..) | Command::DelObject(..)
+			| Command::Stop(..) | Command::Continue(..) | Command::Quit(..) | Command::Execute(..)
+			| Command::Screenshot(..) | Command::WaitScreen(..) | Command::SendKey(..) | Command::Type(..)
+			| Command::Mouse(..) | Command::Input(..) | Command::Qom(..) | Command::Devices(..) => false,
+		}
+	}
 }
 
 #[tokio::main]
@@ -64,47 +130,43 @@ async fn main() -> Result<()> {
 
 	let args = Cli::parse();
 
-	let (stream, _caps) = args.connection.connect().await?;
-	let (qmp, mut stream) = stream.into_parts();
-	let (event_send, events) = broadcast::channel(8);
-
-	let _ = stream.release();
-	let handle = tokio::spawn(async move {
-		while let Some(event) = stream.next().await {
-			match event {
-				Ok(e) => match event_send.send(e) {
-					Err(e) =>
-						log::info!("event ignored: {:?}", e.0),
-					Ok(..) => (),
-				},
-				Err(e) => {
-					log::warn!("stream error: {:?}", e);
-					break
-				},
-			}
-		}
-	});
+	let (qmp, events) = args.connection.connect(args.command.reads_events()).await?;
+	let events = || events.unwrap_or_else(|| qmp.subscribe());
 
 	let res = match args.command {
-		Command::Ping => {
-			drop(qmp);
-			Ok(0)
-		},
-		Command::Status(c) => c.run(qmp, args.args).await,
-		Command::HumanCommand(c) => c.run(qmp, args.args).await,
-		Command::AddDevice(c) => c.run(qmp, events, args.args).await,
-		Command::DelDevice(c) => c.run(qmp, events, args.args).await,
-		Command::AddObject(c) => c.run(qmp, args.args).await,
-		Command::DelObject(c) => c.run(qmp, args.args).await,
-		Command::Stop(c) => c.run(qmp, args.args).await,
-		Command::Continue(c) => c.run(qmp, args.args).await,
-		Command::Quit(c) => c.run(qmp, args.args).await,
+		Command::Ping => args.args.output().print_done().map(|()| 0),
+		Command::Status(c) => c.run(&qmp, args.args).await,
+		Command::HumanCommand(c) => c.run(&qmp, args.args).await,
+		Command::AddDevice(c) => c.run(&qmp, args.args).await,
+		Command::DelDevice(c) => c.run(&qmp, args.args).await,
+		Command::AddDisk(c) => c.run(&qmp, args.connection.is_local(), args.args).await,
+		Command::DelDisk(c) => c.run(&qmp, args.args).await,
+		Command::AddObject(c) => c.run(&qmp, args.args).await,
+		Command::DelObject(c) => c.run(&qmp, args.args).await,
+		Command::Stop(c) => c.run(&qmp, args.args).await,
+		Command::Continue(c) => c.run(&qmp, args.args).await,
+		Command::Quit(c) => c.run(&qmp, args.args).await,
+		Command::Execute(c) => c.run(&qmp, args.args).await,
+		Command::Events(c) => c.run(&qmp, events(), args.args).await,
+		Command::WaitEvent(c) => c.run(&qmp, events(), args.args).await,
+		Command::Shell(c) => c.run(&qmp, events(), args.args).await,
+		Command::Media(c) => c.run(&qmp, events(), args.args).await,
+		Command::Snapshot(c) => c.run(&qmp, events(), args.args).await,
+		Command::Migrate(c) => c.run(&qmp, events(), args.args).await,
+		Command::MigrateIncoming(c) => c.run(&qmp, events(), args.args).await,
+		Command::SaveState(c) => c.run(&qmp, events(), args.args).await,
+		Command::RestoreState(c) => c.run(&qmp, events(), args.args).await,
+		Command::Screenshot(c) => c.run(&qmp, args.args).await,
+		Command::WaitScreen(c) => c.run(&qmp, args.args).await,
+		Command::SendKey(c) => c.run(&qmp, args.args).await,
+		Command::Type(c) => c.run(&qmp, args.args).await,
+		Command::Mouse(c) => c.run(&qmp, args.args).await,
+		Command::Input(c) => c.run(&qmp, args.args).await,
+		Command::Qom(c) => c.run(&qmp, args.args).await,
+		Command::Devices(c) => c.run(&qmp, args.args).await,
 	};
 
-	match timeout(Duration::from_secs(1), handle).await {
-		Err(_elapsed) => log::warn!("timed out waiting for handle to clean up"),
-		Ok(res) => res?,
-	}
+	qmp.close().await;
 
 	match res {
 		Err(e) => Err(e),
@@ -117,22 +179,27 @@ async fn main() -> Result<()> {
 
 impl ConnectionArgs {
 	fn timeout(&self) -> Option<Option<Duration>> {
-		match self.wait {
+		match self.wait || self.listen {
 			true => Some(self.timeout_seconds.map(Duration::from_secs)),
 			false => None,
 		}
 	}
 
-	async fn connect(&self) -> Result<(qapi::futures::QapiStream<QmpStreamRead, QmpStreamWrite>, qapi::qmp::QapiCapabilities)> {
-		if let Some(timeout) = self.timeout() {
-			qemucomm::wait(timeout, qemucomm::wait_for_socket(&self.socket)).await?;
-		}
+	/// Whether QEMU is on this machine, which only a unix socket guarantees
+	fn is_local(&self) -> bool {
+		matches!(self.socket, SocketAddr::Unix(..) | SocketAddr::UnixAbstract(..))
+	}
 
-		let stream = qapi::futures::QmpStreamTokio::open_uds(&self.socket).await?;
-		let capabilities = stream.capabilities.clone();
-		log::trace!("QEMU QMP Capabilities: {:#?}", capabilities);
-		let stream = stream.negotiate().await?;
+	async fn connect(&self, subscribe: bool) -> Result<(Client, Option<EventStream>)> {
+		let stream = match self.timeout() {
+			Some(timeout) if self.listen => qemucomm::wait(timeout, self.socket.accept()).await?,
+			Some(timeout) => qemucomm::wait(timeout, self.socket.wait_connect()).await?,
+			None => self.socket.connect().await?,
+		};
 
-		Ok((stream, capabilities))
+		match subscribe {
+			true => Client::with_events(stream).await.map(|(qmp, events)| (qmp, Some(events))),
+			false => Client::new(stream).await.map(|qmp| (qmp, None)),
+		}
 	}
 }
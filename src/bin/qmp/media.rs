@@ -0,0 +1,217 @@
+use anyhow::{Result, format_err};
+use clap::{Parser, Subcommand, ValueEnum};
+use qapi::qmp;
+use tokio::time::Duration;
+use qemucomm::{OutputFormat, print_table};
+use qemucomm::qmp::{Client, EventFilter, Events};
+use super::block::{Drive, DriveExt, drives, find_drive};
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Manages the media of removable drives like CD-ROMs and floppies
+///
+/// Drives are named by the id of their device or the name of their block backend.
+/// When the guest has locked a tray, it is asked to open it, and the command waits until it does.
+pub(crate) struct Media {
+	#[command(subcommand)]
+	command: MediaCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum MediaCommand {
+	List(List),
+	Eject(Eject),
+	Insert(Insert),
+	Change(Change),
+}
+
+#[derive(Parser, Debug)]
+/// Lists removable drives and their media
+struct List {
+	/// include drives that aren't removable
+	#[clap(short, long)]
+	all: bool,
+}
+
+#[derive(Parser, Debug)]
+/// Removes the medium from a drive, leaving its tray open
+struct Eject {
+	drive: String,
+	/// open the tray even if the guest has locked it
+	#[clap(short, long)]
+	force: bool,
+	#[clap(flatten)]
+	wait: WaitArgs,
+}
+
+#[derive(Parser, Debug)]
+/// Inserts an existing block node into an empty drive and closes its tray
+struct Insert {
+	drive: String,
+	node_name: String,
+	/// leave the tray open
+	#[clap(long)]
+	open: bool,
+	#[clap(flatten)]
+	wait: WaitArgs,
+}
+
+#[derive(Parser, Debug)]
+/// Replaces the medium of a drive with an image file
+struct Change {
+	drive: String,
+	filename: String,
+	/// image format, probed by QEMU when unspecified
+	#[clap(short = 'F', long)]
+	format: Option<String>,
+	#[clap(short, long, value_enum)]
+	read_only_mode: Option<ReadOnlyMode>,
+	/// open the tray even if the guest has locked it
+	#[clap(short, long)]
+	force: bool,
+	#[clap(flatten)]
+	wait: WaitArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct WaitArgs {
+	/// how long to wait for the guest to open a locked tray
+	#[clap(short, long = "timeout")]
+	timeout_seconds: Option<u64>,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum ReadOnlyMode {
+	/// keep the read-only setting of the current medium
+	Retain,
+	ReadOnly,
+	ReadWrite,
+}
+
+impl From<ReadOnlyMode> for qmp::BlockdevChangeReadOnlyMode {
+	fn from(mode: ReadOnlyMode) -> Self {
+		match mode {
+			ReadOnlyMode::Retain => qmp::BlockdevChangeReadOnlyMode::retain,
+			ReadOnlyMode::ReadOnly => qmp::BlockdevChangeReadOnlyMode::read_only,
+			ReadOnlyMode::ReadWrite => qmp::BlockdevChangeReadOnlyMode::read_write,
+		}
+	}
+}
+
+/// Opens the tray of a drive, waiting for the guest to release it if it's locked
+#[allow(deprecated)]
+async fn open_tray(qmp: &Client, mut events: Events, drive: &Drive, force: bool, wait: &WaitArgs) -> Result<()> {
+	if drive.tray_open != Some(false) {
+		return Ok(())
+	}
+
+	let (device, id) = drive.target();
+	qmp.execute(qmp::blockdev_open_tray { device, id, force: Some(force) }).await?;
+	// an unlocked tray opens immediately, otherwise the guest has only been asked to
+	if find_drive(qmp, drive.name()).await?.tray_open != Some(false) {
+		return Ok(())
+	}
+
+	log::info!("waiting for the guest to open the tray of {}", drive.name());
+	let filter = EventFilter::new("DEVICE_TRAY_MOVED")
+		.with_data(if drive.qdev.is_some() { "id" } else { "device" }, drive.name())
+		.with_data("tray-open", "true");
+	let duration = wait.timeout_seconds.map(Duration::from_secs);
+	qemucomm::wait(duration, events.wait(&filter)).await
+		.map_err(|e| format_err!("the guest didn't open the tray of {}: {}", drive.name(), e))?;
+	Ok(())
+}
+
+impl Media {
+	pub async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		match self.command {
+			MediaCommand::List(c) => c.run(qmp, args).await,
+			MediaCommand::Eject(c) => c.run(qmp, events, args).await,
+			MediaCommand::Insert(c) => c.run(qmp, events, args).await,
+			MediaCommand::Change(c) => c.run(qmp, events, args).await,
+		}
+	}
+}
+
+impl List {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let drives: Vec<_> = drives(qmp).await?.into_iter()
+			.filter(|drive| self.all || drive.removable)
+			.collect();
+		match args.output() {
+			OutputFormat::Human => print_table(&["drive", "backend", "tray", "locked", "medium", "format", "ro"], drives.iter().map(|drive| {
+				let inserted = drive.inserted.as_ref();
+				vec![
+					drive.name().into(),
+					drive.device.clone(),
+					drive.tray().into(),
+					drive.locked.to_string(),
+					inserted.map(|i| i.file.clone()).unwrap_or_else(|| "-".into()),
+					inserted.map(|i| i.drv.clone()).unwrap_or_else(|| "-".into()),
+					inserted.map(|i| i.ro.to_string()).unwrap_or_else(|| "-".into()),
+				]
+			}))?,
+			format => format.print(&drives)?,
+		}
+		Ok(0)
+	}
+}
+
+impl Eject {
+	#[allow(deprecated)]
+	async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let drive = find_drive(qmp, &self.drive).await?;
+		match self.force {
+			true => {
+				let (device, id) = drive.target();
+				qmp.execute(qmp::eject { device, id, force: Some(true) }).await?;
+			},
+			false => {
+				open_tray(qmp, events, &drive, false, &self.wait).await?;
+				qmp.execute(qmp::blockdev_remove_medium { id: drive.id()? }).await?;
+			},
+		}
+		args.output().print_done()?;
+		Ok(0)
+	}
+}
+
+impl Insert {
+	#[allow(deprecated)]
+	async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let drive = find_drive(qmp, &self.drive).await?;
+		let id = drive.id()?;
+		open_tray(qmp, events, &drive, false, &self.wait).await?;
+		qmp.execute(qmp::blockdev_insert_medium {
+			id: id.clone(),
+			node_name: self.node_name,
+		}).await?;
+		if !self.open {
+			qmp.execute(qmp::blockdev_close_tray { device: None, id: Some(id) }).await?;
+		}
+		args.output().print_done()?;
+		Ok(0)
+	}
+}
+
+impl Change {
+	#[allow(deprecated)]
+	async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let drive = find_drive(qmp, &self.drive).await?;
+		if !self.force {
+			// blockdev-change-medium fails rather than waiting for a locked tray
+			open_tray(qmp, events, &drive, false, &self.wait).await?;
+		}
+		let (device, id) = drive.target();
+		qmp.execute(qmp::blockdev_change_medium {
+			device,
+			id,
+			filename: self.filename,
+			format: self.format,
+			read_only_mode: self.read_only_mode.map(Into::into),
+			force: Some(self.force),
+		}).await?;
+		args.output().print_done()?;
+		Ok(0)
+	}
+}
@@ -0,0 +1,311 @@
+use anyhow::{Result, format_err};
+use clap::{Args, Parser};
+use futures::{StreamExt, pin_mut, select, future::FutureExt};
+use qapi::qmp;
+use serde::Deserialize;
+use is_terminal::IsTerminal;
+use std::io::{self, Write};
+use tokio::time::{Duration, sleep};
+use qemucomm::{OutputFormat, format_size, parse_size};
+use qemucomm::qmp::{Client, EventFilter, Events};
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Migrates the VM, printing progress until it completes
+///
+/// Progress is printed to stderr, or as records of `query-migrate` on stdout with --output json|yaml.
+pub(crate) struct Migrate {
+	/// destination, like tcp:HOST:PORT, unix:PATH, exec:COMMAND, or file:PATH
+	uri: String,
+	#[clap(flatten)]
+	migration: MigrationArgs,
+	#[clap(flatten)]
+	wait: WaitArgs,
+	/// cancel the migration on ctrl-c instead of leaving it running
+	#[clap(long)]
+	cancel_on_interrupt: bool,
+}
+
+#[derive(Parser, Debug)]
+/// Receives a migration into a VM started with `-incoming defer`
+pub(crate) struct MigrateIncoming {
+	/// where to listen, like tcp:HOST:PORT, unix:PATH, exec:COMMAND, or file:PATH
+	uri: String,
+	#[clap(flatten)]
+	migration: MigrationArgs,
+	#[clap(flatten)]
+	wait: WaitArgs,
+	/// return once the migration has started
+	#[clap(long)]
+	no_wait: bool,
+}
+
+/// Capabilities and parameters, which must match on both sides
+#[derive(Args, Debug)]
+pub(crate) struct MigrationArgs {
+	/// send RAM over this many parallel channels
+	#[clap(long, value_name = "CHANNELS")]
+	multifd: Option<u8>,
+	/// switch to postcopy after the first pass over RAM
+	#[clap(long)]
+	postcopy: bool,
+	/// maximum bandwidth in bytes per second, with an optional K/M/G suffix
+	#[clap(long, value_parser = size)]
+	bandwidth: Option<u64>,
+	/// maximum downtime in milliseconds
+	#[clap(long, value_name = "MS")]
+	downtime: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct WaitArgs {
+	/// how often to check progress, in milliseconds
+	#[clap(long, default_value = "1000", value_name = "MS")]
+	interval: u64,
+}
+
+fn size(s: &str) -> Result<u64> {
+	parse_size(s).ok_or_else(|| format_err!("invalid size {}", s))
+}
+
+impl MigrationArgs {
+	pub async fn apply(&self, qmp: &Client) -> Result<()> {
+		let mut capabilities = Vec::new();
+		if self.multifd.is_some() {
+			capabilities.push(qmp::MigrationCapability::multifd);
+		}
+		if self.postcopy {
+			capabilities.push(qmp::MigrationCapability::postcopy_ram);
+		}
+		if !capabilities.is_empty() {
+			qmp.execute(qmp::migrate_set_capabilities {
+				capabilities: capabilities.into_iter()
+					.map(|capability| qmp::MigrationCapabilityStatus { capability, state: true })
+					.collect(),
+			}).await?;
+		}
+
+		if self.multifd.is_some() || self.bandwidth.is_some() || self.downtime.is_some() {
+			qmp.execute(qmp::migrate_set_parameters(qmp::MigrateSetParameters {
+				multifd_channels: self.multifd,
+				max_bandwidth: self.bandwidth,
+				downtime_limit: self.downtime,
+				..Default::default()
+			})).await?;
+		}
+		Ok(())
+	}
+}
+
+/// The parts of `query-migrate` we care about, parsed loosely since its statistics vary between QEMU versions
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct MigrationInfo {
+	pub status: Option<String>,
+	ram: Option<RamStats>,
+	expected_downtime: Option<u64>,
+	total_time: Option<u64>,
+	downtime: Option<u64>,
+	pub error_desc: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct RamStats {
+	transferred: u64,
+	remaining: u64,
+	total: u64,
+	dirty_pages_rate: u64,
+	page_size: u64,
+	mbps: f64,
+	dirty_sync_count: u64,
+}
+
+impl MigrationInfo {
+	pub fn status(&self) -> &str {
+		self.status.as_deref().unwrap_or("none")
+	}
+
+	fn is_finished(&self) -> bool {
+		matches!(self.status(), "completed" | "failed" | "cancelled")
+	}
+
+	/// A line of progress, like `active  1.0 GiB of 4.0 GiB (25%), ...`
+	fn progress(&self) -> String {
+		let mut line = self.status().to_owned();
+		if let Some(ram) = &self.ram {
+			let percent = match ram.total {
+				0 => 0,
+				total => ram.transferred.saturating_mul(100) / total,
+			};
+			line += &format!("  {} of {} ({}%), {} remaining, dirty {}/s, {:.0} Mbps",
+				format_size(ram.transferred), format_size(ram.total), percent, format_size(ram.remaining),
+				format_size(ram.dirty_pages_rate * ram.page_size), ram.mbps,
+			);
+		}
+		if let Some(downtime) = self.expected_downtime.filter(|_| !self.is_finished()) {
+			line += &format!(", expected downtime {} ms", downtime);
+		}
+		line
+	}
+}
+
+/// Prints progress in place on a terminal, or a line at a time otherwise
+struct Progress {
+	format: OutputFormat,
+	terminal: bool,
+	last: Option<String>,
+}
+
+impl Progress {
+	fn new(format: OutputFormat) -> Self {
+		Progress {
+			format,
+			terminal: io::stderr().is_terminal(),
+			last: None,
+		}
+	}
+
+	fn update(&mut self, info: &MigrationInfo, value: &qapi::Any) -> Result<()> {
+		if self.format != OutputFormat::Human {
+			return self.format.write_record(io::stdout().lock(), value)
+		}
+		let line = info.progress();
+		if self.last.as_ref() == Some(&line) {
+			return Ok(())
+		}
+		let mut stderr = io::stderr().lock();
+		match self.terminal {
+			true => write!(stderr, "\r\x1b[K{}", line)?,
+			false => writeln!(stderr, "{}", line)?,
+		}
+		stderr.flush()?;
+		self.last = Some(line);
+		Ok(())
+	}
+
+	fn finish(&mut self) {
+		if self.terminal && self.last.is_some() {
+			eprintln!();
+		}
+	}
+}
+
+async fn query(qmp: &Client) -> Result<(MigrationInfo, qapi::Any)> {
+	let value = qmp.execute_any("query-migrate", None).await?;
+	Ok((serde_json::from_value(value.clone())?, value))
+}
+
+/// Waits for a migration to finish, printing its progress along the way
+///
+/// Progress is checked whenever a `MIGRATION` event arrives, or every `interval`.
+/// With `postcopy`, the migration switches to postcopy once a pass over RAM has completed.
+/// With `cancel_on_interrupt`, ctrl-c cancels the migration (and a second one gives up waiting).
+pub(crate) async fn wait_migration(qmp: &Client, events: Events, wait: &WaitArgs, format: OutputFormat, postcopy: bool, cancel_on_interrupt: bool) -> Result<MigrationInfo> {
+	let filter = EventFilter::new("MIGRATION");
+	let events = events.filter(|e| futures::future::ready(filter.matches(e))).fuse();
+	pin_mut!(events);
+	let ctrlc = match cancel_on_interrupt {
+		true => Some(async_ctrlc::CtrlC::new()?),
+		false => None,
+	};
+	let ctrlc = futures::stream::iter(ctrlc).flatten().fuse();
+	pin_mut!(ctrlc);
+	let interval = Duration::from_millis(wait.interval);
+	let mut progress = Progress::new(format);
+	let mut postcopy_started = false;
+	let mut cancelled = false;
+
+	let info = loop {
+		let (info, value) = query(qmp).await?;
+		progress.update(&info, &value)?;
+		if info.is_finished() {
+			break info
+		}
+		if postcopy && !postcopy_started && info.status() == "active" && matches!(&info.ram, Some(ram) if ram.dirty_sync_count >= 2) {
+			log::info!("starting postcopy");
+			qmp.execute(qmp::migrate_start_postcopy { }).await?;
+			postcopy_started = true;
+		}
+
+		let tick = sleep(interval).fuse();
+		pin_mut!(tick);
+		select! {
+			_ = tick => (),
+			event = events.next() => if event.is_none() {
+				progress.finish();
+				return Err(format_err!("QMP connection closed during migration"))
+			},
+			interrupt = ctrlc.next() => match cancelled {
+				// without --cancel-on-interrupt the stream is empty and ends immediately
+				_ if interrupt.is_none() => (),
+				false => {
+					log::info!("cancelling migration");
+					qmp.execute(qmp::migrate_cancel { }).await?;
+					cancelled = true;
+				},
+				true => {
+					progress.finish();
+					return Err(format_err!("interrupted while the migration was being cancelled"))
+				},
+			},
+		}
+	};
+	progress.finish();
+
+	match info.status() {
+		"completed" => Ok(info),
+		"failed" => Err(format_err!("migration failed: {}", info.error_desc.as_deref().unwrap_or("unknown error"))),
+		"cancelled" => Err(format_err!("migration was cancelled")),
+		status => Err(format_err!("unexpected migration status {}", status)),
+	}
+}
+
+fn summary(info: &MigrationInfo) {
+	let mut line = "migration completed".to_owned();
+	if let Some(time) = info.total_time {
+		line += &format!(" in {:.1} s", time as f64 / 1000.0);
+	}
+	if let Some(downtime) = info.downtime {
+		line += &format!(" with {} ms downtime", downtime);
+	}
+	println!("{}", line);
+}
+
+impl Migrate {
+	pub async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		self.migration.apply(qmp).await?;
+		qmp.execute(qmp::migrate {
+			uri: self.uri,
+			blk: None,
+			inc: None,
+			detach: None,
+			resume: None,
+		}).await?;
+
+		let format = args.output();
+		let info = wait_migration(qmp, events, &self.wait, format, self.migration.postcopy, self.cancel_on_interrupt).await?;
+		if format == OutputFormat::Human {
+			summary(&info);
+		}
+		Ok(0)
+	}
+}
+
+impl MigrateIncoming {
+	pub async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		self.migration.apply(qmp).await?;
+		qmp.execute(qmp::migrate_incoming { uri: self.uri }).await?;
+		if self.no_wait {
+			args.output().print_done()?;
+			return Ok(0)
+		}
+
+		let format = args.output();
+		let info = wait_migration(qmp, events, &self.wait, format, false, false).await?;
+		if format == OutputFormat::Human {
+			summary(&info);
+		}
+		Ok(0)
+	}
+}
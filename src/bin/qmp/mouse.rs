@@ -0,0 +1,309 @@
+use anyhow::{Result, format_err};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use qapi::qmp::{self, InputAxis, InputButton};
+use std::str::FromStr;
+use tokio::time::{Duration, sleep};
+use qemucomm::qmp::Client;
+use super::screen::{Screen, InputArgs, DumpArgs};
+use super::GlobalArgs;
+
+/// The range of absolute coordinates, from `INPUT_EVENT_ABS_MAX` in QEMU
+const ABS_MAX: i64 = 0x7fff;
+
+#[derive(Parser, Debug)]
+/// Moves the mouse pointer and presses its buttons
+///
+/// Coordinates are in pixels, scaled against the resolution of a screendump, or fractions of the screen like 0.5.
+/// Absolute movement needs a pointer device that supports it, like usb-tablet.
+pub(crate) struct Mouse {
+	#[command(subcommand)]
+	command: MouseCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum MouseCommand {
+	Move(Move),
+	Click(Click),
+	Scroll(Scroll),
+	Drag(Drag),
+}
+
+#[derive(Args, Debug)]
+struct Target {
+	// the same display is captured to find the resolution for pixel coordinates
+	#[clap(flatten)]
+	input: InputArgs,
+	#[clap(flatten)]
+	dump: DumpArgs,
+	/// how long to hold buttons, and to wait between steps, in milliseconds
+	#[clap(long, default_value = "50", value_name = "MS")]
+	delay: u64,
+}
+
+#[derive(Parser, Debug)]
+/// Moves the pointer to a position, or by an offset
+struct Move {
+	#[clap(allow_negative_numbers = true)]
+	x: Coordinate,
+	#[clap(allow_negative_numbers = true)]
+	y: Coordinate,
+	/// move by X,Y from where the pointer is, as a relative mouse would
+	#[clap(short, long)]
+	relative: bool,
+	#[clap(flatten)]
+	target: Target,
+}
+
+#[derive(Parser, Debug)]
+/// Clicks a button where the pointer is
+struct Click {
+	#[clap(value_enum, default_value = "left")]
+	button: Button,
+	#[clap(long)]
+	double: bool,
+	#[clap(flatten)]
+	target: Target,
+}
+
+#[derive(Parser, Debug)]
+/// Turns the scroll wheel
+struct Scroll {
+	#[clap(value_enum)]
+	direction: Direction,
+	/// how many notches to scroll
+	#[clap(default_value = "1")]
+	count: u32,
+	#[clap(flatten)]
+	target: Target,
+}
+
+#[derive(Parser, Debug)]
+/// Presses a button at one position and releases it at another
+struct Drag {
+	from_x: Coordinate,
+	from_y: Coordinate,
+	to_x: Coordinate,
+	to_y: Coordinate,
+	#[clap(short, long, value_enum, default_value = "left")]
+	button: Button,
+	/// how many moves to make along the way
+	#[clap(long, default_value = "10")]
+	steps: u32,
+	#[clap(flatten)]
+	target: Target,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Button {
+	Left,
+	Middle,
+	Right,
+	Side,
+	Extra,
+}
+
+impl From<Button> for InputButton {
+	fn from(button: Button) -> Self {
+		match button {
+			Button::Left => InputButton::left,
+			Button::Middle => InputButton::middle,
+			Button::Right => InputButton::right,
+			Button::Side => InputButton::side,
+			Button::Extra => InputButton::extra,
+		}
+	}
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Direction {
+	Up,
+	Down,
+	Left,
+	Right,
+}
+
+/// A position along an axis, in pixels or as a fraction of the screen
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Coordinate {
+	Pixels(i64),
+	Fraction(f64),
+}
+
+impl FromStr for Coordinate {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.contains('.') {
+			true => s.parse().map(Coordinate::Fraction)
+				.map_err(|e| format_err!("invalid fraction {}: {}", s, e)),
+			false => s.parse().map(Coordinate::Pixels)
+				.map_err(|e| format_err!("invalid pixel coordinate {}: {}", s, e)),
+		}
+	}
+}
+
+impl Coordinate {
+	/// Scales the coordinate to `0..=max`, where `size` is the screen size in pixels
+	fn scale(self, size: Option<u32>, max: i64) -> Result<i64> {
+		match (self, size) {
+			(Coordinate::Fraction(f), _) => Ok((f * max as f64).round() as i64),
+			(Coordinate::Pixels(px), Some(size)) => Ok(px * max / (size as i64 - 1).max(1)),
+			(Coordinate::Pixels(_), None) => Err(format_err!("pixel coordinates need the screen resolution")),
+		}
+	}
+
+	/// A relative movement in pixels, where `size` is the screen size in pixels
+	fn offset(self, size: Option<u32>) -> Result<i64> {
+		match (self, size) {
+			(Coordinate::Pixels(px), _) => Ok(px),
+			(Coordinate::Fraction(f), Some(size)) => Ok((f * size as f64).round() as i64),
+			(Coordinate::Fraction(_), None) => Err(format_err!("fractions need the screen resolution")),
+		}
+	}
+}
+
+fn move_event(axis: InputAxis, value: i64, relative: bool) -> qmp::InputEvent {
+	let event = qmp::InputMoveEvent { axis, value };
+	match relative {
+		false => qmp::InputEvent::abs(event.into()),
+		true => qmp::InputEvent::rel(event.into()),
+	}
+}
+
+fn button_event(button: InputButton, down: bool) -> qmp::InputEvent {
+	qmp::InputEvent::btn(qmp::InputBtnEvent { button, down }.into())
+}
+
+impl Target {
+	async fn send(&self, qmp: &Client, events: Vec<qmp::InputEvent>) -> Result<()> {
+		qmp.execute(qmp::input_send_event {
+			device: self.input.device.clone(),
+			head: self.input.head,
+			events,
+		}).await?;
+		Ok(())
+	}
+
+	/// The screen resolution, if it's needed to scale coordinates
+	async fn resolution(&self, qmp: &Client, needed: bool) -> Result<Option<(u32, u32)>> {
+		if !needed {
+			return Ok(None)
+		}
+		let image = Screen::new(qmp, self.input.screen(), self.dump.clone()).await?
+			.capture(qmp).await
+			.map_err(|e| format_err!("failed to find the screen resolution: {}", e))?;
+		log::info!("screen is {}x{}", image.width, image.height);
+		Ok(Some((image.width, image.height)))
+	}
+
+	/// Absolute coordinates of a point
+	fn position(&self, (x, y): (Coordinate, Coordinate), resolution: Option<(u32, u32)>) -> Result<(i64, i64)> {
+		let (x, y) = (x.scale(resolution.map(|r| r.0), ABS_MAX)?, y.scale(resolution.map(|r| r.1), ABS_MAX)?);
+		match (0..=ABS_MAX).contains(&x) && (0..=ABS_MAX).contains(&y) {
+			true => Ok((x, y)),
+			false => Err(format_err!("position is off the screen")),
+		}
+	}
+
+	async fn move_to(&self, qmp: &Client, (x, y): (i64, i64)) -> Result<()> {
+		self.send(qmp, vec![move_event(InputAxis::x, x, false), move_event(InputAxis::y, y, false)]).await
+	}
+
+	async fn click(&self, qmp: &Client, button: InputButton) -> Result<()> {
+		self.send(qmp, vec![button_event(button, true)]).await?;
+		sleep(Duration::from_millis(self.delay)).await;
+		self.send(qmp, vec![button_event(button, false)]).await
+	}
+}
+
+impl Mouse {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		match self.command {
+			MouseCommand::Move(c) => c.run(qmp).await?,
+			MouseCommand::Click(c) => c.run(qmp).await?,
+			MouseCommand::Scroll(c) => c.run(qmp).await?,
+			MouseCommand::Drag(c) => c.run(qmp).await?,
+		}
+		args.output().print_done()?;
+		Ok(0)
+	}
+}
+
+fn any_pixels(coordinates: &[Coordinate]) -> bool {
+	coordinates.iter().any(|c| matches!(c, Coordinate::Pixels(_)))
+}
+
+impl Move {
+	async fn run(self, qmp: &Client) -> Result<()> {
+		let target = &self.target;
+		let (x, y) = (self.x, self.y);
+		match self.relative {
+			false => {
+				let resolution = target.resolution(qmp, any_pixels(&[x, y])).await?;
+				target.move_to(qmp, target.position((x, y), resolution)?).await
+			},
+			true => {
+				// relative motion is in pixels, so only fractions need scaling
+				let resolution = target.resolution(qmp, !any_pixels(&[x]) || !any_pixels(&[y])).await?;
+				let (dx, dy) = (x.offset(resolution.map(|r| r.0))?, y.offset(resolution.map(|r| r.1))?);
+				target.send(qmp, vec![move_event(InputAxis::x, dx, true), move_event(InputAxis::y, dy, true)]).await
+			},
+		}
+	}
+}
+
+impl Click {
+	async fn run(self, qmp: &Client) -> Result<()> {
+		let button = self.button.into();
+		self.target.click(qmp, button).await?;
+		if self.double {
+			sleep(Duration::from_millis(self.target.delay)).await;
+			self.target.click(qmp, button).await?;
+		}
+		Ok(())
+	}
+}
+
+impl Scroll {
+	async fn run(self, qmp: &Client) -> Result<()> {
+		let button = match self.direction {
+			Direction::Up => InputButton::wheel_up,
+			Direction::Down => InputButton::wheel_down,
+			Direction::Left => InputButton::wheel_left,
+			Direction::Right => InputButton::wheel_right,
+		};
+		for i in 0..self.count {
+			if i > 0 {
+				sleep(Duration::from_millis(self.target.delay)).await;
+			}
+			// wheels are buttons that are pressed and released in one go
+			self.target.send(qmp, vec![button_event(button, true), button_event(button, false)]).await?;
+		}
+		Ok(())
+	}
+}
+
+impl Drag {
+	async fn run(self, qmp: &Client) -> Result<()> {
+		let target = &self.target;
+		let resolution = target.resolution(qmp, any_pixels(&[self.from_x, self.from_y, self.to_x, self.to_y])).await?;
+		let from = target.position((self.from_x, self.from_y), resolution)?;
+		let to = target.position((self.to_x, self.to_y), resolution)?;
+		let button = self.button.into();
+		let delay = Duration::from_millis(target.delay);
+
+		target.move_to(qmp, from).await?;
+		sleep(delay).await;
+		target.send(qmp, vec![button_event(button, true)]).await?;
+		let steps = self.steps.max(1) as i64;
+		for step in 1..=steps {
+			sleep(delay).await;
+			target.move_to(qmp, (
+				from.0 + (to.0 - from.0) * step / steps,
+				from.1 + (to.1 - from.1) * step / steps,
+			)).await?;
+		}
+		sleep(delay).await;
+		target.send(qmp, vec![button_event(button, false)]).await
+	}
+}
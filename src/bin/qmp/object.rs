@@ -1,14 +1,19 @@
 use anyhow::Result;
 use clap::Parser;
 use qapi::qmp;
-use qemucomm::{Pair, args_options};
-use super::{GlobalArgs, QmpStream};
+use qemucomm::{Pair, Key, object_options};
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 pub(crate) struct AddObject {
 	#[clap(short, long)]
 	id: Option<String>,
-	arguments: Vec<Pair<String, String>>,
+	/// object properties, including `qom-type`
+	///
+	/// Values are typed according to the properties of the object type, and can be
+	/// overridden with an explicit `KEY:str=value` (or int, bool, size, number, json).
+	arguments: Vec<Pair<Key, String>>,
 }
 
 #[derive(Parser, Debug)]
@@ -17,17 +22,24 @@ pub(crate) struct DelObject {
 }
 
 impl AddObject {
-	pub async fn run(self, qmp: QmpStream, _args: GlobalArgs) -> Result<i32> {
-		qmp.execute(qmp::object_add(args_options(self.id, self.arguments)?)).await?;
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let qom_type = self.arguments.iter()
+			.find(|arg| arg.key.path == ["qom-type"])
+			.map(|arg| arg.value.clone());
+		let types = match qom_type {
+			Some(typename) => qmp.property_types(qmp::qom_list_properties { typename }).await,
+			None => Default::default(),
+		};
+		qmp.object_add(object_options(self.id, self.arguments, &types)?).await?;
+		args.output().print_done()?;
 		Ok(0)
 	}
 }
 
 impl DelObject {
-	pub async fn run(self, qmp: QmpStream, _args: GlobalArgs) -> Result<i32> {
-		qmp.execute(qmp::object_del {
-			id: self.id,
-		}).await?;
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		qmp.object_del(&self.id).await?;
+		args.output().print_done()?;
 		Ok(0)
 	}
 }
@@ -0,0 +1,231 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use futures::future::{BoxFuture, FutureExt, join_all};
+use qapi::qmp;
+use serde::Serialize;
+use std::io::{self, Write};
+use qemucomm::{OutputFormat, ValueType, Key, print_table};
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Browses the QOM tree of objects and their properties
+///
+/// Devices with an id are under /machine/peripheral, those without under /machine/peripheral-anon,
+/// and objects made with add-object under /objects.
+pub(crate) struct Qom {
+	#[command(subcommand)]
+	command: QomCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum QomCommand {
+	Ls(Ls),
+	Get(Get),
+	Set(Set),
+	Tree(Tree),
+}
+
+#[derive(Parser, Debug)]
+/// Lists the properties and children of an object
+struct Ls {
+	path: String,
+}
+
+#[derive(Parser, Debug)]
+/// Prints the value of a property
+struct Get {
+	path: String,
+	property: String,
+}
+
+#[derive(Parser, Debug)]
+/// Changes the value of a property
+struct Set {
+	path: String,
+	property: String,
+	/// typed according to the property, unless --type is given
+	value: String,
+	/// how to parse the value: str, bool, int, size, number, or json
+	#[clap(short, long = "type")]
+	ty: Option<ValueType>,
+}
+
+#[derive(Parser, Debug)]
+/// Prints an object and its children, recursively
+struct Tree {
+	#[clap(default_value = "/")]
+	path: String,
+	/// how many levels of children to show [default: all of them]
+	#[clap(long)]
+	depth: Option<usize>,
+	/// show the values of properties too
+	#[clap(long)]
+	props: bool,
+}
+
+/// An object in the tree, and everything below it
+#[derive(Serialize, Debug)]
+struct Node {
+	name: String,
+	#[serde(rename = "type")]
+	ty: String,
+	#[serde(skip_serializing_if = "qapi::Dictionary::is_empty")]
+	properties: qapi::Dictionary,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	children: Vec<Node>,
+}
+
+fn child_path(path: &str, name: &str) -> String {
+	format!("{}/{}", path.trim_end_matches('/'), name)
+}
+
+/// The type of a child, from a property type like `child<virtio-net-pci>`
+fn child_type(ty: &str) -> Option<&str> {
+	ty.strip_prefix("child<")?.strip_suffix('>')
+}
+
+impl Qom {
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		match self.command {
+			QomCommand::Ls(c) => c.run(qmp, args).await,
+			QomCommand::Get(c) => c.run(qmp, args).await,
+			QomCommand::Set(c) => c.run(qmp, args).await,
+			QomCommand::Tree(c) => c.run(qmp, args).await,
+		}
+	}
+}
+
+impl Ls {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let props = qmp.execute(qmp::qom_list { path: self.path }).await?;
+		match args.output() {
+			OutputFormat::Human => print_table(&["name", "type"], props.iter().map(|prop| vec![
+				prop.name.clone(),
+				prop.type_.clone(),
+			]))?,
+			format => format.print(&props)?,
+		}
+		Ok(0)
+	}
+}
+
+impl Get {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let value = qmp.execute(qmp::qom_get {
+			path: self.path,
+			property: self.property,
+		}).await?;
+		args.output().print(&value)?;
+		Ok(0)
+	}
+}
+
+impl Set {
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let types = match self.ty {
+			Some(..) => Default::default(),
+			None => qmp.property_types(qmp::qom_list { path: self.path.clone() }).await,
+		};
+		let key = Key {
+			path: vec![self.property.clone()],
+			ty: self.ty,
+		};
+		let value = types.parse(&key, &self.value)?;
+		qmp.execute(qmp::qom_set {
+			path: self.path,
+			property: self.property,
+			value,
+		}).await?;
+		args.output().print_done()?;
+		Ok(0)
+	}
+}
+
+impl Tree {
+	/// Reads an object, and its children down to `depth` levels below it
+	fn walk<'a>(&'a self, qmp: &'a Client, path: String, name: String, ty: String, depth: usize) -> BoxFuture<'a, Result<Node>> {
+		async move {
+			let props = qmp.execute(qmp::qom_list { path: path.clone() }).await?;
+			let below = self.depth.map(|max| depth < max).unwrap_or(true);
+			let children = join_all(props.iter()
+				.filter(|_| below)
+				.filter_map(|prop| child_type(&prop.type_).map(|ty| (prop, ty)))
+				.map(|(prop, ty)| self.walk(qmp, child_path(&path, &prop.name), prop.name.clone(), ty.into(), depth + 1))
+			).await.into_iter()
+				.filter_map(|child| match child {
+					Ok(child) => Some(child),
+					// objects can disappear while walking, like a device being unplugged
+					Err(e) => {
+						log::warn!("skipping a child of {}: {}", path, e);
+						None
+					},
+				})
+				.collect();
+			let properties = match self.props {
+				false => Default::default(),
+				true => {
+					// links are shown as the path they point to, rather than followed,
+					// and the type is already shown alongside the name
+					let props: Vec<_> = props.iter()
+						.filter(|prop| child_type(&prop.type_).is_none() && prop.name != "type")
+						.collect();
+					let values = join_all(props.iter().map(|prop| qmp.execute(qmp::qom_get {
+						path: path.clone(),
+						property: prop.name.clone(),
+					}))).await;
+					props.into_iter().zip(values)
+						.filter_map(|(prop, value)| match value {
+							Ok(value) => Some((prop.name.clone(), value)),
+							// some properties can't be read, like those that are write-only
+							Err(e) => {
+								log::info!("failed to read {}.{}: {}", path, prop.name, e);
+								None
+							},
+						})
+						.collect()
+				},
+			};
+			Ok(Node {
+				name,
+				ty,
+				properties,
+				children,
+			})
+		}.boxed()
+	}
+
+	async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let ty = qmp.execute(qmp::qom_get {
+			path: self.path.clone(),
+			property: "type".into(),
+		}).await?;
+		let ty = ty.as_str().unwrap_or_default().into();
+		let tree = self.walk(qmp, self.path.clone(), self.path.clone(), ty, 0).await?;
+		match args.output() {
+			OutputFormat::Human => {
+				let mut stdout = io::stdout().lock();
+				print_node(&mut stdout, &tree, 0)?;
+				stdout.flush()?;
+			},
+			format => format.print(&tree)?,
+		}
+		Ok(0)
+	}
+}
+
+fn print_node<W: Write>(w: &mut W, node: &Node, indent: usize) -> io::Result<()> {
+	writeln!(w, "{:indent$}{} ({})", "", node.name, node.ty, indent = indent)?;
+	for (name, value) in &node.properties {
+		// strings are shown without JSON quotes
+		let value = match value {
+			qapi::Any::String(value) => value.clone(),
+			value => value.to_string(),
+		};
+		writeln!(w, "{:indent$}{} = {}", "", name, value, indent = indent + 2)?;
+	}
+	for child in &node.children {
+		print_node(w, child, indent + 2)?;
+	}
+	Ok(())
+}
@@ -0,0 +1,136 @@
+use anyhow::{Result, format_err};
+use clap::Args;
+use rustyline::{Editor, Helper, ExternalPrinter, Config, CompletionType};
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use std::path::PathBuf;
+use std::io::{self, Write};
+use std::{env, fs};
+
+#[derive(Args, Debug)]
+pub(crate) struct HistoryArgs {
+	/// history file [default: $XDG_STATE_HOME/qemucomm/<command>_history]
+	#[clap(long)]
+	history: Option<PathBuf>,
+	/// don't load or save history
+	#[clap(long, conflicts_with = "history")]
+	no_history: bool,
+}
+
+impl HistoryArgs {
+	/// The history file to use, with a default location under `$XDG_STATE_HOME/qemucomm`
+	pub fn path(self, name: &str) -> Option<PathBuf> {
+		match self.no_history {
+			true => None,
+			false => self.history.or_else(|| state_dir().map(|dir| dir.join(name))),
+		}
+	}
+}
+
+/// Where to keep state between runs, `$XDG_STATE_HOME/qemucomm`
+pub(crate) fn state_dir() -> Option<PathBuf> {
+	env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+		.or_else(|| env::var_os("HOME").filter(|dir| !dir.is_empty()).map(|home| PathBuf::from(home).join(".local/state")))
+		.map(|dir| dir.join("qemucomm"))
+}
+
+/// A line editor with persistent history
+///
+/// Lines are read on a blocking thread, so the runtime is free to do other work (like printing events) meanwhile.
+pub(crate) struct Readline<H: Helper> {
+	editor: Option<Editor<H, FileHistory>>,
+	history: Option<PathBuf>,
+}
+
+impl<H: Helper + Send + 'static> Readline<H> {
+	pub fn new(helper: H, history: Option<PathBuf>) -> Result<Self> {
+		let config = Config::builder()
+			.max_history_size(1000)?
+			.history_ignore_space(true)
+			.completion_type(CompletionType::List)
+			.build();
+		let mut editor = Editor::with_config(config)?;
+		editor.set_helper(Some(helper));
+		if let Some(path) = &history {
+			match editor.load_history(path) {
+				Ok(()) => (),
+				Err(ReadlineError::Io(e)) if e.kind() == io::ErrorKind::NotFound => (),
+				Err(e) => log::warn!("failed to load history from {}: {}", path.display(), e),
+			}
+		}
+
+		Ok(Readline {
+			editor: Some(editor),
+			history,
+		})
+	}
+
+	/// Prints above the line being edited
+	pub fn printer(&mut self) -> Printer {
+		match self.editor.as_mut().map(|e| e.create_external_printer()) {
+			Some(Ok(printer)) => Printer::External(Box::new(printer)),
+			// not a terminal, so there's no line being edited to worry about
+			_ => Printer::Stdout,
+		}
+	}
+
+	/// Reads a line, or `None` at the end of input
+	///
+	/// An interrupted line is returned as empty.
+	pub async fn read(&mut self, prompt: &str) -> Result<Option<String>> {
+		let mut editor = self.editor.take()
+			.ok_or_else(|| format_err!("readline was interrupted"))?;
+		let prompt = prompt.to_owned();
+		let (editor, line) = tokio::task::spawn_blocking(move || {
+			let line = editor.readline(&prompt);
+			(editor, line)
+		}).await?;
+		let editor = self.editor.insert(editor);
+
+		match line {
+			Ok(line) => {
+				if !line.trim().is_empty() {
+					editor.add_history_entry(&line)?;
+				}
+				Ok(Some(line))
+			},
+			Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+			Err(ReadlineError::Eof) => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Saves history
+	pub fn close(mut self) -> Result<()> {
+		match (self.editor.as_mut(), &self.history) {
+			(Some(editor), Some(path)) => {
+				if let Some(dir) = path.parent() {
+					fs::create_dir_all(dir)?;
+				}
+				editor.save_history(path)
+					.map_err(|e| format_err!("failed to save history to {}: {}", path.display(), e))
+			},
+			_ => Ok(()),
+		}
+	}
+}
+
+pub(crate) enum Printer {
+	External(Box<dyn ExternalPrinter + Send>),
+	Stdout,
+}
+
+impl Printer {
+	/// Prints lines of text, which should end with a newline
+	pub fn print(&mut self, text: String) {
+		match self {
+			Printer::External(printer) => if let Err(e) = printer.print(text) {
+				log::warn!("failed to print: {}", e);
+			},
+			Printer::Stdout => {
+				let mut stdout = io::stdout().lock();
+				let _ = stdout.write_all(text.as_bytes()).and_then(|()| stdout.flush());
+			},
+		}
+	}
+}
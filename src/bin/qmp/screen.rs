@@ -0,0 +1,314 @@
+use anyhow::{Result, format_err};
+use clap::Args;
+use qapi::qmp;
+use std::io::Write;
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use std::fs;
+use qemucomm::qmp::{Client, SchemaType};
+
+/// Which display to capture
+#[derive(Args, Debug, Clone)]
+pub(crate) struct ScreenArgs {
+	/// id of the display device [default: the first console]
+	#[clap(short, long)]
+	pub device: Option<String>,
+	/// head of a multi-head display device
+	#[clap(long, requires = "device")]
+	pub head: Option<i64>,
+}
+
+/// Which display's console input events are sent to
+#[derive(Args, Debug, Clone)]
+pub(crate) struct InputArgs {
+	/// id of the display device to send input to [default: the console QEMU routes input to]
+	#[clap(short, long)]
+	pub device: Option<String>,
+	/// head of a multi-head display device to send input to
+	#[clap(long, requires = "device")]
+	pub head: Option<i64>,
+}
+
+impl InputArgs {
+	/// The display that shows what the input is aimed at
+	pub fn screen(&self) -> ScreenArgs {
+		ScreenArgs {
+			device: self.device.clone(),
+			head: self.head,
+		}
+	}
+}
+
+/// Where QEMU writes screendumps, which it opens itself
+#[derive(Args, Debug, Clone)]
+pub(crate) struct DumpArgs {
+	/// directory QEMU can write screendumps to [default: a private temporary directory,
+	/// which only works if QEMU runs as the same user, so not under libvirt or as a system service]
+	#[clap(long = "dump-dir", value_name = "DIR")]
+	pub dir: Option<PathBuf>,
+}
+
+/// A file for QEMU to write a screendump to, removed when dropped
+enum DumpFile {
+	/// in a fresh private directory, so nobody else can plant a symlink there
+	Private(tempfile::TempDir, PathBuf),
+	/// created exclusively in a shared directory, writable but not readable by others
+	Shared(tempfile::TempPath),
+}
+
+impl DumpFile {
+	fn new(dir: Option<&Path>, extension: &str) -> Result<Self> {
+		let mut builder = tempfile::Builder::new();
+		builder.prefix("qemucomm-screendump-");
+		Ok(match dir {
+			None => {
+				let dir = builder.tempdir()?;
+				let path = dir.path().join(format!("screendump{}", extension));
+				DumpFile::Private(dir, path)
+			},
+			Some(dir) => {
+				let file = builder.suffix(extension).tempfile_in(dir)
+					.map_err(|e| format_err!("failed to create a screendump file in {}: {}", dir.display(), e))?;
+				file.as_file().set_permissions(fs::Permissions::from_mode(0o622))?;
+				DumpFile::Shared(file.into_temp_path())
+			},
+		})
+	}
+
+	fn path(&self) -> &Path {
+		match self {
+			DumpFile::Private(_, path) => path,
+			DumpFile::Shared(path) => path,
+		}
+	}
+
+	fn close(self) {
+		let res = match self {
+			DumpFile::Private(dir, _) => dir.close(),
+			DumpFile::Shared(path) => path.close(),
+		};
+		if let Err(e) = res {
+			log::warn!("failed to remove temporary screendump: {}", e);
+		}
+	}
+}
+
+/// A screendump as QEMU wrote it
+pub(crate) enum Dump {
+	Png(Vec<u8>),
+	Ppm(Vec<u8>),
+}
+
+/// A rectangle of the screen, parsed from `X,Y,WIDTH,HEIGHT`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Region {
+	pub x: u32,
+	pub y: u32,
+	pub width: u32,
+	pub height: u32,
+}
+
+impl FromStr for Region {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let fields = s.split(',')
+			.map(|field| field.trim().parse())
+			.collect::<Result<Vec<u32>, _>>()
+			.map_err(|e| format_err!("invalid region {}: {}", s, e))?;
+		match fields[..] {
+			[x, y, width, height] if width > 0 && height > 0 => Ok(Region { x, y, width, height }),
+			[_, _, _, _] => Err(format_err!("region {} is empty", s)),
+			_ => Err(format_err!("invalid region {}, expected X,Y,WIDTH,HEIGHT", s)),
+		}
+	}
+}
+
+/// A decoded RGB image
+pub(crate) struct Image {
+	pub width: u32,
+	pub height: u32,
+	/// 3 bytes per pixel, row by row
+	pub pixels: Vec<u8>,
+}
+
+/// Takes screendumps into a temporary file, as PNG if QEMU was built with libpng
+pub(crate) struct Screen {
+	args: ScreenArgs,
+	dump: DumpArgs,
+	png: bool,
+}
+
+impl Screen {
+	pub async fn new(qmp: &Client, args: ScreenArgs, dump: DumpArgs) -> Result<Self> {
+		let png = match qmp.schema().await {
+			Ok(schema) => matches!(
+				schema.argument_type("screendump", &["format"]).and_then(|ty| schema.get(ty)),
+				Some(SchemaType::Enum(values)) if values.iter().any(|v| v == "png")
+			),
+			Err(e) => {
+				log::warn!("failed to query QMP schema, assuming screendump only writes PPM: {}", e);
+				false
+			},
+		};
+		Ok(Screen {
+			args,
+			dump,
+			png,
+		})
+	}
+
+	pub async fn dump(&self, qmp: &Client) -> Result<Dump> {
+		// QEMU writes the file itself, so this only works on the same host
+		let file = DumpFile::new(self.dump.dir.as_deref(), if self.png { ".png" } else { ".ppm" })?;
+		let path = file.path();
+		let filename = path.to_str()
+			.ok_or_else(|| format_err!("invalid temporary path {}", path.display()))?;
+		qmp.execute(qmp::screendump {
+			filename: filename.into(),
+			device: self.args.device.clone(),
+			head: self.args.head,
+			format: Some(qmp::ImageFormat::png).filter(|_| self.png),
+		}).await?;
+		let data = fs::read(path)
+			.map_err(|e| format_err!("failed to read screendump {}: {}", path.display(), e))?;
+		file.close();
+		Ok(match self.png {
+			true => Dump::Png(data),
+			false => Dump::Ppm(data),
+		})
+	}
+
+	pub async fn capture(&self, qmp: &Client) -> Result<Image> {
+		self.dump(qmp).await?.decode()
+	}
+}
+
+impl Dump {
+	pub fn decode(self) -> Result<Image> {
+		match self {
+			Dump::Png(data) => Image::from_png(&data),
+			Dump::Ppm(data) => Image::from_ppm(&data),
+		}
+	}
+
+	/// Writes the dump as PNG, converting it if necessary
+	pub fn write_png<W: Write>(self, mut w: W) -> Result<()> {
+		match self {
+			Dump::Png(data) => Ok(w.write_all(&data)?),
+			Dump::Ppm(data) => Image::from_ppm(&data)?.write_png(w),
+		}
+	}
+}
+
+impl Image {
+	/// Parses a binary (P6) PPM with 8 bits per channel, as written by `screendump`
+	pub fn from_ppm(data: &[u8]) -> Result<Self> {
+		let mut rest = data;
+		let mut fields = [0u32; 3];
+		let magic = next_token(&mut rest);
+		if magic != Some(&b"P6"[..]) {
+			return Err(format_err!("screendump is not a binary PPM"))
+		}
+		for field in &mut fields {
+			*field = next_token(&mut rest)
+				.and_then(|token| std::str::from_utf8(token).ok())
+				.and_then(|token| token.parse().ok())
+				.ok_or_else(|| format_err!("invalid PPM header"))?;
+		}
+		let [width, height, max] = fields;
+		if max != 255 {
+			return Err(format_err!("unsupported PPM maximum value {}", max))
+		}
+		// a single whitespace character separates the header from the pixels
+		let pixels = rest.get(1..).unwrap_or_default();
+		let size = width as usize * height as usize * 3;
+		if pixels.len() < size {
+			return Err(format_err!("PPM is truncated: expected {} bytes of pixels, got {}", size, pixels.len()))
+		}
+		Ok(Image {
+			width,
+			height,
+			pixels: pixels[..size].to_vec(),
+		})
+	}
+
+	/// Decodes a PNG of any colour type, dropping transparency
+	pub fn from_png(data: &[u8]) -> Result<Self> {
+		let mut decoder = png::Decoder::new(data);
+		decoder.set_transformations(png::Transformations::normalize_to_color8());
+		let mut reader = decoder.read_info()?;
+		let mut buffer = vec![0; reader.output_buffer_size()];
+		let info = reader.next_frame(&mut buffer)?;
+		buffer.truncate(info.buffer_size());
+		let pixels = match info.color_type {
+			png::ColorType::Rgb => buffer,
+			png::ColorType::Rgba => buffer.chunks(4).flat_map(|p| [p[0], p[1], p[2]]).collect(),
+			png::ColorType::Grayscale => buffer.iter().flat_map(|&v| [v, v, v]).collect(),
+			png::ColorType::GrayscaleAlpha => buffer.chunks(2).flat_map(|p| [p[0], p[0], p[0]]).collect(),
+			// expanded to RGB by normalize_to_color8
+			png::ColorType::Indexed => return Err(format_err!("unexpected indexed PNG")),
+		};
+		Ok(Image {
+			width: info.width,
+			height: info.height,
+			pixels,
+		})
+	}
+
+	/// The part of the image within `region`, if it fits
+	pub fn crop(&self, region: Region) -> Option<Image> {
+		if region.x.checked_add(region.width)? > self.width || region.y.checked_add(region.height)? > self.height {
+			return None
+		}
+		let stride = self.width as usize * 3;
+		let pixels = (region.y..region.y + region.height).flat_map(|y| {
+			let start = y as usize * stride + region.x as usize * 3;
+			&self.pixels[start..start + region.width as usize * 3]
+		}).copied().collect();
+		Some(Image {
+			width: region.width,
+			height: region.height,
+			pixels,
+		})
+	}
+
+	/// The fraction of pixels whose channels all differ by at most `tolerance`, or 0 if the sizes differ
+	pub fn similarity(&self, other: &Image, tolerance: u8) -> f64 {
+		if (self.width, self.height) != (other.width, other.height) || self.pixels.is_empty() {
+			return 0.0
+		}
+		let matching = self.pixels.chunks(3).zip(other.pixels.chunks(3))
+			.filter(|(a, b)| a.iter().zip(b.iter()).all(|(a, b)| a.abs_diff(*b) <= tolerance))
+			.count();
+		matching as f64 / (self.pixels.len() / 3) as f64
+	}
+
+	pub fn write_png<W: Write>(&self, w: W) -> Result<()> {
+		let mut encoder = png::Encoder::new(w, self.width, self.height);
+		encoder.set_color(png::ColorType::Rgb);
+		encoder.set_depth(png::BitDepth::Eight);
+		let mut writer = encoder.write_header()?;
+		writer.write_image_data(&self.pixels)?;
+		writer.finish()?;
+		Ok(())
+	}
+}
+
+/// Splits the next whitespace-separated token off a PPM header, skipping comments
+fn next_token<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+	loop {
+		let start = data.iter().position(|c| !c.is_ascii_whitespace())?;
+		*data = &data[start..];
+		if data[0] != b'#' {
+			break
+		}
+		let end = data.iter().position(|&c| c == b'\n')?;
+		*data = &data[end..];
+	}
+	let end = data.iter().position(|c| c.is_ascii_whitespace()).unwrap_or(data.len());
+	let (token, rest) = data.split_at(end);
+	*data = rest;
+	Some(token)
+}
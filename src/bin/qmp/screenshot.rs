@@ -0,0 +1,105 @@
+use anyhow::{Result, format_err};
+use clap::Parser;
+use serde::Serialize;
+use is_terminal::IsTerminal;
+use std::io::{self, Write};
+use std::path::Path;
+use std::fs::File;
+use tokio::time::{Duration, MissedTickBehavior, interval};
+use qemucomm::qmp::Client;
+use super::screen::{Screen, ScreenArgs, DumpArgs};
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Saves the guest screen as PNG
+///
+/// With --every, screenshots are numbered like `FILE-0001.png`, or written back to back on stdout,
+/// until --count of them have been taken.
+pub(crate) struct Screenshot {
+	/// PNG file to write, or - for stdout
+	#[clap(default_value = "-")]
+	file: String,
+	#[clap(flatten)]
+	screen: ScreenArgs,
+	#[clap(flatten)]
+	dump: DumpArgs,
+	/// take a screenshot every SECONDS
+	#[clap(long, value_name = "SECONDS", value_parser = seconds)]
+	every: Option<Duration>,
+	/// how many screenshots to take [default: until interrupted]
+	#[clap(long, requires = "every")]
+	count: Option<u64>,
+}
+
+/// A screenshot written to a file
+#[derive(Serialize, Debug)]
+struct Capture {
+	file: String,
+}
+
+fn seconds(s: &str) -> Result<Duration> {
+	s.parse().ok()
+		.and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+		.filter(|duration| !duration.is_zero())
+		.ok_or_else(|| format_err!("expected a positive number of seconds"))
+}
+
+impl Screenshot {
+	fn stdout(&self) -> bool {
+		self.file == "-"
+	}
+
+	/// The file for the `index`th screenshot, counting from 1
+	fn file(&self, index: u64) -> String {
+		if self.every.is_none() {
+			return self.file.clone()
+		}
+		let path = Path::new(&self.file);
+		let numbered = match (path.file_stem(), path.extension()) {
+			(Some(stem), Some(ext)) => format!("{}-{:04}.{}", stem.to_string_lossy(), index, ext.to_string_lossy()),
+			_ => format!("{}-{:04}.png", self.file, index),
+		};
+		path.with_file_name(numbered).to_string_lossy().into_owned()
+	}
+
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		if self.stdout() && io::stdout().is_terminal() {
+			return Err(format_err!("refusing to write PNG to a terminal, specify a file"))
+		}
+		let count = match self.every {
+			None => Some(1),
+			Some(_) => self.count,
+		};
+
+		let screen = Screen::new(qmp, self.screen.clone(), self.dump.clone()).await?;
+		let mut ticks = self.every.map(|every| {
+			let mut ticks = interval(every);
+			ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+			ticks
+		});
+		let mut index = 0;
+		while count != Some(index) {
+			if let Some(ticks) = &mut ticks {
+				ticks.tick().await;
+			}
+			index += 1;
+			let dump = screen.dump(qmp).await?;
+			match self.stdout() {
+				true => {
+					let mut stdout = io::stdout().lock();
+					dump.write_png(&mut stdout)?;
+					stdout.flush()?;
+				},
+				false => {
+					let file = self.file(index);
+					dump.write_png(File::create(&file)?)?;
+					log::info!("saved screenshot to {}", file);
+					if args.output() != qemucomm::OutputFormat::Human {
+						args.output().write_record(io::stdout().lock(), &Capture { file })?;
+					}
+				},
+			}
+		}
+		Ok(0)
+	}
+}
@@ -0,0 +1,273 @@
+use anyhow::{Result, format_err};
+use clap::Parser;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::Helper;
+use serde::Deserialize;
+use std::sync::Arc;
+use qemucomm::{Pair, Key, keyval_dict};
+use qemucomm::qmp::{Client, Events, Schema, SchemaType, event_value};
+use super::readline::{Readline, HistoryArgs};
+use super::events::render_event;
+use super::GlobalArgs;
+
+const PROMPT: &str = "qmp> ";
+const BUILTINS: [&str; 2] = ["help", "exit"];
+
+#[derive(Parser, Debug)]
+/// Runs QMP commands interactively
+///
+/// Each line is either `COMMAND [KEY=value ...]`, or a raw JSON `{"execute": COMMAND, "arguments": {..}}`.
+/// Command names and arguments are completed from the QMP schema, and `help [COMMAND]` describes them.
+/// Events are printed as they arrive.
+pub(crate) struct Shell {
+	#[clap(flatten)]
+	history: HistoryArgs,
+	/// don't print events
+	#[clap(long)]
+	no_events: bool,
+}
+
+enum Line {
+	Empty,
+	Help(Option<String>),
+	Exit,
+	Execute(String, Option<qapi::Dictionary>),
+}
+
+#[derive(Deserialize)]
+struct RawCommand {
+	execute: String,
+	arguments: Option<qapi::Dictionary>,
+}
+
+impl Shell {
+	pub async fn run(self, qmp: &Client, mut events: Events, args: GlobalArgs) -> Result<i32> {
+		let format = args.raw_output();
+		let schema = match qmp.schema().await {
+			Ok(schema) => schema,
+			Err(e) => {
+				log::warn!("failed to query QMP schema, commands won't be completed: {}", e);
+				Default::default()
+			},
+		};
+		let schema = Arc::new(schema);
+
+		let history = self.history.path("qmp_history");
+		let mut readline = Readline::new(ShellHelper { schema: schema.clone() }, history)?;
+
+		let printer = match self.no_events {
+			true => {
+				drop(events);
+				None
+			},
+			false => {
+				let mut printer = readline.printer();
+				Some(tokio::spawn(async move {
+					while let Some(event) = events.recv().await {
+						match render_event(&event_value(&event), format) {
+							Ok(event) => printer.print(event),
+							Err(e) => log::warn!("failed to render event: {}", e),
+						}
+					}
+				}))
+			},
+		};
+
+		let res = loop {
+			let line = match readline.read(PROMPT).await {
+				Ok(Some(line)) => line,
+				Ok(None) => break Ok(0),
+				Err(e) => break Err(e),
+			};
+			let line = match parse_line(&line, &schema) {
+				Ok(line) => line,
+				Err(e) => {
+					eprintln!("{:#}", e);
+					continue
+				},
+			};
+			match line {
+				Line::Empty => (),
+				Line::Help(command) => help(&schema, command.as_deref()),
+				Line::Exit => break Ok(0),
+				Line::Execute(command, arguments) => match qmp.execute_any(command, arguments).await {
+					Ok(res) => format.print(&res)?,
+					Err(qapi::ExecuteError::Qapi(e)) => eprintln!("{:?}: {}", e.class, e.desc),
+					Err(e) => break Err(e.into()),
+				},
+			}
+		};
+
+		if let Some(printer) = printer {
+			printer.abort();
+		}
+		readline.close()?;
+
+		res
+	}
+}
+
+fn parse_line(line: &str, schema: &Schema) -> Result<Line> {
+	let line = line.trim();
+	if line.starts_with('{') {
+		let raw: RawCommand = serde_json::from_str(line)?;
+		return Ok(Line::Execute(raw.execute, raw.arguments))
+	}
+
+	let mut words = split_words(line)?.into_iter();
+	let command = match words.next() {
+		Some(command) => command,
+		None => return Ok(Line::Empty),
+	};
+	match &command[..] {
+		"help" => return Ok(Line::Help(words.next())),
+		"exit" => return Ok(Line::Exit),
+		_ => (),
+	}
+
+	let arguments = words
+		.map(|word| word.parse::<Pair<Key, String>>())
+		.collect::<Result<Vec<_>>>()?;
+	let arguments = match arguments.is_empty() {
+		true => None,
+		false => Some(keyval_dict(arguments, &schema.property_types(&command))?),
+	};
+	Ok(Line::Execute(command, arguments))
+}
+
+/// Splits a line into words at whitespace, honouring quotes and backslash escapes
+fn split_words(line: &str) -> Result<Vec<String>> {
+	let mut words = Vec::new();
+	let mut word: Option<String> = None;
+	let mut quote = None;
+	let mut chars = line.chars();
+	while let Some(c) = chars.next() {
+		match (quote, c) {
+			(None, c) if c.is_whitespace() => words.extend(word.take()),
+			(None, '"' | '\'') => {
+				quote = Some(c);
+				word.get_or_insert_with(String::new);
+			},
+			(Some(q), c) if q == c => quote = None,
+			(None | Some('"'), '\\') => match chars.next() {
+				Some(c) => word.get_or_insert_with(String::new).push(c),
+				None => return Err(format_err!("trailing backslash")),
+			},
+			(_, c) => word.get_or_insert_with(String::new).push(c),
+		}
+	}
+	if quote.is_some() {
+		return Err(format_err!("unterminated quote"))
+	}
+	words.extend(word);
+
+	Ok(words)
+}
+
+fn help(schema: &Schema, command: Option<&str>) {
+	let command = match command {
+		Some(command) => command,
+		None => {
+			for command in schema.commands() {
+				println!("{}", command);
+			}
+			return
+		},
+	};
+	if !schema.has_command(command) {
+		eprintln!("unknown command {}", command);
+		return
+	}
+
+	println!("{}", command);
+	for arg in schema.arguments(command) {
+		let optional = match arg.optional {
+			true => "?",
+			false => "",
+		};
+		println!("  {}{}: {}", arg.name, optional, schema.describe(&arg.ty));
+	}
+}
+
+struct ShellHelper {
+	schema: Arc<Schema>,
+}
+
+impl ShellHelper {
+	fn commands<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item=String> + 'a {
+		self.schema.commands()
+			.filter(move |c| c.starts_with(prefix))
+			.map(Into::into)
+	}
+
+	/// Completes a `KEY=value` argument
+	fn argument(&self, command: &str, previous: &[&str], word: &str) -> Vec<String> {
+		let schema = &self.schema;
+		if let Some((key, value)) = word.split_once('=') {
+			let path: Vec<&str> = key.split(':').next().unwrap_or_default().split('.').collect();
+			let values: Vec<&str> = match schema.argument_type(command, &path).and_then(|ty| schema.get(ty)) {
+				Some(SchemaType::Enum(values)) => values.iter().map(|v| &v[..]).collect(),
+				Some(SchemaType::Builtin(json)) if json == "boolean" => vec!["on", "off"],
+				_ => Vec::new(),
+			};
+			return values.into_iter()
+				.filter(|v| v.starts_with(value))
+				.map(|v| format!("{}={}", key, v))
+				.collect()
+		}
+
+		let (parent, prefix) = match word.rsplit_once('.') {
+			Some((parent, prefix)) => (parent.split('.').collect(), prefix),
+			None => (Vec::new(), word),
+		};
+		let members = match schema.argument_type(command, &parent) {
+			Some(ty) => schema.members(ty),
+			None => return Vec::new(),
+		};
+		members.into_iter()
+			.filter(|m| m.name.starts_with(prefix))
+			.map(|m| {
+				let key = parent.iter().copied().chain(Some(&m.name[..])).collect::<Vec<_>>().join(".");
+				match schema.get(&m.ty) {
+					Some(SchemaType::Object { .. }) => key + ".",
+					_ => key + "=",
+				}
+			})
+			.filter(|key| !key.ends_with('=') || !previous.iter().any(|p| p.starts_with(&key[..])))
+			.collect()
+	}
+}
+
+impl Completer for ShellHelper {
+	type Candidate = String;
+
+	fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context) -> rustyline::Result<(usize, Vec<String>)> {
+		let line = &line[..pos];
+		let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+		let word = &line[start..];
+		let previous: Vec<&str> = line[..start].split_whitespace().collect();
+		let candidates = match previous.split_first() {
+			_ if line.trim_start().starts_with('{') => Vec::new(),
+			None => BUILTINS.iter().filter(|b| b.starts_with(word)).map(|&b| b.into())
+				.chain(self.commands(word))
+				.collect(),
+			Some((&"help", [])) => self.commands(word).collect(),
+			Some((&"help" | &"exit", _)) => Vec::new(),
+			Some((command, previous)) => self.argument(command, previous, word),
+		};
+		Ok((start, candidates))
+	}
+}
+
+impl Hinter for ShellHelper {
+	type Hint = String;
+}
+
+impl Highlighter for ShellHelper { }
+
+impl Validator for ShellHelper { }
+
+impl Helper for ShellHelper { }
@@ -0,0 +1,14 @@
+use std::borrow::Cow;
+
+/// Quotes a word for the shell, like the one that runs `exec:` migrations
+pub(crate) fn shell_quote(word: &str) -> String {
+	format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+/// Leaves words alone unless the shell would mangle them
+pub(crate) fn shell_word(word: &str) -> Cow<'_, str> {
+	match !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "-_.,:/=@%+".contains(c)) {
+		true => word.into(),
+		false => shell_quote(word).into(),
+	}
+}
@@ -0,0 +1,291 @@
+use anyhow::{Result, format_err};
+use clap::{Args, Parser, Subcommand};
+use qapi::qmp::{self, QmpCommand};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::time::Duration;
+use qemucomm::{OutputFormat, print_table, format_size, error_exit_code};
+use qemucomm::qmp::{Client, Events};
+use super::block::drives;
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Manages internal snapshots of the VM and its disks
+///
+/// Snapshots are saved, loaded, and deleted by QMP jobs. A job that fails exits with status 3,
+/// and QMP errors exit with the status of their class, as with execute.
+pub(crate) struct Snapshot {
+	#[command(subcommand)]
+	command: SnapshotCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+	List,
+	Save(Save),
+	Load(Load),
+	Delete(Delete),
+}
+
+#[derive(Args, Debug)]
+struct JobArgs {
+	tag: String,
+	/// block node to include, repeatable [default: every writable disk to save, or those with the snapshot]
+	#[clap(short, long = "device")]
+	devices: Vec<String>,
+	#[clap(short, long = "timeout")]
+	timeout_seconds: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+/// Saves the VM state and disks as a snapshot
+struct Save {
+	#[clap(flatten)]
+	job: JobArgs,
+	/// block node to save the VM state to [default: the first device]
+	#[clap(long)]
+	vmstate: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+/// Reverts the VM and its disks to a snapshot
+struct Load {
+	#[clap(flatten)]
+	job: JobArgs,
+	/// block node with the VM state [default: the device that has it]
+	#[clap(long)]
+	vmstate: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+/// Deletes a snapshot from its disks
+struct Delete {
+	#[clap(flatten)]
+	job: JobArgs,
+}
+
+/// A snapshot as it appears across all block nodes
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct SnapshotEntry {
+	tag: String,
+	date_sec: i64,
+	vm_clock_sec: i64,
+	vm_clock_nsec: i64,
+	vm_state_size: i64,
+	/// the node with the VM state
+	vmstate: Option<String>,
+	devices: Vec<String>,
+}
+
+async fn snapshots(qmp: &Client) -> Result<BTreeMap<String, SnapshotEntry>> {
+	let mut snapshots = BTreeMap::new();
+	for node in qmp.block_nodes().await? {
+		let node_name = match node.node_name {
+			Some(node_name) => node_name,
+			None => continue,
+		};
+		for info in node.image.base.snapshots.into_iter().flatten() {
+			let entry = snapshots.entry(info.name.clone()).or_insert_with(|| SnapshotEntry {
+				tag: info.name.clone(),
+				date_sec: info.date_sec,
+				vm_clock_sec: info.vm_clock_sec,
+				vm_clock_nsec: info.vm_clock_nsec,
+				vm_state_size: 0,
+				vmstate: None,
+				devices: Vec::new(),
+			});
+			if info.vm_state_size > 0 {
+				entry.vm_state_size = info.vm_state_size;
+				entry.vmstate = Some(node_name.clone());
+			}
+			entry.devices.push(node_name.clone());
+		}
+	}
+	Ok(snapshots)
+}
+
+impl JobArgs {
+	/// The devices of an existing snapshot, unless they were given
+	async fn snapshot(&self, qmp: &Client) -> Result<(Vec<String>, Option<String>)> {
+		let snapshot = snapshots(qmp).await?.remove(&self.tag);
+		match (snapshot, self.devices.is_empty()) {
+			(_, false) => Ok((self.devices.clone(), None)),
+			(Some(snapshot), true) => Ok((snapshot.devices, snapshot.vmstate)),
+			(None, true) => Err(format_err!("snapshot {} not found", self.tag)),
+		}
+	}
+
+	/// Runs a job until it concludes
+	async fn run<C: QmpCommand>(&self, qmp: &Client, mut events: Events, command: C, job_id: &str, args: GlobalArgs) -> Result<i32> {
+		qmp.execute(command).await?;
+		let duration = self.timeout_seconds.map(Duration::from_secs);
+		let job = match qemucomm::wait(duration, qmp.wait_job(&mut events, job_id)).await {
+			Ok(job) => job,
+			Err(e) => {
+				if let Err(e) = qmp.cancel_job(&mut events, job_id, CANCEL_TIMEOUT).await {
+					log::warn!("failed to cancel job {}: {}", job_id, e);
+				}
+				return Err(format_err!("{} of {} didn't finish: {}", C::NAME, self.tag, e))
+			},
+		};
+		args.output().print(&job)?;
+		match job.error {
+			Some(error) => {
+				eprintln!("{} of {} failed: {}", C::NAME, self.tag, error);
+				Ok(error_exit_code(&qapi::ErrorClass::GenericError))
+			},
+			None => Ok(0),
+		}
+	}
+}
+
+/// How long a job that timed out is given to conclude once cancelled
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn job_id(verb: &str) -> String {
+	format!("qemucomm-snapshot-{}-{}", verb, std::process::id())
+}
+
+impl Snapshot {
+	pub async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let res = match self.command {
+			SnapshotCommand::List => list(qmp, args).await,
+			SnapshotCommand::Save(c) => c.run(qmp, events, args).await,
+			SnapshotCommand::Load(c) => c.run(qmp, events, args).await,
+			SnapshotCommand::Delete(c) => c.run(qmp, events, args).await,
+		};
+		match res {
+			Err(e) => match e.downcast_ref::<qapi::ExecuteError>() {
+				Some(qapi::ExecuteError::Qapi(qe)) => {
+					eprintln!("{:?}: {}", qe.class, qe.desc);
+					Ok(error_exit_code(&qe.class))
+				},
+				_ => Err(e),
+			},
+			res => res,
+		}
+	}
+}
+
+async fn list(qmp: &Client, args: GlobalArgs) -> Result<i32> {
+	let snapshots: Vec<_> = snapshots(qmp).await?.into_values().collect();
+	match args.output() {
+		OutputFormat::Human => print_table(&["tag", "date", "vm state", "vm clock", "devices"], snapshots.iter().map(|s| vec![
+			s.tag.clone(),
+			format_date(s.date_sec),
+			match s.vm_state_size {
+				0 => "-".into(),
+				size => format_size(size as u64),
+			},
+			format_clock(s.vm_clock_sec, s.vm_clock_nsec),
+			s.devices.join(", "),
+		]))?,
+		format => format.print(&snapshots)?,
+	}
+	Ok(0)
+}
+
+impl Save {
+	async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let devices = match self.job.devices.is_empty() {
+			false => self.job.devices.clone(),
+			true => drives(qmp).await?.into_iter()
+				.filter_map(|drive| drive.inserted)
+				.filter(|inserted| !inserted.ro)
+				.filter_map(|inserted| inserted.node_name)
+				.collect(),
+		};
+		let vmstate = self.vmstate.clone().or_else(|| devices.first().cloned())
+			.ok_or_else(|| format_err!("no writable disks to save {} to", self.job.tag))?;
+		let id = job_id("save");
+		let save = qmp::snapshot_save {
+			job_id: id.clone(),
+			tag: self.job.tag.clone(),
+			vmstate,
+			devices,
+		};
+		self.job.run(qmp, events, save, &id, args).await
+	}
+}
+
+impl Load {
+	async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let (devices, vmstate) = self.job.snapshot(qmp).await?;
+		let vmstate = self.vmstate.clone().or(vmstate)
+			.ok_or_else(|| format_err!("snapshot {} has no VM state, specify --vmstate", self.job.tag))?;
+		let id = job_id("load");
+		let load = qmp::snapshot_load {
+			job_id: id.clone(),
+			tag: self.job.tag.clone(),
+			vmstate,
+			devices,
+		};
+		self.job.run(qmp, events, load, &id, args).await
+	}
+}
+
+impl Delete {
+	async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let (devices, _) = self.job.snapshot(qmp).await?;
+		let id = job_id("delete");
+		let delete = qmp::snapshot_delete {
+			job_id: id.clone(),
+			tag: self.job.tag.clone(),
+			devices,
+		};
+		self.job.run(qmp, events, delete, &id, args).await
+	}
+}
+
+/// Formats seconds since the epoch as a UTC date and time
+fn format_date(secs: i64) -> String {
+	let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+	// civil_from_days, from http://howardhinnant.github.io/date_algorithms.html
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+	format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Formats guest run time like `info snapshots` does
+fn format_clock(secs: i64, nsecs: i64) -> String {
+	format!("{:02}:{:02}:{:02}.{:03}", secs / 3600, secs / 60 % 60, secs % 60, nsecs / 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn dates() {
+		assert_eq!(format_date(0), "1970-01-01 00:00:00");
+		assert_eq!(format_date(-1), "1969-12-31 23:59:59");
+		assert_eq!(format_date(946684799), "1999-12-31 23:59:59");
+		assert_eq!(format_date(1700000000), "2023-11-14 22:13:20");
+	}
+
+	#[test]
+	fn leap_years() {
+		assert_eq!(format_date(1709195415), "2024-02-29 08:30:15");
+		// divisible by 400
+		assert_eq!(format_date(951825600), "2000-02-29 12:00:00");
+		assert_eq!(format_date(951868800), "2000-03-01 00:00:00");
+		// divisible by 100, but not 400
+		assert_eq!(format_date(-2203891201), "1900-02-28 23:59:59");
+		assert_eq!(format_date(-2203891200), "1900-03-01 00:00:00");
+		assert_eq!(format_date(4107542400), "2100-03-01 00:00:00");
+	}
+
+	#[test]
+	fn clock() {
+		assert_eq!(format_clock(0, 0), "00:00:00.000");
+		assert_eq!(format_clock(3723, 456_789_000), "01:02:03.456");
+	}
+}
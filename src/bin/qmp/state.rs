@@ -0,0 +1,158 @@
+use anyhow::{Result, Context, format_err};
+use clap::{Parser, ValueEnum};
+use qapi::qmp;
+use std::path::Path;
+use qemucomm::OutputFormat;
+use qemucomm::qmp::{Client, Events};
+use super::migrate::{WaitArgs, wait_migration};
+use super::GlobalArgs;
+use super::shell_words::shell_quote;
+
+#[derive(Parser, Debug)]
+/// Saves the VM state to a file and quits, like a managed save
+///
+/// The VM is stopped before migrating to the file. If the save fails, it is left paused
+/// rather than quitting, and can be resumed with `continue`.
+pub(crate) struct SaveState {
+	/// path on the QEMU host
+	file: String,
+	#[clap(flatten)]
+	compression: CompressionArgs,
+	#[clap(flatten)]
+	wait: WaitArgs,
+	/// leave the VM paused after saving instead of quitting
+	#[clap(long)]
+	no_quit: bool,
+}
+
+#[derive(Parser, Debug)]
+/// Restores the VM state saved by save-state into a VM started with `-incoming defer`
+///
+/// The VM is resumed once the state has been loaded, and left paused if loading fails.
+pub(crate) struct RestoreState {
+	/// path on the QEMU host
+	file: String,
+	#[clap(flatten)]
+	compression: CompressionArgs,
+	#[clap(flatten)]
+	wait: WaitArgs,
+	/// leave the VM paused after restoring
+	#[clap(long)]
+	paused: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompressionArgs {
+	/// compress the file with an external command [default: from the extension of the file]
+	#[clap(short, long, value_enum)]
+	compress: Option<Compression>,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Compression {
+	None,
+	Gzip,
+	Zstd,
+	Xz,
+}
+
+impl Compression {
+	fn from_extension(file: &str) -> Self {
+		match Path::new(file).extension().and_then(|e| e.to_str()) {
+			Some("gz") => Compression::Gzip,
+			Some("zst") => Compression::Zstd,
+			Some("xz") => Compression::Xz,
+			_ => Compression::None,
+		}
+	}
+
+	fn command(self) -> Option<&'static str> {
+		match self {
+			Compression::None => None,
+			Compression::Gzip => Some("gzip"),
+			Compression::Zstd => Some("zstd -q"),
+			Compression::Xz => Some("xz -T0"),
+		}
+	}
+}
+
+impl CompressionArgs {
+	fn compression(&self, file: &str) -> Compression {
+		self.compress.unwrap_or_else(|| Compression::from_extension(file))
+	}
+
+	/// The migration URI that writes to the file
+	fn save_uri(&self, file: &str) -> Result<String> {
+		match self.compression(file).command() {
+			None => file_uri(file),
+			Some(command) => Ok(format!("exec:{} -c > {}", command, shell_quote(file))),
+		}
+	}
+
+	/// The migration URI that reads from the file
+	fn restore_uri(&self, file: &str) -> Result<String> {
+		match self.compression(file).command() {
+			None => file_uri(file),
+			Some(command) => Ok(format!("exec:{} -dc < {}", command, shell_quote(file))),
+		}
+	}
+}
+
+/// A `file:` migration URI, which has no way to escape the commas that separate its options
+fn file_uri(file: &str) -> Result<String> {
+	match file.contains(',') {
+		true => Err(format_err!("file:{} can't be used as a migration URI because the path contains a comma", file)),
+		false => Ok(format!("file:{}", file)),
+	}
+}
+
+fn summary(format: OutputFormat, verb: &str, file: &str) {
+	if format == OutputFormat::Human {
+		println!("VM state {} {}", verb, file);
+	}
+}
+
+impl SaveState {
+	pub async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let uri = self.compression.save_uri(&self.file)?;
+		qmp.stop().await?;
+		let format = args.output();
+		let saved = async {
+			qmp.execute(qmp::migrate {
+				uri,
+				blk: None,
+				inc: None,
+				detach: None,
+				resume: None,
+			}).await?;
+			wait_migration(qmp, events, &self.wait, format, false, false).await
+		}.await;
+		saved.with_context(|| format!("failed to save the VM state to {}, leaving the VM paused", self.file))?;
+
+		summary(format, "saved to", &self.file);
+		if !self.no_quit {
+			qmp.quit().await?;
+		}
+		Ok(0)
+	}
+}
+
+impl RestoreState {
+	pub async fn run(self, qmp: &Client, events: Events, args: GlobalArgs) -> Result<i32> {
+		let uri = self.compression.restore_uri(&self.file)?;
+		let format = args.output();
+		let restored = async {
+			qmp.execute(qmp::migrate_incoming {
+				uri,
+			}).await?;
+			wait_migration(qmp, events, &self.wait, format, false, false).await
+		}.await;
+		restored.with_context(|| format!("failed to restore the VM state from {}, leaving the VM paused", self.file))?;
+
+		summary(format, "restored from", &self.file);
+		if !self.paused {
+			qmp.cont().await?;
+		}
+		Ok(0)
+	}
+}
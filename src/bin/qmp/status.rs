@@ -1,16 +1,16 @@
 use anyhow::Result;
 use clap::Parser;
-use qapi::qmp;
-use super::{GlobalArgs, QmpStream};
+use qemucomm::qmp::Client;
+use super::GlobalArgs;
 
 #[derive(Parser, Debug)]
 pub(crate) struct Status {
 }
 
 impl Status {
-	pub async fn run(self, qmp: QmpStream, _args: GlobalArgs) -> Result<i32> {
-		let status = qmp.execute(qmp::query_status { }).await?;
-		println!("VCPU Status: {:#?}", status);
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		let status = qmp.status().await?;
+		args.output().print(&status)?;
 		Ok(0)
 	}
 }
@@ -0,0 +1,114 @@
+use anyhow::{Result, format_err};
+use clap::Parser;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::fs;
+use tokio::time::{Duration, sleep};
+use qemucomm::OutputFormat;
+use qemucomm::qmp::Client;
+use super::screen::{Screen, ScreenArgs, DumpArgs, Image, Region};
+use super::GlobalArgs;
+
+#[derive(Parser, Debug)]
+/// Waits until part of the guest screen matches a reference image
+///
+/// The reference is either the size of the region, or a full screenshot that the region is cut out of.
+/// Screens are compared by the fraction of pixels whose colours are within --tolerance of the reference.
+pub(crate) struct WaitScreen {
+	/// PNG image to look for
+	#[clap(short, long)]
+	reference: PathBuf,
+	/// area of the screen to compare, as X,Y,WIDTH,HEIGHT [default: the size of the reference at 0,0]
+	#[clap(long)]
+	region: Option<Region>,
+	/// fraction of pixels that must match, from 0 to 1
+	#[clap(long, default_value = "0.95")]
+	threshold: f64,
+	/// how far each colour channel of a pixel may be from the reference, from 0 to 255
+	#[clap(long, default_value = "24")]
+	tolerance: u8,
+	/// how often to take a screenshot, in milliseconds
+	#[clap(long, default_value = "1000", value_name = "MS")]
+	interval: u64,
+	#[clap(short, long = "timeout")]
+	timeout_seconds: Option<u64>,
+	#[clap(flatten)]
+	screen: ScreenArgs,
+	#[clap(flatten)]
+	dump: DumpArgs,
+}
+
+/// How well the screen matched
+#[derive(Serialize, Debug)]
+struct Match {
+	similarity: f64,
+	screenshots: u64,
+}
+
+impl WaitScreen {
+	/// The reference to compare the region of the screen against
+	fn needle(&self) -> Result<(Region, Image)> {
+		let data = fs::read(&self.reference)
+			.map_err(|e| format_err!("failed to read {}: {}", self.reference.display(), e))?;
+		let reference = Image::from_png(&data)
+			.map_err(|e| format_err!("failed to decode {}: {}", self.reference.display(), e))?;
+		match self.region {
+			None => Ok((Region { x: 0, y: 0, width: reference.width, height: reference.height }, reference)),
+			Some(region) if (region.width, region.height) == (reference.width, reference.height) => Ok((region, reference)),
+			Some(region) => reference.crop(region)
+				.map(|needle| (region, needle))
+				.ok_or_else(|| format_err!("region {},{},{},{} doesn't fit in {} ({}x{})",
+					region.x, region.y, region.width, region.height,
+					self.reference.display(), reference.width, reference.height,
+				)),
+		}
+	}
+
+	pub async fn run(self, qmp: &Client, args: GlobalArgs) -> Result<i32> {
+		if !(0.0..=1.0).contains(&self.threshold) {
+			return Err(format_err!("threshold must be between 0 and 1"))
+		}
+		let (region, needle) = self.needle()?;
+		let screen = Screen::new(qmp, self.screen.clone(), self.dump.clone()).await?;
+		let interval = Duration::from_millis(self.interval);
+
+		let mut best = Match { similarity: 0.0, screenshots: 0 };
+		let wait = async {
+			loop {
+				let image = screen.capture(qmp).await?;
+				let similarity = match image.crop(region) {
+					Some(image) => image.similarity(&needle, self.tolerance),
+					None => {
+						log::info!("screen is only {}x{}", image.width, image.height);
+						0.0
+					},
+				};
+				best.screenshots += 1;
+				best.similarity = best.similarity.max(similarity);
+				log::info!("screen matches {:.3}", similarity);
+				if similarity >= self.threshold {
+					return Ok::<_, anyhow::Error>(similarity)
+				}
+				sleep(interval).await;
+			}
+		};
+		let duration = self.timeout_seconds.map(Duration::from_secs);
+		let similarity = match qemucomm::wait(duration, wait).await {
+			Err(e) if e.is::<tokio::time::error::Elapsed>() => return Err(format_err!(
+				"screen didn't match {} within {} s, the best of {} screenshots matched {:.3}",
+				self.reference.display(), self.timeout_seconds.unwrap_or_default(), best.screenshots, best.similarity,
+			)),
+			res => res?,
+		};
+
+		let found = Match {
+			similarity,
+			screenshots: best.screenshots,
+		};
+		match args.output() {
+			OutputFormat::Human => println!("matched {} ({:.3})", self.reference.display(), found.similarity),
+			format => format.print(&found)?,
+		}
+		Ok(0)
+	}
+}
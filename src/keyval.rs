@@ -0,0 +1,452 @@
+use anyhow::{Result, Error, format_err};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::fmt;
+use crate::Pair;
+
+/// How the value of a `KEY=value` pair is interpreted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValueType {
+	/// a plain decimal integer or `true`/`false`, and a string otherwise
+	Auto,
+	String,
+	Bool,
+	Int,
+	/// an integer with an optional `K`/`M`/`G`/`T`/`P`/`E` suffix
+	Size,
+	Number,
+	/// the value is parsed as raw JSON
+	Json,
+}
+
+impl ValueType {
+	/// Maps a QOM property type name (as reported by `qom-list-properties`) to a value type
+	pub fn from_qom(ty: &str) -> Self {
+		match ty {
+			"bool" => ValueType::Bool,
+			"int" | "int8" | "int16" | "int32" | "int64"
+				| "uint8" | "uint16" | "uint32" | "uint64" => ValueType::Int,
+			"size" => ValueType::Size,
+			"number" | "double" => ValueType::Number,
+			ty if ty.ends_with("List") => ValueType::from_qom(&ty[..ty.len() - 4]),
+			// str, link<..>, child<..>, and QAPI enums are all passed as strings
+			_ => ValueType::String,
+		}
+	}
+
+	pub fn from_property(prop: &qapi::qmp::ObjectPropertyInfo) -> Self {
+		match (&prop.name[..], &prop.type_[..]) {
+			// PCI `addr` is an int32 property that QEMU parses as a `slot.function` string
+			("addr", "int32") => ValueType::String,
+			(_, ty) => ValueType::from_qom(ty),
+		}
+	}
+
+	pub fn parse(self, value: &str) -> Result<qapi::Any> {
+		let invalid = || format_err!("invalid {} value `{}`", self, value);
+		match self {
+			// only guess what can't be mistaken for a string: no octal, size suffixes, or on/off
+			ValueType::Auto => Ok(match value {
+				"true" => qapi::Any::Bool(true),
+				"false" => qapi::Any::Bool(false),
+				value if is_decimal(value) => parse_int(value).unwrap_or_else(|| qapi::Any::String(value.into())),
+				value => qapi::Any::String(value.into()),
+			}),
+			ValueType::String => Ok(qapi::Any::String(value.into())),
+			ValueType::Bool => parse_bool(value).map(qapi::Any::Bool).ok_or_else(invalid),
+			ValueType::Int => parse_int(value).ok_or_else(invalid),
+			ValueType::Size => parse_size(value).map(Into::into).ok_or_else(invalid),
+			ValueType::Number => parse_int(value)
+				.or_else(|| value.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(qapi::Any::Number))
+				.ok_or_else(invalid),
+			ValueType::Json => serde_json::from_str(value).map_err(Into::into),
+		}
+	}
+
+	/// Like `parse`, but values that don't match a type hint are left for QEMU to reject
+	pub fn parse_lenient(self, value: &str) -> qapi::Any {
+		self.parse(value)
+			.unwrap_or_else(|_| qapi::Any::String(value.into()))
+	}
+}
+
+impl FromStr for ValueType {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		Ok(match s {
+			"auto" => ValueType::Auto,
+			"str" | "string" => ValueType::String,
+			"bool" => ValueType::Bool,
+			"int" => ValueType::Int,
+			"size" => ValueType::Size,
+			"number" => ValueType::Number,
+			"json" => ValueType::Json,
+			_ => return Err(format_err!("unknown value type `{}`", s)),
+		})
+	}
+}
+
+impl fmt::Display for ValueType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			ValueType::Auto => "auto",
+			ValueType::String => "str",
+			ValueType::Bool => "bool",
+			ValueType::Int => "int",
+			ValueType::Size => "size",
+			ValueType::Number => "number",
+			ValueType::Json => "json",
+		})
+	}
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+	match value {
+		"on" | "yes" | "true" => Some(true),
+		"off" | "no" | "false" => Some(false),
+		_ => None,
+	}
+}
+
+fn is_decimal(value: &str) -> bool {
+	let digits = value.strip_prefix('-').unwrap_or(value);
+	match digits.as_bytes() {
+		[] => false,
+		[b'0', _, ..] => false,
+		digits => digits.iter().all(u8::is_ascii_digit),
+	}
+}
+
+pub fn parse_int(value: &str) -> Option<qapi::Any> {
+	let (negative, digits) = match value.strip_prefix('-') {
+		Some(digits) => (true, digits),
+		None => (false, value),
+	};
+	let (radix, digits) = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+		(16, hex)
+	} else if digits.len() > 1 && digits.starts_with('0') {
+		(8, &digits[1..])
+	} else {
+		(10, digits)
+	};
+	if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+		return None
+	}
+	let value = u64::from_str_radix(digits, radix).ok()?;
+	match negative {
+		false => Some(value.into()),
+		true => 0i64.checked_sub_unsigned(value).map(Into::into),
+	}
+}
+
+/// Parses a size the way `qemu_strtosz` does, defaulting to bytes
+pub fn parse_size(value: &str) -> Option<u64> {
+	let (number, shift) = match value.char_indices().last()? {
+		(i, c) if c.is_ascii_alphabetic() && !value.starts_with("0x") => (&value[..i], match c.to_ascii_uppercase() {
+			'B' => 0,
+			'K' => 10,
+			'M' => 20,
+			'G' => 30,
+			'T' => 40,
+			'P' => 50,
+			'E' => 60,
+			_ => return None,
+		}),
+		_ => (value, 0),
+	};
+	if let Some(qapi::Any::Number(n)) = parse_int(number) {
+		return n.as_u64().and_then(|n| n.checked_mul(1 << shift))
+	}
+	match number.parse::<f64>() {
+		Ok(n) if n >= 0.0 && number.chars().all(|c| c.is_ascii_digit() || c == '.') => {
+			let n = n * (1u64 << shift) as f64;
+			if n < u64::MAX as f64 {
+				Some(n as u64)
+			} else {
+				None
+			}
+		},
+		_ => None,
+	}
+}
+
+/// A dotted `KEY` path with an optional `:type` annotation, as in `host-nodes.0:int`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+	pub path: Vec<String>,
+	pub ty: Option<ValueType>,
+}
+
+impl Key {
+	pub fn name(&self) -> &str {
+		&self.path[0]
+	}
+}
+
+impl FromStr for Key {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let (path, ty) = match s.rsplit_once(':') {
+			Some((path, ty)) => (path, Some(ty.parse()?)),
+			None => (s, None),
+		};
+		let path: Vec<String> = path.split('.').map(Into::into).collect();
+		if path.iter().any(|p| p.is_empty()) {
+			return Err(format_err!("invalid KEY `{}`", s))
+		}
+		Ok(Key {
+			path,
+			ty,
+		})
+	}
+}
+
+impl fmt::Display for Key {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.path.join("."))?;
+		match self.ty {
+			Some(ty) => write!(f, ":{}", ty),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Property types used to decide how values are parsed, keyed by top-level property name
+#[derive(Debug, Clone, Default)]
+pub struct PropertyTypes {
+	types: BTreeMap<String, ValueType>,
+}
+
+impl PropertyTypes {
+	pub fn from_properties<'a, I: IntoIterator<Item=&'a qapi::qmp::ObjectPropertyInfo>>(props: I) -> Self {
+		PropertyTypes {
+			types: props.into_iter()
+				.map(|prop| (prop.name.clone(), ValueType::from_property(prop)))
+				.collect(),
+		}
+	}
+
+	pub fn get(&self, name: &str) -> ValueType {
+		self.types.get(name).copied().unwrap_or(ValueType::Auto)
+	}
+
+	pub fn parse(&self, key: &Key, value: &str) -> Result<qapi::Any> {
+		match key.ty {
+			Some(ty) => ty.parse(value),
+			None => Ok(self.get(key.name()).parse_lenient(value)),
+		}
+	}
+}
+
+impl FromIterator<(String, ValueType)> for PropertyTypes {
+	fn from_iter<I: IntoIterator<Item=(String, ValueType)>>(iter: I) -> Self {
+		PropertyTypes {
+			types: iter.into_iter().collect(),
+		}
+	}
+}
+
+/// Builds a (possibly nested) dictionary from `KEY=value` pairs, following QEMU's keyval rules
+///
+/// Dotted keys produce nested dictionaries, and a dictionary whose keys are exactly `0..n` becomes a list.
+pub fn keyval_dict<I: IntoIterator<Item=Pair<Key, String>>>(args: I, types: &PropertyTypes) -> Result<qapi::Dictionary> {
+	let mut dict = qapi::Dictionary::new();
+	for Pair { key, value } in args {
+		let value = types.parse(&key, &value)?;
+		let (last, parents) = key.path.split_last().unwrap();
+		let mut parent = &mut dict;
+		for (i, name) in parents.iter().enumerate() {
+			let entry = parent.entry(name.clone())
+				.or_insert_with(|| qapi::Any::Object(Default::default()));
+			parent = match entry {
+				qapi::Any::Object(dict) => dict,
+				_ => return Err(format_err!("{} conflicts with a value at {}", key, key.path[..=i].join("."))),
+			};
+		}
+		match parent.get(last) {
+			Some(qapi::Any::Object(..)) => return Err(format_err!("{} conflicts with nested keys", key)),
+			_ => parent.insert(last.clone(), value),
+		};
+	}
+
+	Ok(dict.into_iter().map(|(k, v)| (k, keyval_lists(v))).collect())
+}
+
+fn keyval_lists(value: qapi::Any) -> qapi::Any {
+	match value {
+		qapi::Any::Object(dict) => {
+			let is_list = (0..dict.len()).all(|i| dict.contains_key(&i.to_string()));
+			if is_list && !dict.is_empty() {
+				let mut dict = dict;
+				qapi::Any::Array((0..dict.len())
+					.map(|i| keyval_lists(dict.remove(&i.to_string()).unwrap()))
+					.collect()
+				)
+			} else {
+				qapi::Any::Object(dict.into_iter().map(|(k, v)| (k, keyval_lists(v))).collect())
+			}
+		},
+		value => value,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn args(pairs: &[(&str, &str)]) -> Vec<Pair<Key, String>> {
+		pairs.iter()
+			.map(|&(k, v)| Pair { key: k.parse().unwrap(), value: v.into() })
+			.collect()
+	}
+
+	fn dict(pairs: &[(&str, &str)]) -> Result<qapi::Any> {
+		keyval_dict(args(pairs), &PropertyTypes::default()).map(qapi::Any::Object)
+	}
+
+	#[test]
+	fn int() {
+		assert_eq!(parse_int("0"), Some(json!(0)));
+		assert_eq!(parse_int("42"), Some(json!(42)));
+		assert_eq!(parse_int("-42"), Some(json!(-42)));
+		assert_eq!(parse_int("010"), Some(json!(8)));
+		assert_eq!(parse_int("0x1f"), Some(json!(31)));
+		assert_eq!(parse_int("0X1F"), Some(json!(31)));
+		assert_eq!(parse_int("-0x10"), Some(json!(-16)));
+		assert_eq!(parse_int("18446744073709551615"), Some(json!(u64::MAX)));
+		assert_eq!(parse_int("-9223372036854775808"), Some(json!(i64::MIN)));
+		assert_eq!(parse_int("-9223372036854775809"), None);
+		assert_eq!(parse_int("18446744073709551616"), None);
+		assert_eq!(parse_int("08"), None);
+		assert_eq!(parse_int("0x"), None);
+		assert_eq!(parse_int("-"), None);
+		assert_eq!(parse_int("1.5"), None);
+	}
+
+	#[test]
+	fn size() {
+		assert_eq!(parse_size("512"), Some(512));
+		assert_eq!(parse_size("512b"), Some(512));
+		assert_eq!(parse_size("4k"), Some(4 << 10));
+		assert_eq!(parse_size("4K"), Some(4 << 10));
+		assert_eq!(parse_size("1M"), Some(1 << 20));
+		assert_eq!(parse_size("2G"), Some(2 << 30));
+		assert_eq!(parse_size("1T"), Some(1 << 40));
+		assert_eq!(parse_size("1P"), Some(1 << 50));
+		assert_eq!(parse_size("15E"), Some(15 << 60));
+		assert_eq!(parse_size("1.5G"), Some(3 << 29));
+		assert_eq!(parse_size("0x10"), Some(16));
+		assert_eq!(parse_size("16E"), None);
+		assert_eq!(parse_size("16384P"), None);
+		assert_eq!(parse_size("-1K"), None);
+		assert_eq!(parse_size("1X"), None);
+		assert_eq!(parse_size("K"), None);
+		assert_eq!(parse_size(""), None);
+	}
+
+	#[test]
+	fn key() {
+		let key: Key = "host-nodes.0:int".parse().unwrap();
+		assert_eq!(key.path, ["host-nodes", "0"]);
+		assert_eq!(key.ty, Some(ValueType::Int));
+		assert_eq!(key.name(), "host-nodes");
+		assert_eq!(key.to_string(), "host-nodes.0:int");
+
+		let key: Key = "size".parse().unwrap();
+		assert_eq!(key.path, ["size"]);
+		assert_eq!(key.ty, None);
+
+		assert!("a..b".parse::<Key>().is_err());
+		assert!(".a".parse::<Key>().is_err());
+		assert!("a:nope".parse::<Key>().is_err());
+	}
+
+	#[test]
+	fn typed_values() {
+		let types: PropertyTypes = [("size".to_owned(), ValueType::Size), ("id".to_owned(), ValueType::String)]
+			.into_iter().collect();
+		let dict = keyval_dict(args(&[("size", "1M"), ("id", "1"), ("share", "true"), ("prealloc:str", "on")]), &types).unwrap();
+		assert_eq!(qapi::Any::Object(dict), json!({
+			"size": 1 << 20,
+			"id": "1",
+			"share": true,
+			"prealloc": "on",
+		}));
+
+		assert!(keyval_dict(args(&[("size:int", "big")]), &types).is_err());
+		// type hints from QEMU are lenient
+		let dict = keyval_dict(args(&[("size", "big")]), &types).unwrap();
+		assert_eq!(dict["size"], json!("big"));
+	}
+
+	#[test]
+	fn auto() {
+		assert_eq!(ValueType::Auto.parse("42").unwrap(), json!(42));
+		assert_eq!(ValueType::Auto.parse("-42").unwrap(), json!(-42));
+		assert_eq!(ValueType::Auto.parse("0").unwrap(), json!(0));
+		assert_eq!(ValueType::Auto.parse("true").unwrap(), json!(true));
+		assert_eq!(ValueType::Auto.parse("false").unwrap(), json!(false));
+		// anything QEMU's keyval could read differently stays a string
+		assert_eq!(ValueType::Auto.parse("010").unwrap(), json!("010"));
+		assert_eq!(ValueType::Auto.parse("0x10").unwrap(), json!("0x10"));
+		assert_eq!(ValueType::Auto.parse("2E").unwrap(), json!("2E"));
+		assert_eq!(ValueType::Auto.parse("on").unwrap(), json!("on"));
+		assert_eq!(ValueType::Auto.parse("off").unwrap(), json!("off"));
+		assert_eq!(ValueType::Auto.parse("1.5").unwrap(), json!("1.5"));
+		assert_eq!(ValueType::Auto.parse("-").unwrap(), json!("-"));
+		assert_eq!(ValueType::Auto.parse("18446744073709551616").unwrap(), json!("18446744073709551616"));
+	}
+
+	#[test]
+	fn nested() {
+		assert_eq!(dict(&[("a.b.c", "1"), ("a.b.d", "x"), ("a.e", "false")]).unwrap(), json!({
+			"a": {
+				"b": { "c": 1, "d": "x" },
+				"e": false,
+			},
+		}));
+	}
+
+	#[test]
+	fn lists() {
+		assert_eq!(dict(&[("host-nodes.1", "3"), ("host-nodes.0", "2")]).unwrap(), json!({
+			"host-nodes": [2, 3],
+		}));
+		assert_eq!(dict(&[("l.0.a", "1"), ("l.1.a", "2")]).unwrap(), json!({
+			"l": [{ "a": 1 }, { "a": 2 }],
+		}));
+		// not contiguous from zero, so it stays a dictionary
+		assert_eq!(dict(&[("l.0", "1"), ("l.2", "2")]).unwrap(), json!({
+			"l": { "0": 1, "2": 2 },
+		}));
+	}
+
+	#[test]
+	fn conflicts() {
+		assert!(dict(&[("a", "1"), ("a.b", "2")]).is_err());
+		assert!(dict(&[("a.b", "1"), ("a", "2")]).is_err());
+		assert!(dict(&[("a.b", "1"), ("a.b.c", "2")]).is_err());
+		// repeated keys take the last value
+		assert_eq!(dict(&[("a", "1"), ("a", "2")]).unwrap(), json!({ "a": 2 }));
+	}
+
+	#[test]
+	fn pci_addr() {
+		let prop = |name: &str, ty: &str| qapi::qmp::ObjectPropertyInfo {
+			name: name.into(),
+			type_: ty.into(),
+			description: None,
+			default_value: None,
+		};
+		assert_eq!(ValueType::from_property(&prop("addr", "int32")), ValueType::String);
+		assert_eq!(ValueType::from_property(&prop("addr", "uint8")), ValueType::Int);
+		assert_eq!(ValueType::from_property(&prop("bootindex", "int32")), ValueType::Int);
+
+		let types = PropertyTypes::from_properties(&[prop("addr", "int32")]);
+		let dict = keyval_dict(args(&[("addr", "0x3")]), &types).unwrap();
+		assert_eq!(dict["addr"], json!("0x3"));
+	}
+}
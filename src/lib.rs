@@ -6,6 +6,18 @@ use std::path::Path;
 use std::str::FromStr;
 use std::{io, fs};
 
+mod keyval;
+mod socket;
+mod output;
+pub mod qmp;
+pub mod qga;
+#[cfg(feature = "mock")]
+pub mod mock;
+
+pub use self::keyval::{ValueType, Key, PropertyTypes, keyval_dict, parse_bool, parse_int, parse_size};
+pub use self::socket::{SocketAddr, Connection};
+pub use self::output::{OutputFormat, human, print_table, format_size};
+
 pub fn key_val<K: FromStr, V: FromStr>(s: &str) -> Result<(K, V)> where
 	K::Err: Into<Error>,
 	V::Err: Into<Error>,
@@ -25,8 +37,17 @@ pub struct Pair<K, V> {
 }
 
 impl Pair<String, String> {
+	#[deprecated(note = "use `keyval_dict` to parse typed and nested properties")]
 	pub fn object_pair(self) -> (String, qapi::Any) {
-		(self.key, object_value(self.value))
+		let key = Key {
+			path: vec![self.key],
+			ty: Some(ValueType::String),
+		};
+		let pair = Pair { key, value: self.value };
+		keyval_dict(Some(pair), &Default::default())
+			.expect("string values always parse")
+			.into_iter().next()
+			.unwrap()
 	}
 }
 
@@ -52,10 +73,14 @@ impl<K, V> From<(K, V)> for Pair<K, V> {
 
 pub type Arguments = Vec<Pair<String, String>>;
 
+#[deprecated(note = "use `keyval_dict` to parse typed and nested properties")]
+#[allow(deprecated)]
 pub fn object_value(value: String) -> qapi::Any {
-	qapi::Any::String(value) // TODO: determine how to parse numbers and so on
+	Pair { key: String::new(), value }.object_pair().1
 }
 
+#[deprecated(note = "use `object_options` to parse typed and nested properties")]
+#[allow(deprecated)]
 pub fn args_options(id: Option<String>, args: Arguments) -> Result<qapi::qmp::ObjectOptions> {
 	let props = args.into_iter()
 		.map(Pair::object_pair)
@@ -65,6 +90,27 @@ pub fn args_options(id: Option<String>, args: Arguments) -> Result<qapi::qmp::Ob
 	Deserialize::deserialize(props).map_err(Into::into)
 }
 
+/// Builds `object-add` options from `KEY[:TYPE]=value` arguments, typed by the object's properties
+pub fn object_options(id: Option<String>, args: Vec<Pair<Key, String>>, types: &PropertyTypes) -> Result<qapi::qmp::ObjectOptions> {
+	let mut props = keyval_dict(args, types)?;
+	if let Some(id) = id {
+		props.insert("id".into(), qapi::Any::String(id));
+	}
+	let props = qapi::Any::Object(props).into_deserializer();
+	Deserialize::deserialize(props).map_err(Into::into)
+}
+
+/// Maps a QAPI error to a distinct process exit code
+pub fn error_exit_code(class: &qapi::ErrorClass) -> i32 {
+	match class {
+		qapi::ErrorClass::GenericError => 3,
+		qapi::ErrorClass::CommandNotFound => 4,
+		qapi::ErrorClass::DeviceNotActive => 5,
+		qapi::ErrorClass::DeviceNotFound => 6,
+		qapi::ErrorClass::KVMMissingCap => 7,
+	}
+}
+
 pub async fn wait<O, E, F: Future<Output=std::result::Result<O, E>>>(duration: Option<Duration>, future: F) -> Result<O> where
 	E: Into<Error>
 {
@@ -0,0 +1,222 @@
+//! Fake QEMU endpoints, for testing without a running VM
+
+use qapi::{Any, Dictionary, ErrorClass};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, sleep};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::io;
+
+mod qmp;
+mod qga;
+
+pub use self::qmp::MockQmp;
+pub use self::qga::MockQga;
+
+/// A canned response to a command
+#[derive(Debug, Clone)]
+pub enum Reply {
+	Return(Any),
+	Error(ErrorClass, String),
+	/// never respond, as a wedged QEMU would
+	Ignore,
+	/// stall the connection before replying, as a busy guest would
+	Delay(Duration, Box<Reply>),
+}
+
+impl Reply {
+	pub fn empty() -> Self {
+		Reply::Return(Any::Object(Default::default()))
+	}
+
+	pub fn error<D: Into<String>>(desc: D) -> Self {
+		Reply::Error(ErrorClass::GenericError, desc.into())
+	}
+
+	pub fn delayed(self, delay: Duration) -> Self {
+		Reply::Delay(delay, Box::new(self))
+	}
+
+	/// Queues the reply on a session, if there is one
+	pub(crate) fn send(&self, sender: &mpsc::UnboundedSender<Outgoing>, id: Option<&Any>) {
+		match self {
+			Reply::Delay(delay, reply) => {
+				let _ = sender.send(Outgoing::Delay(*delay));
+				reply.send(sender, id)
+			},
+			reply => if let Some(response) = reply.response(id) {
+				let _ = sender.send(Outgoing::Message(response));
+			},
+		}
+	}
+
+	fn response(&self, id: Option<&Any>) -> Option<Any> {
+		let mut response = match self {
+			Reply::Return(value) => serde_json::json!({ "return": value }),
+			Reply::Error(class, desc) => serde_json::json!({
+				"error": {
+					"class": class,
+					"desc": desc,
+				},
+			}),
+			Reply::Ignore => return None,
+			Reply::Delay(_, reply) => return reply.response(id),
+		};
+		if let Some(id) = id {
+			response["id"] = id.clone();
+		}
+		Some(response)
+	}
+}
+
+impl<T: Into<Any>> From<T> for Reply {
+	fn from(value: T) -> Self {
+		Reply::Return(value.into())
+	}
+}
+
+/// Canned replies that override a mock's built-in behaviour
+#[derive(Debug, Default)]
+pub(crate) struct Replies {
+	always: BTreeMap<String, Reply>,
+	once: BTreeMap<String, VecDeque<Reply>>,
+}
+
+impl Replies {
+	pub fn set(&mut self, command: String, reply: Reply) {
+		self.always.insert(command, reply);
+	}
+
+	pub fn push(&mut self, command: String, reply: Reply) {
+		self.once.entry(command).or_default().push_back(reply);
+	}
+
+	pub fn take(&mut self, command: &str) -> Option<Reply> {
+		self.once.get_mut(command).and_then(|r| r.pop_front())
+			.or_else(|| self.always.get(command).cloned())
+	}
+}
+
+/// A command as it was received by a mock
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received {
+	pub command: String,
+	pub arguments: Dictionary,
+}
+
+impl Received {
+	pub fn str(&self, key: &str) -> Option<&str> {
+		self.arguments.get(key).and_then(|v| v.as_str())
+	}
+
+	/// An optional boolean argument, false when missing
+	pub fn bool(&self, key: &str) -> bool {
+		self.arguments.get(key).and_then(|v| v.as_bool()).unwrap_or(false)
+	}
+}
+
+pub(crate) fn event(name: &str, data: Option<Any>) -> Any {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	let mut event = serde_json::json!({
+		"event": name,
+		"timestamp": {
+			"seconds": now.as_secs(),
+			"microseconds": now.subsec_micros(),
+		},
+	});
+	if let Some(data) = data {
+		event["data"] = data;
+	}
+	event
+}
+
+/// Something for a session to write, in order
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+	Message(Any),
+	Raw(Vec<u8>),
+	/// stop reading and writing for a while
+	Delay(Duration),
+}
+
+/// What a session should do after handling a message
+pub(crate) enum Handled {
+	Continue,
+	Close,
+}
+
+/// Runs a line-delimited JSON session, interleaving responses with anything sent on `outgoing`
+///
+/// Leading `0xff` sentinel bytes are skipped, as QEMU does after a delimited guest sync.
+pub(crate) async fn serve_lines<S, F>(stream: S, mut outgoing: mpsc::UnboundedReceiver<Outgoing>, mut handle: F) -> io::Result<()> where
+	S: AsyncRead + AsyncWrite,
+	F: FnMut(Result<Any, serde_json::Error>) -> Handled,
+{
+	let (read, mut write) = tokio::io::split(stream);
+	let mut read = BufReader::new(read);
+	let mut line = Vec::new();
+	let mut closing = false;
+	while !closing {
+		tokio::select! {
+			biased;
+			message = outgoing.recv() => match message {
+				Some(message) => write_message(&mut write, &message).await?,
+				None => break,
+			},
+			res = read.read_until(b'\n', &mut line) => {
+				if res? == 0 {
+					break
+				}
+				let start = line.iter().position(|&b| b != 0xff).unwrap_or(line.len());
+				let message = &line[start..];
+				if !message.iter().all(u8::is_ascii_whitespace) {
+					log::trace!("mock received {}", String::from_utf8_lossy(message).trim_end());
+					closing = matches!(handle(serde_json::from_slice(message)), Handled::Close);
+				}
+				line.clear();
+			},
+		}
+	}
+	// flush whatever the handler queued before hanging up
+	while let Ok(message) = outgoing.try_recv() {
+		write_message(&mut write, &message).await?;
+	}
+	write.shutdown().await
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(write: &mut W, message: &Outgoing) -> io::Result<()> {
+	match message {
+		Outgoing::Message(message) => {
+			let mut message = serde_json::to_vec(message)?;
+			message.push(b'\n');
+			write.write_all(&message).await
+		},
+		Outgoing::Raw(data) => write.write_all(data).await,
+		Outgoing::Delay(delay) => {
+			write.flush().await?;
+			sleep(*delay).await;
+			Ok(())
+		},
+	}
+}
+
+/// Splits a `{"execute": ..., "arguments": ..., "id": ...}` message into its parts
+pub(crate) fn parse_execute(message: Any) -> Result<(Received, Option<Any>), Reply> {
+	let mut message = match message {
+		Any::Object(message) => message,
+		_ => return Err(Reply::error("QMP input must be a JSON object")),
+	};
+	let id = message.remove("id");
+	let command = match message.remove("execute") {
+		Some(Any::String(command)) => command,
+		Some(..) => return Err(Reply::error("QMP input member 'execute' must be a string")),
+		None => return Err(Reply::error("QMP input lacks member 'execute'")),
+	};
+	let arguments = match message.remove("arguments") {
+		Some(Any::Object(arguments)) => arguments,
+		Some(..) => return Err(Reply::error("QMP input member 'arguments' must be an object")),
+		None => Default::default(),
+	};
+	Ok((Received { command, arguments }, id))
+}
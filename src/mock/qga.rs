@@ -0,0 +1,386 @@
+use qapi::{Any, ErrorClass};
+use qapi::qga;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf, Component};
+use std::process::{Command, Child, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use super::{Reply, Replies, Received, Handled, Outgoing, parse_execute, serve_lines};
+
+/// QEMU refuses reads larger than this
+const MAX_READ: i64 = 48 << 20;
+
+struct Process {
+	child: Child,
+	stdout: Option<thread::JoinHandle<Vec<u8>>>,
+	stderr: Option<thread::JoinHandle<Vec<u8>>>,
+}
+
+struct State {
+	root: PathBuf,
+	os_id: String,
+	replies: Replies,
+	delays: BTreeMap<String, VecDeque<Duration>>,
+	received: Vec<Received>,
+	files: BTreeMap<i64, File>,
+	next_handle: i64,
+	processes: BTreeMap<i64, Process>,
+}
+
+impl Drop for State {
+	fn drop(&mut self) {
+		for process in self.processes.values_mut() {
+			let _ = process.child.kill();
+			let _ = process.child.wait();
+		}
+	}
+}
+
+/// An in-process guest agent that treats a host directory as the guest filesystem
+///
+/// `guest-exec` runs host processes inside that directory. Clones share the same state.
+#[derive(Clone)]
+pub struct MockQga {
+	state: Arc<Mutex<State>>,
+}
+
+impl MockQga {
+	pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+		MockQga {
+			state: Arc::new(Mutex::new(State {
+				root: root.into(),
+				os_id: "linux".into(),
+				replies: Default::default(),
+				delays: Default::default(),
+				received: Default::default(),
+				files: Default::default(),
+				next_handle: 1000,
+				processes: Default::default(),
+			})),
+		}
+	}
+
+	fn state(&self) -> std::sync::MutexGuard<'_, State> {
+		self.state.lock().unwrap()
+	}
+
+	/// The id reported by `guest-get-osinfo`, such as `mswindows`
+	pub fn with_os_id<I: Into<String>>(self, id: I) -> Self {
+		self.state().os_id = id.into();
+		self
+	}
+
+	/// Always answers `command` with `reply`, overriding any built-in behaviour
+	pub fn with_reply<C: Into<String>, R: Into<Reply>>(self, command: C, reply: R) -> Self {
+		self.state().replies.set(command.into(), reply.into());
+		self
+	}
+
+	/// Answers the next `command` with `reply`, queued ahead of any other replies
+	pub fn with_reply_once<C: Into<String>, R: Into<Reply>>(self, command: C, reply: R) -> Self {
+		self.state().replies.push(command.into(), reply.into());
+		self
+	}
+
+	pub fn with_error<C: Into<String>, D: Into<String>>(self, command: C, class: ErrorClass, desc: D) -> Self {
+		self.with_reply(command, Reply::Error(class, desc.into()))
+	}
+
+	/// Holds back the reply to the next `command` for `delay`, after it has been carried out
+	pub fn with_delay_once<C: Into<String>>(self, command: C, delay: Duration) -> Self {
+		self.state().delays.entry(command.into()).or_default().push_back(delay);
+		self
+	}
+
+	pub fn root(&self) -> PathBuf {
+		self.state().root.clone()
+	}
+
+	/// Every command received so far, across all connections
+	pub fn received(&self) -> Vec<Received> {
+		self.state().received.clone()
+	}
+
+	/// The number of guest files that are still open
+	pub fn open_files(&self) -> usize {
+		self.state().files.len()
+	}
+
+	/// Listens on a unix socket, serving every client that connects until the task is aborted
+	pub fn listen<P: AsRef<Path>>(&self, path: P) -> io::Result<JoinHandle<()>> {
+		let listener = UnixListener::bind(path)?;
+		let mock = self.clone();
+		Ok(tokio::spawn(async move {
+			loop {
+				let stream = match listener.accept().await {
+					Ok((stream, _)) => stream,
+					Err(e) => {
+						log::warn!("mock QGA failed to accept: {}", e);
+						break
+					},
+				};
+				let mock = mock.clone();
+				tokio::spawn(async move {
+					if let Err(e) = mock.serve(stream).await {
+						log::warn!("mock QGA session failed: {}", e);
+					}
+				});
+			}
+		}))
+	}
+
+	/// Serves a single client until it disconnects
+	pub async fn serve<S: AsyncRead + AsyncWrite>(&self, stream: S) -> io::Result<()> {
+		let (sender, outgoing) = mpsc::unbounded_channel();
+		serve_lines(stream, outgoing, |message| {
+			let received = match message.map_err(|e| Reply::error(format!("JSON parse error, {}", e))).and_then(parse_execute) {
+				// the guest agent never echoes ids
+				Ok((received, _id)) => received,
+				Err(reply) => {
+					reply.send(&sender, None);
+					return Handled::Continue
+				},
+			};
+			let mut state = self.state();
+			state.received.push(received.clone());
+			if received.command == "guest-sync-delimited" {
+				let _ = sender.send(Outgoing::Raw(vec![0xff]));
+			}
+			let reply = state.execute(&received);
+			match state.delays.get_mut(&received.command).and_then(|d| d.pop_front()) {
+				Some(delay) => reply.delayed(delay),
+				None => reply,
+			}.send(&sender, None);
+			Handled::Continue
+		}).await
+	}
+}
+
+fn arguments<T: DeserializeOwned>(received: &Received) -> Result<T, Reply> {
+	serde_json::from_value(Any::Object(received.arguments.clone()))
+		.map_err(|e| Reply::error(format!("Invalid arguments for {}: {}", received.command, e)))
+}
+
+fn reply<T: serde::Serialize>(value: T) -> Reply {
+	Reply::Return(serde_json::to_value(value).unwrap())
+}
+
+fn failed<E: std::fmt::Display>(e: E) -> Reply {
+	Reply::error(format!("Guest agent command failed, error was '{}'", e))
+}
+
+impl State {
+	fn execute(&mut self, received: &Received) -> Reply {
+		if let Some(reply) = self.replies.take(&received.command) {
+			return reply
+		}
+
+		let res = match &received.command[..] {
+			"guest-sync" | "guest-sync-delimited" => match received.arguments.get("id") {
+				Some(id) => Ok(Reply::Return(id.clone())),
+				None => Err(Reply::error("Parameter 'id' is missing")),
+			},
+			"guest-ping" => Ok(Reply::empty()),
+			"guest-info" => Ok(self.info()),
+			"guest-get-osinfo" => Ok(Reply::Return(serde_json::json!({
+				"id": self.os_id,
+				"name": self.os_id,
+				"pretty-name": format!("Mock {}", self.os_id),
+			}))),
+			// the real agent doesn't respond to a successful shutdown
+			"guest-shutdown" => arguments::<qga::guest_shutdown>(received).map(|_| Reply::Ignore),
+			"guest-file-open" => arguments(received).and_then(|args| self.file_open(args)),
+			"guest-file-close" => arguments(received).and_then(|qga::guest_file_close { handle }| {
+				self.files.remove(&handle).ok_or_else(|| handle_not_found(handle)).map(|_| Reply::empty())
+			}),
+			"guest-file-read" => arguments(received).and_then(|args| self.file_read(args)),
+			"guest-file-write" => arguments(received).and_then(|args| self.file_write(args)),
+			"guest-file-seek" => arguments(received).and_then(|args| self.file_seek(args)),
+			"guest-file-flush" => arguments(received).and_then(|qga::guest_file_flush { handle }| {
+				self.file(handle)?.flush().map_err(failed)?;
+				Ok(Reply::empty())
+			}),
+			"guest-exec" => arguments(received).and_then(|args| self.exec(args)),
+			"guest-exec-status" => arguments(received).and_then(|args| self.exec_status(args)),
+			command => Err(Reply::Error(ErrorClass::CommandNotFound, format!("The command {} has not been found", command))),
+		};
+		res.unwrap_or_else(|e| e)
+	}
+
+	fn info(&self) -> Reply {
+		let commands = [
+			"guest-sync", "guest-sync-delimited", "guest-ping", "guest-info", "guest-get-osinfo", "guest-shutdown",
+			"guest-file-open", "guest-file-close", "guest-file-read", "guest-file-write", "guest-file-seek", "guest-file-flush",
+			"guest-exec", "guest-exec-status",
+		];
+		Reply::Return(serde_json::json!({
+			"version": "8.0.0",
+			"supported_commands": commands.iter().map(|&name| serde_json::json!({
+				"name": name,
+				"enabled": true,
+				"success-response": name != "guest-shutdown",
+			})).collect::<Vec<_>>(),
+		}))
+	}
+
+	/// Maps a guest path into the root directory
+	fn path(&self, path: &str) -> Result<PathBuf, Reply> {
+		let path = Path::new(path);
+		if path.components().any(|c| c == Component::ParentDir) {
+			return Err(failed(format!("'{}' escapes the mock guest filesystem", path.display())))
+		}
+		Ok(self.root.join(path.strip_prefix("/").unwrap_or(path)))
+	}
+
+	fn file(&mut self, handle: i64) -> Result<&mut File, Reply> {
+		self.files.get_mut(&handle).ok_or_else(|| handle_not_found(handle))
+	}
+
+	fn file_open(&mut self, qga::guest_file_open { path, mode }: qga::guest_file_open) -> Result<Reply, Reply> {
+		let mode = mode.unwrap_or_else(|| "r".into());
+		let mut options = OpenOptions::new();
+		match &mode.replace('b', "")[..] {
+			"r" => options.read(true),
+			"r+" => options.read(true).write(true),
+			"w" => options.write(true).create(true).truncate(true),
+			"w+" => options.read(true).write(true).create(true).truncate(true),
+			"a" => options.append(true).create(true),
+			"a+" => options.read(true).append(true).create(true),
+			_ => return Err(failed(format!("invalid file open mode '{}'", mode))),
+		};
+		let file = options.open(self.path(&path)?)
+			.map_err(|e| failed(format!("failed to open file '{}' (mode: '{}'): {}", path, mode, e)))?;
+		let handle = self.next_handle;
+		self.next_handle += 1;
+		self.files.insert(handle, file);
+		Ok(Reply::Return(handle.into()))
+	}
+
+	fn file_read(&mut self, qga::guest_file_read { handle, count }: qga::guest_file_read) -> Result<Reply, Reply> {
+		let count = count.unwrap_or(4096);
+		if !(0..=MAX_READ).contains(&count) {
+			return Err(Reply::error(format!("value '{}' is invalid for argument count", count)))
+		}
+		let mut buf = Vec::new();
+		self.file(handle)?.take(count as u64).read_to_end(&mut buf).map_err(failed)?;
+		Ok(reply(qga::GuestFileRead {
+			count: buf.len() as i64,
+			eof: (buf.len() as i64) < count,
+			buf_b64: buf,
+		}))
+	}
+
+	fn file_write(&mut self, qga::guest_file_write { handle, buf_b64, count }: qga::guest_file_write) -> Result<Reply, Reply> {
+		let count = count.unwrap_or(buf_b64.len() as i64);
+		if count < 0 || count as usize > buf_b64.len() {
+			return Err(Reply::error(format!("value '{}' is invalid for argument count", count)))
+		}
+		self.file(handle)?.write_all(&buf_b64[..count as usize]).map_err(failed)?;
+		Ok(reply(qga::GuestFileWrite {
+			count,
+			eof: false,
+		}))
+	}
+
+	fn file_seek(&mut self, qga::guest_file_seek { handle, offset, whence }: qga::guest_file_seek) -> Result<Reply, Reply> {
+		let whence = match whence {
+			qga::GuestFileWhence::name(whence) => whence,
+			qga::GuestFileWhence::value(0) => qga::QGASeek::set,
+			qga::GuestFileWhence::value(1) => qga::QGASeek::cur,
+			qga::GuestFileWhence::value(2) => qga::QGASeek::end,
+			qga::GuestFileWhence::value(whence) => return Err(Reply::error(format!("invalid whence code {}", whence))),
+		};
+		let position = match whence {
+			qga::QGASeek::set => SeekFrom::Start(offset.try_into().map_err(failed)?),
+			qga::QGASeek::cur => SeekFrom::Current(offset),
+			qga::QGASeek::end => SeekFrom::End(offset),
+		};
+		let position = self.file(handle)?.seek(position).map_err(failed)?;
+		Ok(reply(qga::GuestFileSeek {
+			position: position as i64,
+			eof: false,
+		}))
+	}
+
+	fn exec(&mut self, exec: qga::guest_exec) -> Result<Reply, Reply> {
+		let capture = exec.capture_output.unwrap_or(false);
+		let output = |capture| match capture {
+			true => Stdio::piped(),
+			false => Stdio::null(),
+		};
+		let mut command = Command::new(&exec.path);
+		command.args(exec.arg.iter().flatten())
+			.current_dir(&self.root)
+			.stdin(output(exec.input_data.is_some()))
+			.stdout(output(capture))
+			.stderr(output(capture));
+		if let Some(env) = &exec.env {
+			command.env_clear();
+			for var in env {
+				let (key, value) = var.split_once('=').unwrap_or((var, ""));
+				command.env(key, value);
+			}
+		}
+		let mut child = command.spawn()
+			.map_err(|e| failed(format!("Failed to execute child process \u{201c}{}\u{201d} ({})", exec.path, e)))?;
+
+		if let (Some(mut stdin), Some(input)) = (child.stdin.take(), exec.input_data) {
+			thread::spawn(move || stdin.write_all(&input));
+		}
+		fn collect<R: Read + Send + 'static>(read: Option<R>) -> Option<thread::JoinHandle<Vec<u8>>> {
+			read.map(|mut read| thread::spawn(move || {
+				let mut buf = Vec::new();
+				let _ = read.read_to_end(&mut buf);
+				buf
+			}))
+		}
+		let pid = child.id() as i64;
+		self.processes.insert(pid, Process {
+			stdout: collect(child.stdout.take()),
+			stderr: collect(child.stderr.take()),
+			child,
+		});
+		Ok(Reply::Return(serde_json::json!({ "pid": pid })))
+	}
+
+	fn exec_status(&mut self, qga::guest_exec_status { pid }: qga::guest_exec_status) -> Result<Reply, Reply> {
+		let process = self.processes.get_mut(&pid)
+			.ok_or_else(|| Reply::error("Invalid parameter 'pid'"))?;
+		let status = match process.child.try_wait().map_err(failed)? {
+			Some(status) => status,
+			None => return Ok(reply(qga::GuestExecStatus {
+				exited: false,
+				exitcode: None,
+				signal: None,
+				out_data: None,
+				err_data: None,
+				out_truncated: None,
+				err_truncated: None,
+			})),
+		};
+		let process = self.processes.remove(&pid).unwrap();
+		let join = |output: Option<thread::JoinHandle<Vec<u8>>>| output.map(|t| t.join().unwrap_or_default());
+		Ok(reply(qga::GuestExecStatus {
+			exited: true,
+			exitcode: status.code().map(Into::into),
+			signal: status.signal().map(Into::into),
+			out_data: join(process.stdout),
+			err_data: join(process.stderr),
+			out_truncated: None,
+			err_truncated: None,
+		}))
+	}
+}
+
+fn handle_not_found(handle: i64) -> Reply {
+	Reply::error(format!("handle '{}' has not been found", handle))
+}
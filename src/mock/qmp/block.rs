@@ -0,0 +1,281 @@
+use qapi::Any;
+use crate::mock::{Reply, Received};
+use super::{State, Node, missing_parameter, device_not_found};
+
+impl State {
+	pub(super) fn anon_node(&mut self, node: Node) -> String {
+		self.anon_nodes += 1;
+		let node_name = format!("#block{:03}", self.anon_nodes);
+		self.nodes.insert(node_name.clone(), node);
+		node_name
+	}
+
+	/// A complete `BlockDeviceInfo`, as both `query-block` and `query-named-block-nodes` report it
+	fn block_device_info(node_name: &str, node: &Node) -> Any {
+		let mut image = serde_json::json!({
+			"filename": node.filename,
+			"format": node.driver,
+			"virtual-size": 0,
+		});
+		if !node.snapshots.is_empty() {
+			image["snapshots"] = node.snapshots.iter().map(|s| serde_json::json!({
+				"id": s.id.to_string(),
+				"name": s.tag,
+				"vm-state-size": s.vm_state_size,
+				"date-sec": s.date.as_secs(),
+				"date-nsec": s.date.subsec_nanos(),
+				"vm-clock-sec": 0,
+				"vm-clock-nsec": 0,
+			})).collect();
+		}
+		serde_json::json!({
+			"node-name": node_name,
+			"file": node.filename,
+			"drv": node.driver,
+			"ro": node.read_only,
+			"encrypted": false,
+			"backing_file_depth": 0,
+			"image": image,
+			"cache": {
+				"direct": false,
+				"no-flush": false,
+				"writeback": true,
+			},
+			"detect_zeroes": "off",
+			"bps": 0,
+			"bps_rd": 0,
+			"bps_wr": 0,
+			"iops": 0,
+			"iops_rd": 0,
+			"iops_wr": 0,
+			"write_threshold": 0,
+		})
+	}
+
+	pub(super) fn query_block(&self) -> Reply {
+		let inserted = |node_name: &str| self.nodes.get(node_name)
+			.map(|node| Self::block_device_info(node_name, node));
+		// disks attached with device_add have no backend name
+		let disks = self.devices.iter().filter_map(|(id, dev)| {
+			let node_name = dev.properties.get("drive")?.as_str()?;
+			Some(serde_json::json!({
+				"device": "",
+				"qdev": id,
+				"type": "unknown",
+				"removable": false,
+				"locked": false,
+				"inserted": inserted(node_name)?,
+			}))
+		});
+		Any::Array(disks.chain(self.drives.iter().map(|(id, drive)| {
+			let mut info = serde_json::json!({
+				"device": drive.backend,
+				"qdev": id,
+				"type": "unknown",
+				"removable": true,
+				"locked": drive.locked,
+				"tray_open": drive.tray_open,
+			});
+			if let Some(inserted) = drive.medium.as_deref().and_then(inserted) {
+				info["inserted"] = inserted;
+			}
+			info
+		})).collect()).into()
+	}
+
+	pub(super) fn query_named_block_nodes(&self) -> Reply {
+		Any::Array(self.nodes.iter()
+			.map(|(node_name, node)| Self::block_device_info(node_name, node))
+			.collect()
+		).into()
+	}
+
+	/// Describes what is using a node, if anything
+	pub(super) fn node_user(&self, node_name: &str) -> Option<String> {
+		let drive = self.drives.iter()
+			.find(|(_, drive)| drive.medium.as_deref() == Some(node_name))
+			.map(|(id, _)| format!("device '{}'", id));
+		let device = || self.devices.iter()
+			.find(|(_, dev)| dev.properties.get("drive").and_then(|d| d.as_str()) == Some(node_name))
+			.map(|(id, _)| format!("device '{}'", id));
+		let parent = || self.nodes.iter()
+			.find(|(_, node)| node.file.as_deref() == Some(node_name))
+			.map(|(parent, _)| format!("node '{}'", parent));
+		drive.or_else(device).or_else(parent)
+	}
+
+	pub(super) fn blockdev_add(&mut self, received: &Received) -> Reply {
+		let node_name = match received.str("node-name") {
+			Some(node_name) => node_name.to_owned(),
+			None => return Reply::error("A top-level node needs a node-name"),
+		};
+		let driver = match received.str("driver") {
+			Some(driver) => driver.to_owned(),
+			None => return missing_parameter("driver"),
+		};
+		if self.nodes.contains_key(&node_name) {
+			return Reply::error(format!("Duplicate nodes with node-name='{}'", node_name))
+		}
+		let (filename, file) = match (received.str("filename"), received.str("file")) {
+			(Some(filename), _) => (filename.to_owned(), None),
+			(None, Some(file)) => match self.nodes.get(file) {
+				Some(child) => (child.filename.clone(), Some(file.to_owned())),
+				None => return Reply::error(format!("Cannot find device='' nor node-name='{}'", file)),
+			},
+			(None, None) => return missing_parameter("file"),
+		};
+		self.nodes.insert(node_name, Node {
+			driver,
+			filename,
+			file,
+			read_only: received.bool("read-only"),
+			snapshots: Default::default(),
+		});
+		Reply::empty()
+	}
+
+	pub(super) fn blockdev_del(&mut self, received: &Received) -> Reply {
+		let node_name = match received.str("node-name") {
+			Some(node_name) => node_name,
+			None => return missing_parameter("node-name"),
+		};
+		if !self.nodes.contains_key(node_name) {
+			return Reply::error(format!("Failed to find node with node-name='{}'", node_name))
+		}
+		if let Some(user) = self.node_user(node_name) {
+			return Reply::error(format!("Node '{}' is busy: in use by {}", node_name, user))
+		}
+		self.nodes.remove(node_name);
+		Reply::empty()
+	}
+
+	/// Finds a drive by the `id` of its device, or the (deprecated) `device` name of its backend
+	pub(super) fn drive(&self, received: &Received) -> Result<String, Reply> {
+		let found = match (received.str("id"), received.str("device")) {
+			(Some(id), _) => self.drives.get_key_value(id).map(|(id, _)| id),
+			(None, Some(device)) => self.drives.iter()
+				.find(|(_, drive)| drive.backend == device)
+				.map(|(id, _)| id),
+			(None, None) => return Err(Reply::error("Either 'device' or 'id' must be specified")),
+		};
+		match found {
+			Some(id) => Ok(id.clone()),
+			None => Err(device_not_found(received.str("id").or(received.str("device")).unwrap_or_default())),
+		}
+	}
+
+	/// Returns whether the tray opened, or was already open
+	///
+	/// A locked tray is only opened when forced, otherwise the guest is asked to release it,
+	/// and that is an error if `strict`.
+	pub(super) fn open_tray(&mut self, id: &str, force: bool, strict: bool) -> Result<bool, Reply> {
+		let drive = &self.drives[id];
+		if drive.tray_open {
+			return Ok(true)
+		}
+		if drive.locked && !force {
+			if let Some(release) = drive.release {
+				self.pending_trays.push((id.into(), release));
+			}
+			return match strict {
+				true => Err(Reply::error(format!("Device '{}' is locked and force was not specified, wait for tray to open and try again", id))),
+				false => Ok(false),
+			}
+		}
+		self.move_tray(id, true);
+		Ok(true)
+	}
+
+	pub(super) fn move_tray(&mut self, id: &str, open: bool) {
+		let drive = match self.drives.get_mut(id) {
+			Some(drive) if drive.tray_open != open => drive,
+			_ => return,
+		};
+		drive.tray_open = open;
+		if open {
+			drive.locked = false;
+		}
+		let data = serde_json::json!({
+			"device": drive.backend,
+			"id": id,
+			"tray-open": open,
+		});
+		self.emit("DEVICE_TRAY_MOVED", Some(data));
+	}
+
+	pub(super) fn remove_medium(&mut self, id: &str) -> Result<(), Reply> {
+		let drive = self.drives.get_mut(id).unwrap();
+		if !drive.tray_open {
+			return Err(Reply::error(format!("Tray of device '{}' is not open", id)))
+		}
+		if let Some(node_name) = drive.medium.take() {
+			// implicitly created nodes go away with the medium
+			if node_name.starts_with('#') {
+				self.nodes.remove(&node_name);
+			}
+		}
+		Ok(())
+	}
+
+	pub(super) fn insert_medium(&mut self, received: &Received) -> Reply {
+		let id = match self.drive(received) {
+			Ok(id) => id,
+			Err(reply) => return reply,
+		};
+		let node_name = match received.str("node-name") {
+			Some(node_name) => node_name,
+			None => return missing_parameter("node-name"),
+		};
+		if !self.nodes.contains_key(node_name) {
+			return Reply::error(format!("Cannot find node {}", node_name))
+		}
+		if self.drives.values().any(|d| d.medium.as_deref() == Some(node_name)) {
+			return Reply::error(format!("Node '{}' is already in use", node_name))
+		}
+		let drive = self.drives.get_mut(&id).unwrap();
+		if !drive.tray_open {
+			return Reply::error(format!("Tray of device '{}' is not open", id))
+		}
+		if drive.medium.is_some() {
+			return Reply::error(format!("There already is a medium in device '{}'", id))
+		}
+		drive.medium = Some(node_name.into());
+		Reply::empty()
+	}
+
+	pub(super) fn change_medium(&mut self, received: &Received) -> Reply {
+		let id = match self.drive(received) {
+			Ok(id) => id,
+			Err(reply) => return reply,
+		};
+		let filename = match received.str("filename") {
+			Some(filename) => filename.to_owned(),
+			None => return missing_parameter("filename"),
+		};
+		let read_only = match received.str("read-only-mode") {
+			Some("read-only") => true,
+			Some("read-write") => false,
+			_ => self.drives[&id].medium.as_ref()
+				.and_then(|node| self.nodes.get(node))
+				.map(|node| node.read_only)
+				.unwrap_or(false),
+		};
+		let node = Node {
+			driver: received.str("format").unwrap_or("raw").into(),
+			filename,
+			file: None,
+			read_only,
+			snapshots: Default::default(),
+		};
+		if let Err(reply) = self.open_tray(&id, received.bool("force"), true) {
+			return reply
+		}
+		if let Err(reply) = self.remove_medium(&id) {
+			return reply
+		}
+		let node_name = self.anon_node(node);
+		self.drives.get_mut(&id).unwrap().medium = Some(node_name);
+		self.move_tray(&id, false);
+		Reply::empty()
+	}
+}
@@ -0,0 +1,132 @@
+use qapi::{Any, ErrorClass};
+use crate::mock::{Reply, Received};
+use super::{State, Device, missing_parameter};
+
+impl State {
+	pub(super) fn device_add(&mut self, received: &Received) -> Reply {
+		let mut properties = received.arguments.clone();
+		let driver = match properties.remove("driver") {
+			Some(Any::String(driver)) => driver,
+			_ => return missing_parameter("driver"),
+		};
+		let id = match properties.remove("id") {
+			Some(Any::String(id)) => id,
+			Some(..) => return Reply::error("Invalid parameter type for 'id', expected: string"),
+			None => {
+				self.anon_devices += 1;
+				format!("device[{}]", self.anon_devices - 1)
+			},
+		};
+		if self.devices.contains_key(&id) {
+			return Reply::error(format!("Duplicate device ID '{}'", id))
+		}
+		match properties.get("drive") {
+			Some(Any::String(node_name)) if !self.nodes.contains_key(node_name) =>
+				return Reply::error(format!("Property '{}.drive' can't find value '{}'", driver, node_name)),
+			Some(Any::String(node_name)) => if let Some(user) = self.node_user(node_name) {
+				return Reply::error(format!("Node '{}' is already in use by {}", node_name, user))
+			},
+			_ => (),
+		}
+		// an OnOffAuto property, which QEMU won't accept as a bool
+		match properties.get("write-cache") {
+			None | Some(Any::String(..)) => (),
+			Some(..) => return Reply::error(format!("Invalid parameter type for '{}.write-cache', expected: string", driver)),
+		}
+		let bus = match properties.remove("bus") {
+			Some(Any::String(bus)) => Some(bus),
+			_ => None,
+		};
+		// PCI devices are given the first free slot on their bus, unless they ask for one
+		let devfn = match properties.remove("addr") {
+			Some(Any::String(addr)) => match parse_devfn(&addr) {
+				Some(devfn) => Some(devfn),
+				None => return Reply::error(format!("Property '{}.addr' doesn't take value '{}'", driver, addr)),
+			},
+			Some(..) => return Reply::error("Invalid parameter type for 'addr', expected: string"),
+			None if driver.ends_with("-pci") => (3..32).map(|slot| slot << 3)
+				.find(|&devfn| self.pci_device(bus.as_deref(), devfn).is_none()),
+			None => None,
+		};
+		if let Some(devfn) = devfn {
+			if let Some(user) = self.pci_device(bus.as_deref(), devfn) {
+				return Reply::error(format!("PCI: slot {} function {} not available for {}, in use by {}",
+					devfn >> 3, devfn & 7, driver, self.devices[user].driver,
+				))
+			}
+			properties.insert("addr".into(), devfn.into());
+		}
+		self.devices.insert(id, Device {
+			driver,
+			bus,
+			properties,
+		});
+		Reply::empty()
+	}
+
+	/// The id of the device at a PCI address
+	fn pci_device(&self, bus: Option<&str>, devfn: u64) -> Option<&String> {
+		self.devices.iter()
+			.find(|(_, dev)| dev.bus.as_deref() == bus && dev.properties.get("addr").and_then(|addr| addr.as_u64()) == Some(devfn))
+			.map(|(id, _)| id)
+	}
+
+	/// Lists the devices on the root PCI bus
+	pub(super) fn query_pci(&self) -> Reply {
+		let mut devices: Vec<_> = self.devices.iter()
+			.filter(|(_, dev)| dev.bus.is_none())
+			.filter_map(|(id, dev)| Some((id, dev.properties.get("addr")?.as_u64()?)))
+			.collect();
+		devices.sort_by_key(|&(_, devfn)| devfn);
+		serde_json::json!([{
+			"bus": 0,
+			"devices": devices.into_iter().map(|(id, devfn)| serde_json::json!({
+				"bus": 0,
+				"slot": devfn >> 3,
+				"function": devfn & 7,
+				"class_info": { "class": 0 },
+				"id": { "vendor": 0x1af4, "device": 0x1000 },
+				"qdev_id": if id.starts_with("device[") { "" } else { id },
+				"regions": [],
+			})).collect::<Vec<_>>(),
+		}]).into()
+	}
+
+	pub(super) fn device_del(&mut self, received: &Received) -> Reply {
+		let id = match received.str("id") {
+			Some(id) => id.to_owned(),
+			None => return missing_parameter("id"),
+		};
+		if !self.devices.contains_key(&id) {
+			return Reply::Error(ErrorClass::DeviceNotFound, format!("Device '{}' not found", id))
+		}
+		let delay = match self.unplug_delay {
+			Some(delay) => delay,
+			None => return Reply::empty(),
+		};
+		match delay.is_zero() {
+			true => self.unplug(&id),
+			false => self.pending_unplugs.push((id, delay)),
+		}
+		Reply::empty()
+	}
+
+	pub(super) fn unplug(&mut self, id: &str) {
+		if self.devices.remove(id).is_some() {
+			self.emit("DEVICE_DELETED", Some(serde_json::json!({
+				"device": id,
+				"path": format!("/machine/peripheral/{}", id),
+			})));
+		}
+	}
+}
+
+/// Parses a PCI `SLOT[.FUNCTION]` in hex, as the `addr` property of PCI devices is given
+fn parse_devfn(addr: &str) -> Option<u64> {
+	let (slot, function) = addr.split_once('.').unwrap_or((addr, "0"));
+	let (slot, function) = (u64::from_str_radix(slot, 16).ok()?, u64::from_str_radix(function, 16).ok()?);
+	match slot < 32 && function < 8 {
+		true => Some(slot << 3 | function),
+		false => None,
+	}
+}
@@ -0,0 +1,71 @@
+use qapi::Any;
+use crate::mock::{Reply, Received};
+use super::{State, missing_parameter, device_not_found};
+
+impl State {
+	/// Presses keys together, then releases them in reverse
+	pub(super) fn send_key(&mut self, received: &Received) -> Reply {
+		let keys = match received.arguments.get("keys") {
+			Some(Any::Array(keys)) if !keys.is_empty() => keys,
+			Some(..) => return Reply::error("Parameter 'keys' expects a non-empty list"),
+			None => return missing_parameter("keys"),
+		};
+		let keys = match keys.iter().map(key_name).collect::<Result<Vec<_>, _>>() {
+			Ok(keys) => keys,
+			Err(reply) => return reply,
+		};
+		self.keys.extend(keys.iter().map(|key| (key.clone(), true)));
+		self.keys.extend(keys.into_iter().rev().map(|key| (key, false)));
+		Reply::empty()
+	}
+
+	pub(super) fn input_send_event(&mut self, received: &Received) -> Reply {
+		let events = match received.arguments.get("events") {
+			Some(Any::Array(events)) => events,
+			Some(..) => return Reply::error("Invalid parameter type for 'events', expected: array"),
+			None => return missing_parameter("events"),
+		};
+		if let Some(device) = received.str("device") {
+			if !self.devices.contains_key(device) {
+				return device_not_found(device)
+			}
+		}
+		let (mut keys, mut pointer) = (Vec::new(), Vec::new());
+		for event in events {
+			let data = &event["data"];
+			match event["type"].as_str() {
+				Some("key") => match (key_name(&data["key"]), data["down"].as_bool()) {
+					(Ok(key), Some(down)) => keys.push((key, down)),
+					(Err(reply), _) => return reply,
+					(_, None) => return missing_parameter("down"),
+				},
+				Some(ty @ ("abs" | "rel")) => match (data["axis"].as_str(), data["value"].as_i64()) {
+					(Some(axis @ ("x" | "y")), Some(value)) => pointer.push(format!("{} {} {}", ty, axis, value)),
+					_ => return Reply::error("Invalid parameter type for 'data', expected: InputMoveEvent"),
+				},
+				Some("btn") => match (data["button"].as_str(), data["down"].as_bool()) {
+					(Some(button), Some(down)) if button.parse::<qapi::qmp::InputButton>().is_ok() =>
+						pointer.push(format!("btn {} {}", button, if down { "down" } else { "up" })),
+					_ => return Reply::error("Invalid parameter type for 'data', expected: InputBtnEvent"),
+				},
+				_ => return Reply::error(format!("Invalid parameter 'type' value {}", event["type"])),
+			}
+		}
+		self.keys.extend(keys);
+		self.pointer.extend(pointer);
+		Reply::empty()
+	}
+}
+
+/// The name of a `KeyValue`, or its scancode in hex
+fn key_name(key: &Any) -> Result<String, Reply> {
+	match (key["type"].as_str(), &key["data"]) {
+		(Some("qcode"), Any::String(qcode)) if qcode.parse::<qapi::qmp::QKeyCode>().is_ok() => Ok(qcode.clone()),
+		(Some("qcode"), qcode) => Err(Reply::error(format!("Parameter 'data' does not accept value {}", qcode))),
+		(Some("number"), number) => match number.as_u64() {
+			Some(number) => Ok(format!("{:#x}", number)),
+			None => Err(Reply::error("Invalid parameter type for 'data', expected: integer")),
+		},
+		_ => Err(Reply::error("Invalid parameter type for 'key', expected: KeyValue")),
+	}
+}
@@ -0,0 +1,106 @@
+use crate::mock::{Reply, Received};
+use super::{State, Migration, missing_parameter};
+
+impl State {
+	pub(super) fn migrate_set_capabilities(&mut self, received: &Received) -> Reply {
+		let capabilities = match received.arguments.get("capabilities").and_then(|c| c.as_array()) {
+			Some(capabilities) => capabilities,
+			None => return missing_parameter("capabilities"),
+		};
+		for capability in capabilities {
+			match (capability["capability"].as_str(), capability["state"].as_bool()) {
+				(Some(name), Some(state)) => {
+					self.migrate_capabilities.insert(name.into(), state);
+				},
+				_ => return Reply::error("Invalid parameter type for 'capabilities', expected: MigrationCapabilityStatus"),
+			}
+		}
+		Reply::empty()
+	}
+
+	pub(super) fn migrate(&mut self, received: &Received, incoming: bool) -> Reply {
+		let uri = match received.str("uri") {
+			Some(uri) => uri.to_owned(),
+			None => return missing_parameter("uri"),
+		};
+		match (&self.migration, incoming) {
+			(Some(m), false) if matches!(m.status, "setup" | "active" | "postcopy-active" | "cancelling") =>
+				return Reply::error("There's a migration process in progress"),
+			(_, true) if !self.incoming => return Reply::error("'-incoming' was not specified on the command line"),
+			_ => (),
+		}
+		if incoming {
+			self.incoming = false;
+		}
+		self.migration = Some(Migration {
+			status: "none",
+			uri,
+			transferred: 0,
+			polls: 0,
+			postcopy: false,
+			incoming,
+		});
+		self.migration_status("setup");
+		Reply::empty()
+	}
+
+	pub(super) fn migration_status(&mut self, status: &'static str) {
+		if let Some(migration) = &mut self.migration {
+			migration.status = status;
+		}
+		self.emit("MIGRATION", Some(serde_json::json!({ "status": status })));
+		if status == "completed" && !matches!(&self.migration, Some(m) if m.incoming) && self.running {
+			// the source stays paused after migrating
+			self.running = false;
+			self.emit("STOP", None);
+		}
+	}
+
+	/// Reports on the migration, advancing it a step each time
+	pub(super) fn query_migrate(&mut self) -> Reply {
+		let (size, polls) = (self.migration_size, self.migration_polls);
+		let next = match &mut self.migration {
+			None => return serde_json::json!({ }).into(),
+			Some(migration) => match migration.status {
+				"setup" => Some("active"),
+				"active" | "postcopy-active" => {
+					migration.polls += 1;
+					migration.transferred = (size * migration.polls as u64 / polls as u64).min(size);
+					match (&self.migration_error, migration.transferred >= size, migration.postcopy) {
+						(Some(_), ..) => Some("failed"),
+						(None, true, _) => Some("completed"),
+						(None, false, true) if migration.status == "active" => Some("postcopy-active"),
+						_ => None,
+					}
+				},
+				"cancelling" => Some("cancelled"),
+				_ => None,
+			},
+		};
+		if let Some(status) = next {
+			self.migration_status(status);
+		}
+
+		let migration = self.migration.as_ref().unwrap();
+		let mut info = serde_json::json!({ "status": migration.status });
+		if migration.status != "setup" {
+			info["ram"] = serde_json::json!({
+				"transferred": migration.transferred,
+				"remaining": size - migration.transferred,
+				"total": size,
+				"dirty-pages-rate": 256,
+				"page-size": 4096,
+				"mbps": 1000.0,
+				"dirty-sync-count": migration.polls,
+			});
+			info["expected-downtime"] = 300.into();
+			info["total-time"] = (migration.polls * 100).into();
+		}
+		match migration.status {
+			"completed" => info["downtime"] = 42.into(),
+			"failed" => info["error-desc"] = self.migration_error.clone().into(),
+			_ => (),
+		}
+		info.into()
+	}
+}
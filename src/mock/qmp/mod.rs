@@ -0,0 +1,730 @@
+use qapi::{Any, Dictionary, ErrorClass};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::io;
+use super::{Reply, Replies, Received, Handled, Outgoing, event, parse_execute, serve_lines};
+
+mod device;
+mod qom;
+mod block;
+mod snapshot;
+mod migration;
+mod screen;
+mod input;
+
+#[derive(Debug, Clone)]
+struct Device {
+	driver: String,
+	bus: Option<String>,
+	properties: Dictionary,
+}
+
+/// A removable drive, keyed by the id of its device
+#[derive(Debug, Clone)]
+struct Drive {
+	/// the block backend name, listed as `device` by `query-block`
+	backend: String,
+	locked: bool,
+	tray_open: bool,
+	/// the node name of the inserted medium
+	medium: Option<String>,
+	/// how long the guest takes to unlock and open the tray when asked to
+	release: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+	driver: String,
+	filename: String,
+	/// the node name of its protocol child
+	file: Option<String>,
+	read_only: bool,
+	snapshots: Vec<Snapshot>,
+}
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+	id: usize,
+	tag: String,
+	vm_state_size: u64,
+	date: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum JobAction {
+	Save { tag: String, vmstate: String, devices: Vec<String> },
+	Load { tag: String, vmstate: String, devices: Vec<String> },
+	Delete { tag: String, devices: Vec<String> },
+}
+
+#[derive(Debug, Clone)]
+struct Job {
+	action: JobAction,
+	status: &'static str,
+	error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Migration {
+	status: &'static str,
+	uri: String,
+	transferred: u64,
+	polls: usize,
+	postcopy: bool,
+	incoming: bool,
+}
+
+struct State {
+	name: Option<String>,
+	running: bool,
+	cpus: usize,
+	devices: BTreeMap<String, Device>,
+	anon_devices: usize,
+	objects: BTreeMap<String, Device>,
+	properties: BTreeMap<String, Vec<(String, String)>>,
+	drives: BTreeMap<String, Drive>,
+	nodes: BTreeMap<String, Node>,
+	anon_nodes: usize,
+	pending_trays: Vec<(String, Duration)>,
+	jobs: BTreeMap<String, Job>,
+	pending_jobs: Vec<String>,
+	/// jobs keep running until they're cancelled
+	stall_jobs: bool,
+	migration: Option<Migration>,
+	migration_size: u64,
+	migration_polls: usize,
+	migration_error: Option<String>,
+	incoming: bool,
+	migrate_capabilities: BTreeMap<String, bool>,
+	migrate_parameters: Dictionary,
+	screen: Option<(u32, u32)>,
+	png: bool,
+	screendumps: u32,
+	keys: Vec<(String, bool)>,
+	pointer: Vec<String>,
+	replies: Replies,
+	unplug_delay: Option<Duration>,
+	pending_unplugs: Vec<(String, Duration)>,
+	received: Vec<Received>,
+	sessions: Vec<mpsc::UnboundedSender<Outgoing>>,
+}
+
+impl Default for State {
+	fn default() -> Self {
+		State {
+			name: None,
+			running: true,
+			cpus: 1,
+			devices: Default::default(),
+			anon_devices: 0,
+			objects: Default::default(),
+			properties: Default::default(),
+			drives: Default::default(),
+			nodes: Default::default(),
+			anon_nodes: 0,
+			pending_trays: Default::default(),
+			jobs: Default::default(),
+			pending_jobs: Default::default(),
+			stall_jobs: false,
+			migration: None,
+			migration_size: 1 << 30,
+			migration_polls: 3,
+			migration_error: None,
+			incoming: false,
+			migrate_capabilities: Default::default(),
+			migrate_parameters: Default::default(),
+			screen: Some((640, 480)),
+			png: true,
+			screendumps: 0,
+			keys: Default::default(),
+			pointer: Default::default(),
+			replies: Default::default(),
+			unplug_delay: Some(Duration::ZERO),
+			pending_unplugs: Default::default(),
+			received: Default::default(),
+			sessions: Default::default(),
+		}
+	}
+}
+
+/// An in-process QMP server that imitates just enough of QEMU for testing
+///
+/// It keeps a fake `/machine/peripheral` QOM tree for `device_add` and friends,
+/// block nodes and removable drives for `blockdev-add` and the media commands, snapshot jobs,
+/// a migration that progresses each time it's queried, a screen that changes with every `screendump`,
+/// and a keyboard and mouse that record what's pressed. It replies to anything else it doesn't know about
+/// with `CommandNotFound` unless a canned reply has been configured. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct MockQmp {
+	state: Arc<Mutex<State>>,
+}
+
+impl MockQmp {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	fn state(&self) -> std::sync::MutexGuard<'_, State> {
+		self.state.lock().unwrap()
+	}
+
+	/// Adds a device to the peripheral tree, as if it were on the QEMU command line
+	pub fn with_device<I: Into<String>, D: Into<String>>(self, id: I, driver: D) -> Self {
+		self.state().devices.insert(id.into(), Device {
+			driver: driver.into(),
+			bus: None,
+			properties: Default::default(),
+		});
+		self
+	}
+
+	pub fn with_object<I: Into<String>, T: Into<String>>(self, id: I, qom_type: T) -> Self {
+		self.state().objects.insert(id.into(), Device {
+			driver: qom_type.into(),
+			bus: None,
+			properties: Default::default(),
+		});
+		self
+	}
+
+	/// Sets the `(name, type)` pairs listed by `device-list-properties` and `qom-list-properties`
+	pub fn with_properties<T: Into<String>>(self, typename: T, properties: &[(&str, &str)]) -> Self {
+		self.state().properties.insert(typename.into(), properties.iter()
+			.map(|&(name, ty)| (name.into(), ty.into()))
+			.collect()
+		);
+		self
+	}
+
+	/// Always answers `command` with `reply`, overriding any built-in behaviour
+	pub fn with_reply<C: Into<String>, R: Into<Reply>>(self, command: C, reply: R) -> Self {
+		self.state().replies.set(command.into(), reply.into());
+		self
+	}
+
+	/// Answers the next `command` with `reply`, queued ahead of any other replies
+	pub fn with_reply_once<C: Into<String>, R: Into<Reply>>(self, command: C, reply: R) -> Self {
+		self.state().replies.push(command.into(), reply.into());
+		self
+	}
+
+	pub fn with_error<C: Into<String>, D: Into<String>>(self, command: C, class: ErrorClass, desc: D) -> Self {
+		self.with_reply(command, Reply::Error(class, desc.into()))
+	}
+
+	/// How long the guest takes to release a device after `device_del`
+	///
+	/// `None` means the guest never responds, so `DEVICE_DELETED` is never emitted.
+	pub fn with_unplug_delay(self, delay: Option<Duration>) -> Self {
+		self.state().unplug_delay = delay;
+		self
+	}
+
+	/// Jobs never finish on their own, only when cancelled
+	pub fn with_stalled_jobs(self) -> Self {
+		self.state().stall_jobs = true;
+		self
+	}
+
+	pub fn with_running(self, running: bool) -> Self {
+		self.state().running = running;
+		self
+	}
+
+	/// The number of vCPUs listed by `query-cpus-fast`
+	pub fn with_cpus(self, cpus: usize) -> Self {
+		self.state().cpus = cpus;
+		self
+	}
+
+	/// Adds an empty CD drive with its tray closed
+	pub fn with_drive<I: Into<String>, B: Into<String>>(self, id: I, backend: B) -> Self {
+		self.state().drives.insert(id.into(), Drive {
+			backend: backend.into(),
+			locked: false,
+			tray_open: false,
+			medium: None,
+			release: None,
+		});
+		self
+	}
+
+	/// Adds a block node that isn't attached to anything
+	pub fn with_node<N: Into<String>, D: Into<String>, F: Into<String>>(self, node_name: N, driver: D, filename: F) -> Self {
+		self.state().nodes.insert(node_name.into(), Node {
+			driver: driver.into(),
+			filename: filename.into(),
+			file: None,
+			read_only: false,
+			snapshots: Default::default(),
+		});
+		self
+	}
+
+	/// Inserts a raw image into a drive added by `with_drive`
+	pub fn with_medium<I: AsRef<str>, F: Into<String>>(self, id: I, filename: F) -> Self {
+		{
+			let mut state = self.state();
+			let node_name = state.anon_node(Node {
+				driver: "raw".into(),
+				filename: filename.into(),
+				file: None,
+				read_only: true,
+				snapshots: Default::default(),
+			});
+			if let Some(drive) = state.drives.get_mut(id.as_ref()) {
+				drive.medium = Some(node_name);
+			}
+		}
+		self
+	}
+
+	/// Has the guest lock the tray of a drive
+	///
+	/// When asked to eject, the guest unlocks and opens it after `release`, or never if `None`.
+	pub fn with_tray_lock<I: AsRef<str>>(self, id: I, release: Option<Duration>) -> Self {
+		if let Some(drive) = self.state().drives.get_mut(id.as_ref()) {
+			drive.locked = true;
+			drive.release = release;
+		}
+		self
+	}
+
+	/// Adds an internal snapshot to a node added by `with_node`, with VM state if `vm_state_size` isn't zero
+	pub fn with_snapshot<N: AsRef<str>, T: Into<String>>(self, node_name: N, tag: T, vm_state_size: u64) -> Self {
+		if let Some(node) = self.state().nodes.get_mut(node_name.as_ref()) {
+			node.snapshots.push(Snapshot {
+				id: node.snapshots.len() + 1,
+				tag: tag.into(),
+				vm_state_size,
+				date: Duration::from_secs(1_700_000_000),
+			});
+		}
+		self
+	}
+
+	/// The tags of the internal snapshots of a node
+	pub fn snapshots(&self, node_name: &str) -> Vec<String> {
+		self.state().nodes.get(node_name).into_iter()
+			.flat_map(|node| node.snapshots.iter().map(|s| s.tag.clone()))
+			.collect()
+	}
+
+	/// The filename of the medium in a drive
+	pub fn medium(&self, id: &str) -> Option<String> {
+		let state = self.state();
+		let node = state.drives.get(id)?.medium.as_ref()?;
+		state.nodes.get(node).map(|node| node.filename.clone())
+	}
+
+	/// Returns the driver of each named block node
+	pub fn nodes(&self) -> BTreeMap<String, String> {
+		self.state().nodes.iter()
+			.filter(|(name, _)| !name.starts_with('#'))
+			.map(|(name, node)| (name.clone(), node.driver.clone()))
+			.collect()
+	}
+
+	pub fn tray_open(&self, id: &str) -> Option<bool> {
+		self.state().drives.get(id).map(|drive| drive.tray_open)
+	}
+
+	/// How much RAM a migration sends, and how many `query-migrate` polls it takes
+	pub fn with_migration(self, size: u64, polls: usize) -> Self {
+		{
+			let mut state = self.state();
+			state.migration_size = size;
+			state.migration_polls = polls.max(1);
+		}
+		self
+	}
+
+	/// Fails migrations once they've started
+	pub fn with_migration_error<D: Into<String>>(self, desc: D) -> Self {
+		self.state().migration_error = Some(desc.into());
+		self
+	}
+
+	/// Waits for `migrate-incoming`, as if started with `-incoming defer`
+	pub fn with_incoming(self) -> Self {
+		self.state().incoming = true;
+		self
+	}
+
+	/// The status and URI of the last migration
+	pub fn migration(&self) -> Option<(String, String)> {
+		self.state().migration.as_ref().map(|m| (m.status.into(), m.uri.clone()))
+	}
+
+	pub fn migrate_capabilities(&self) -> BTreeMap<String, bool> {
+		self.state().migrate_capabilities.clone()
+	}
+
+	pub fn migrate_parameters(&self) -> Dictionary {
+		self.state().migrate_parameters.clone()
+	}
+
+	/// The name of the VM, as given by `-name`
+	pub fn with_name<N: Into<String>>(self, name: N) -> Self {
+		self.state().name = Some(name.into());
+		self
+	}
+
+	/// The resolution of the screen, or `None` for a VM without a display
+	pub fn with_screen(self, screen: Option<(u32, u32)>) -> Self {
+		self.state().screen = screen;
+		self
+	}
+
+	/// Whether `screendump` can write PNG, as when QEMU is built with libpng
+	pub fn with_png(self, png: bool) -> Self {
+		self.state().png = png;
+		self
+	}
+
+	/// Key presses and releases from `send-key` and `input-send-event`, with scancodes in hex
+	pub fn key_events(&self) -> Vec<(String, bool)> {
+		self.state().keys.clone()
+	}
+
+	/// Mouse events from `input-send-event`, like `abs x 16384`, `rel y -5`, or `btn left down`
+	pub fn pointer_events(&self) -> Vec<String> {
+		self.state().pointer.clone()
+	}
+
+	/// Every command received so far, across all connections
+	pub fn received(&self) -> Vec<Received> {
+		self.state().received.clone()
+	}
+
+	/// Returns the driver of each device in the peripheral tree
+	pub fn devices(&self) -> BTreeMap<String, String> {
+		self.state().devices.iter()
+			.map(|(id, dev)| (id.clone(), dev.driver.clone()))
+			.collect()
+	}
+
+	pub fn device_properties(&self, id: &str) -> Option<Dictionary> {
+		self.state().devices.get(id).map(|dev| dev.properties.clone())
+	}
+
+	/// Returns the type of each user-created object
+	pub fn objects(&self) -> BTreeMap<String, String> {
+		self.state().objects.iter()
+			.map(|(id, obj)| (id.clone(), obj.driver.clone()))
+			.collect()
+	}
+
+	pub fn object_properties(&self, id: &str) -> Option<Dictionary> {
+		self.state().objects.get(id).map(|obj| obj.properties.clone())
+	}
+
+	pub fn running(&self) -> bool {
+		self.state().running
+	}
+
+	/// Sends an event to every connected client
+	pub fn emit(&self, name: &str, data: Option<Any>) {
+		self.state().emit(name, data)
+	}
+
+	/// Listens on a unix socket, serving every client that connects until the task is aborted
+	pub fn listen<P: AsRef<Path>>(&self, path: P) -> io::Result<JoinHandle<()>> {
+		let listener = UnixListener::bind(path)?;
+		let mock = self.clone();
+		Ok(tokio::spawn(async move {
+			loop {
+				let stream = match listener.accept().await {
+					Ok((stream, _)) => stream,
+					Err(e) => {
+						log::warn!("mock QMP failed to accept: {}", e);
+						break
+					},
+				};
+				let mock = mock.clone();
+				tokio::spawn(async move {
+					if let Err(e) = mock.serve(stream).await {
+						log::warn!("mock QMP session failed: {}", e);
+					}
+				});
+			}
+		}))
+	}
+
+	/// Serves a single client until it disconnects or sends `quit`
+	pub async fn serve<S: AsyncRead + AsyncWrite>(&self, stream: S) -> io::Result<()> {
+		let (sender, outgoing) = mpsc::unbounded_channel();
+		let _ = sender.send(Outgoing::Message(serde_json::json!({
+			"QMP": {
+				"version": {
+					"qemu": { "major": 8, "minor": 0, "micro": 0 },
+					"package": "",
+				},
+				"capabilities": ["oob"],
+			},
+		})));
+		let mut negotiated = false;
+		serve_lines(stream, outgoing, |message| {
+			let (received, id) = match message.map_err(|e| Reply::error(format!("JSON parse error, {}", e))).and_then(parse_execute) {
+				Ok(res) => res,
+				Err(reply) => {
+					reply.send(&sender, None);
+					return Handled::Continue
+				},
+			};
+			let mut state = self.state();
+			state.received.push(received.clone());
+			let (reply, handled) = match (negotiated, &received.command[..]) {
+				(false, "qmp_capabilities") => {
+					negotiated = true;
+					state.sessions.push(sender.clone());
+					(Reply::empty(), Handled::Continue)
+				},
+				(false, _) => (Reply::Error(ErrorClass::CommandNotFound,
+					"Expecting capabilities negotiation with 'qmp_capabilities'".into()
+				), Handled::Continue),
+				(true, "qmp_capabilities") => (Reply::Error(ErrorClass::CommandNotFound,
+					"Capabilities negotiation is already complete, command ignored".into()
+				), Handled::Continue),
+				(true, _) => state.execute(&received),
+			};
+			reply.send(&sender, id.as_ref());
+			for (id, delay) in state.pending_unplugs.drain(..) {
+				let mock = self.clone();
+				tokio::spawn(async move {
+					sleep(delay).await;
+					mock.state().unplug(&id);
+				});
+			}
+			for (id, delay) in state.pending_trays.drain(..) {
+				let mock = self.clone();
+				tokio::spawn(async move {
+					sleep(delay).await;
+					mock.state().move_tray(&id, true);
+				});
+			}
+			for id in state.pending_jobs.drain(..) {
+				let mock = self.clone();
+				tokio::spawn(async move {
+					mock.state().run_job(&id);
+				});
+			}
+			if let Handled::Close = handled {
+				state.emit("SHUTDOWN", Some(serde_json::json!({ "guest": false, "reason": "host-qmp-quit" })));
+			}
+			handled
+		}).await?;
+		self.state().sessions.retain(|s| !s.same_channel(&sender));
+		Ok(())
+	}
+}
+
+impl State {
+	fn emit(&mut self, name: &str, data: Option<Any>) {
+		let event = event(name, data);
+		self.sessions.retain(|s| s.send(Outgoing::Message(event.clone())).is_ok());
+	}
+
+	fn execute(&mut self, received: &Received) -> (Reply, Handled) {
+		if let Some(reply) = self.replies.take(&received.command) {
+			return (reply, Handled::Continue)
+		}
+
+		let reply = match &received.command[..] {
+			"query-name" => match &self.name {
+				Some(name) => serde_json::json!({ "name": name }).into(),
+				None => Reply::empty(),
+			},
+			"query-uuid" => serde_json::json!({ "UUID": "00000000-0000-0000-0000-000000000000" }).into(),
+			"query-status" => serde_json::json!({
+				"running": self.running,
+				"singlestep": false,
+				"status": if self.running { "running" } else { "paused" },
+			}).into(),
+			"stop" => {
+				if self.running {
+					self.running = false;
+					self.emit("STOP", None);
+				}
+				Reply::empty()
+			},
+			"cont" => {
+				if !self.running {
+					self.running = true;
+					self.emit("RESUME", None);
+				}
+				Reply::empty()
+			},
+			"quit" => return (Reply::empty(), Handled::Close),
+			"query-cpus-fast" => Any::Array((0..self.cpus)
+				.map(|i| serde_json::json!({
+					"cpu-index": i,
+					"qom-path": format!("/machine/unattached/device[{}]", i),
+					"thread-id": 1000 + i,
+					"target": "x86_64",
+				}))
+				.collect()
+			).into(),
+			"human-monitor-command" => Reply::Return("".into()),
+			"device-list-properties" | "qom-list-properties" => self.list_properties(received),
+			"device_add" => self.device_add(received),
+			"device_del" => self.device_del(received),
+			"object-add" => self.object_add(received),
+			"object-del" => match received.str("id") {
+				Some(id) if self.objects.remove(id).is_some() => Reply::empty(),
+				Some(id) => Reply::error(format!("object '{}' not found", id)),
+				None => missing_parameter("id"),
+			},
+			"qom-list" => self.qom_list(received),
+			"qom-get" => self.qom_get(received),
+			"qom-set" => self.qom_set(received),
+			"query-pci" => self.query_pci(),
+			"query-block" => self.query_block(),
+			"query-named-block-nodes" => self.query_named_block_nodes(),
+			"blockdev-add" => self.blockdev_add(received),
+			"blockdev-del" => self.blockdev_del(received),
+			"snapshot-save" | "snapshot-load" | "snapshot-delete" => self.snapshot_job(received),
+			"query-jobs" => self.query_jobs(),
+			"migrate-set-capabilities" => self.migrate_set_capabilities(received),
+			"migrate-set-parameters" => {
+				self.migrate_parameters.extend(received.arguments.clone());
+				Reply::empty()
+			},
+			"migrate" => self.migrate(received, false),
+			"migrate-incoming" => self.migrate(received, true),
+			"query-migrate" => self.query_migrate(),
+			"migrate_cancel" => {
+				if let Some(migration) = &self.migration {
+					if matches!(migration.status, "setup" | "active" | "postcopy-active") {
+						self.migration_status("cancelling");
+					}
+				}
+				Reply::empty()
+			},
+			"migrate-start-postcopy" => match (&mut self.migration, self.migrate_capabilities.get("postcopy-ram")) {
+				(_, None | Some(false)) => Reply::error("Enable postcopy with migrate_set_capability before the start of migration"),
+				(None, _) => Reply::error("Postcopy must be started after migration has been started"),
+				(Some(migration), _) => {
+					migration.postcopy = true;
+					Reply::empty()
+				},
+			},
+			"job-dismiss" => self.job_dismiss(received),
+			"job-cancel" => self.job_cancel(received),
+			"blockdev-open-tray" => self.drive(received)
+				.and_then(|id| self.open_tray(&id, received.bool("force"), false))
+				.map_or_else(|e| e, |_opened| Reply::empty()),
+			"blockdev-close-tray" => match self.drive(received) {
+				Ok(id) => {
+					self.move_tray(&id, false);
+					Reply::empty()
+				},
+				Err(reply) => reply,
+			},
+			"blockdev-remove-medium" => self.drive(received)
+				.and_then(|id| self.remove_medium(&id))
+				.map_or_else(|e| e, |()| Reply::empty()),
+			"blockdev-insert-medium" => self.insert_medium(received),
+			"blockdev-change-medium" => self.change_medium(received),
+			"eject" => self.drive(received)
+				.and_then(|id| self.open_tray(&id, received.bool("force"), true).map(|_| id))
+				.and_then(|id| self.remove_medium(&id))
+				.map_or_else(|e| e, |()| Reply::empty()),
+			"screendump" => self.screendump(received),
+			"send-key" => self.send_key(received),
+			"input-send-event" => self.input_send_event(received),
+			"query-qmp-schema" => schema(self.png).into(),
+			command => Reply::Error(ErrorClass::CommandNotFound, format!("The command {} has not been found", command)),
+		};
+		(reply, Handled::Continue)
+	}
+
+}
+
+/// The commands that the mock implements and their arguments, with optional ones marked by `*` as in QAPI
+const COMMANDS: &[(&str, &[(&str, &str)])] = &[
+	("qmp_capabilities", &[]),
+	("query-qmp-schema", &[]),
+	("query-name", &[]),
+	("query-uuid", &[]),
+	("query-status", &[]),
+	("stop", &[]),
+	("cont", &[]),
+	("quit", &[]),
+	("query-cpus-fast", &[]),
+	("human-monitor-command", &[("command-line", "str"), ("*cpu-index", "int")]),
+	("device-list-properties", &[("typename", "str")]),
+	("qom-list-properties", &[("typename", "str")]),
+	("device_add", &[("driver", "str"), ("*id", "str"), ("*bus", "str")]),
+	("device_del", &[("id", "str")]),
+	("object-add", &[("qom-type", "str"), ("id", "str")]),
+	("object-del", &[("id", "str")]),
+	("qom-list", &[("path", "str")]),
+	("qom-get", &[("path", "str"), ("property", "str")]),
+	("qom-set", &[("path", "str"), ("property", "str"), ("value", "any")]),
+	("query-pci", &[]),
+	("query-block", &[]),
+	("query-named-block-nodes", &[("*flat", "bool")]),
+	("blockdev-add", &[("driver", "str"), ("node-name", "str"), ("*filename", "str"), ("*file", "str"), ("*read-only", "bool")]),
+	("blockdev-del", &[("node-name", "str")]),
+	("snapshot-save", &[("job-id", "str"), ("tag", "str"), ("vmstate", "str"), ("devices", "any")]),
+	("snapshot-load", &[("job-id", "str"), ("tag", "str"), ("vmstate", "str"), ("devices", "any")]),
+	("snapshot-delete", &[("job-id", "str"), ("tag", "str"), ("devices", "any")]),
+	("query-jobs", &[]),
+	("job-dismiss", &[("id", "str")]),
+	("job-cancel", &[("id", "str")]),
+	("migrate-set-capabilities", &[("capabilities", "any")]),
+	("migrate-set-parameters", &[("*multifd-channels", "int"), ("*max-bandwidth", "int"), ("*downtime-limit", "int")]),
+	("migrate", &[("uri", "str")]),
+	("migrate-incoming", &[("uri", "str")]),
+	("query-migrate", &[]),
+	("migrate_cancel", &[]),
+	("migrate-start-postcopy", &[]),
+	("blockdev-open-tray", &[("*device", "str"), ("*id", "str"), ("*force", "bool")]),
+	("blockdev-close-tray", &[("*device", "str"), ("*id", "str")]),
+	("blockdev-remove-medium", &[("id", "str")]),
+	("blockdev-insert-medium", &[("id", "str"), ("node-name", "str")]),
+	("blockdev-change-medium", &[("*device", "str"), ("*id", "str"), ("filename", "str"), ("*format", "str"), ("*force", "bool"), ("*read-only-mode", "str")]),
+	("eject", &[("*device", "str"), ("*id", "str"), ("*force", "bool")]),
+	("send-key", &[("keys", "any"), ("*hold-time", "int")]),
+	("input-send-event", &[("events", "any"), ("*device", "str"), ("*head", "int")]),
+	("screendump", &[("filename", "str"), ("*device", "str"), ("*head", "int"), ("*format", "ImageFormat")]),
+];
+
+/// `query-qmp-schema` for `COMMANDS`, with argument types named by number like QEMU does
+///
+/// `ImageFormat` only includes `png` if `png` is set, since QEMU leaves it out when built without libpng.
+fn schema(png: bool) -> Any {
+	let builtins = [("str", "string"), ("int", "int"), ("bool", "boolean"), ("any", "value")].into_iter()
+		.map(|(name, json)| serde_json::json!({ "name": name, "meta-type": "builtin", "json-type": json }));
+	let formats: Vec<Any> = ["ppm", "png"].into_iter()
+		.filter(|&format| png || format != "png")
+		.map(|format| serde_json::json!({ "name": format }))
+		.collect();
+	let enums = [serde_json::json!({ "name": "ImageFormat", "meta-type": "enum", "members": formats })];
+	let commands = COMMANDS.iter().enumerate().flat_map(|(i, (command, args))| {
+		let members: Vec<Any> = args.iter().map(|(name, ty)| match name.strip_prefix('*') {
+			Some(name) => serde_json::json!({ "name": name, "type": ty, "default": null }),
+			None => serde_json::json!({ "name": name, "type": ty }),
+		}).collect();
+		[
+			serde_json::json!({ "name": i.to_string(), "meta-type": "object", "members": members }),
+			serde_json::json!({ "name": command, "meta-type": "command", "arg-type": i.to_string(), "ret-type": "any" }),
+		]
+	});
+	Any::Array(builtins.chain(enums).chain(commands).collect())
+}
+
+fn missing_parameter(name: &str) -> Reply {
+	Reply::error(format!("Parameter '{}' is missing", name))
+}
+
+fn device_not_found(path: &str) -> Reply {
+	Reply::Error(ErrorClass::DeviceNotFound, format!("Device '{}' not found", path))
+}
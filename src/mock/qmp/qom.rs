@@ -0,0 +1,155 @@
+use qapi::Any;
+use crate::mock::{Reply, Received};
+use super::{State, Device, missing_parameter, device_not_found};
+
+impl State {
+	pub(super) fn list_properties(&self, received: &Received) -> Reply {
+		let typename = match received.str("typename") {
+			Some(typename) => typename,
+			None => return missing_parameter("typename"),
+		};
+		let props = self.properties.get(typename).map(|p| &p[..]).unwrap_or_default();
+		Any::Array(props.iter()
+			.map(|(name, ty)| serde_json::json!({ "name": name, "type": ty }))
+			.collect()
+		).into()
+	}
+
+	pub(super) fn object_add(&mut self, received: &Received) -> Reply {
+		let mut properties = received.arguments.clone();
+		let qom_type = match properties.remove("qom-type") {
+			Some(Any::String(ty)) => ty,
+			_ => return missing_parameter("qom-type"),
+		};
+		let id = match properties.remove("id") {
+			Some(Any::String(id)) => id,
+			_ => return missing_parameter("id"),
+		};
+		if self.objects.contains_key(&id) {
+			return Reply::error(format!("attempt to add duplicate property '{}' to object (type 'container')", id))
+		}
+		self.objects.insert(id, Device {
+			driver: qom_type,
+			bus: None,
+			properties,
+		});
+		Reply::empty()
+	}
+
+	fn qom_children(&self, path: &str) -> Option<Vec<(String, String)>> {
+		let child = |(id, dev): (&String, &Device)| (id.clone(), format!("child<{}>", dev.driver));
+		let named = |anon: bool| self.devices.iter()
+			.filter(move |(id, _)| id.starts_with("device[") == anon);
+		let container = |name: &str| (name.to_owned(), "child<container>".to_owned());
+		Some(match path.trim_end_matches('/') {
+			"" => vec![container("machine"), container("objects")],
+			"/machine" => vec![container("peripheral"), container("peripheral-anon")],
+			"/machine/peripheral" => named(false).map(child).collect(),
+			"/machine/peripheral-anon" => named(true).map(child).collect(),
+			"/objects" => self.objects.iter().map(child).collect(),
+			path => {
+				let dev = self.lookup(path)?;
+				dev.properties.iter()
+					.map(|(name, value)| (name.clone(), match value {
+						Any::Bool(..) => "bool",
+						Any::Number(..) => "int",
+						_ => "str",
+					}.into()))
+					.chain(Some(("realized".into(), "bool".into())))
+					.chain(Some(("parent_bus".into(), "link<bus>".into())).filter(|_| path.starts_with("/machine/")))
+					.collect()
+			},
+		})
+	}
+
+	fn lookup(&self, path: &str) -> Option<&Device> {
+		let (parent, id) = path.rsplit_once('/')?;
+		match parent {
+			"/machine/peripheral" | "/machine/peripheral-anon" => self.devices.get(id),
+			"/objects" => self.objects.get(id),
+			_ => None,
+		}
+	}
+
+	fn lookup_mut(&mut self, path: &str) -> Option<&mut Device> {
+		let (parent, id) = path.rsplit_once('/')?;
+		match parent {
+			"/machine/peripheral" | "/machine/peripheral-anon" => self.devices.get_mut(id),
+			"/objects" => self.objects.get_mut(id),
+			_ => None,
+		}
+	}
+
+	pub(super) fn qom_list(&self, received: &Received) -> Reply {
+		let path = match received.str("path") {
+			Some(path) => path,
+			None => return missing_parameter("path"),
+		};
+		match self.qom_children(path) {
+			Some(children) => Any::Array(Some(("type".to_owned(), "string".to_owned())).into_iter()
+				.chain(children)
+				.map(|(name, ty)| serde_json::json!({ "name": name, "type": ty }))
+				.collect()
+			).into(),
+			None => device_not_found(path),
+		}
+	}
+
+	pub(super) fn qom_get(&self, received: &Received) -> Reply {
+		let (path, property) = match (received.str("path"), received.str("property")) {
+			(Some(path), Some(property)) => (path, property),
+			(None, _) => return missing_parameter("path"),
+			(_, None) => return missing_parameter("property"),
+		};
+		let path = path.trim_end_matches('/');
+		if property == "type" && matches!(path, "" | "/machine" | "/machine/peripheral" | "/machine/peripheral-anon" | "/objects") {
+			return Reply::Return("container".into())
+		}
+		let dev = match self.lookup(path) {
+			Some(dev) => dev,
+			None => return device_not_found(path),
+		};
+		match property {
+			"type" => Reply::Return(dev.driver.clone().into()),
+			"realized" => Reply::Return(true.into()),
+			"parent_bus" if path.starts_with("/machine/") => Reply::Return(match (&dev.bus, dev.properties.contains_key("addr")) {
+				(Some(bus), _) => format!("/machine/i440fx/{}", bus),
+				(None, true) => "/machine/i440fx/pci.0".into(),
+				(None, false) => "".into(),
+			}.into()),
+			property => match dev.properties.get(property) {
+				Some(value) => Reply::Return(value.clone()),
+				None => Reply::error(format!("Property '{}.{}' not found", dev.driver, property)),
+			},
+		}
+	}
+
+	pub(super) fn qom_set(&mut self, received: &Received) -> Reply {
+		let (path, property) = match (received.str("path"), received.str("property")) {
+			(Some(path), Some(property)) => (path, property),
+			(None, _) => return missing_parameter("path"),
+			(_, None) => return missing_parameter("property"),
+		};
+		let value = match received.arguments.get("value") {
+			Some(value) => value.clone(),
+			None => return missing_parameter("value"),
+		};
+		let dev = match self.lookup_mut(path.trim_end_matches('/')) {
+			Some(dev) => dev,
+			None => return device_not_found(path),
+		};
+		match dev.properties.get_mut(property) {
+			Some(prop) if prop.is_boolean() != value.is_boolean() || prop.is_number() != value.is_number() =>
+				Reply::error(format!("Invalid parameter type for '{}', expected: {}", property, match prop {
+					Any::Bool(..) => "boolean",
+					Any::Number(..) => "integer",
+					_ => "string",
+				})),
+			Some(prop) => {
+				*prop = value;
+				Reply::empty()
+			},
+			None => Reply::error(format!("Property '{}.{}' not found", dev.driver, property)),
+		}
+	}
+}
@@ -0,0 +1,57 @@
+use crate::mock::{Reply, Received};
+use super::{State, missing_parameter, device_not_found};
+
+impl State {
+	/// Writes the screen to a file, with a gradient that shifts each time
+	pub(super) fn screendump(&mut self, received: &Received) -> Reply {
+		let filename = match received.str("filename") {
+			Some(filename) => filename,
+			None => return missing_parameter("filename"),
+		};
+		let png = match received.str("format") {
+			None | Some("ppm") => false,
+			Some("png") if self.png => true,
+			Some(format) => return Reply::error(format!("Parameter 'format' does not accept value '{}'", format)),
+		};
+		match (received.str("device"), received.arguments.get("head").and_then(|h| h.as_u64())) {
+			(None, Some(_)) => return Reply::error("'head' must be specified together with 'device'"),
+			(Some(device), _) if !self.devices.contains_key(device) => return device_not_found(device),
+			(Some(device), Some(head)) if head > 0 => return Reply::error(format!("Device '{}' (head {}) is not bound to a QemuConsole", device, head)),
+			_ => (),
+		}
+		let (width, height) = match self.screen {
+			Some(screen) => screen,
+			None => return Reply::error("There is no QemuConsole I can screendump from."),
+		};
+
+		self.screendumps += 1;
+		let shade = (self.screendumps * 40 % 256) as u8;
+		let pixels: Vec<u8> = (0..height).flat_map(|y| (0..width).flat_map(move |x| [
+			(x * 255 / width.max(1)) as u8,
+			(y * 255 / height.max(1)) as u8,
+			shade,
+		])).collect();
+		let data = match png {
+			false => {
+				let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+				data.extend(pixels);
+				data
+			},
+			true => {
+				let mut data = Vec::new();
+				let mut encoder = png::Encoder::new(&mut data, width, height);
+				encoder.set_color(png::ColorType::Rgb);
+				encoder.set_depth(png::BitDepth::Eight);
+				let written = encoder.write_header().and_then(|mut writer| writer.write_image_data(&pixels));
+				if let Err(e) = written {
+					return Reply::error(format!("failed to encode PNG: {}", e))
+				}
+				data
+			},
+		};
+		match std::fs::write(filename, data) {
+			Ok(()) => Reply::empty(),
+			Err(e) => Reply::error(format!("failed to open file '{}': {}", filename, e)),
+		}
+	}
+}
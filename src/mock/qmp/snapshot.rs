@@ -0,0 +1,167 @@
+use qapi::Any;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::mock::{Reply, Received};
+use super::{State, Snapshot, JobAction, Job, missing_parameter};
+
+impl State {
+	pub(super) fn snapshot_job(&mut self, received: &Received) -> Reply {
+		let arguments = (received.str("job-id"), received.str("tag"), received.arguments.get("devices").and_then(|d| d.as_array()));
+		let (id, tag, devices) = match arguments {
+			(Some(id), Some(tag), Some(devices)) => (id.to_owned(), tag.to_owned(), devices.iter()
+				.filter_map(|d| d.as_str().map(ToOwned::to_owned))
+				.collect()
+			),
+			(None, ..) => return missing_parameter("job-id"),
+			(_, None, _) => return missing_parameter("tag"),
+			(.., None) => return missing_parameter("devices"),
+		};
+		let vmstate = received.str("vmstate").map(ToOwned::to_owned);
+		let action = match (&received.command[..], vmstate) {
+			("snapshot-delete", _) => JobAction::Delete { tag, devices },
+			(_, None) => return missing_parameter("vmstate"),
+			("snapshot-save", Some(vmstate)) => JobAction::Save { tag, vmstate, devices },
+			(_, Some(vmstate)) => JobAction::Load { tag, vmstate, devices },
+		};
+		if self.jobs.contains_key(&id) {
+			return Reply::error(format!("Job ID '{}' already in use", id))
+		}
+		self.jobs.insert(id.clone(), Job {
+			action,
+			status: "created",
+			error: None,
+		});
+		self.job_status(&id, "created");
+		self.pending_jobs.push(id);
+		Reply::empty()
+	}
+
+	fn job_status(&mut self, id: &str, status: &'static str) {
+		if let Some(job) = self.jobs.get_mut(id) {
+			job.status = status;
+		}
+		self.emit("JOB_STATUS_CHANGE", Some(serde_json::json!({ "id": id, "status": status })));
+	}
+
+	pub(super) fn run_job(&mut self, id: &str) {
+		let action = match self.jobs.get(id) {
+			Some(job) => job.action.clone(),
+			None => return,
+		};
+		self.job_status(id, "running");
+		if self.stall_jobs {
+			return
+		}
+		let res = match action {
+			JobAction::Save { tag, vmstate, devices } => self.snapshot_save(&tag, &vmstate, &devices),
+			JobAction::Load { tag, vmstate, devices } => self.snapshot_load(&tag, &vmstate, &devices),
+			JobAction::Delete { tag, devices } => {
+				for device in &devices {
+					if let Some(node) = self.nodes.get_mut(device) {
+						node.snapshots.retain(|s| s.tag != tag);
+					}
+				}
+				Ok(())
+			},
+		};
+		let statuses: &[_] = match &res {
+			Ok(()) => &["waiting", "pending", "concluded"],
+			Err(_) => &["aborting", "concluded"],
+		};
+		if let Some(job) = self.jobs.get_mut(id) {
+			job.error = res.err();
+		}
+		for status in statuses {
+			self.job_status(id, status);
+		}
+	}
+
+	fn snapshot_save(&mut self, tag: &str, vmstate: &str, devices: &[String]) -> Result<(), String> {
+		if let Some(device) = devices.iter().find(|d| !self.nodes.contains_key(&d[..])) {
+			return Err(format!("No block device node '{}'", device))
+		}
+		if !devices.iter().any(|d| d == vmstate) {
+			return Err(format!("vmstate block device '{}' does not exist", vmstate))
+		}
+		if devices.iter().any(|d| self.nodes[d].snapshots.iter().any(|s| s.tag == tag)) {
+			return Err(format!("Snapshot '{}' already exists in one or more devices", tag))
+		}
+		let date = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+		for device in devices {
+			let node = self.nodes.get_mut(device).unwrap();
+			node.snapshots.push(Snapshot {
+				id: node.snapshots.iter().map(|s| s.id).max().unwrap_or(0) + 1,
+				tag: tag.into(),
+				vm_state_size: if device == vmstate { 1 << 20 } else { 0 },
+				date,
+			});
+		}
+		Ok(())
+	}
+
+	fn snapshot_load(&mut self, tag: &str, vmstate: &str, devices: &[String]) -> Result<(), String> {
+		let snapshot = |device: &str| self.nodes.get(device)
+			.and_then(|node| node.snapshots.iter().find(|s| s.tag == tag));
+		if devices.iter().any(|d| snapshot(d).is_none()) {
+			return Err(format!("Snapshot '{}' does not exist in one or more devices", tag))
+		}
+		match snapshot(vmstate) {
+			Some(s) if s.vm_state_size > 0 => Ok(()),
+			_ => Err(format!("Snapshot '{}' does not exist in vmstate device '{}'", tag, vmstate)),
+		}
+	}
+
+	pub(super) fn query_jobs(&self) -> Reply {
+		Any::Array(self.jobs.iter().map(|(id, job)| {
+			let ty = match job.action {
+				JobAction::Save { .. } => "snapshot-save",
+				JobAction::Load { .. } => "snapshot-load",
+				JobAction::Delete { .. } => "snapshot-delete",
+			};
+			let done = job.status == "concluded";
+			let mut info = serde_json::json!({
+				"id": id,
+				"type": ty,
+				"status": job.status,
+				"current-progress": if done { 1 } else { 0 },
+				"total-progress": 1,
+			});
+			if let Some(error) = &job.error {
+				info["error"] = error.clone().into();
+			}
+			info
+		}).collect()).into()
+	}
+
+	pub(super) fn job_cancel(&mut self, received: &Received) -> Reply {
+		let id = match received.str("id") {
+			Some(id) => id,
+			None => return missing_parameter("id"),
+		};
+		match self.jobs.get_mut(id) {
+			None => Reply::error(format!("Job not found: {}", id)),
+			Some(job) if job.status == "concluded" => Reply::error(format!("Job '{}' in state 'concluded' cannot accept command verb 'cancel'", id)),
+			Some(job) => {
+				job.error = Some("Operation cancelled".into());
+				self.job_status(id, "aborting");
+				self.job_status(id, "concluded");
+				Reply::empty()
+			},
+		}
+	}
+
+	pub(super) fn job_dismiss(&mut self, received: &Received) -> Reply {
+		let id = match received.str("id") {
+			Some(id) => id,
+			None => return missing_parameter("id"),
+		};
+		match self.jobs.get(id).map(|job| job.status) {
+			None => Reply::error(format!("Job not found: {}", id)),
+			Some("concluded") => {
+				self.jobs.remove(id);
+				self.emit("JOB_STATUS_CHANGE", Some(serde_json::json!({ "id": id, "status": "null" })));
+				Reply::empty()
+			},
+			Some(status) => Reply::error(format!("Job '{}' in state '{}' cannot accept command verb 'dismiss'", id, status)),
+		}
+	}
+}
@@ -0,0 +1,231 @@
+use anyhow::{Result, Error, format_err};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::fmt;
+
+/// How command results are printed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+	/// plain text and aligned tables
+	Human,
+	Json,
+	Yaml,
+}
+
+impl OutputFormat {
+	pub fn write<W: Write, T: Serialize + ?Sized>(self, mut w: W, value: &T) -> Result<()> {
+		match self {
+			OutputFormat::Human => human(&mut w, &serde_json::to_value(value)?)?,
+			OutputFormat::Json => {
+				serde_json::to_writer_pretty(&mut w, value)?;
+				writeln!(w)?;
+			},
+			OutputFormat::Yaml => serde_yaml::to_writer(w, value)?,
+		}
+		Ok(())
+	}
+
+	/// Renders one of a stream of values: JSON is kept to a line each, and YAML is split into documents
+	pub fn write_record<W: Write, T: Serialize + ?Sized>(self, mut w: W, value: &T) -> Result<()> {
+		match self {
+			OutputFormat::Json => {
+				serde_json::to_writer(&mut w, value)?;
+				writeln!(w)?;
+				Ok(())
+			},
+			OutputFormat::Yaml => {
+				writeln!(w, "---")?;
+				self.write(w, value)
+			},
+			OutputFormat::Human => self.write(w, value),
+		}
+	}
+
+	pub fn print<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+		let mut stdout = io::stdout().lock();
+		self.write(&mut stdout, value)?;
+		stdout.flush().map_err(Into::into)
+	}
+
+	/// Prints the empty result of a command that succeeded, which humans don't need to see
+	pub fn print_done(self) -> Result<()> {
+		match self {
+			OutputFormat::Human => Ok(()),
+			_ => self.print(&qapi::Empty { }),
+		}
+	}
+}
+
+impl FromStr for OutputFormat {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		Ok(match s {
+			"human" => OutputFormat::Human,
+			"json" => OutputFormat::Json,
+			"yaml" => OutputFormat::Yaml,
+			_ => return Err(format_err!("unknown output format `{}`, expected human, json, or yaml", s)),
+		})
+	}
+}
+
+impl fmt::Display for OutputFormat {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			OutputFormat::Human => "human",
+			OutputFormat::Json => "json",
+			OutputFormat::Yaml => "yaml",
+		})
+	}
+}
+
+/// Renders a value as plain text
+///
+/// Objects become a table of (dotted) keys and values, and lists of objects become a table
+/// with a column per key. Lists of objects within objects are rendered after their parent.
+pub fn human<W: Write>(w: &mut W, value: &qapi::Any) -> io::Result<()> {
+	match value {
+		qapi::Any::Object(object) => human_object(w, object),
+		qapi::Any::Array(list) if is_table(list) => {
+			let records: Vec<_> = list.iter()
+				.filter_map(|v| v.as_object())
+				.map(flatten)
+				.collect();
+			if records.iter().any(|(_, sections)| !sections.is_empty()) {
+				// too nested for a single table, so each gets its own
+				for (i, object) in list.iter().filter_map(|v| v.as_object()).enumerate() {
+					if i > 0 {
+						writeln!(w)?;
+					}
+					human_object(w, object)?;
+				}
+				return Ok(())
+			}
+
+			let mut columns: Vec<&str> = Vec::new();
+			for (key, _) in records.iter().flat_map(|(fields, _)| fields) {
+				if !columns.contains(&&key[..]) {
+					columns.push(key);
+				}
+			}
+			let header = columns.iter().map(|c| c.to_uppercase().into()).collect();
+			let rows = records.iter().map(|(fields, _)| columns.iter()
+				.map(|&c| fields.iter()
+					.find(|(key, _)| key == c)
+					.map(|(_, value)| value.clone())
+					.unwrap_or_else(|| "-".into())
+				)
+				.collect()
+			);
+			table(w, Some(header).into_iter().chain(rows))
+		},
+		qapi::Any::Array(list) => list.iter()
+			.try_for_each(|value| writeln!(w, "{}", scalar(value))),
+		qapi::Any::Null => Ok(()),
+		value => writeln!(w, "{}", scalar(value)),
+	}
+}
+
+type Fields<'a> = Vec<(String, Cow<'a, str>)>;
+type Sections<'a> = Vec<(String, &'a qapi::Any)>;
+
+/// Splits an object into (dotted) scalar fields, and nested lists of objects
+fn flatten(object: &qapi::Dictionary) -> (Fields<'_>, Sections<'_>) {
+	fn flatten_into<'a>(prefix: &str, object: &'a qapi::Dictionary, fields: &mut Fields<'a>, sections: &mut Sections<'a>) {
+		for (key, value) in object {
+			let key = format!("{}{}", prefix, key);
+			match value {
+				qapi::Any::Object(object) if !object.is_empty() => flatten_into(&format!("{}.", key), object, fields, sections),
+				qapi::Any::Array(list) if is_table(list) => sections.push((key, value)),
+				value => fields.push((key, scalar(value))),
+			}
+		}
+	}
+
+	let mut fields = Vec::new();
+	let mut sections = Vec::new();
+	flatten_into("", object, &mut fields, &mut sections);
+	(fields, sections)
+}
+
+fn human_object<W: Write>(w: &mut W, object: &qapi::Dictionary) -> io::Result<()> {
+	let (fields, sections) = flatten(object);
+	let mut separate = !fields.is_empty();
+	table(w, fields.into_iter().map(|(key, value)| vec![key.into(), value]))?;
+	for (key, value) in sections {
+		if separate {
+			writeln!(w)?;
+		}
+		separate = true;
+		writeln!(w, "{}:", key)?;
+		human(w, value)?;
+	}
+	Ok(())
+}
+
+fn is_table(list: &[qapi::Any]) -> bool {
+	!list.is_empty() && list.iter().all(|v| v.is_object())
+}
+
+/// A value that fits in a single table cell
+fn scalar(value: &qapi::Any) -> Cow<'_, str> {
+	match value {
+		qapi::Any::String(s) => s.into(),
+		qapi::Any::Null => "-".into(),
+		qapi::Any::Array(list) if !list.iter().any(|v| v.is_object() || v.is_array()) => list.iter()
+			.map(scalar)
+			.collect::<Vec<_>>()
+			.join(", ")
+			.into(),
+		value => value.to_string().into(),
+	}
+}
+
+/// Formats a byte count with a binary unit, like `1.5 GiB`
+pub fn format_size(bytes: u64) -> String {
+	const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+	if bytes < 1024 {
+		return format!("{} B", bytes)
+	}
+	let mut size = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Prints a table with headers in the given order, unlike `human` which sorts columns by name
+pub fn print_table<H: AsRef<str>, R: IntoIterator<Item=Vec<String>>>(header: &[H], rows: R) -> Result<()> {
+	let header = header.iter().map(|h| h.as_ref().to_uppercase().into()).collect();
+	let rows = rows.into_iter().map(|row| row.into_iter().map(Cow::Owned).collect());
+	let mut stdout = io::stdout().lock();
+	table(&mut stdout, Some(header).into_iter().chain(rows))?;
+	stdout.flush().map_err(Into::into)
+}
+
+fn table<'a, W: Write, R: IntoIterator<Item=Vec<Cow<'a, str>>>>(w: &mut W, rows: R) -> io::Result<()> {
+	let rows: Vec<_> = rows.into_iter().collect();
+	let mut widths: Vec<usize> = Vec::new();
+	for row in &rows {
+		for (i, cell) in row.iter().enumerate() {
+			let width = cell.chars().count();
+			match widths.get_mut(i) {
+				Some(w) => *w = (*w).max(width),
+				None => widths.push(width),
+			}
+		}
+	}
+
+	for row in &rows {
+		let mut line = String::new();
+		for (cell, width) in row.iter().zip(&widths) {
+			line.push_str(&format!("{:width$}  ", cell, width = width));
+		}
+		writeln!(w, "{}", line.trim_end())?;
+	}
+	Ok(())
+}
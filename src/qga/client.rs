@@ -0,0 +1,68 @@
+use anyhow::{Result, format_err};
+use futures::Future;
+use qapi::qga::{self, QgaCommand};
+use crate::Connection;
+use super::{Service, QgaFile};
+
+/// A connection to a QEMU guest agent
+pub struct Client {
+	service: Service,
+}
+
+impl Client {
+	pub fn new(stream: Connection) -> Self {
+		Client {
+			service: Service::new(stream),
+		}
+	}
+
+	pub fn service(&self) -> &Service {
+		&self.service
+	}
+
+	pub fn execute<C: QgaCommand + 'static>(&self, command: C) -> impl Future<Output=qapi::ExecuteResult<C>> {
+		self.service.execute(command)
+	}
+
+	pub async fn guest_sync(&self, sync_value: i32) -> Result<()> {
+		let id = sync_value.into();
+		match self.execute(qga::guest_sync { id }).await? {
+			res if res == id => Ok(()),
+			res => Err(format_err!("QGA sync failed, expected {} but got {}", id, res)),
+		}
+	}
+
+	/// Discards any stale responses left over by a previous client or abandoned command
+	pub async fn sync(&self) -> Result<()> {
+		self.service.sync().await.map_err(Into::into)
+	}
+
+	/// Disconnects from the guest agent
+	///
+	/// Any open `QgaFile`s keep the connection alive until they are dropped.
+	pub async fn close(self) {
+		drop(self.service);
+	}
+
+	pub async fn ping(&self) -> Result<()> {
+		self.execute(qga::guest_ping { }).await?;
+		Ok(())
+	}
+
+	pub async fn info(&self) -> Result<qga::GuestAgentInfo> {
+		self.execute(qga::guest_info { }).await.map_err(Into::into)
+	}
+
+	pub async fn osinfo(&self) -> Result<qga::GuestOSInfo> {
+		self.execute(qga::guest_get_osinfo { }).await.map_err(Into::into)
+	}
+
+	pub async fn shutdown(&self, mode: Option<qga::GuestShutdownMode>) -> Result<()> {
+		self.execute(qga::guest_shutdown { mode }).await?;
+		Ok(())
+	}
+
+	pub async fn file_open<P: Into<String>>(&self, path: P, mode: Option<String>) -> Result<QgaFile> {
+		QgaFile::open(self.service.clone(), path, mode).await.map_err(Into::into)
+	}
+}
@@ -0,0 +1,303 @@
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use qapi::qga;
+use log::{debug, warn};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncSeek, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use super::Service;
+
+type QgaResult<T> = std::result::Result<T, qapi::ExecuteError>;
+
+/// The largest single guest-file-read or guest-file-write issued by `AsyncRead` and `AsyncWrite`
+const CHUNK_SIZE: usize = 0x10000;
+
+/// A file opened inside the guest
+///
+/// The guest handle is closed when dropped, but `close` should be preferred
+/// where possible to observe errors.
+pub struct QgaFile {
+	service: Service,
+	handle: i64,
+	read: Option<BoxFuture<'static, QgaResult<qga::GuestFileRead>>>,
+	read_buf: BytesMut,
+	/// a guest-file-write in flight, or one that `poll_flush` completed before `poll_write` could report it
+	write: Option<BoxFuture<'static, QgaResult<qga::GuestFileWrite>>>,
+	flush: Option<BoxFuture<'static, QgaResult<qapi::Empty>>>,
+	seek: Option<BoxFuture<'static, QgaResult<qga::GuestFileSeek>>>,
+	/// read data that was buffered when the guest position was queried
+	seek_buffered: u64,
+	closed: bool,
+}
+
+impl QgaFile {
+	pub async fn open<P: Into<String>>(service: Service, path: P, mode: Option<String>) -> QgaResult<QgaFile> {
+		let path = path.into();
+		debug!("opening guest path {:?}", path);
+		let handle = service.execute(qga::guest_file_open {
+			path,
+			mode,
+		}).await?;
+		debug!("opened guest file:{}", handle);
+		Ok(QgaFile {
+			service,
+			handle,
+			read: None,
+			read_buf: BytesMut::new(),
+			write: None,
+			flush: None,
+			seek: None,
+			seek_buffered: 0,
+			closed: false,
+		})
+	}
+
+	pub fn handle(&self) -> i64 {
+		self.handle
+	}
+
+	pub async fn close(mut self) -> QgaResult<()> {
+		self.closed = true;
+		let res = self.service.execute(qga::guest_file_close {
+			handle: self.handle,
+		}).await;
+		if let Err(e) = &res {
+			warn!("failed to close guest file: {e}");
+		}
+		res.map(drop)
+	}
+
+	pub async fn seek(&self, whence: qga::QGASeek, offset: i64) -> QgaResult<qga::GuestFileSeek> {
+		let res = self.service.execute(qga::guest_file_seek {
+			handle: self.handle,
+			offset,
+			whence: qga::GuestFileWhence::name(whence),
+		}).await?;
+		debug!("seeked file:{} to {}{}", self.handle, res.position, if res.eof { " (EOF)" } else { "" });
+		Ok(res)
+	}
+
+	pub async fn write_buf<B: Into<Vec<u8>>>(&self, buf: B) -> QgaResult<qga::GuestFileWrite> {
+		let buf = buf.into();
+		self.service.execute(qga::guest_file_write {
+			handle: self.handle,
+			count: Some(buf.len() as i64),
+			buf_b64: buf,
+		}).await
+	}
+
+	pub async fn read_buf(&self, len: Option<usize>) -> QgaResult<qga::GuestFileRead> {
+		self.service.execute(qga::guest_file_read {
+			handle: self.handle,
+			count: len.and_then(|len| len.try_into().ok()),
+		}).await
+	}
+
+	pub async fn flush(&self) -> QgaResult<()> {
+		self.service.execute(qga::guest_file_flush {
+			handle: self.handle,
+		}).await.map(drop)
+	}
+
+	pub async fn write_from<R: AsyncRead + Unpin>(&self, mut read: R) -> Result<u64> {
+		let mut buf = BytesMut::with_capacity(0x1000);
+		let mut total = 0u64;
+		loop {
+			match read.read_buf(&mut buf).await? {
+				0 if buf.is_empty() => break,
+				_ => (),
+			}
+			let written = match self.write_buf(&buf[..]).await?.count {
+				w if w <= 0 => return Err(anyhow!("Guest wrote {w} bytes")),
+				w => w as usize,
+			};
+			total = total.saturating_add(written as u64);
+			let _ = buf.split_to(written);
+		}
+		Ok(total)
+	}
+
+	pub async fn read_to<W: AsyncWrite + Unpin>(&self, mut write: W) -> Result<u64> {
+		let mut total = 0u64;
+		loop {
+			let read = self.read_buf(None).await?;
+			write.write_all(&read.buf_b64).await?;
+			total = total.saturating_add(read.count as u64);
+			if read.eof || read.count == 0 {
+				break
+			}
+		}
+		Ok(total)
+	}
+
+	fn busy(&self) -> bool {
+		self.read.is_some() || self.write.is_some() || self.flush.is_some() || self.seek.is_some()
+	}
+
+	/// Completes a flush that was interrupted by a caller such as `tokio::io::copy`
+	///
+	/// The service holds its write lock until a response has been polled, so a new
+	/// command can't be issued until then.
+	fn poll_flushed(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+		if let Some(flush) = &mut self.flush {
+			let res = futures::ready!(flush.poll_unpin(cx));
+			self.flush = None;
+			res.map_err(io_error)?;
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+fn io_error(e: qapi::ExecuteError) -> io::Error {
+	e.into()
+}
+
+/// Only one guest command can be in flight per file, as its future holds the service's write lock
+fn busy_error() -> io::Error {
+	io::Error::new(io::ErrorKind::Other, "guest file operation already in progress")
+}
+
+impl AsyncRead for QgaFile {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		if buf.remaining() == 0 {
+			return Poll::Ready(Ok(()))
+		}
+
+		if this.read_buf.is_empty() {
+			futures::ready!(this.poll_flushed(cx))?;
+			if this.read.is_none() && this.busy() {
+				return Poll::Ready(Err(busy_error()))
+			}
+			let read = this.read.get_or_insert_with(|| this.service.execute(qga::guest_file_read {
+				handle: this.handle,
+				count: Some(buf.remaining().min(CHUNK_SIZE) as i64),
+			}).boxed());
+			let res = futures::ready!(read.poll_unpin(cx));
+			this.read = None;
+			this.read_buf.extend_from_slice(&res.map_err(io_error)?.buf_b64);
+		}
+
+		let len = this.read_buf.len().min(buf.remaining());
+		buf.put_slice(&this.read_buf.split_to(len));
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsyncWrite for QgaFile {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		futures::ready!(this.poll_flushed(cx))?;
+		if this.write.is_none() && this.busy() {
+			return Poll::Ready(Err(busy_error()))
+		}
+
+		// a write already in flight is reported even if the caller retries with a different buffer
+		let write = this.write.get_or_insert_with(|| {
+			let data = buf[..buf.len().min(CHUNK_SIZE)].to_vec();
+			this.service.execute(qga::guest_file_write {
+				handle: this.handle,
+				count: Some(data.len() as i64),
+				buf_b64: data,
+			}).boxed()
+		});
+		let res = futures::ready!(write.poll_unpin(cx));
+		this.write = None;
+		match res.map_err(io_error)?.count {
+			count if count <= 0 => Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+			count => Poll::Ready(Ok(count as usize)),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		if let Some(write) = &mut this.write {
+			// the command has to complete before the flush can be sent, but its result is left for `poll_write`
+			let res = futures::ready!(write.poll_unpin(cx));
+			this.write = Some(futures::future::ready(res).boxed());
+		}
+
+		let flush = this.flush.get_or_insert_with(|| this.service.execute(qga::guest_file_flush {
+			handle: this.handle,
+		}).boxed());
+		let res = futures::ready!(flush.poll_unpin(cx));
+		this.flush = None;
+		Poll::Ready(res.map(drop).map_err(io_error))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		self.poll_flush(cx)
+	}
+}
+
+impl AsyncSeek for QgaFile {
+	fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+		let this = self.get_mut();
+		if this.busy() {
+			return Err(busy_error())
+		}
+
+		let (whence, offset) = match position {
+			SeekFrom::Start(offset) => (qga::QGASeek::set, offset.try_into().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?),
+			// buffered data hasn't been consumed yet, so the guest is ahead of us
+			SeekFrom::Current(offset) => (qga::QGASeek::cur, offset - this.read_buf.len() as i64),
+			SeekFrom::End(offset) => (qga::QGASeek::end, offset),
+		};
+		this.read_buf.clear();
+		this.seek_buffered = 0;
+		this.seek = Some(this.service.execute(qga::guest_file_seek {
+			handle: this.handle,
+			offset,
+			whence: qga::GuestFileWhence::name(whence),
+		}).boxed());
+		Ok(())
+	}
+
+	fn poll_complete(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+		let this = self.get_mut();
+		if this.seek.is_none() && this.busy() {
+			return Poll::Ready(Err(busy_error()))
+		}
+		let seek = match &mut this.seek {
+			Some(seek) => seek,
+			None => {
+				// no seek was started, so just report the current position
+				this.seek_buffered = this.read_buf.len() as u64;
+				this.seek.insert(this.service.execute(qga::guest_file_seek {
+					handle: this.handle,
+					offset: 0,
+					whence: qga::GuestFileWhence::name(qga::QGASeek::cur),
+				}).boxed())
+			},
+		};
+		let res = futures::ready!(seek.poll_unpin(cx));
+		this.seek = None;
+		let res = res.map_err(io_error)?;
+		debug!("seeked file:{} to {}{}", this.handle, res.position, if res.eof { " (EOF)" } else { "" });
+		Poll::Ready(Ok((res.position as u64).saturating_sub(this.seek_buffered)))
+	}
+}
+
+impl Drop for QgaFile {
+	fn drop(&mut self) {
+		if self.closed {
+			return
+		}
+
+		let close = self.service.execute(qga::guest_file_close {
+			handle: self.handle,
+		});
+		let handle = self.handle;
+		match tokio::runtime::Handle::try_current() {
+			Ok(runtime) => drop(runtime.spawn(async move {
+				if let Err(e) = close.await {
+					warn!("failed to close guest file:{handle}: {e}");
+				}
+			})),
+			Err(..) => warn!("guest file:{handle} leaked, no runtime to close it on"),
+		}
+	}
+}
@@ -0,0 +1,7 @@
+mod client;
+mod service;
+mod file;
+
+pub use self::client::Client;
+pub use self::service::Service;
+pub use self::file::QgaFile;
@@ -0,0 +1,155 @@
+use futures::Future;
+use qapi::{Any, Execute, ExecuteResult};
+use qapi::qga::{self, QgaCommand};
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::io;
+use crate::Connection;
+
+/// Sent by the guest agent ahead of a `guest-sync-delimited` response, and resets its parser when received
+const DELIMITER: u8 = 0xff;
+
+/// Executes guest agent commands one at a time
+///
+/// Guest agent responses aren't tagged with an id, so a command that is abandoned before
+/// its response arrives leaves the connection out of step. The next command resynchronizes
+/// with `guest-sync-delimited` first, discarding whatever stale output is left over.
+#[derive(Clone)]
+pub struct Service {
+	inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+	read: BufReader<ReadHalf<Connection>>,
+	write: WriteHalf<Connection>,
+	/// set while a command is in flight, and left set if it was abandoned
+	desynced: bool,
+	sync_id: i64,
+}
+
+impl Service {
+	pub fn new(stream: Connection) -> Self {
+		let (read, write) = tokio::io::split(stream);
+		// sync ids only need to differ from those of any client that came before
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+		let sync_id = (std::process::id() as i64) << 32 | now.subsec_nanos() as i64;
+		Service {
+			inner: Arc::new(Mutex::new(Inner {
+				read: BufReader::new(read),
+				write,
+				desynced: false,
+				sync_id,
+			})),
+		}
+	}
+
+	pub fn execute<C: QgaCommand + 'static>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> + Send + 'static {
+		let inner = self.inner.clone();
+		async move {
+			inner.lock().await.execute(command).await
+		}
+	}
+
+	/// Resynchronizes with the guest agent, discarding anything left over from earlier commands
+	pub async fn sync(&self) -> io::Result<()> {
+		self.inner.lock().await.sync().await
+	}
+}
+
+impl Inner {
+	async fn execute<C: QgaCommand>(&mut self, command: C) -> ExecuteResult<C> {
+		if self.desynced {
+			log::info!("resynchronizing with guest agent after an abandoned command");
+			self.sync().await?;
+		}
+
+		self.desynced = true;
+		self.send(&Execute::<C>::new(command, None)).await?;
+		let res = self.recv().await?;
+		self.desynced = false;
+
+		C::Ok::deserialize(&res?)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+	}
+
+	async fn sync(&mut self) -> io::Result<()> {
+		self.desynced = true;
+		self.sync_id = self.sync_id.wrapping_add(1);
+		let id = self.sync_id;
+
+		self.write.write_all(&[DELIMITER]).await?;
+		self.send(&Execute::<_>::new(qga::guest_sync_delimited { id }, None)).await?;
+		loop {
+			let mut stale = Vec::new();
+			self.read.read_until(DELIMITER, &mut stale).await?;
+			match stale.pop() {
+				Some(DELIMITER) => (),
+				_ => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "guest agent disconnected")),
+			}
+			if !stale.is_empty() {
+				log::debug!("discarding stale guest agent output: {}", String::from_utf8_lossy(&stale).trim_end());
+			}
+
+			match self.recv().await? {
+				Ok(Any::Number(n)) if n.as_i64() == Some(id) => break,
+				res => log::debug!("ignoring stale sync response {:?}", res),
+			}
+		}
+		self.desynced = false;
+
+		Ok(())
+	}
+
+	async fn send<T: Serialize>(&mut self, message: &T) -> io::Result<()> {
+		let mut line = serde_json::to_vec(message)?;
+		line.push(b'\n');
+		self.write.write_all(&line).await
+	}
+
+	async fn recv(&mut self) -> io::Result<Result<Any, qapi::Error>> {
+		let mut line = Vec::new();
+		loop {
+			line.clear();
+			if self.read.read_until(b'\n', &mut line).await? == 0 {
+				return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "guest agent disconnected"))
+			}
+			let start = line.iter().position(|&b| b != DELIMITER).unwrap_or(line.len());
+			let response = &line[start..];
+			if !response.iter().all(u8::is_ascii_whitespace) {
+				break parse_response(response)
+			}
+		}
+	}
+}
+
+fn parse_response(line: &[u8]) -> io::Result<Result<Any, qapi::Error>> {
+	#[derive(Deserialize)]
+	struct ErrorResponse {
+		class: qapi::ErrorClass,
+		desc: String,
+	}
+
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Response {
+		Return {
+			#[serde(rename = "return")]
+			return_: Any,
+		},
+		Error {
+			error: ErrorResponse,
+		},
+	}
+
+	match serde_json::from_slice(line)? {
+		Response::Return { return_ } => Ok(Ok(return_)),
+		Response::Error { error: ErrorResponse { class, desc } } => Ok(Err(qapi::Error {
+			class,
+			desc,
+			id: None,
+		})),
+	}
+}
@@ -0,0 +1,275 @@
+use anyhow::{Result, format_err};
+use futures::{Future, StreamExt, TryFutureExt, future};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep, timeout};
+use qapi::qmp::{self, QmpCommand, QapiCapabilities, Event};
+use qapi::futures::QapiService;
+use std::sync::{Arc, Mutex};
+use crate::{Connection, PropertyTypes};
+use super::{QmpWrite, EventFilter, Events, Execute, Schema};
+
+pub type Service = QapiService<QmpWrite<Connection>>;
+
+#[derive(Default)]
+struct Subscribers {
+	senders: Vec<mpsc::UnboundedSender<Event>>,
+	closed: bool,
+}
+
+/// A negotiated QMP connection
+///
+/// Responses and events are read by a background task for as long as the client is alive.
+pub struct Client {
+	service: Service,
+	capabilities: QapiCapabilities,
+	subscribers: Arc<Mutex<Subscribers>>,
+	handle: JoinHandle<()>,
+}
+
+impl Client {
+	pub async fn new(stream: Connection) -> Result<Self> {
+		Self::connect(stream, false).await.map(|(client, _)| client)
+	}
+
+	/// Connects along with a subscription that receives every event since negotiation
+	///
+	/// Unlike a later `subscribe`, this can't miss an event that arrives right after connecting.
+	pub async fn with_events(stream: Connection) -> Result<(Self, Events)> {
+		let (client, events) = Self::connect(stream, true).await?;
+		Ok((client, events.unwrap()))
+	}
+
+	async fn connect(stream: Connection, subscribe: bool) -> Result<(Self, Option<Events>)> {
+		let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+		let subscription = match subscribe {
+			true => {
+				let (sender, receiver) = mpsc::unbounded_channel();
+				subscribers.lock().unwrap().senders.push(sender);
+				Some(Events::new(receiver))
+			},
+			false => None,
+		};
+
+		let stream = super::open(stream).await?;
+		let capabilities = stream.capabilities.clone();
+		log::trace!("QEMU QMP Capabilities: {:#?}", capabilities);
+		let (service, mut events) = stream.negotiate().await?.into_parts();
+
+		let _ = events.release();
+		let handle = tokio::spawn({
+			let subscribers = subscribers.clone();
+			async move {
+				while let Some(event) = events.next().await {
+					match event {
+						Ok(e) => {
+							log::debug!("QMP event {:?}", e);
+							subscribers.lock().unwrap().senders
+								.retain(|s| s.send(e.clone()).is_ok())
+						},
+						Err(e) => {
+							log::warn!("stream error: {:?}", e);
+							break
+						},
+					}
+				}
+				let mut subscribers = subscribers.lock().unwrap();
+				subscribers.senders.clear();
+				subscribers.closed = true;
+			}
+		});
+
+		Ok((Client {
+			service,
+			capabilities,
+			subscribers,
+			handle,
+		}, subscription))
+	}
+
+	pub fn capabilities(&self) -> &QapiCapabilities {
+		&self.capabilities
+	}
+
+	pub fn service(&self) -> &Service {
+		&self.service
+	}
+
+	pub fn execute<C: QmpCommand>(&self, command: C) -> impl Future<Output=qapi::ExecuteResult<C>> {
+		self.service.execute(command)
+	}
+
+	/// Executes a command that isn't known until runtime
+	pub fn execute_any<C: Into<String>>(&self, command: C, arguments: Option<qapi::Dictionary>) -> impl Future<Output=qapi::ExecuteResult<Execute>> {
+		self.execute(Execute::new(command, arguments))
+	}
+
+	/// Receives all events that arrive from now on
+	pub fn subscribe(&self) -> Events {
+		let (sender, receiver) = mpsc::unbounded_channel();
+		let mut subscribers = self.subscribers.lock().unwrap();
+		if !subscribers.closed {
+			subscribers.senders.push(sender);
+		}
+		Events::new(receiver)
+	}
+
+	/// Disconnects, waiting briefly for the background task to finish
+	pub async fn close(self) {
+		drop(self.service);
+		match timeout(Duration::from_secs(1), self.handle).await {
+			Err(_elapsed) => log::warn!("timed out waiting for handle to clean up"),
+			Ok(Err(e)) => log::warn!("QMP event task failed: {}", e),
+			Ok(Ok(())) => (),
+		}
+	}
+
+	pub async fn status(&self) -> Result<qmp::StatusInfo> {
+		self.execute(qmp::query_status { }).await.map_err(Into::into)
+	}
+
+	pub async fn stop(&self) -> Result<()> {
+		self.execute(qmp::stop { }).await?;
+		Ok(())
+	}
+
+	pub async fn cont(&self) -> Result<()> {
+		self.execute(qmp::cont { }).await?;
+		Ok(())
+	}
+
+	pub async fn quit(&self) -> Result<()> {
+		self.execute(qmp::quit { }).await?;
+		Ok(())
+	}
+
+	/// Introspects the commands and types that QEMU supports
+	pub async fn schema(&self) -> Result<Schema> {
+		let schema = self.execute_any("query-qmp-schema", None).await?;
+		Schema::from_value(schema)
+	}
+
+	/// Looks up property types to parse arguments with, falling back to guessing on failure
+	///
+	/// `command` is either `device-list-properties` or `qom-list-properties`.
+	pub async fn property_types<C>(&self, command: C) -> PropertyTypes where
+		C: QmpCommand<Ok=Vec<qmp::ObjectPropertyInfo>>,
+	{
+		match self.execute(command).await {
+			Ok(props) => PropertyTypes::from_properties(&props),
+			Err(e) => {
+				log::warn!("failed to query {} for property types: {}", C::NAME, e);
+				Default::default()
+			},
+		}
+	}
+
+	pub async fn device_add(&self, add: qmp::device_add) -> Result<()> {
+		self.execute(add).await?;
+		Ok(())
+	}
+
+	pub async fn device_exists(&self, id: &str) -> Result<bool> {
+		let path = format!("/machine/peripheral/{}", id);
+		match self.execute(qmp::qom_list { path }).await {
+			Ok(..) => Ok(true),
+			Err(qapi::ExecuteError::Qapi(qapi::Error { class: qapi::ErrorClass::DeviceNotFound, .. })) =>
+				Ok(false),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Removes a device, optionally waiting for the guest to release it
+	pub async fn device_del(&self, id: &str, wait: bool) -> Result<()> {
+		let mut events = match wait {
+			true => Some(self.subscribe()),
+			false => None,
+		};
+		let delete = self.execute(qmp::device_del { id: id.into() })
+			.map_err(Into::into)
+			.map_ok(drop);
+		match &mut events {
+			Some(events) => {
+				let filter = EventFilter::new("DEVICE_DELETED")
+					.with_data("device", id);
+				let wait = async move {
+					events.wait(&filter).await?;
+					// work around qemu bug. without this delay, device_add will work but the new device might be immediately deleted
+					sleep(Duration::from_millis(128)).await;
+					Ok(())
+				};
+				future::try_join(wait, delete).await
+					.map(|((), ())| ())
+			},
+			None => delete.await,
+		}
+	}
+
+	pub async fn blockdev_add(&self, options: qmp::BlockdevOptions) -> Result<()> {
+		self.execute(qmp::blockdev_add(options)).await?;
+		Ok(())
+	}
+
+	pub async fn blockdev_del(&self, node_name: &str) -> Result<()> {
+		self.execute(qmp::blockdev_del { node_name: node_name.into() }).await?;
+		Ok(())
+	}
+
+	pub async fn block_nodes(&self) -> Result<Vec<qmp::BlockDeviceInfo>> {
+		self.execute(qmp::query_named_block_nodes { flat: None }).await.map_err(Into::into)
+	}
+
+	/// Lists the names of all named block nodes
+	pub async fn block_node_names(&self) -> Result<Vec<String>> {
+		Ok(self.block_nodes().await?.into_iter()
+			.filter_map(|node| node.node_name)
+			.collect()
+		)
+	}
+
+	/// Waits for a job to conclude, then dismisses it
+	///
+	/// `events` must be subscribed to before the job starts. The returned job has an `error`
+	/// if it failed.
+	pub async fn wait_job(&self, events: &mut Events, id: &str) -> Result<qmp::JobInfo> {
+		let filter = EventFilter::new("JOB_STATUS_CHANGE")
+			.with_data("id", id);
+		loop {
+			if let Event::JOB_STATUS_CHANGE { data, .. } = events.wait(&filter).await? {
+				log::info!("job {} is {:?}", id, data.status);
+				if data.status == qmp::JobStatus::concluded {
+					break
+				}
+			}
+		}
+
+		let job = self.execute(qmp::query_jobs { }).await?.into_iter()
+			.find(|job| job.id == id)
+			.ok_or_else(|| format_err!("job {} disappeared", id))?;
+		self.execute(qmp::job_dismiss { id: id.into() }).await?;
+		Ok(job)
+	}
+
+	/// Cancels a job that is taking too long, waiting up to `duration` for it to conclude before dismissing it
+	pub async fn cancel_job(&self, events: &mut Events, id: &str, duration: Duration) -> Result<()> {
+		match self.execute(qmp::job_cancel { id: id.into() }).await {
+			Ok(_) => crate::wait(Some(duration), self.wait_job(events, id)).await.map(drop),
+			// it may have concluded in the meantime
+			Err(qapi::ExecuteError::Qapi(_)) => {
+				self.execute(qmp::job_dismiss { id: id.into() }).await?;
+				Ok(())
+			},
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	pub async fn object_add(&self, options: qmp::ObjectOptions) -> Result<()> {
+		self.execute(qmp::object_add(options)).await?;
+		Ok(())
+	}
+
+	pub async fn object_del(&self, id: &str) -> Result<()> {
+		self.execute(qmp::object_del { id: id.into() }).await?;
+		Ok(())
+	}
+}
@@ -0,0 +1,103 @@
+use anyhow::{Result, format_err};
+use futures::Stream;
+use tokio::sync::mpsc;
+use qapi::qmp::Event;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::{Pair, Key, ValueType};
+
+/// The wire representation of an event, with `event`, `data`, and `timestamp` fields
+pub fn event_value(event: &Event) -> qapi::Any {
+	serde_json::to_value(event).unwrap_or_default()
+}
+
+pub fn event_name(event: &qapi::Any) -> &str {
+	event["event"].as_str().unwrap_or_default()
+}
+
+/// Matches events by name and the contents of their `data`
+#[derive(Debug, Clone)]
+pub struct EventFilter {
+	pub name: String,
+	/// dotted paths into `data` and the values they must have
+	pub data: Vec<Pair<Key, String>>,
+}
+
+impl EventFilter {
+	pub fn new<N: Into<String>>(name: N) -> Self {
+		EventFilter {
+			name: name.into(),
+			data: Default::default(),
+		}
+	}
+
+	pub fn with_data<K: AsRef<str>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+		self.data.push(Pair {
+			key: Key {
+				path: key.as_ref().split('.').map(Into::into).collect(),
+				ty: None,
+			},
+			value: value.into(),
+		});
+		self
+	}
+
+	pub fn matches(&self, event: &Event) -> bool {
+		self.matches_value(&event_value(event))
+	}
+
+	pub fn matches_value(&self, event: &qapi::Any) -> bool {
+		event_name(event).eq_ignore_ascii_case(&self.name) && self.data.iter().all(|Pair { key, value }| {
+			let field = key.path.iter()
+				.try_fold(&event["data"], |data, name| match data {
+					qapi::Any::Array(list) => name.parse::<usize>().ok().and_then(|i| list.get(i)),
+					data => data.get(name),
+				});
+			match (field, key.ty) {
+				(None, _) => false,
+				(Some(qapi::Any::String(field)), None) => field == value,
+				(Some(field), ty) => ty.unwrap_or(ValueType::Auto).parse(value).ok().as_ref() == Some(field),
+			}
+		})
+	}
+}
+
+/// A subscription to QMP events
+///
+/// Every event received after subscribing is queued until it is read.
+#[derive(Debug)]
+pub struct Events {
+	receiver: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Events {
+	pub(crate) fn new(receiver: mpsc::UnboundedReceiver<Event>) -> Self {
+		Events {
+			receiver,
+		}
+	}
+
+	/// Returns `None` once the QMP connection has closed
+	pub async fn recv(&mut self) -> Option<Event> {
+		self.receiver.recv().await
+	}
+
+	/// Waits for the next event that matches `filter`
+	pub async fn wait(&mut self, filter: &EventFilter) -> Result<Event> {
+		while let Some(event) = self.recv().await {
+			if filter.matches(&event) {
+				return Ok(event)
+			}
+		}
+
+		Err(format_err!("QMP connection closed while waiting for {}", filter.name))
+	}
+}
+
+impl Stream for Events {
+	type Item = Event;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		self.get_mut().receiver.poll_recv(cx)
+	}
+}
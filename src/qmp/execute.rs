@@ -0,0 +1,238 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::borrow::Cow;
+use std::io;
+use serde::{Serialize, Serializer, Deserialize, ser::SerializeStruct};
+use tokio::io::AsyncWrite;
+
+/// A QMP command whose name is only known at runtime
+///
+/// `qapi::Command` names are static, so this is sent under a placeholder name
+/// that `ExecuteWrite` replaces on its way out. The placeholder is matched on the
+/// parsed JSON rather than its serialized layout.
+#[derive(Debug, Clone)]
+pub struct Execute {
+	pub command: String,
+	pub arguments: Option<qapi::Dictionary>,
+}
+
+const EXECUTE_NAME: &str = "qemucomm-execute";
+
+impl Execute {
+	pub fn new<C: Into<String>>(command: C, arguments: Option<qapi::Dictionary>) -> Self {
+		Execute {
+			command: command.into(),
+			arguments,
+		}
+	}
+}
+
+impl Serialize for Execute {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut s = serializer.serialize_struct("Execute", 2)?;
+		s.serialize_field("execute", &self.command)?;
+		match &self.arguments {
+			Some(arguments) => s.serialize_field("arguments", arguments)?,
+			None => s.skip_field("arguments")?,
+		}
+		s.end()
+	}
+}
+
+impl qapi::Command for Execute {
+	type Ok = qapi::Any;
+
+	const NAME: &'static str = EXECUTE_NAME;
+	const ALLOW_OOB: bool = false;
+}
+
+impl qapi::qmp::QmpCommand for Execute { }
+
+/// Just enough of an outgoing message to tell whether it's a placeholder
+#[derive(Deserialize)]
+struct MessageName<'a> {
+	#[serde(borrow)]
+	execute: Option<Cow<'a, str>>,
+}
+
+/// Rewrites outgoing `Execute` commands into their real form
+pub struct ExecuteWrite<W> {
+	inner: W,
+	line: Vec<u8>,
+	pending: Vec<u8>,
+}
+
+impl<W> ExecuteWrite<W> {
+	pub fn new(inner: W) -> Self {
+		ExecuteWrite {
+			inner,
+			line: Default::default(),
+			pending: Default::default(),
+		}
+	}
+
+	pub fn into_inner(self) -> W {
+		self.inner
+	}
+
+	/// Replaces a placeholder `Execute` line with the command it wraps
+	///
+	/// Only the top-level `execute` name is considered, so any other line passes through unchanged.
+	fn rewrite(line: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+		match serde_json::from_slice::<MessageName>(line) {
+			Ok(MessageName { execute: Some(execute) }) if execute == EXECUTE_NAME => (),
+			_ => return Ok(Cow::Borrowed(line)),
+		}
+
+		let malformed = || io::Error::new(io::ErrorKind::InvalidInput, "malformed QMP execute");
+		let mut message: qapi::Dictionary = serde_json::from_slice(line)?;
+		message.remove("execute");
+		let mut command = match message.remove("arguments") {
+			Some(qapi::Any::Object(command)) => command,
+			_ => return Err(malformed()),
+		};
+		match command.get("execute") {
+			Some(qapi::Any::String(..)) => (),
+			_ => return Err(malformed()),
+		}
+		if let Some(id) = message.remove("id") {
+			command.insert("id".into(), id);
+		}
+		if !message.is_empty() {
+			return Err(malformed())
+		}
+		let mut line = serde_json::to_vec(&command)?;
+		line.push(b'\n');
+		Ok(Cow::Owned(line))
+	}
+}
+
+impl<W: AsyncWrite + Unpin> ExecuteWrite<W> {
+	fn poll_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+		while !self.pending.is_empty() {
+			match futures::ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending)) {
+				Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+				Ok(len) => drop(self.pending.drain(..len)),
+				Err(e) => return Poll::Ready(Err(e)),
+			}
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ExecuteWrite<W> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+			return Poll::Ready(Err(e))
+		}
+
+		this.line.extend_from_slice(buf);
+		while let Some(end) = this.line.iter().position(|&b| b == b'\n') {
+			let line: Vec<u8> = this.line.drain(..=end).collect();
+			let line = Self::rewrite(&line)?;
+			this.pending.extend_from_slice(&line);
+		}
+
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		futures::ready!(this.poll_pending(cx))?;
+		Pin::new(&mut this.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		futures::ready!(this.poll_pending(cx))?;
+		Pin::new(&mut this.inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::{json, Value};
+	use tokio::io::AsyncWriteExt;
+
+	fn encode<C: qapi::Command>(command: C, id: Option<u32>) -> Vec<u8> {
+		let mut line = serde_json::to_vec(&qapi::Execute::new(command, id)).unwrap();
+		line.push(b'\n');
+		line
+	}
+
+	fn rewrite(line: &[u8]) -> io::Result<Value> {
+		ExecuteWrite::<()>::rewrite(line)
+			.map(|line| serde_json::from_slice(&line).unwrap())
+	}
+
+	#[test]
+	fn serialized_form() {
+		// pins how qapi wraps the placeholder, which `rewrite` relies on
+		let line = encode(Execute::new("query-status", None), Some(3));
+		assert_eq!(serde_json::from_slice::<Value>(&line).unwrap(), json!({
+			"execute": EXECUTE_NAME,
+			"arguments": { "execute": "query-status" },
+			"id": 3,
+		}));
+	}
+
+	#[test]
+	fn rewrites() {
+		let args = json!({ "command-line": "info status" });
+		let args = match args { Value::Object(args) => args, _ => unreachable!() };
+		let line = encode(Execute::new("human-monitor-command", Some(args)), Some(7));
+		assert_eq!(rewrite(&line).unwrap(), json!({
+			"execute": "human-monitor-command",
+			"arguments": { "command-line": "info status" },
+			"id": 7,
+		}));
+
+		let line = encode(Execute::new("query-status", None), None);
+		assert_eq!(rewrite(&line).unwrap(), json!({ "execute": "query-status" }));
+	}
+
+	#[test]
+	fn independent_of_layout() {
+		let line = format!("{{ \"id\": 1,\n\"arguments\": {{ \"execute\": \"stop\" }}, \"execute\": \"{}\" }}\n", EXECUTE_NAME);
+		assert_eq!(rewrite(line.as_bytes()).unwrap(), json!({ "execute": "stop", "id": 1 }));
+	}
+
+	#[test]
+	fn passes_typed_commands() {
+		let line = encode(qapi::qmp::stop { }, Some(1));
+		assert!(matches!(ExecuteWrite::<()>::rewrite(&line).unwrap(), Cow::Borrowed(..)));
+
+		// the placeholder name is only special as the command itself
+		let line = encode(qapi::qmp::human_monitor_command {
+			command_line: format!("echo {}", EXECUTE_NAME),
+			cpu_index: None,
+		}, Some(2));
+		assert!(matches!(ExecuteWrite::<()>::rewrite(&line).unwrap(), Cow::Borrowed(..)));
+		let line = format!("{{\"execute\":\"stop\",\"arguments\":{{\"execute\":\"{}\"}}}}\n", EXECUTE_NAME);
+		assert!(matches!(ExecuteWrite::<()>::rewrite(line.as_bytes()).unwrap(), Cow::Borrowed(..)));
+	}
+
+	#[test]
+	fn rejects_unmatched_placeholder() {
+		for line in [
+			format!("{{\"execute\":\"{}\"}}\n", EXECUTE_NAME),
+			format!("{{\"execute\":\"{}\",\"arguments\":{{}}}}\n", EXECUTE_NAME),
+			format!("{{\"execute\":\"{}\",\"arguments\":{{\"execute\":\"stop\"}},\"extra\":1}}\n", EXECUTE_NAME),
+		] {
+			assert!(ExecuteWrite::<()>::rewrite(line.as_bytes()).is_err(), "{}", line);
+		}
+	}
+
+	#[tokio::test]
+	async fn split_writes() {
+		let line = encode(Execute::new("cont", None), Some(2));
+		let mut write = ExecuteWrite::new(Vec::new());
+		for chunk in line.chunks(5) {
+			write.write_all(chunk).await.unwrap();
+		}
+		write.flush().await.unwrap();
+		assert_eq!(write.into_inner(), b"{\"execute\":\"cont\",\"id\":2}\n");
+	}
+}
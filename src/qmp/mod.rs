@@ -0,0 +1,24 @@
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf, split};
+use qapi::futures::{QmpStreamTokio, QmpStreamNegotiation};
+use std::io;
+
+mod execute;
+mod event;
+mod client;
+mod schema;
+
+pub use self::execute::{Execute, ExecuteWrite};
+pub use self::event::{EventFilter, Events, event_value, event_name};
+pub use self::client::{Client, Service};
+pub use self::schema::{Schema, SchemaType, SchemaMember};
+
+pub type QmpRead<S> = QmpStreamTokio<ReadHalf<S>>;
+pub type QmpWrite<S> = QmpStreamTokio<ExecuteWrite<WriteHalf<S>>>;
+
+/// Opens a QMP connection that supports both typed commands and `Execute`
+pub async fn open<S>(stream: S) -> io::Result<QmpStreamNegotiation<QmpRead<S>, QmpWrite<S>>> where
+	S: AsyncRead + AsyncWrite,
+{
+	let (r, w) = split(stream);
+	QmpStreamTokio::open_split(r, ExecuteWrite::new(w)).await
+}
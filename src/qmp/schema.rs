@@ -0,0 +1,192 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use crate::{PropertyTypes, ValueType};
+
+/// A type as introspected by `query-qmp-schema`
+///
+/// Types other than builtins are usually named by opaque numbers, so they're only useful for lookups.
+#[derive(Debug, Clone)]
+pub enum SchemaType {
+	/// a builtin with its JSON type: `string`, `int`, `number`, `boolean`, `null`, or `value` for any
+	Builtin(String),
+	Enum(Vec<String>),
+	Array(String),
+	Object {
+		members: Vec<SchemaMember>,
+		/// types whose members are valid depending on the value of a discriminator member
+		variants: Vec<String>,
+	},
+	Alternate(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct SchemaMember {
+	pub name: String,
+	pub ty: String,
+	pub optional: bool,
+}
+
+/// The commands and types that a QEMU instance supports
+///
+/// Parsed loosely rather than with `qapi::qmp::SchemaInfo`, so that schemas from QEMU
+/// versions with deprecated fields removed can still be used.
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+	/// command names and their argument types
+	commands: BTreeMap<String, String>,
+	types: BTreeMap<String, SchemaType>,
+}
+
+#[derive(Deserialize)]
+struct SchemaInfo {
+	name: String,
+	#[serde(rename = "meta-type")]
+	meta_type: String,
+	#[serde(rename = "arg-type")]
+	arg_type: Option<String>,
+	#[serde(rename = "json-type")]
+	json_type: Option<String>,
+	#[serde(rename = "element-type")]
+	element_type: Option<String>,
+	#[serde(default)]
+	members: Vec<qapi::Dictionary>,
+	#[serde(default)]
+	variants: Vec<SchemaVariant>,
+}
+
+#[derive(Deserialize)]
+struct SchemaVariant {
+	#[serde(rename = "type")]
+	ty: String,
+}
+
+impl Schema {
+	/// Parses the return value of `query-qmp-schema`
+	pub fn from_value(value: qapi::Any) -> Result<Self> {
+		let infos: Vec<SchemaInfo> = serde_json::from_value(value)?;
+		let mut schema = Schema::default();
+		for info in infos {
+			let member_str = |m: &qapi::Dictionary, key: &str| m.get(key)
+				.and_then(|v| v.as_str())
+				.map(ToOwned::to_owned);
+			let ty = match &info.meta_type[..] {
+				"command" => {
+					schema.commands.insert(info.name, info.arg_type.unwrap_or_default());
+					continue
+				},
+				"builtin" => SchemaType::Builtin(info.json_type.unwrap_or_default()),
+				"enum" => SchemaType::Enum(info.members.iter()
+					.filter_map(|m| member_str(m, "name"))
+					.collect()
+				),
+				"array" => SchemaType::Array(info.element_type.unwrap_or_default()),
+				"object" => SchemaType::Object {
+					members: info.members.iter()
+						.filter_map(|m| Some(SchemaMember {
+							name: member_str(m, "name")?,
+							ty: member_str(m, "type")?,
+							// optional members are introspected with a (usually null) default
+							optional: m.contains_key("default"),
+						}))
+						.collect(),
+					variants: info.variants.into_iter().map(|v| v.ty).collect(),
+				},
+				"alternate" => SchemaType::Alternate(info.members.iter()
+					.filter_map(|m| member_str(m, "type"))
+					.collect()
+				),
+				_ => continue,
+			};
+			schema.types.insert(info.name, ty);
+		}
+
+		Ok(schema)
+	}
+
+	pub fn commands(&self) -> impl Iterator<Item=&str> {
+		self.commands.keys().map(|c| &c[..])
+	}
+
+	pub fn has_command(&self, command: &str) -> bool {
+		self.commands.contains_key(command)
+	}
+
+	pub fn get(&self, ty: &str) -> Option<&SchemaType> {
+		self.types.get(ty)
+	}
+
+	/// The members of an object type, including those of all its variants
+	pub fn members(&self, ty: &str) -> Vec<&SchemaMember> {
+		let mut members: Vec<&SchemaMember> = Vec::new();
+		if let Some(SchemaType::Object { members: base, variants }) = self.get(ty) {
+			members.extend(base);
+			for variant in variants {
+				for member in self.members(variant) {
+					if !members.iter().any(|m| m.name == member.name) {
+						members.push(member);
+					}
+				}
+			}
+		}
+		members
+	}
+
+	pub fn arguments(&self, command: &str) -> Vec<&SchemaMember> {
+		match self.commands.get(command) {
+			Some(ty) => self.members(ty),
+			None => Vec::new(),
+		}
+	}
+
+	/// Finds the type of a (dotted) argument path, where list elements are indexed by number
+	pub fn argument_type<S: AsRef<str>>(&self, command: &str, path: &[S]) -> Option<&str> {
+		let mut ty = &self.commands.get(command)?[..];
+		for name in path {
+			ty = match self.get(ty)? {
+				SchemaType::Array(element) if name.as_ref().parse::<usize>().is_ok() => element,
+				SchemaType::Object { .. } => &self.members(ty).into_iter()
+					.find(|m| m.name == name.as_ref())?
+					.ty,
+				_ => return None,
+			};
+		}
+		Some(ty)
+	}
+
+	/// How `KEY=value` arguments of this type should be parsed
+	pub fn value_type(&self, ty: &str) -> ValueType {
+		match self.get(ty) {
+			Some(SchemaType::Builtin(json)) => match &json[..] {
+				"string" => ValueType::String,
+				"int" => ValueType::Int,
+				"number" => ValueType::Number,
+				"boolean" => ValueType::Bool,
+				_ => ValueType::Auto,
+			},
+			Some(SchemaType::Enum(..)) => ValueType::String,
+			Some(SchemaType::Array(element)) => self.value_type(element),
+			_ => ValueType::Auto,
+		}
+	}
+
+	pub fn property_types(&self, command: &str) -> PropertyTypes {
+		self.arguments(command).into_iter()
+			.map(|m| (m.name.clone(), self.value_type(&m.ty)))
+			.collect()
+	}
+
+	/// A short human readable description of a type
+	pub fn describe(&self, ty: &str) -> String {
+		match self.get(ty) {
+			Some(SchemaType::Builtin(..)) | None => ty.into(),
+			Some(SchemaType::Enum(values)) => values.join("|"),
+			Some(SchemaType::Array(element)) => format!("[{}]", self.describe(element)),
+			Some(SchemaType::Object { .. }) => "object".into(),
+			Some(SchemaType::Alternate(types)) => types.iter()
+				.map(|ty| self.describe(ty))
+				.collect::<Vec<_>>()
+				.join(" or "),
+		}
+	}
+}
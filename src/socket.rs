@@ -0,0 +1,247 @@
+use anyhow::{Result, Error, format_err};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{UnixStream, UnixListener, TcpStream, TcpListener};
+use tokio::time::{Duration, sleep};
+use std::os::fd::{RawFd, OwnedFd, AsRawFd, FromRawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::str::FromStr;
+use std::{fmt, fs, io};
+
+/// Where to find a QEMU chardev socket
+///
+/// Parsed from `unix:PATH`, `unix-abstract:NAME` (or `@NAME`), `tcp:HOST:PORT`, `fd:N`, or a plain path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketAddr {
+	Unix(PathBuf),
+	UnixAbstract(String),
+	Tcp(String),
+	/// an already open socket inherited from the parent process
+	Fd(RawFd),
+}
+
+impl SocketAddr {
+	pub async fn connect(&self) -> io::Result<Connection> {
+		log::debug!("connecting to {}", self);
+		match self {
+			SocketAddr::Unix(path) => UnixStream::connect(path).await.map(Connection::Unix),
+			SocketAddr::UnixAbstract(name) => {
+				let socket = unix_socket()?;
+				let (addr, len) = abstract_addr(name)?;
+				cvt(unsafe { libc::connect(socket.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, len) })?;
+				let stream = std::os::unix::net::UnixStream::from(socket);
+				stream.set_nonblocking(true)?;
+				UnixStream::from_std(stream).map(Connection::Unix)
+			},
+			SocketAddr::Tcp(addr) => TcpStream::connect(addr).await.map(Connection::Tcp),
+			&SocketAddr::Fd(fd) => {
+				// the fd is owned by us from here on; it's the caller's responsibility to only connect once
+				let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+				let stream = std::os::unix::net::UnixStream::from(fd);
+				stream.set_nonblocking(true)?;
+				if stream.local_addr().is_ok() {
+					UnixStream::from_std(stream).map(Connection::Unix)
+				} else {
+					let stream = std::net::TcpStream::from(OwnedFd::from(stream));
+					TcpStream::from_std(stream).map(Connection::Tcp)
+				}
+			},
+		}
+	}
+
+	/// Listens on the address and accepts a single connection from QEMU
+	///
+	/// A stale socket left at a filesystem path is replaced, and removed again once connected.
+	/// An inherited fd is expected to be a listening socket.
+	pub async fn accept(&self) -> Result<Connection> {
+		log::debug!("listening on {}", self);
+		let stream = match self {
+			SocketAddr::Unix(path) => {
+				match fs::symlink_metadata(path) {
+					Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+					Ok(..) => return Err(format_err!("{} already exists and is not a socket", path.display())),
+					Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+					Err(e) => return Err(e.into()),
+				}
+				let listener = UnixListener::bind(path)?;
+				let _remove = RemoveOnDrop(path);
+				Connection::Unix(listener.accept().await?.0)
+			},
+			SocketAddr::UnixAbstract(name) => {
+				let socket = unix_socket()?;
+				let (addr, len) = abstract_addr(name)?;
+				cvt(unsafe { libc::bind(socket.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, len) })?;
+				cvt(unsafe { libc::listen(socket.as_raw_fd(), 1) })?;
+				let listener = std::os::unix::net::UnixListener::from(socket);
+				listener.set_nonblocking(true)?;
+				Connection::Unix(UnixListener::from_std(listener)?.accept().await?.0)
+			},
+			SocketAddr::Tcp(addr) => Connection::Tcp(TcpListener::bind(addr).await?.accept().await?.0),
+			&SocketAddr::Fd(fd) => {
+				let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+				let listener = std::os::unix::net::UnixListener::from(fd);
+				listener.set_nonblocking(true)?;
+				if listener.local_addr().is_ok() {
+					Connection::Unix(UnixListener::from_std(listener)?.accept().await?.0)
+				} else {
+					let listener = std::net::TcpListener::from(OwnedFd::from(listener));
+					Connection::Tcp(TcpListener::from_std(listener)?.accept().await?.0)
+				}
+			},
+		};
+		log::debug!("accepted connection on {}", self);
+		Ok(stream)
+	}
+
+	/// Waits for the socket to become available, then connects to it
+	///
+	/// Filesystem sockets are watched with inotify, while everything else is polled.
+	pub async fn wait_connect(&self) -> Result<Connection> {
+		match self {
+			SocketAddr::Unix(path) => {
+				crate::wait_for_socket(path).await?;
+				self.connect().await.map_err(Into::into)
+			},
+			SocketAddr::Fd(..) => self.connect().await.map_err(Into::into),
+			SocketAddr::UnixAbstract(..) | SocketAddr::Tcp(..) => loop {
+				match self.connect().await {
+					Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound) => {
+						log::info!("{} not yet available: {}", self, e);
+						sleep(Duration::from_millis(100)).await;
+					},
+					res => break res.map_err(Into::into),
+				}
+			},
+		}
+	}
+}
+
+impl FromStr for SocketAddr {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		Ok(if let Some(path) = s.strip_prefix("unix:") {
+			SocketAddr::Unix(path.into())
+		} else if let Some(name) = s.strip_prefix("unix-abstract:").or_else(|| s.strip_prefix('@')) {
+			SocketAddr::UnixAbstract(name.into())
+		} else if let Some(addr) = s.strip_prefix("tcp:") {
+			SocketAddr::Tcp(addr.into())
+		} else if let Some(fd) = s.strip_prefix("fd:") {
+			SocketAddr::Fd(fd.parse().map_err(|e| format_err!("invalid fd `{}`: {}", fd, e))?)
+		} else {
+			SocketAddr::Unix(s.into())
+		})
+	}
+}
+
+impl fmt::Display for SocketAddr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SocketAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+			SocketAddr::UnixAbstract(name) => write!(f, "unix-abstract:{}", name),
+			SocketAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+			SocketAddr::Fd(fd) => write!(f, "fd:{}", fd),
+		}
+	}
+}
+
+/// Abstract socket addresses are a leading NUL followed by the name, without a terminator
+///
+/// std only gained support for them in Rust 1.70.
+fn abstract_addr(name: &str) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+	let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+	addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+	if name.len() >= addr.sun_path.len() {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "abstract socket name is too long"))
+	}
+	for (dst, &src) in addr.sun_path[1..].iter_mut().zip(name.as_bytes()) {
+		*dst = src as libc::c_char;
+	}
+	let len = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+	Ok((addr, len as libc::socklen_t))
+}
+
+fn unix_socket() -> io::Result<OwnedFd> {
+	let fd = cvt(unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+	Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn cvt(res: libc::c_int) -> io::Result<libc::c_int> {
+	match res {
+		-1 => Err(io::Error::last_os_error()),
+		res => Ok(res),
+	}
+}
+
+struct RemoveOnDrop<'a>(&'a Path);
+
+impl Drop for RemoveOnDrop<'_> {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(self.0);
+	}
+}
+
+/// A connected QMP or guest agent socket
+#[derive(Debug)]
+pub enum Connection {
+	Unix(UnixStream),
+	Tcp(TcpStream),
+}
+
+impl AsyncRead for Connection {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Connection::Unix(s) => Pin::new(s).poll_read(cx, buf),
+			Connection::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for Connection {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Connection::Unix(s) => Pin::new(s).poll_write(cx, buf),
+			Connection::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Connection::Unix(s) => Pin::new(s).poll_flush(cx),
+			Connection::Tcp(s) => Pin::new(s).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Connection::Unix(s) => Pin::new(s).poll_shutdown(cx),
+			Connection::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	#[tokio::test]
+	async fn unix_abstract() {
+		let addr: SocketAddr = format!("@qemucomm-test-{}", std::process::id()).parse().unwrap();
+		let listener = tokio::spawn({
+			let addr = addr.clone();
+			async move { addr.accept().await }
+		});
+		let mut client = addr.wait_connect().await.unwrap();
+		let mut server = listener.await.unwrap().unwrap();
+		client.write_all(b"ping").await.unwrap();
+		let mut buf = [0u8; 4];
+		server.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"ping");
+
+		let long: SocketAddr = format!("@{}", "x".repeat(200)).parse().unwrap();
+		assert_eq!(long.connect().await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+	}
+}
@@ -0,0 +1,118 @@
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio, Child};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use qemucomm::mock::{MockQmp, MockQga};
+
+/// A mock listening on a socket in a temporary directory, served from a background runtime
+pub struct Harness<M> {
+	pub mock: M,
+	pub socket: PathBuf,
+	pub dir: TempDir,
+	bin: &'static str,
+	runtime: Runtime,
+}
+
+impl<M> Harness<M> {
+	fn new<F: FnOnce(&M, &PathBuf)>(dir: TempDir, mock: M, bin: &'static str, listen: F) -> Self {
+		let socket = dir.path().join("mock.sock");
+		let runtime = tokio::runtime::Builder::new_multi_thread()
+			.worker_threads(1)
+			.enable_all()
+			.build().unwrap();
+		{
+			let _guard = runtime.enter();
+			listen(&mock, &socket);
+		}
+		Harness {
+			mock,
+			socket,
+			dir,
+			bin,
+			runtime,
+		}
+	}
+
+	pub fn block_on<F: Future>(&self, f: F) -> F::Output {
+		self.runtime.block_on(f)
+	}
+
+	pub fn command(&self) -> Command {
+		let mut command = Command::new(self.bin);
+		command.arg("--socket").arg(&self.socket)
+			.env_remove("QEMUCOMM_QMP_SOCKET_PATH")
+			.env_remove("QEMUCOMM_QGA_SOCKET_PATH");
+		command
+	}
+
+	pub fn run<I: IntoIterator<Item=S>, S: AsRef<std::ffi::OsStr>>(&self, args: I) -> Output {
+		self.command().args(args).output().unwrap()
+	}
+
+	pub fn spawn<I: IntoIterator<Item=S>, S: AsRef<std::ffi::OsStr>>(&self, args: I) -> Child {
+		self.command().args(args)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn().unwrap()
+	}
+}
+
+impl Harness<MockQmp> {
+	pub fn qmp(mock: MockQmp) -> Self {
+		let dir = tempfile::tempdir().unwrap();
+		Self::new(dir, mock, env!("CARGO_BIN_EXE_qmp"), |mock, socket| drop(mock.listen(socket).unwrap()))
+	}
+
+	/// Waits until `count` clients have negotiated, so that events sent from then on will be seen
+	///
+	/// Subcommands that read events subscribe before negotiating, so there's nothing else to wait for.
+	pub fn wait_negotiated(&self, count: usize) {
+		wait_until(|| self.mock.received().iter().filter(|r| r.command == "qmp_capabilities").count() >= count);
+	}
+}
+
+impl Harness<MockQga> {
+	/// Serves a guest agent whose filesystem is a `guest` directory next to the socket
+	pub fn qga<F: FnOnce(MockQga) -> MockQga>(configure: F) -> Self {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path().join("guest");
+		std::fs::create_dir(&root).unwrap();
+		let mock = configure(MockQga::new(root));
+		Self::new(dir, mock, env!("CARGO_BIN_EXE_qga"), |mock, socket| drop(mock.listen(socket).unwrap()))
+	}
+
+	pub fn guest_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+		self.mock.root().join(path)
+	}
+
+	pub fn connect(&self) -> qemucomm::qga::Client {
+		let stream = self.block_on(qemucomm::SocketAddr::Unix(self.socket.clone()).connect()).unwrap();
+		qemucomm::qga::Client::new(stream)
+	}
+}
+
+pub fn wait_until<F: FnMut() -> bool>(mut f: F) {
+	let deadline = Instant::now() + Duration::from_secs(10);
+	while !f() {
+		assert!(Instant::now() < deadline, "timed out waiting for condition");
+		std::thread::sleep(Duration::from_millis(10));
+	}
+}
+
+pub fn stdout(output: &Output) -> String {
+	String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+	String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[track_caller]
+pub fn assert_success(output: &Output) {
+	assert!(output.status.success(), "command failed with {}: {}", output.status, stderr(output));
+}
@@ -0,0 +1,314 @@
+use std::io::{Write, SeekFrom};
+use std::pin::Pin;
+use std::process::Command;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncReadExt, AsyncWriteExt, AsyncSeekExt};
+use qemucomm::mock::Reply;
+
+mod common;
+use common::{Harness, assert_success, stdout, stderr, wait_until};
+
+const CHUNK_SIZE: i64 = 0x10000;
+
+fn pattern(len: usize) -> Vec<u8> {
+	(0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn commands(qga: &Harness<qemucomm::mock::MockQga>) -> Vec<String> {
+	qga.mock.received().into_iter().map(|r| r.command).collect()
+}
+
+#[test]
+fn ping() {
+	let qga = Harness::qga(|mock| mock);
+	assert_success(&qga.run(["ping"]));
+	assert_eq!(commands(&qga), ["guest-sync-delimited", "guest-ping"]);
+}
+
+#[test]
+fn ping_no_sync() {
+	let qga = Harness::qga(|mock| mock);
+	assert_success(&qga.run(["--no-sync", "ping"]));
+	assert_eq!(commands(&qga), ["guest-ping"]);
+}
+
+#[test]
+fn ping_repeat_recovers() {
+	let qga = Harness::qga(|mock| mock.with_reply_once("guest-ping", Reply::Ignore));
+	assert_success(&qga.run(["ping", "--repeat", "--timeout", "10"]));
+	let commands = commands(&qga);
+	assert_eq!(commands.iter().filter(|c| *c == "guest-ping").count(), 2);
+	assert_eq!(commands.last().unwrap(), "guest-ping");
+}
+
+#[test]
+fn sync_discards_stale_response() {
+	let qga = Harness::qga(|mock| mock
+		.with_reply_once("guest-ping", Reply::empty().delayed(Duration::from_millis(500)))
+	);
+	let client = qga.connect();
+	qga.block_on(async {
+		let ping = tokio::time::timeout(Duration::from_millis(100), client.ping()).await;
+		assert!(ping.is_err(), "ping should have timed out");
+
+		// the late ping response must not be mistaken for this one
+		let info = client.info().await.unwrap();
+		assert_eq!(info.version, "8.0.0");
+	});
+	assert_eq!(commands(&qga), ["guest-ping", "guest-sync-delimited", "guest-info"]);
+}
+
+#[test]
+fn info() {
+	let qga = Harness::qga(|mock| mock.with_os_id("mswindows"));
+	let output = qga.run(["info", "--os"]);
+	assert_success(&output);
+	assert!(stdout(&output).contains("guest-file-open"), "{}", stdout(&output));
+	assert!(stdout(&output).contains("mswindows"), "{}", stdout(&output));
+}
+
+#[test]
+fn info_json() {
+	let qga = Harness::qga(|mock| mock);
+	let output = qga.run(["--output", "json", "info", "--os"]);
+	assert_success(&output);
+	let info: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(info["version"], "8.0.0");
+	assert_eq!(info["os"]["id"], "linux");
+}
+
+#[test]
+fn file_output() {
+	let qga = Harness::qga(|mock| mock);
+	let mut child = qga.spawn(["--output", "json", "write-file", "--mode", "w", "/data"]);
+	child.stdin.take().unwrap().write_all(b"hello").unwrap();
+	let output = child.wait_with_output().unwrap();
+	assert_success(&output);
+	let written: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(written["path"], "/data");
+	assert_eq!(written["bytes-written"], 5);
+	assert!(written["handle"].is_i64());
+
+	let output = qga.run(["--output", "yaml", "read-file", "/data"]);
+	assert_success(&output);
+	assert!(stdout(&output).contains("bytes-read: 5\n"), "{}", stdout(&output));
+	assert!(stdout(&output).contains("buf-b64: aGVsbG8=\n"), "{}", stdout(&output));
+}
+
+#[test]
+fn exec_json() {
+	let qga = Harness::qga(|mock| mock);
+	let output = qga.run(["--output", "json", "exec", "--", "/bin/sh", "-c", "echo hello; exit 2"]);
+	assert_eq!(output.status.code(), Some(2));
+	let status: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(status["exited"], true);
+	assert_eq!(status["exitcode"], 2);
+	assert_eq!(status["out-data"], "aGVsbG8K");
+	assert!(status["pid"].is_i64());
+}
+
+#[test]
+fn write_read_file() {
+	let qga = Harness::qga(|mock| mock);
+	let data = pattern(300_000);
+
+	let mut child = qga.spawn(["write-file", "--mode", "w", "/data"]);
+	child.stdin.take().unwrap().write_all(&data).unwrap();
+	assert_success(&child.wait_with_output().unwrap());
+	assert!(std::fs::read(qga.guest_path("data")).unwrap() == data);
+
+	let output = qga.run(["read-file", "/data"]);
+	assert_success(&output);
+	assert!(output.stdout == data);
+	assert_eq!(qga.mock.open_files(), 0);
+}
+
+#[test]
+fn read_file_offset() {
+	let qga = Harness::qga(|mock| mock);
+	std::fs::write(qga.guest_path("hello"), "hello world").unwrap();
+
+	let output = qga.run(["read-file", "--offset", "6", "/hello"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "world");
+
+	let output = qga.run(["read-file", "--seek", "end", "--offset=-5", "/hello"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "world");
+}
+
+#[test]
+fn read_file_missing() {
+	let qga = Harness::qga(|mock| mock);
+	let output = qga.run(["read-file", "/nothing"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("failed to open file '/nothing'"), "{}", stderr(&output));
+}
+
+#[test]
+fn file_chunking() {
+	let qga = Harness::qga(|mock| mock);
+	let client = qga.connect();
+	let data = pattern(200_000);
+	qga.block_on(async {
+		let mut file = client.file_open("/chunked", Some("w+".into())).await.unwrap();
+		file.write_all(&data).await.unwrap();
+		file.flush().await.unwrap();
+
+		assert_eq!(AsyncSeekExt::seek(&mut file, SeekFrom::Start(0)).await.unwrap(), 0);
+		let mut read = Vec::new();
+		file.read_to_end(&mut read).await.unwrap();
+		assert!(read == data);
+
+		assert_eq!(AsyncSeekExt::seek(&mut file, SeekFrom::Start(100_000)).await.unwrap(), 100_000);
+		let mut buf = [0u8; 10];
+		file.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf[..], data[100_000..100_010]);
+		// the rest of the chunk is buffered, which mustn't skew the reported position
+		assert_eq!(file.stream_position().await.unwrap(), 100_010);
+		assert_eq!(AsyncSeekExt::seek(&mut file, SeekFrom::Current(-10)).await.unwrap(), 100_000);
+
+		file.close().await.unwrap();
+	});
+	assert!(std::fs::read(qga.guest_path("chunked")).unwrap() == data);
+
+	let received = qga.mock.received();
+	let count = |r: &qemucomm::mock::Received| r.arguments.get("count").and_then(|c| c.as_i64());
+	let writes: Vec<_> = received.iter().filter(|r| r.command == "guest-file-write").collect();
+	assert!(writes.len() >= 4, "expected the write to be split into chunks");
+	assert!(writes.iter().all(|w| count(w).unwrap() <= CHUNK_SIZE));
+	assert!(received.iter().filter(|r| r.command == "guest-file-read").all(|r| count(r).unwrap() <= CHUNK_SIZE));
+	assert_eq!(qga.mock.open_files(), 0);
+}
+
+#[test]
+fn file_write_parts() {
+	// each interrupted write stays in flight until it's flushed or retried
+	let delay = Duration::from_millis(200);
+	let qga = Harness::qga(|mock| mock
+		.with_delay_once("guest-file-write", delay)
+		.with_delay_once("guest-file-write", delay)
+		.with_delay_once("guest-file-write", delay)
+	);
+	let client = qga.connect();
+	qga.block_on(async {
+		let mut file = client.file_open("/parts", Some("w".into())).await.unwrap();
+
+		// a write interrupted by a flush is reported by the next poll_write
+		let write = futures::poll!(futures::future::poll_fn(|cx| Pin::new(&mut file).poll_write(cx, b"two ")));
+		assert!(write.is_pending());
+		AsyncWriteExt::flush(&mut file).await.unwrap();
+		assert_eq!(file.write(b"two three").await.unwrap(), 4);
+
+		// ... even if that's retried with a different buffer
+		let write = futures::poll!(futures::future::poll_fn(|cx| Pin::new(&mut file).poll_write(cx, b"four ")));
+		assert!(write.is_pending());
+		assert_eq!(file.write(b"five ").await.unwrap(), 5);
+
+		// other operations can't start until it's been reported
+		let write = futures::poll!(futures::future::poll_fn(|cx| Pin::new(&mut file).poll_write(cx, b"six")));
+		assert!(write.is_pending());
+		assert!(file.read(&mut [0u8; 4]).await.is_err());
+		assert!(AsyncSeekExt::seek(&mut file, SeekFrom::Start(0)).await.is_err());
+		assert_eq!(file.write(b"six").await.unwrap(), 3);
+
+		file.close().await.unwrap();
+	});
+	assert_eq!(std::fs::read_to_string(qga.guest_path("parts")).unwrap(), "two four six");
+}
+
+#[test]
+fn file_closed_on_drop() {
+	let qga = Harness::qga(|mock| mock);
+	std::fs::write(qga.guest_path("hello"), "hello").unwrap();
+	let client = qga.connect();
+	qga.block_on(async {
+		let file = client.file_open("/hello", None).await.unwrap();
+		drop(file);
+	});
+	wait_until(|| qga.mock.open_files() == 0);
+}
+
+#[test]
+fn exec() {
+	let qga = Harness::qga(|mock| mock);
+	let output = qga.run(["exec", "echo", "hello"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "hello\n");
+
+	let output = qga.run(["exec", "pwd"]);
+	assert_success(&output);
+	assert_eq!(std::path::Path::new(stdout(&output).trim_end()), qga.guest_path("").canonicalize().unwrap());
+}
+
+#[test]
+fn exec_exit_code() {
+	let qga = Harness::qga(|mock| mock);
+	let output = qga.run(["exec", "--", "/bin/sh", "-c", "echo oops >&2; exit 3"]);
+	assert_eq!(output.status.code(), Some(3));
+	assert_eq!(stderr(&output), "oops\n");
+}
+
+#[test]
+fn exec_stdin_env() {
+	let qga = Harness::qga(|mock| mock);
+	let mut child = qga.spawn(["exec", "--stdin", "-", "cat"]);
+	child.stdin.take().unwrap().write_all(b"piped").unwrap();
+	let output = child.wait_with_output().unwrap();
+	assert_success(&output);
+	assert_eq!(stdout(&output), "piped");
+
+	let output = qga.run(["exec", "-e", "FOO=bar", "--", "/bin/sh", "-c", "echo $FOO"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "bar\n");
+}
+
+/// Starts a long running guest process, then interrupts the `qga exec` waiting on it
+fn exec_interrupted(qga: &Harness<qemucomm::mock::MockQga>) -> (std::process::Output, i64) {
+	let child = qga.spawn(["exec", "sleep", "30"]);
+	wait_until(|| qga.mock.received().iter().any(|r| r.command == "guest-exec-status"));
+	let pid = qga.mock.received().iter()
+		.find(|r| r.command == "guest-exec-status")
+		.and_then(|r| r.arguments["pid"].as_i64())
+		.unwrap();
+	let status = Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+	assert!(status.success());
+	(child.wait_with_output().unwrap(), pid)
+}
+
+fn kill_args(qga: &Harness<qemucomm::mock::MockQga>, path: &str) -> Vec<String> {
+	let kill = qga.mock.received().into_iter()
+		.find(|r| r.command == "guest-exec" && r.str("path") == Some(path))
+		.unwrap_or_else(|| panic!("{} was never executed", path));
+	kill.arguments["arg"].as_array().unwrap().iter()
+		.map(|a| a.as_str().unwrap().to_owned())
+		.collect()
+}
+
+#[test]
+fn exec_ctrlc() {
+	let qga = Harness::qga(|mock| mock);
+	let (output, pid) = exec_interrupted(&qga);
+	// the guest process was terminated by the kill, and reported as interrupted
+	assert_eq!(output.status.code(), Some(128 + 2), "{}", stderr(&output));
+	assert!(commands(&qga).contains(&"guest-get-osinfo".to_owned()));
+	assert_eq!(kill_args(&qga, "kill"), [pid.to_string()]);
+}
+
+#[test]
+fn exec_ctrlc_windows() {
+	let qga = Harness::qga(|mock| mock.with_os_id("mswindows"));
+	let (output, pid) = exec_interrupted(&qga);
+	// there's no powershell on the host, so the kill fails
+	assert!(!output.status.success());
+	assert_eq!(kill_args(&qga, "powershell.exe"), ["-Command".to_owned(), format!("Stop-Process -Id {}", pid)]);
+}
+
+#[test]
+fn shutdown() {
+	let qga = Harness::qga(|mock| mock);
+	assert_success(&qga.run(["shutdown", "--mode", "reboot"]));
+	let shutdown = qga.mock.received().pop().unwrap();
+	assert_eq!(shutdown.command, "guest-shutdown");
+	assert_eq!(shutdown.str("mode"), Some("reboot"));
+}
@@ -0,0 +1,1183 @@
+use qapi::ErrorClass;
+use std::time::Duration;
+use qemucomm::mock::{MockQmp, Reply};
+
+mod common;
+use common::{Harness, assert_success, stdout, stderr};
+
+#[test]
+fn ping() {
+	let qmp = Harness::qmp(MockQmp::new());
+	assert_success(&qmp.run(["ping"]));
+	let received = qmp.mock.received();
+	assert_eq!(received.len(), 1);
+	assert_eq!(received[0].command, "qmp_capabilities");
+}
+
+#[test]
+fn status() {
+	let qmp = Harness::qmp(MockQmp::new().with_running(false));
+	let output = qmp.run(["status"]);
+	assert_success(&output);
+	assert!(stdout(&output).contains("paused"), "{}", stdout(&output));
+}
+
+#[test]
+fn status_output() {
+	let qmp = Harness::qmp(MockQmp::new().with_running(false));
+	let output = qmp.run(["status"]);
+	assert_eq!(stdout(&output).lines().collect::<Vec<_>>(), ["running     false", "singlestep  false", "status      paused"]);
+
+	let output = qmp.run(["--output", "json", "status"]);
+	assert_success(&output);
+	let status: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(status["status"], "paused");
+
+	let output = qmp.run(["status", "--output", "yaml"]);
+	assert_success(&output);
+	assert!(stdout(&output).contains("status: paused\n"), "{}", stdout(&output));
+}
+
+#[test]
+fn execute_human() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_reply("query-cpus-fast", serde_json::json!([
+			{ "cpu-index": 0, "props": { "core-id": 0 }, "thread-id": 100 },
+			{ "cpu-index": 1, "props": { "core-id": 1 }, "thread-id": 101 },
+		]))
+	);
+	let output = qmp.run(["--output", "human", "execute", "query-cpus-fast"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output).lines().collect::<Vec<_>>(), [
+		"CPU-INDEX  PROPS.CORE-ID  THREAD-ID",
+		"0          0              100",
+		"1          1              101",
+	]);
+}
+
+#[test]
+fn stop_cont() {
+	let qmp = Harness::qmp(MockQmp::new());
+	assert_success(&qmp.run(["stop"]));
+	assert!(!qmp.mock.running());
+	assert_success(&qmp.run(["cont"]));
+	assert!(qmp.mock.running());
+}
+
+#[test]
+fn quit() {
+	let qmp = Harness::qmp(MockQmp::new());
+	assert_success(&qmp.run(["quit"]));
+	assert_eq!(qmp.mock.received().last().unwrap().command, "quit");
+}
+
+#[test]
+fn hmp() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_reply("human-monitor-command", "VM status: running\r\n")
+	);
+	let output = qmp.run(["hmp", "info status"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output).trim_end(), "VM status: running");
+	assert_eq!(qmp.mock.received().last().unwrap().str("command-line"), Some("info status"));
+}
+
+#[test]
+fn hmp_interactive() {
+	use std::io::Write;
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_cpus(2)
+		.with_reply("human-monitor-command", "RAX=0000000000000000\r\n")
+	);
+	let history = qmp.dir.path().join("hmp_history");
+	let mut child = qmp.spawn(["hmp", "-i", "--history", history.to_str().unwrap()]);
+	child.stdin.take().unwrap().write_all(b"info registers\ncpu 1\ncpu 2\ninfo registers\ncpu default\ninfo status\n").unwrap();
+	let output = child.wait_with_output().unwrap();
+	assert_success(&output);
+	assert_eq!(stdout(&output).matches("RAX=0000000000000000\n").count(), 3, "{}", stdout(&output));
+	assert!(stderr(&output).contains("CPU 2 not found"), "{}", stderr(&output));
+
+	let commands: Vec<(Option<String>, Option<i64>)> = qmp.mock.received().into_iter()
+		.filter(|r| r.command == "human-monitor-command")
+		.map(|r| (r.str("command-line").map(Into::into), r.arguments.get("cpu-index").and_then(|c| c.as_i64())))
+		.collect();
+	// the first two are `help` and `info` for completion
+	assert_eq!(commands[2..], [
+		(Some("info registers".into()), None),
+		(Some("info registers".into()), Some(1)),
+		(Some("info status".into()), None),
+	]);
+	assert!(std::fs::read_to_string(&history).unwrap().contains("cpu 1"));
+}
+
+#[test]
+fn add_device_typed() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_properties("virtio-net-pci", &[("mac", "str"), ("vectors", "uint32"), ("mq", "bool")])
+	);
+	assert_success(&qmp.run(["add-device", "-i", "net0", "virtio-net-pci", "mac=52:54:00:12:34:56", "vectors=010", "mq=on", "serial:str=123"]));
+	assert_eq!(qmp.mock.devices()["net0"], "virtio-net-pci");
+	let props = qmp.mock.device_properties("net0").unwrap();
+	assert_eq!(props["mac"], "52:54:00:12:34:56");
+	assert_eq!(props["vectors"], 8);
+	assert_eq!(props["mq"], true);
+	assert_eq!(props["serial"], "123");
+}
+
+#[test]
+fn add_device_output() {
+	let qmp = Harness::qmp(MockQmp::new().with_device("net0", "e1000"));
+	let output = qmp.run(["--output", "json", "add-device", "--no-clobber", "-i", "net0", "virtio-net-pci"]);
+	assert_success(&output);
+	let added: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(added, serde_json::json!({ "id": "net0", "driver": "virtio-net-pci", "added": false, "replaced": false }));
+
+	let output = qmp.run(["add-device", "-i", "net1", "virtio-net-pci"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "added     true\ndriver    virtio-net-pci\nid        net1\nreplaced  false\n");
+
+	// nothing to say to humans once it's gone
+	let output = qmp.run(["del-device", "net1"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn add_device_duplicate() {
+	let qmp = Harness::qmp(MockQmp::new().with_device("net0", "e1000"));
+	let output = qmp.run(["add-device", "-i", "net0", "virtio-net-pci"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("Duplicate device ID 'net0'"), "{}", stderr(&output));
+	assert_eq!(qmp.mock.devices()["net0"], "e1000");
+}
+
+#[test]
+fn add_device_force() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_device("net0", "e1000")
+		.with_unplug_delay(Some(Duration::from_millis(200)))
+	);
+	assert_success(&qmp.run(["add-device", "--force", "-i", "net0", "virtio-net-pci"]));
+	assert_eq!(qmp.mock.devices()["net0"], "virtio-net-pci");
+	let commands: Vec<_> = qmp.mock.received().into_iter()
+		.map(|r| r.command)
+		.filter(|c| c.starts_with("device_"))
+		.collect();
+	assert_eq!(commands, ["device_del", "device_add"]);
+}
+
+#[test]
+fn add_device_no_clobber() {
+	let qmp = Harness::qmp(MockQmp::new().with_device("net0", "e1000"));
+	assert_success(&qmp.run(["add-device", "--no-clobber", "-i", "net0", "virtio-net-pci"]));
+	assert_eq!(qmp.mock.devices()["net0"], "e1000");
+	assert!(!qmp.mock.received().iter().any(|r| r.command == "device_add"));
+}
+
+#[test]
+fn del_device_wait() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_device("net0", "e1000")
+		.with_unplug_delay(Some(Duration::from_millis(200)))
+	);
+	assert_success(&qmp.run(["del-device", "--wait", "net0"]));
+	assert!(qmp.mock.devices().is_empty());
+}
+
+#[test]
+fn del_device_missing() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["del-device", "net0"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("Device 'net0' not found"), "{}", stderr(&output));
+}
+
+#[test]
+fn object() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_properties("memory-backend-ram", &[("size", "size"), ("share", "bool")])
+	);
+	assert_success(&qmp.run(["add-object", "-i", "mem0", "qom-type=memory-backend-ram", "size=1G", "share=yes"]));
+	assert_eq!(qmp.mock.objects()["mem0"], "memory-backend-ram");
+	let props = qmp.mock.object_properties("mem0").unwrap();
+	assert_eq!(props["size"], 1u64 << 30);
+	assert_eq!(props["share"], true);
+
+	assert_success(&qmp.run(["del-object", "mem0"]));
+	assert!(qmp.mock.objects().is_empty());
+}
+
+#[test]
+fn execute() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_reply("query-kvm", serde_json::json!({ "enabled": true, "present": true }))
+	);
+	let output = qmp.run(["execute", "query-kvm"]);
+	assert_success(&output);
+	let value: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(value["enabled"], true);
+}
+
+#[test]
+fn execute_arguments() {
+	let qmp = Harness::qmp(MockQmp::new().with_reply("x-test", Reply::empty()));
+	assert_success(&qmp.run(["execute", "x-test", "a.b=1", "list.0=x", "list.1=y"]));
+	let received = qmp.mock.received().pop().unwrap();
+	assert_eq!(serde_json::Value::Object(received.arguments), serde_json::json!({
+		"a": { "b": 1 },
+		"list": ["x", "y"],
+	}));
+}
+
+#[test]
+fn execute_error() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_error("query-kvm", ErrorClass::DeviceNotActive, "no KVM here")
+	);
+	let output = qmp.run(["execute", "query-kvm"]);
+	assert_eq!(output.status.code(), Some(5));
+	assert!(stderr(&output).contains("no KVM here"), "{}", stderr(&output));
+
+	let output = qmp.run(["execute", "query-nothing"]);
+	assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn events() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let child = qmp.spawn(["events", "-f", "STOP", "-f", "RESUME", "--count", "2"]);
+	qmp.wait_negotiated(1);
+	qmp.mock.emit("STOP", None);
+	qmp.mock.emit("POWERDOWN", None);
+	qmp.mock.emit("RESUME", None);
+
+	let output = child.wait_with_output().unwrap();
+	assert_success(&output);
+	let names: Vec<String> = stdout(&output).lines()
+		.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["event"].as_str().unwrap().to_owned())
+		.collect();
+	assert_eq!(names, ["STOP", "RESUME"]);
+}
+
+#[test]
+fn events_until_filtered() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let child = qmp.spawn(["events", "-f", "BLOCK_JOB_COMPLETED", "--until", "SHUTDOWN"]);
+	qmp.wait_negotiated(1);
+	qmp.mock.emit("BLOCK_JOB_COMPLETED", Some(serde_json::json!({ "type": "mirror", "device": "job0", "len": 0, "offset": 0, "speed": 0 })));
+	qmp.mock.emit("STOP", None);
+	qmp.mock.emit("SHUTDOWN", Some(serde_json::json!({ "guest": true, "reason": "guest-shutdown" })));
+
+	let output = child.wait_with_output().unwrap();
+	assert_success(&output);
+	let names: Vec<String> = stdout(&output).lines()
+		.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["event"].as_str().unwrap().to_owned())
+		.collect();
+	assert_eq!(names, ["BLOCK_JOB_COMPLETED", "SHUTDOWN"]);
+}
+
+#[test]
+fn wait_event() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let child = qmp.spawn(["wait-event", "DEVICE_DELETED", "-m", "device=net1", "--timeout", "10"]);
+	qmp.wait_negotiated(1);
+	qmp.mock.emit("DEVICE_DELETED", Some(serde_json::json!({ "device": "net0", "path": "/machine/peripheral/net0" })));
+	qmp.mock.emit("DEVICE_DELETED", Some(serde_json::json!({ "device": "net1", "path": "/machine/peripheral/net1" })));
+
+	let output = child.wait_with_output().unwrap();
+	assert_success(&output);
+	let event: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(event["data"]["device"], "net1");
+}
+
+#[test]
+fn wait_event_timeout() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["wait-event", "SHUTDOWN", "--timeout", "1"]);
+	assert!(!output.status.success());
+}
+
+fn shell(qmp: &Harness<MockQmp>, args: &[&str], input: &str) -> std::process::Output {
+	use std::io::Write;
+	let mut child = qmp.spawn(["shell"].iter().chain(args));
+	child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+	child.wait_with_output().unwrap()
+}
+
+#[test]
+fn shell_commands() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = shell(&qmp, &["--no-history"], concat!(
+		"query-status\n",
+		"{\"execute\": \"stop\"}\n",
+		"human-monitor-command command-line=\"123\" cpu-index=1\n",
+		"query-nothing\n",
+		"help human-monitor-command\n",
+		"exit\n",
+		"cont\n",
+	));
+	assert_success(&output);
+	assert!(stdout(&output).contains("\"running\": true"), "{}", stdout(&output));
+	assert!(stdout(&output).contains("cpu-index?: int"), "{}", stdout(&output));
+	assert!(stderr(&output).contains("CommandNotFound"), "{}", stderr(&output));
+	assert!(!qmp.mock.running());
+
+	// arguments are typed by the schema, rather than guessed
+	let hmp = qmp.mock.received().into_iter().find(|r| r.command == "human-monitor-command").unwrap();
+	assert_eq!(serde_json::Value::Object(hmp.arguments), serde_json::json!({
+		"command-line": "123",
+		"cpu-index": 1,
+	}));
+	assert!(!qmp.mock.received().iter().any(|r| r.command == "cont"));
+}
+
+#[test]
+fn shell_history() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let history = qmp.dir.path().join("state/history");
+	let history_arg = history.to_str().unwrap();
+	assert_success(&shell(&qmp, &["--history", history_arg], "query-status\n\nstop\n"));
+	assert_success(&shell(&qmp, &["--history", history_arg], "cont\n"));
+	let lines: Vec<String> = std::fs::read_to_string(&history).unwrap().lines()
+		.filter(|l| !l.starts_with('#'))
+		.map(Into::into)
+		.collect();
+	assert_eq!(lines, ["query-status", "stop", "cont"]);
+}
+
+#[test]
+fn shell_events() {
+	use std::io::{BufRead, BufReader, Write};
+	let qmp = Harness::qmp(MockQmp::new());
+	let mut child = qmp.spawn(["shell", "--no-history"]);
+	let mut stdin = child.stdin.take().unwrap();
+	stdin.write_all(b"stop\n").unwrap();
+	// the event arrives while the shell waits for another line
+	let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+	let event = lines.by_ref()
+		.filter_map(|line| serde_json::from_str::<serde_json::Value>(&line.unwrap()).ok())
+		.find(|line| line.get("event").is_some())
+		.unwrap();
+	assert_eq!(event["event"], "STOP");
+	drop(stdin);
+	// keep stdout open until the shell exits so it never writes to a closed pipe
+	lines.for_each(drop);
+	assert_success(&child.wait_with_output().unwrap());
+}
+
+fn drives() -> MockQmp {
+	MockQmp::new()
+		.with_drive("cd0", "ide1-cd0")
+		.with_medium("cd0", "/isos/install.iso")
+		.with_drive("fd0", "floppy0")
+}
+
+#[test]
+fn media_list() {
+	let qmp = Harness::qmp(drives());
+	let output = qmp.run(["media", "list"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output).lines().collect::<Vec<_>>(), [
+		"DRIVE  BACKEND   TRAY    LOCKED  MEDIUM             FORMAT  RO",
+		"cd0    ide1-cd0  closed  false   /isos/install.iso  raw     true",
+		"fd0    floppy0   closed  false   -                  -       -",
+	]);
+
+	let output = qmp.run(["--output", "json", "media", "list"]);
+	assert_success(&output);
+	let drives: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(drives[0]["inserted"]["file"], "/isos/install.iso");
+	assert_eq!(drives[1]["device"], "floppy0");
+}
+
+#[test]
+fn media_change() {
+	let qmp = Harness::qmp(drives());
+	assert_success(&qmp.run(["media", "change", "ide1-cd0", "/isos/live.iso", "-F", "raw", "--read-only-mode", "read-write"]));
+	assert_eq!(qmp.mock.medium("cd0").as_deref(), Some("/isos/live.iso"));
+	assert_eq!(qmp.mock.tray_open("cd0"), Some(false));
+	let change = qmp.mock.received().into_iter().find(|r| r.command == "blockdev-change-medium").unwrap();
+	assert_eq!(change.str("id"), Some("cd0"));
+	assert_eq!(change.str("read-only-mode"), Some("read-write"));
+}
+
+#[test]
+fn media_eject_insert() {
+	let qmp = Harness::qmp(drives().with_node("iso1", "raw", "/isos/drivers.iso"));
+	assert_success(&qmp.run(["media", "eject", "cd0"]));
+	assert_eq!(qmp.mock.medium("cd0"), None);
+	assert_eq!(qmp.mock.tray_open("cd0"), Some(true));
+
+	assert_success(&qmp.run(["media", "insert", "cd0", "iso1"]));
+	assert_eq!(qmp.mock.medium("cd0").as_deref(), Some("/isos/drivers.iso"));
+	assert_eq!(qmp.mock.tray_open("cd0"), Some(false));
+
+	let output = qmp.run(["media", "insert", "fd0", "iso1"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("already in use"), "{}", stderr(&output));
+	assert_eq!(qmp.mock.tray_open("fd0"), Some(true));
+}
+
+#[test]
+fn media_eject_locked() {
+	let qmp = Harness::qmp(drives()
+		.with_tray_lock("cd0", Some(Duration::from_millis(200)))
+	);
+	assert_success(&qmp.run(["media", "eject", "cd0", "--timeout", "10"]));
+	assert_eq!(qmp.mock.medium("cd0"), None);
+	assert_eq!(qmp.mock.tray_open("cd0"), Some(true));
+}
+
+#[test]
+fn media_eject_locked_timeout() {
+	let qmp = Harness::qmp(drives().with_tray_lock("cd0", None));
+	let output = qmp.run(["media", "eject", "cd0", "--timeout", "1"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("didn't open the tray of cd0"), "{}", stderr(&output));
+	assert!(qmp.mock.medium("cd0").is_some());
+
+	assert_success(&qmp.run(["media", "eject", "cd0", "--force"]));
+	assert_eq!(qmp.mock.medium("cd0"), None);
+}
+
+#[test]
+fn media_missing() {
+	let qmp = Harness::qmp(drives());
+	let output = qmp.run(["media", "eject", "cd1"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("drive cd1 not found"), "{}", stderr(&output));
+}
+
+#[test]
+fn add_disk() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["--output", "json", "add-disk", "disk0", "/images/root.qcow2", "--cache", "none", "--aio", "native", "--discard", "unmap", "serial=abc"]);
+	assert_success(&output);
+	let disk: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(disk["nodes"], serde_json::json!(["disk0-file", "disk0-format"]));
+	assert_eq!(qmp.mock.nodes().into_iter().collect::<Vec<_>>(), [
+		("disk0-file".to_owned(), "file".to_owned()),
+		("disk0-format".to_owned(), "qcow2".to_owned()),
+	]);
+	assert_eq!(qmp.mock.devices()["disk0"], "virtio-blk-pci");
+	let props = qmp.mock.device_properties("disk0").unwrap();
+	assert_eq!(props["drive"], "disk0-format");
+	assert_eq!(props["write-cache"], "on");
+	assert_eq!(props["serial"], "abc");
+
+	let adds: Vec<_> = qmp.mock.received().into_iter()
+		.filter(|r| r.command == "blockdev-add")
+		.map(|r| serde_json::Value::Object(r.arguments))
+		.collect();
+	assert_eq!(adds, [
+		serde_json::json!({
+			"driver": "file",
+			"node-name": "disk0-file",
+			"filename": "/images/root.qcow2",
+			"aio": "native",
+			"cache": { "direct": true, "no-flush": false },
+			"discard": "unmap",
+		}),
+		serde_json::json!({
+			"driver": "qcow2",
+			"node-name": "disk0-format",
+			"file": "disk0-file",
+			"cache": { "direct": true, "no-flush": false },
+			"discard": "unmap",
+		}),
+	]);
+}
+
+#[test]
+fn add_disk_rollback() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_error("device_add", ErrorClass::GenericError, "Bus 'pci.9' not found")
+	);
+	let output = qmp.run(["add-disk", "disk0", "/images/data.img", "--bus", "pci.9"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("Bus 'pci.9' not found"), "{}", stderr(&output));
+	assert!(qmp.mock.nodes().is_empty());
+	let deleted: Vec<_> = qmp.mock.received().into_iter()
+		.filter(|r| r.command == "blockdev-del")
+		.map(|r| r.str("node-name").unwrap().to_owned())
+		.collect();
+	assert_eq!(deleted, ["disk0-format", "disk0-file"]);
+}
+
+#[test]
+fn del_disk() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_unplug_delay(Some(Duration::from_millis(200)))
+	);
+	let output = qmp.run(["add-disk", "disk0", "/images/data.img", "--format", "raw", "--read-only"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "id     disk0\nnodes  disk0-file, disk0-format\n");
+	assert_eq!(qmp.mock.nodes()["disk0-format"], "raw");
+	// the nodes are in use until the device is gone
+	let output = qmp.run(["execute", "blockdev-del", "node-name=disk0-format"]);
+	assert!(!output.status.success());
+
+	assert_success(&qmp.run(["del-disk", "disk0", "--timeout", "10"]));
+	assert!(qmp.mock.devices().is_empty());
+	assert!(qmp.mock.nodes().is_empty());
+}
+
+fn disks() -> Harness<MockQmp> {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_node("data", "qcow2", "/images/data.qcow2")
+		.with_snapshot("data", "old", 0)
+	);
+	assert_success(&qmp.run(["add-disk", "disk0", "/images/root.qcow2"]));
+	assert_success(&qmp.run(["add-disk", "disk1", "/images/ro.img", "--read-only"]));
+	qmp
+}
+
+#[test]
+fn snapshot_save() {
+	let qmp = disks();
+	let output = qmp.run(["--output", "json", "snapshot", "save", "first"]);
+	assert_success(&output);
+	let job: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(job["type"], "snapshot-save");
+	assert_eq!(job["status"], "concluded");
+	// only writable disks are included by default
+	let save = qmp.mock.received().into_iter().find(|r| r.command == "snapshot-save").unwrap();
+	assert_eq!(save.arguments["devices"], serde_json::json!(["disk0-format"]));
+	assert_eq!(save.str("vmstate"), Some("disk0-format"));
+	assert_eq!(qmp.mock.snapshots("disk0-format"), ["first"]);
+	// and the job is dismissed
+	let output = qmp.run(["execute", "query-jobs"]);
+	assert_eq!(stdout(&output).trim(), "[]");
+
+	let output = qmp.run(["snapshot", "save", "first"]);
+	assert_eq!(output.status.code(), Some(3));
+	assert!(stderr(&output).contains("already exists"), "{}", stderr(&output));
+}
+
+#[test]
+fn snapshot_timeout_cancels() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_node("data", "qcow2", "/images/data.qcow2")
+		.with_stalled_jobs()
+	);
+	let output = qmp.run(["snapshot", "save", "first", "--device", "data", "--timeout", "1"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("snapshot-save of first didn't finish"), "{}", stderr(&output));
+	let commands: Vec<_> = qmp.mock.received().into_iter().map(|r| r.command).collect();
+	assert!(commands.ends_with(&["job-cancel".to_owned(), "query-jobs".to_owned(), "job-dismiss".to_owned()]), "{:?}", commands);
+	let output = qmp.run(["execute", "query-jobs"]);
+	assert_eq!(stdout(&output).trim(), "[]");
+}
+
+#[test]
+fn snapshot_list() {
+	let qmp = disks();
+	assert_success(&qmp.run(["snapshot", "save", "first"]));
+	let output = qmp.run(["snapshot", "list"]);
+	assert_success(&output);
+	let text = stdout(&output);
+	let lines: Vec<_> = text.lines().map(|l| l.split_whitespace().collect::<Vec<_>>()).collect();
+	assert_eq!(lines.len(), 3, "{}", text);
+	assert_eq!(lines[0], ["TAG", "DATE", "VM", "STATE", "VM", "CLOCK", "DEVICES"]);
+	assert_eq!(lines[1][0], "first");
+	assert_eq!(lines[1][3..], ["1.0", "MiB", "00:00:00.000", "disk0-format"]);
+	assert_eq!(lines[2], ["old", "2023-11-14", "22:13:20", "-", "00:00:00.000", "data"]);
+
+	let output = qmp.run(["--output", "json", "snapshot", "list"]);
+	let snapshots: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(snapshots[0]["vmstate"], "disk0-format");
+	assert_eq!(snapshots[1]["devices"], serde_json::json!(["data"]));
+}
+
+#[test]
+fn snapshot_load_delete() {
+	let qmp = disks();
+	assert_success(&qmp.run(["snapshot", "save", "first"]));
+	assert_success(&qmp.run(["snapshot", "load", "first"]));
+	let load = qmp.mock.received().into_iter().find(|r| r.command == "snapshot-load").unwrap();
+	assert_eq!(load.str("vmstate"), Some("disk0-format"));
+
+	// a snapshot without VM state can't be loaded
+	let output = qmp.run(["snapshot", "load", "old", "--vmstate", "data"]);
+	assert_eq!(output.status.code(), Some(3));
+	assert!(stderr(&output).contains("snapshot-load of old failed"), "{}", stderr(&output));
+
+	assert_success(&qmp.run(["snapshot", "delete", "first"]));
+	assert!(qmp.mock.snapshots("disk0-format").is_empty());
+	let output = qmp.run(["snapshot", "delete", "first"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("snapshot first not found"), "{}", stderr(&output));
+}
+
+#[test]
+fn migrate() {
+	let qmp = Harness::qmp(MockQmp::new().with_migration(4 << 30, 4));
+	let output = qmp.run(["migrate", "tcp:dest:4444", "--multifd", "4", "--bandwidth", "1G", "--downtime", "300", "--interval", "10"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "migration completed in 0.4 s with 42 ms downtime\n");
+	let progress = stderr(&output);
+	assert!(progress.contains("active  1.0 GiB of 4.0 GiB (25%), 3.0 GiB remaining, dirty 1.0 MiB/s, 1000 Mbps, expected downtime 300 ms"), "{}", progress);
+	assert!(progress.lines().last().unwrap().starts_with("completed  4.0 GiB of 4.0 GiB (100%)"), "{}", progress);
+
+	assert_eq!(qmp.mock.migration(), Some(("completed".into(), "tcp:dest:4444".into())));
+	assert!(qmp.mock.migrate_capabilities()["multifd"]);
+	assert_eq!(serde_json::Value::Object(qmp.mock.migrate_parameters()), serde_json::json!({
+		"multifd-channels": 4,
+		"max-bandwidth": 1u64 << 30,
+		"downtime-limit": 300,
+	}));
+	assert!(!qmp.mock.running());
+}
+
+#[test]
+fn migrate_json() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["--output", "json", "migrate", "unix:/tmp/migrate.sock", "--interval", "10"]);
+	assert_success(&output);
+	let statuses: Vec<String> = stdout(&output).lines()
+		.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["status"].as_str().unwrap().to_owned())
+		.collect();
+	assert_eq!(statuses.first().map(|s| &s[..]), Some("active"));
+	assert_eq!(statuses.last().map(|s| &s[..]), Some("completed"));
+}
+
+#[test]
+fn migrate_failed() {
+	let qmp = Harness::qmp(MockQmp::new().with_migration_error("Unable to write to socket: Broken pipe"));
+	let output = qmp.run(["migrate", "tcp:dest:4444", "--interval", "10"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("migration failed: Unable to write to socket: Broken pipe"), "{}", stderr(&output));
+}
+
+#[test]
+fn migrate_postcopy() {
+	let qmp = Harness::qmp(MockQmp::new().with_migration(1 << 30, 5));
+	assert_success(&qmp.run(["migrate", "tcp:dest:4444", "--postcopy", "--interval", "10"]));
+	assert!(qmp.mock.migrate_capabilities()["postcopy-ram"]);
+	assert!(qmp.mock.received().iter().any(|r| r.command == "migrate-start-postcopy"));
+}
+
+#[test]
+fn migrate_cancel_on_interrupt() {
+	let qmp = Harness::qmp(MockQmp::new().with_migration(1 << 30, 10000));
+	let child = qmp.spawn(["migrate", "tcp:dest:4444", "--cancel-on-interrupt", "--interval", "10"]);
+	common::wait_until(|| qmp.mock.received().iter().filter(|r| r.command == "query-migrate").count() >= 2);
+	let kill = std::process::Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+	assert!(kill.success());
+	let output = child.wait_with_output().unwrap();
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("migration was cancelled"), "{}", stderr(&output));
+	assert_eq!(qmp.mock.migration().unwrap().0, "cancelled");
+}
+
+#[test]
+fn migrate_incoming() {
+	let qmp = Harness::qmp(MockQmp::new().with_running(false));
+	let output = qmp.run(["migrate-incoming", "tcp:0:4444"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("'-incoming' was not specified"), "{}", stderr(&output));
+
+	let qmp = Harness::qmp(MockQmp::new().with_running(false).with_incoming());
+	let output = qmp.run(["migrate-incoming", "tcp:0:4444", "--multifd", "2", "--interval", "10"]);
+	assert_success(&output);
+	assert!(stdout(&output).starts_with("migration completed"), "{}", stdout(&output));
+	assert_eq!(qmp.mock.migration(), Some(("completed".into(), "tcp:0:4444".into())));
+	assert!(qmp.mock.migrate_capabilities()["multifd"]);
+}
+
+#[test]
+fn save_state() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["save-state", "/var/lib/vm/dev's.state.gz", "--interval", "10"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "VM state saved to /var/lib/vm/dev's.state.gz\n");
+	assert_eq!(qmp.mock.migration(), Some(("completed".into(), r"exec:gzip -c > '/var/lib/vm/dev'\''s.state.gz'".into())));
+	assert!(!qmp.mock.running());
+	assert!(qmp.mock.received().iter().any(|r| r.command == "quit"));
+
+	let qmp = Harness::qmp(MockQmp::new());
+	assert_success(&qmp.run(["save-state", "/var/lib/vm/dev.state", "--compress", "none", "--no-quit", "--interval", "10"]));
+	assert_eq!(qmp.mock.migration(), Some(("completed".into(), "file:/var/lib/vm/dev.state".into())));
+	assert!(!qmp.mock.received().iter().any(|r| r.command == "quit"));
+}
+
+#[test]
+fn save_state_comma() {
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["save-state", "/var/lib/vm/dev,offset=4096.state", "--interval", "10"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("the path contains a comma"), "{}", stderr(&output));
+	// the VM is left alone
+	assert!(qmp.mock.running());
+	assert!(!qmp.mock.received().iter().any(|r| r.command == "stop" || r.command == "migrate"));
+
+	let output = qmp.run(["restore-state", "/var/lib/vm/dev,1.state"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("the path contains a comma"), "{}", stderr(&output));
+	assert!(!qmp.mock.received().iter().any(|r| r.command == "migrate-incoming"));
+
+	// exec: URIs quote the path, so compressed files can have them
+	assert_success(&qmp.run(["save-state", "/var/lib/vm/dev,1.state.gz", "--interval", "10"]));
+	assert_eq!(qmp.mock.migration(), Some(("completed".into(), "exec:gzip -c > '/var/lib/vm/dev,1.state.gz'".into())));
+}
+
+#[test]
+fn save_state_failed() {
+	let qmp = Harness::qmp(MockQmp::new().with_migration_error("Unable to write to command: Broken pipe"));
+	let output = qmp.run(["save-state", "/var/lib/vm/dev.state", "--compress", "zstd", "--interval", "10"]);
+	assert!(!output.status.success());
+	let stderr = stderr(&output);
+	assert!(stderr.contains("failed to save the VM state to /var/lib/vm/dev.state, leaving the VM paused"), "{}", stderr);
+	assert!(stderr.contains("Broken pipe"), "{}", stderr);
+	assert!(!qmp.mock.running());
+	assert!(!qmp.mock.received().iter().any(|r| r.command == "quit"));
+}
+
+#[test]
+fn restore_state() {
+	let qmp = Harness::qmp(MockQmp::new().with_running(false).with_incoming());
+	let output = qmp.run(["restore-state", "/var/lib/vm/dev.state.xz", "--interval", "10"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "VM state restored from /var/lib/vm/dev.state.xz\n");
+	assert_eq!(qmp.mock.migration(), Some(("completed".into(), "exec:xz -T0 -dc < '/var/lib/vm/dev.state.xz'".into())));
+	assert!(qmp.mock.running());
+
+	let qmp = Harness::qmp(MockQmp::new().with_running(false));
+	let output = qmp.run(["restore-state", "/var/lib/vm/dev.state"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("leaving the VM paused"), "{}", stderr(&output));
+	assert!(!qmp.mock.running());
+}
+
+/// Decodes an RGB PNG into its size and pixels
+fn decode_png(data: &[u8]) -> ((u32, u32), Vec<u8>) {
+	let mut reader = png::Decoder::new(data).read_info().unwrap();
+	let mut pixels = vec![0; reader.output_buffer_size()];
+	let info = reader.next_frame(&mut pixels).unwrap();
+	assert_eq!(info.color_type, png::ColorType::Rgb);
+	pixels.truncate(info.buffer_size());
+	((info.width, info.height), pixels)
+}
+
+#[test]
+fn screenshot_ppm() {
+	let qmp = Harness::qmp(MockQmp::new().with_png(false).with_screen(Some((320, 200))));
+	let file = qmp.dir.path().join("screen.png");
+	assert_success(&qmp.run(["screenshot".as_ref(), file.as_os_str()]));
+	let (size, pixels) = decode_png(&std::fs::read(&file).unwrap());
+	assert_eq!(size, (320, 200));
+	assert_eq!(&pixels[..3], &[0, 0, 40]);
+	let last = &pixels[pixels.len() - 3..];
+	assert_eq!(last, &[(319 * 255 / 320) as u8, (199 * 255 / 200) as u8, 40]);
+
+	let dump = qmp.mock.received().into_iter().find(|r| r.command == "screendump").unwrap();
+	assert!(dump.str("format").is_none());
+	assert!(dump.str("filename").unwrap().ends_with(".ppm"));
+	// it's written to a private directory of its own, which is removed afterwards
+	let dir = std::path::Path::new(dump.str("filename").unwrap()).parent().unwrap();
+	assert_ne!(dir, std::env::temp_dir());
+	assert!(!dir.exists());
+}
+
+#[test]
+fn screenshot_dump_dir() {
+	let qmp = Harness::qmp(MockQmp::new().with_screen(Some((16, 16))));
+	let dumps = qmp.dir.path().join("dumps");
+	std::fs::create_dir(&dumps).unwrap();
+	let output = qmp.run(["screenshot".as_ref(), "--dump-dir".as_ref(), dumps.as_os_str()]);
+	assert_success(&output);
+	let (size, _) = decode_png(&output.stdout);
+	assert_eq!(size, (16, 16));
+
+	// QEMU writes into a file created for it in the shared directory, which is removed afterwards
+	let dump = qmp.mock.received().into_iter().find(|r| r.command == "screendump").unwrap();
+	let path = std::path::Path::new(dump.str("filename").unwrap());
+	assert_eq!(path.parent(), Some(dumps.as_path()));
+	assert!(std::fs::read_dir(&dumps).unwrap().next().is_none());
+
+	let output = qmp.run(["screenshot".as_ref(), "--dump-dir".as_ref(), qmp.dir.path().join("missing").as_os_str()]);
+	assert!(stderr(&output).contains("failed to create a screendump file"), "{}", stderr(&output));
+}
+
+#[test]
+fn screenshot_png_stdout() {
+	let qmp = Harness::qmp(MockQmp::new().with_device("video1", "virtio-gpu-pci"));
+	let output = qmp.run(["screenshot", "--device", "video1"]);
+	assert_success(&output);
+	let (size, _) = decode_png(&output.stdout);
+	assert_eq!(size, (640, 480));
+	let dump = qmp.mock.received().into_iter().find(|r| r.command == "screendump").unwrap();
+	assert_eq!(dump.str("format"), Some("png"));
+	assert_eq!(dump.str("device"), Some("video1"));
+
+	let output = qmp.run(["screenshot", "--device", "video0"]);
+	assert_eq!(output.status.code(), Some(1));
+	assert!(stderr(&output).contains("Device 'video0' not found"), "{}", stderr(&output));
+
+	let qmp = Harness::qmp(MockQmp::new().with_screen(None));
+	let output = qmp.run(["screenshot", "-"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("There is no QemuConsole"), "{}", stderr(&output));
+}
+
+#[test]
+fn screenshot_every() {
+	let qmp = Harness::qmp(MockQmp::new().with_screen(Some((16, 16))));
+	let file = qmp.dir.path().join("boot.png");
+	let output = qmp.run(["--output".as_ref(), "json".as_ref(), "screenshot".as_ref(), file.as_os_str(), "--every".as_ref(), "0.01".as_ref(), "--count".as_ref(), "3".as_ref()]);
+	assert_success(&output);
+	let files: Vec<String> = stdout(&output).lines()
+		.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["file"].as_str().unwrap().to_owned())
+		.collect();
+	let expected: Vec<String> = (1..=3)
+		.map(|i| qmp.dir.path().join(format!("boot-{:04}.png", i)).to_string_lossy().into_owned())
+		.collect();
+	assert_eq!(files, expected);
+	let shades: Vec<u8> = files.iter()
+		.map(|file| decode_png(&std::fs::read(file).unwrap()).1[2])
+		.collect();
+	assert_eq!(shades, [40, 80, 120]);
+
+	let output = qmp.run(["screenshot", "--every", "0"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("expected a positive number of seconds"), "{}", stderr(&output));
+}
+
+/// Encodes the part of the mock screen at `(x, y)` as it looks after `shade / 40` screendumps
+fn mock_screen_png((width, height): (u32, u32), (x, y, w, h): (u32, u32, u32, u32), shade: u8) -> Vec<u8> {
+	let pixels: Vec<u8> = (y..y + h).flat_map(|y| (x..x + w).flat_map(move |x| [
+		(x * 255 / width) as u8,
+		(y * 255 / height) as u8,
+		shade,
+	])).collect();
+	let mut data = Vec::new();
+	let mut encoder = png::Encoder::new(&mut data, w, h);
+	encoder.set_color(png::ColorType::Rgb);
+	encoder.set_depth(png::BitDepth::Eight);
+	encoder.write_header().unwrap().write_image_data(&pixels).unwrap();
+	data
+}
+
+#[test]
+fn wait_screen() {
+	let qmp = Harness::qmp(MockQmp::new().with_screen(Some((64, 48))));
+	let needle = qmp.dir.path().join("needle.png");
+	std::fs::write(&needle, mock_screen_png((64, 48), (8, 8, 16, 16), 120)).unwrap();
+	let output = qmp.run(["wait-screen".as_ref(), "--reference".as_ref(), needle.as_os_str(), "--region".as_ref(), "8,8,16,16".as_ref(), "--interval".as_ref(), "10".as_ref()]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), format!("matched {} (1.000)\n", needle.display()));
+	assert_eq!(qmp.mock.received().iter().filter(|r| r.command == "screendump").count(), 3);
+
+	// a full screenshot as the reference, with the region cut out of it
+	let qmp = Harness::qmp(MockQmp::new().with_png(false).with_screen(Some((64, 48))));
+	let reference = qmp.dir.path().join("reference.png");
+	let mut screen = decode_png(&mock_screen_png((64, 48), (0, 0, 64, 48), 80));
+	// spoil most of the screen outside of the region
+	for pixel in screen.1.chunks_mut(3).skip(48 * 64 / 2) {
+		pixel.copy_from_slice(&[255, 255, 255]);
+	}
+	let mut data = Vec::new();
+	let mut encoder = png::Encoder::new(&mut data, 64, 48);
+	encoder.set_color(png::ColorType::Rgb);
+	encoder.set_depth(png::BitDepth::Eight);
+	encoder.write_header().unwrap().write_image_data(&screen.1).unwrap();
+	std::fs::write(&reference, data).unwrap();
+	let output = qmp.run(["--output".as_ref(), "json".as_ref(), "wait-screen".as_ref(), "-r".as_ref(), reference.as_os_str(), "--region".as_ref(), "0,0,64,16".as_ref(), "--interval".as_ref(), "10".as_ref()]);
+	assert_success(&output);
+	let found: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(found, serde_json::json!({ "similarity": 1.0, "screenshots": 2 }));
+}
+
+#[test]
+fn wait_screen_timeout() {
+	let qmp = Harness::qmp(MockQmp::new().with_screen(Some((64, 48))));
+	let needle = qmp.dir.path().join("needle.png");
+	std::fs::write(&needle, mock_screen_png((64, 48), (0, 0, 64, 48), 140)).unwrap();
+	let output = qmp.run(["wait-screen".as_ref(), "-r".as_ref(), needle.as_os_str(), "--threshold".as_ref(), "0.5".as_ref(), "--tolerance".as_ref(), "2".as_ref(), "--interval".as_ref(), "50".as_ref(), "--timeout".as_ref(), "1".as_ref()]);
+	assert!(!output.status.success());
+	let message = stderr(&output);
+	assert!(message.contains(&format!("screen didn't match {} within 1 s", needle.display())), "{}", message);
+	assert!(message.contains("matched 0.000"), "{}", message);
+
+	let output = qmp.run(["wait-screen".as_ref(), "-r".as_ref(), needle.as_os_str(), "--region".as_ref(), "60,40,16,16".as_ref()]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("region 60,40,16,16 doesn't fit"), "{}", stderr(&output));
+}
+
+fn keys(events: &[(&str, bool)]) -> Vec<(String, bool)> {
+	events.iter().map(|&(key, down)| (key.to_owned(), down)).collect()
+}
+
+#[test]
+fn send_key() {
+	let qmp = Harness::qmp(MockQmp::new());
+	assert_success(&qmp.run(["send-key", "ctrl-alt-delete", "Enter", "ctrl--", "0x1d", "--hold-time", "10", "--delay", "0"]));
+	assert_eq!(qmp.mock.key_events(), keys(&[
+		("ctrl", true), ("alt", true), ("delete", true), ("delete", false), ("alt", false), ("ctrl", false),
+		("ret", true), ("ret", false),
+		("ctrl", true), ("minus", true), ("minus", false), ("ctrl", false),
+		("0x1d", true), ("0x1d", false),
+	]));
+	let hold_times: Vec<_> = qmp.mock.received().into_iter()
+		.filter(|r| r.command == "send-key")
+		.map(|r| r.arguments["hold-time"].clone())
+		.collect();
+	assert_eq!(hold_times, [10, 10, 10, 10]);
+
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["send-key", "ret", "ctrl-banana"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("unknown key banana"), "{}", stderr(&output));
+	assert!(qmp.mock.key_events().is_empty());
+}
+
+#[test]
+fn type_text() {
+	let qmp = Harness::qmp(MockQmp::new());
+	assert_success(&qmp.run(["type", r"Hi! a\tb\n", "--hold-time", "0", "--delay", "0"]));
+	assert_eq!(qmp.mock.key_events(), keys(&[
+		("shift", true), ("h", true), ("h", false), ("shift", false),
+		("i", true), ("i", false),
+		("shift", true), ("1", true), ("1", false), ("shift", false),
+		("spc", true), ("spc", false),
+		("a", true), ("a", false),
+		("tab", true), ("tab", false),
+		("b", true), ("b", false),
+		("ret", true), ("ret", false),
+	]));
+
+	let qmp = Harness::qmp(MockQmp::new());
+	let mut child = qmp.command();
+	child.args(["type", "--layout", "de", "--raw", "--hold-time", "0", "--delay", "0"])
+		.stdin(std::process::Stdio::piped())
+		.stdout(std::process::Stdio::piped())
+		.stderr(std::process::Stdio::piped());
+	let mut child = child.spawn().unwrap();
+	std::io::Write::write_all(child.stdin.as_mut().unwrap(), "zY@\\ä".as_bytes()).unwrap();
+	assert_success(&child.wait_with_output().unwrap());
+	assert_eq!(qmp.mock.key_events(), keys(&[
+		("y", true), ("y", false),
+		("shift", true), ("z", true), ("z", false), ("shift", false),
+		("alt_r", true), ("q", true), ("q", false), ("alt_r", false),
+		("alt_r", true), ("minus", true), ("minus", false), ("alt_r", false),
+		("apostrophe", true), ("apostrophe", false),
+	]));
+
+	let qmp = Harness::qmp(MockQmp::new());
+	let output = qmp.run(["type", "secret ä"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("can't type 'ä' with the us layout"), "{}", stderr(&output));
+	assert!(qmp.mock.key_events().is_empty());
+	let output = qmp.run(["type", r"C:\Windows"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains(r"unknown escape \W"), "{}", stderr(&output));
+}
+
+#[test]
+fn mouse() {
+	let qmp = Harness::qmp(MockQmp::new().with_screen(Some((641, 481))));
+	assert_success(&qmp.run(["mouse", "move", "320", "240"]));
+	assert_eq!(qmp.mock.received().iter().filter(|r| r.command == "screendump").count(), 1);
+	assert_success(&qmp.run(["mouse", "move", "0.25", "1.0"]));
+	assert_success(&qmp.run(["mouse", "move", "--relative", "-10", "5"]));
+	assert_eq!(qmp.mock.received().iter().filter(|r| r.command == "screendump").count(), 1);
+	assert_eq!(qmp.mock.pointer_events(), [
+		"abs x 16383", "abs y 16383",
+		"abs x 8192", "abs y 32767",
+		"rel x -10", "rel y 5",
+	]);
+
+	let qmp = Harness::qmp(MockQmp::new().with_screen(Some((641, 481))));
+	assert_success(&qmp.run(["mouse", "click", "right", "--double", "--delay", "0"]));
+	assert_success(&qmp.run(["mouse", "scroll", "down", "2", "--delay", "0"]));
+	assert_success(&qmp.run(["mouse", "drag", "0", "0", "640", "480", "--steps", "2", "--delay", "0"]));
+	assert_eq!(qmp.mock.pointer_events(), [
+		"btn right down", "btn right up", "btn right down", "btn right up",
+		"btn wheel-down down", "btn wheel-down up", "btn wheel-down down", "btn wheel-down up",
+		"abs x 0", "abs y 0", "btn left down",
+		"abs x 16383", "abs y 16383",
+		"abs x 32767", "abs y 32767", "btn left up",
+	]);
+
+	let output = qmp.run(["mouse", "move", "700", "10"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("position is off the screen"), "{}", stderr(&output));
+	let output = qmp.run(["mouse", "click", "--device", "video0"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("Device 'video0' not found"), "{}", stderr(&output));
+}
+
+#[test]
+fn input() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_name("desktop")
+		.with_properties("input-linux", &[("evdev", "str"), ("grab_all", "bool"), ("repeat", "bool"), ("grab-toggle", "GrabToggleKeys")])
+		.with_object("mem0", "memory-backend-ram")
+	);
+	assert_success(&qmp.run(["add-object", "-i", "kbd", "qom-type=input-linux", "evdev=/dev/input/by-id/kbd-event-kbd", "grab_all=on", "grab-toggle=scrolllock"]));
+	assert_success(&qmp.run(["add-object", "-i", "mouse", "qom-type=input-linux", "evdev=/dev/input/by-id/mouse-event-mouse"]));
+	let state = qmp.dir.path().join("state");
+	let input = |args: &[&str]| {
+		let output = qmp.command().arg("input").args(args).env("XDG_STATE_HOME", &state).output().unwrap();
+		assert_success(&output);
+		stdout(&output)
+	};
+
+	let list = input(&["list"]);
+	assert!(list.lines().nth(1).unwrap().split_whitespace().eq(["kbd", "attached", "/dev/input/by-id/kbd-event-kbd", "true", "-", "scrolllock"]), "{}", list);
+	assert!(!list.contains("mem0"), "{}", list);
+
+	assert_eq!(input(&["toggle"]), "ID     STATE\nkbd    detached\nmouse  detached\n");
+	assert_eq!(qmp.mock.objects().keys().collect::<Vec<_>>(), ["mem0"]);
+	let objects: serde_json::Value = serde_json::from_str(&input(&["--output", "json", "list"])).unwrap();
+	assert_eq!(objects, serde_json::json!([
+		{ "id": "kbd", "attached": false, "evdev": "/dev/input/by-id/kbd-event-kbd", "grab_all": true, "grab-toggle": "scrolllock" },
+		{ "id": "mouse", "attached": false, "evdev": "/dev/input/by-id/mouse-event-mouse" },
+	]));
+	assert!(state.join("qemucomm/input-linux.json").exists());
+
+	assert_eq!(input(&["attach", "kbd"]), "ID   STATE\nkbd  attached\n");
+	let props = qmp.mock.object_properties("kbd").unwrap();
+	assert_eq!(props["evdev"], "/dev/input/by-id/kbd-event-kbd");
+	assert_eq!(props["grab_all"], true);
+	assert_eq!(props["grab-toggle"], "scrolllock");
+	// any attached device is detached, to hand the host back its keyboard
+	assert_eq!(input(&["toggle"]), "ID     STATE\nkbd    detached\nmouse  detached\n");
+	assert_eq!(input(&["toggle", "mouse"]), "ID     STATE\nmouse  attached\n");
+	assert_eq!(qmp.mock.objects()["mouse"], "input-linux");
+	assert_eq!(input(&["detach"]), "ID     STATE\nmouse  detached\n");
+	assert_eq!(input(&["attach"]), "ID     STATE\nkbd    attached\nmouse  attached\n");
+
+	let output = qmp.run(["input", "detach", "tablet", "--state", state.join("other.json").to_str().unwrap()]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("no input-linux object tablet"), "{}", stderr(&output));
+}
+
+#[test]
+fn qom() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_properties("virtio-net-pci", &[("mac", "str"), ("vectors", "uint32"), ("mq", "bool")])
+		.with_object("mem0", "memory-backend-ram")
+	);
+	assert_success(&qmp.run(["add-device", "-i", "net0", "virtio-net-pci", "mac=52:54:00:12:34:56", "vectors=8", "mq=off"]));
+
+	let output = qmp.run(["qom", "ls", "/machine/peripheral"]);
+	assert_success(&output);
+	assert!(stdout(&output).lines().any(|line| line.split_whitespace().eq(["net0", "child<virtio-net-pci>"])), "{}", stdout(&output));
+
+	let output = qmp.run(["qom", "get", "/machine/peripheral/net0", "mac"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "52:54:00:12:34:56\n");
+
+	assert_success(&qmp.run(["qom", "set", "/machine/peripheral/net0", "mq", "on"]));
+	assert_success(&qmp.run(["qom", "set", "/machine/peripheral/net0", "vectors", "0x10"]));
+	let props = qmp.mock.device_properties("net0").unwrap();
+	assert_eq!(props["mq"], true);
+	assert_eq!(props["vectors"], 16);
+	let output = qmp.run(["qom", "set", "/machine/peripheral/net0", "mq", "on", "--type", "str"]);
+	assert!(!output.status.success());
+	assert!(stderr(&output).contains("Invalid parameter type for 'mq'"), "{}", stderr(&output));
+
+	let output = qmp.run(["qom", "tree", "--props"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), concat!(
+		"/ (container)\n",
+		"  machine (container)\n",
+		"    peripheral (container)\n",
+		"      net0 (virtio-net-pci)\n",
+		"        addr = 24\n",
+		"        mac = 52:54:00:12:34:56\n",
+		"        mq = true\n",
+		"        parent_bus = /machine/i440fx/pci.0\n",
+		"        realized = true\n",
+		"        vectors = 16\n",
+		"    peripheral-anon (container)\n",
+		"  objects (container)\n",
+		"    mem0 (memory-backend-ram)\n",
+		"      realized = true\n",
+	));
+
+	let output = qmp.run(["--output", "json", "qom", "tree", "/machine", "--depth", "1"]);
+	assert_success(&output);
+	let tree: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(tree, serde_json::json!({
+		"name": "/machine",
+		"type": "container",
+		"children": [
+			{ "name": "peripheral", "type": "container" },
+			{ "name": "peripheral-anon", "type": "container" },
+		],
+	}));
+}
+
+#[test]
+fn qom_tree_skips_vanished_children() {
+	// the root lists a device that's gone by the time it's walked
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_reply_once("qom-list", serde_json::json!([
+			{ "name": "type", "type": "string" },
+			{ "name": "machine", "type": "child<container>" },
+			{ "name": "gone", "type": "child<virtio-net-pci>" },
+		]))
+	);
+	let output = qmp.run(["qom", "tree", "--depth", "1"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), "/ (container)\n  machine (container)\n");
+}
+
+#[test]
+fn devices() {
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_properties("virtio-net-pci", &[("addr", "int32"), ("mac", "str"), ("mq", "bool"), ("romfile", "str")])
+		.with_properties("virtio-rng-pci", &[("addr", "int32"), ("max-bytes", "uint64")])
+		.with_device("serial0", "isa-serial")
+	);
+	assert_success(&qmp.run(["add-device", "-i", "net0", "virtio-net-pci", "addr=05.0", "mac=52:54:00:12:34:56", "mq=on", "romfile="]));
+	assert_success(&qmp.run(["add-device", "virtio-rng-pci", "max-bytes=1024"]));
+	assert_success(&qmp.run(["add-device", "virtio-rng-pci"]));
+
+	let output = qmp.run(["devices"]);
+	assert_success(&output);
+	let table = stdout(&output);
+	let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split_whitespace().collect()).collect();
+	assert_eq!(lines, [
+		vec!["ID", "TYPE", "BUS", "PCI", "PROPERTIES"],
+		vec!["net0", "virtio-net-pci", "pci.0", "00:05.0", "mac=52:54:00:12:34:56", "mq=on"],
+		vec!["serial0", "isa-serial", "-", "-"],
+		vec!["-", "virtio-rng-pci", "pci.0", "00:03.0", "max-bytes=1024"],
+		vec!["-", "virtio-rng-pci", "pci.0", "00:04.0"],
+	]);
+
+	let output = qmp.run(["--output", "json", "devices"]);
+	assert_success(&output);
+	let devices: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+	assert_eq!(devices[0], serde_json::json!({
+		"id": "net0",
+		"path": "/machine/peripheral/net0",
+		"type": "virtio-net-pci",
+		"bus": "pci.0",
+		"addr": "05.0",
+		"pci": "00:05.0",
+		"properties": { "mac": "52:54:00:12:34:56", "mq": true },
+	}));
+	assert_eq!(devices[2]["id"], serde_json::Value::Null);
+
+	let output = qmp.run(["devices", "--export"]);
+	assert_success(&output);
+	assert_eq!(stdout(&output), concat!(
+		"-i net0 --bus pci.0 virtio-net-pci addr=05.0 mac=52:54:00:12:34:56 mq=on\n",
+		"-i serial0 isa-serial\n",
+		"--bus pci.0 virtio-rng-pci addr=03.0 max-bytes=1024\n",
+		"--bus pci.0 virtio-rng-pci addr=04.0\n",
+	));
+
+	// exported devices can be added back as they were
+	let export = stdout(&output);
+	let qmp = Harness::qmp(MockQmp::new()
+		.with_properties("virtio-net-pci", &[("addr", "int32"), ("mac", "str"), ("mq", "bool"), ("romfile", "str")])
+	);
+	assert_success(&qmp.run(Some("add-device").into_iter().chain(export.lines().next().unwrap().split(' '))));
+	let props = qmp.mock.device_properties("net0").unwrap();
+	assert_eq!(props["addr"], 5 << 3);
+	assert_eq!(props["mq"], true);
+}